@@ -1,14 +1,34 @@
-use std::{
-    env,
-    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+use std::{env, process::ExitCode};
+
+use tp2::{
+    auth::operator_signer,
+    constants::TIMEOUT,
+    errors::{self, ConfigError, Error},
+    local_server::server::operator_addr,
+    operator::OperatorSocket,
 };
 
-fn main() {
-    let socket = UdpSocket::bind("127.0.0.1:5555").expect("Error when binding server socket");
-    let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+const USAGE: &str = "down <shop_id>";
+
+fn main() -> ExitCode {
+    errors::report(run())
+}
+
+fn run() -> Result<(), Error> {
     let args: Vec<String> = env::args().collect();
-    let addr = SocketAddr::new(ip, 3234 + args[1].parse::<u16>().unwrap());
-    socket
-        .send_to("DOWN".as_bytes(), addr)
-        .expect("Error sending message to server");
+    if args.len() != 2 {
+        return Err(ConfigError::Usage(USAGE).into());
+    }
+    let shop_id = match args[1].parse::<u32>() {
+        Ok(shop_id) => shop_id,
+        Err(_) => {
+            return Err(ConfigError::InvalidArgument {
+                name: "shop_id",
+                value: args[1].clone(),
+            }
+            .into())
+        }
+    };
+    let socket = OperatorSocket::bind(operator_signer()?, TIMEOUT)?;
+    socket.send(operator_addr(shop_id), "DOWN")
 }
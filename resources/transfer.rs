@@ -0,0 +1,48 @@
+use std::{env, process::ExitCode};
+
+use tp2::{
+    auth::operator_signer,
+    constants::{TIMEOUT, TRANSFER_TIMEOUT},
+    errors::{self, ConfigError, Error, TransportError},
+    local_server::server::operator_addr,
+    operator::OperatorSocket,
+};
+
+const USAGE: &str = "transfer <shop_id> <target_id>";
+
+fn main() -> ExitCode {
+    errors::report(run())
+}
+
+fn run() -> Result<(), Error> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        return Err(ConfigError::Usage(USAGE).into());
+    }
+    let (shop_id, target) = match (args[1].parse::<u32>(), args[2].parse::<u32>()) {
+        (Ok(shop_id), Ok(target)) => (shop_id, target),
+        (Err(_), _) => {
+            return Err(ConfigError::InvalidArgument {
+                name: "shop_id",
+                value: args[1].clone(),
+            }
+            .into())
+        }
+        (_, Err(_)) => {
+            return Err(ConfigError::InvalidArgument {
+                name: "target_id",
+                value: args[2].clone(),
+            }
+            .into())
+        }
+    };
+    let operation = format!("TRANSFER {}", target);
+    let socket = OperatorSocket::bind(operator_signer()?, TRANSFER_TIMEOUT + TIMEOUT)?;
+    socket.send(operator_addr(shop_id), &operation)?;
+    match socket.receive() {
+        Ok(answer) => println!("{} -> {}", operation, answer),
+        Err(Error::Transport(TransportError::Timeout)) => println!("{} -> timeout", operation),
+        Err(err) => return Err(err),
+    }
+    Ok(())
+}
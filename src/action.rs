@@ -1,5 +1,8 @@
-use crate::payment_method::Method;
-#[derive(PartialEq)]
+use crate::{
+    payment_method::Method,
+    wire::{Account, HistoryEntry, OfflineOperation, PNCounter, ServerStatus},
+};
+#[derive(Debug, Clone, PartialEq)]
 pub enum Action {
     Block(u32, u32),
     CompleteOrder(u32, u32, Method, u32),
@@ -11,7 +14,52 @@ pub enum Action {
     Try,
     Up,
     Down,
-    Sync(u32),
-    SyncStart,
-    SyncEnd,
+    Sync(u64),
+    SyncChunk(u64, Vec<String>),
+    SyncEnd(u64),
+    Snapshot(u64, u32, u32, Vec<Account>),
+    SnapshotRequest(u64, u32),
+    Offline(u32, u64, OfflineOperation),
+    OfflineEnd(u32),
+    OfflineAck(u32, usize),
+    OfflineRedeem(u32, u32, u32),
+    History(u32, Option<u64>, Option<u64>),
+    HistoryPart(u32, u32, u32, Vec<HistoryEntry>),
+    Grant(u32, u32, u32, u32, String),
+    Deduct(u32, u32, u32, u32, String),
+    Refund(u32, u32, u32, u32, String),
+    Reverse(u32, u32, u32, u32, i32, String),
+    Transfer(u32),
+    TakeOver(u64),
+    Alive(u32),
+    PartitionSync(u32),
+    PartitionState(u32, u32, u32, Vec<Account>),
+    Balance(u32, Option<u64>),
+    BalanceState(u32, i32, bool, u64, u64),
+    Settle(u32, u32, u32, u64),
+    Accruals(Vec<(u32, PNCounter)>),
+    Digest(u64, Vec<u64>),
+    Repair(Vec<u32>),
+    RepairState(u64, u32, u32, Vec<Account>),
+    State,
+    StatePart(u32, u64, ServerStatus, u32, u32, Vec<Account>),
+}
+
+impl Action {
+    /// Returns the client of the account the action operates on, if it has one.
+    pub fn client_id(&self) -> Option<u32> {
+        match self {
+            Action::Block(client_id, _)
+            | Action::CompleteOrder(client_id, ..)
+            | Action::FailOrder(client_id, _)
+            | Action::OfflineRedeem(client_id, ..)
+            | Action::Grant(client_id, ..)
+            | Action::Deduct(client_id, ..)
+            | Action::Refund(client_id, ..)
+            | Action::Reverse(client_id, ..)
+            | Action::Balance(client_id, _)
+            | Action::Settle(client_id, ..) => Some(*client_id),
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,50 @@
+use std::{env, process::ExitCode};
+
+use tp2::{
+    action::Action,
+    auth::{KeyId, KeyStore},
+    constants::{KEYS_FILE, TIMEOUT},
+    errors::{self, ConfigError, Error},
+    local_server::server::operator_addr,
+    message_parser::MessageParser,
+    operator::OperatorSocket,
+};
+
+const USAGE: &str =
+    "admin <shop_id> grant|deduct <client_id> <points> <shop> <operator_id> <reason...>
+       admin <shop_id> refund <client_id> <order_id> <shop> <operator_id> <reason...>";
+
+/// Sends the operation signed with the key of its operator to the control socket of the server,
+/// which forwards it to the leader, and returns the answer of the leader.
+fn send_operation(shop_id: u32, operator: u32, operation: &str) -> Result<String, Error> {
+    let signer = KeyStore::from_file(KEYS_FILE)?.signer(KeyId::Operator(operator))?;
+    let socket = OperatorSocket::bind(signer, TIMEOUT)?;
+    socket.send(operator_addr(shop_id), operation)?;
+    socket.receive()
+}
+
+fn main() -> ExitCode {
+    errors::report(run())
+}
+
+fn run() -> Result<(), Error> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 8 || !["grant", "deduct", "refund"].contains(&args[2].as_str()) {
+        return Err(ConfigError::Usage(USAGE).into());
+    }
+    let shop_id = match args[1].parse::<u32>() {
+        Ok(shop_id) => shop_id,
+        Err(_) => return Err(ConfigError::Usage(USAGE).into()),
+    };
+    let operation = args[2..].join(" ");
+    let operator = match MessageParser::parse(operation.clone()) {
+        Ok(Action::Grant(_, _, _, operator, _))
+        | Ok(Action::Deduct(_, _, _, operator, _))
+        | Ok(Action::Refund(_, _, _, operator, _)) => operator,
+        _ => return Err(ConfigError::Usage(USAGE).into()),
+    };
+
+    let answer = send_operation(shop_id, operator, &operation)?;
+    println!("[ADMIN]: {} -> {}", operation, answer);
+    Ok(())
+}
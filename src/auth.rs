@@ -0,0 +1,384 @@
+use std::{
+    collections::HashMap,
+    env, fmt,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::{
+    clock::now_millis,
+    constants::{AUTH_WINDOW, KEYS_FILE, OPERATOR_VAR},
+    errors::{ConfigError, Error, ParseError, StorageError, TransportError},
+    metadata,
+};
+
+/// Key of the metadata with the id of the key that signed a message.
+pub const KEY: &str = "key";
+
+/// Key of the metadata with the milliseconds since the unix epoch when a message was signed.
+pub const TIMESTAMP: &str = "ts";
+
+/// Key of the metadata with the random number that makes each signed message unique.
+pub const NONCE: &str = "nonce";
+
+/// Key of the metadata with the HMAC-SHA256 of the rest of a message, in hexadecimal.
+pub const MAC: &str = "mac";
+
+/// Max bytes [`Signer::sign`] appends to a message: the longest key id, timestamp and nonce,
+/// and the 64 hexadecimal digits of the HMAC.
+pub const SIGNATURE_BYTES: usize = " @key=machine:4294967295:4294967295 @ts=18446744073709551615 \
+     @nonce=18446744073709551615 @mac="
+    .len()
+    + 64;
+
+/// Identifies a shared key: the key of the server of a shop, the key of a coffee machine
+/// of a shop, or the key of an operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyId {
+    Shop(u32),
+    Machine(u32, u32),
+    Operator(u32),
+}
+
+impl fmt::Display for KeyId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyId::Shop(shop_id) => write!(f, "shop:{}", shop_id),
+            KeyId::Machine(shop_id, machine_id) => write!(f, "machine:{}:{}", shop_id, machine_id),
+            KeyId::Operator(operator_id) => write!(f, "operator:{}", operator_id),
+        }
+    }
+}
+
+impl FromStr for KeyId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(':').collect();
+        let ids: Vec<u32> = match fields[1..].iter().map(|id| id.parse::<u32>()).collect() {
+            Ok(ids) => ids,
+            Err(_) => return Err(ParseError::InvalidMessage(s.to_string()).into()),
+        };
+        match (fields[0], ids.as_slice()) {
+            ("shop", [shop_id]) => Ok(KeyId::Shop(*shop_id)),
+            ("machine", [shop_id, machine_id]) => Ok(KeyId::Machine(*shop_id, *machine_id)),
+            ("operator", [operator_id]) => Ok(KeyId::Operator(*operator_id)),
+            _ => Err(ParseError::InvalidMessage(s.to_string()).into()),
+        }
+    }
+}
+
+/// Shared keys of the shops, their coffee machines and the operators.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct KeyStore {
+    pub shops: HashMap<u32, String>,
+    /// Keys of the coffee machines of each shop, by machine id.
+    pub machines: HashMap<u32, HashMap<u32, String>>,
+    pub operators: HashMap<u32, String>,
+}
+
+impl KeyStore {
+    /// Parses a [`KeyStore`] from its json representation.
+    pub fn from_json(keys: &str) -> Result<KeyStore, Error> {
+        match serde_json::from_str::<KeyStore>(keys) {
+            Ok(keys) => Ok(keys),
+            Err(err) => Err(ParseError::InvalidJson(err.into()).into()),
+        }
+    }
+
+    /// Reads the keys from a file of the resources directory.
+    pub fn from_file(filename: &str) -> Result<KeyStore, Error> {
+        let path = Path::new("resources/").join(filename);
+        match std::fs::read_to_string(&path) {
+            Ok(keys) => KeyStore::from_json(&keys),
+            Err(err) => Err(StorageError::FileNotFound {
+                path: path.display().to_string(),
+                cause: err.into(),
+            }
+            .into()),
+        }
+    }
+
+    /// Returns the key with the given id, if there is one.
+    pub fn key(&self, id: KeyId) -> Option<&String> {
+        match id {
+            KeyId::Shop(shop_id) => self.shops.get(&shop_id),
+            KeyId::Machine(shop_id, machine_id) => self.machines.get(&shop_id)?.get(&machine_id),
+            KeyId::Operator(operator_id) => self.operators.get(&operator_id),
+        }
+    }
+
+    /// Returns the signer of the messages sent with the key of the given id.
+    pub fn signer(&self, id: KeyId) -> Result<Signer, Error> {
+        match self.key(id) {
+            Some(key) => Ok(Signer {
+                id,
+                key: key.clone(),
+            }),
+            None => Err(ConfigError::MissingKey(id.to_string()).into()),
+        }
+    }
+}
+
+/// Returns the signer of the operator of the [`OPERATOR_VAR`] variable, or of the operator 0,
+/// with the key of the keys file.
+pub fn operator_signer() -> Result<Signer, Error> {
+    let operator_id = env::var(OPERATOR_VAR)
+        .ok()
+        .and_then(|id| id.parse::<u32>().ok())
+        .unwrap_or(0);
+    KeyStore::from_file(KEYS_FILE)?.signer(KeyId::Operator(operator_id))
+}
+
+/// Signs the messages sent with a shared key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signer {
+    id: KeyId,
+    key: String,
+}
+
+impl Signer {
+    /// Returns the message with the id of the key, the time, a nonce and the HMAC
+    /// of all of them appended as metadata.
+    pub fn sign(&self, message: &str) -> String {
+        let nonce: u64 = rand::thread_rng().gen();
+        let message = metadata::with(message, KEY, &self.id.to_string());
+        let message = metadata::with(&message, TIMESTAMP, &now_millis().to_string());
+        let message = metadata::with(&message, NONCE, &nonce.to_string());
+        let mac = hmac(&self.key, &message).finalize().into_bytes();
+        let mac: String = mac.iter().map(|byte| format!("{:02x}", byte)).collect();
+        metadata::with(&message, MAC, &mac)
+    }
+}
+
+/// Checks the signature of the messages received and rejects the replayed ones.
+/// A message is accepted once, and only within [`AUTH_WINDOW`] of the time it was signed.
+/// It is shared by the threads of a server: the signatures are checked in parallel,
+/// and only the nonces accepted are behind a lock.
+#[derive(Debug, Default)]
+pub struct Authenticator {
+    keys: KeyStore,
+    /// Nonces accepted within the window, by key, with the time of their messages.
+    seen: Mutex<HashMap<KeyId, HashMap<u64, u64>>>,
+    rejected: AtomicU64,
+}
+
+impl Authenticator {
+    /// Creates an [`Authenticator`] of the messages signed with the given keys.
+    pub fn new(keys: KeyStore) -> Authenticator {
+        Authenticator {
+            keys,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the amount of messages rejected.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    /// Returns the id of the key that signed the message and the message without its signature.
+    /// Returns error if the key is not allowed, the signature does not match,
+    /// or the message is out of the window or was already accepted.
+    pub fn verify(
+        &self,
+        message: &str,
+        allowed: impl Fn(KeyId) -> bool,
+    ) -> Result<(KeyId, String), Error> {
+        let verified = self.verify_at(message, allowed, now_millis());
+        if verified.is_err() {
+            self.rejected.fetch_add(1, Ordering::SeqCst);
+        }
+        verified
+    }
+
+    fn verify_at(
+        &self,
+        message: &str,
+        allowed: impl Fn(KeyId) -> bool,
+        now: u64,
+    ) -> Result<(KeyId, String), Error> {
+        let (signed, mac) = match message.rsplit_once(&format!(" @{}=", MAC)) {
+            Some(signature) => signature,
+            None => return Err(TransportError::Unauthenticated.into()),
+        };
+        let id = match metadata::get(signed, KEY).map(|id| id.parse::<KeyId>()) {
+            Some(Ok(id)) if allowed(id) => id,
+            _ => return Err(TransportError::Unauthenticated.into()),
+        };
+        let key = match self.keys.key(id) {
+            Some(key) => key,
+            None => return Err(TransportError::Unauthenticated.into()),
+        };
+        match decode_hex(mac) {
+            Some(mac) if hmac(key, signed).verify_slice(&mac).is_ok() => (),
+            _ => return Err(TransportError::Unauthenticated.into()),
+        }
+
+        let (timestamp, nonce) = match (
+            metadata::get(signed, TIMESTAMP).map(|ts| ts.parse::<u64>()),
+            metadata::get(signed, NONCE).map(|nonce| nonce.parse::<u64>()),
+        ) {
+            (Some(Ok(timestamp)), Some(Ok(nonce))) => (timestamp, nonce),
+            _ => return Err(TransportError::Unauthenticated.into()),
+        };
+        let window = AUTH_WINDOW.as_millis() as u64;
+        if now.abs_diff(timestamp) > window {
+            return Err(TransportError::ReplayedMessage.into());
+        }
+        let mut seen = match self.seen.lock() {
+            Ok(seen) => seen,
+            Err(_) => return Err(TransportError::Unauthenticated.into()),
+        };
+        let seen = seen.entry(id).or_default();
+        seen.retain(|_, seen_at| now.abs_diff(*seen_at) <= window);
+        if seen.insert(nonce, timestamp).is_some() {
+            return Err(TransportError::ReplayedMessage.into());
+        }
+
+        // The signature is the last four metadata tokens of the message
+        let words: Vec<&str> = signed.split(' ').collect();
+        Ok((id, words[..words.len() - 3].join(" ")))
+    }
+}
+
+fn hmac(key: &str, message: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("Error creating the HMAC");
+    mac.update(message.as_bytes());
+    mac
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    fn keys() -> KeyStore {
+        KeyStore::from_json(
+            "{\"shops\": {\"0\": \"shop-key\"}, \"machines\": {\"0\": {\"1\": \"machine-key\"}}}",
+        )
+        .expect("The keys are invalid")
+    }
+
+    fn any(_: KeyId) -> bool {
+        true
+    }
+
+    #[test]
+    fn test01_signed_message_is_verified() {
+        let signer = keys()
+            .signer(KeyId::Machine(0, 1))
+            .expect("There is no key");
+        let auth = Authenticator::new(keys());
+
+        let message = signer.sign("complete 123 10 cash 0 @machine=1");
+
+        assert_eq!(
+            auth.verify(&message, any),
+            Ok((
+                KeyId::Machine(0, 1),
+                "complete 123 10 cash 0 @machine=1".to_string()
+            ))
+        );
+        assert_eq!(auth.rejected(), 0);
+    }
+
+    #[test]
+    fn test02_tampered_or_unsigned_messages_are_rejected() {
+        let signer = keys().signer(KeyId::Shop(0)).expect("There is no key");
+        let auth = Authenticator::new(keys());
+        let message = signer.sign("complete 123 10 cash 0");
+
+        let tampered = message.replacen("10", "100000", 1);
+
+        assert_eq!(
+            auth.verify(&tampered, any),
+            Err(Error::Transport(TransportError::Unauthenticated))
+        );
+        assert_eq!(
+            auth.verify("DOWN", any),
+            Err(Error::Transport(TransportError::Unauthenticated))
+        );
+        assert_eq!(
+            auth.verify(&message, |id| id != KeyId::Shop(0)),
+            Err(Error::Transport(TransportError::Unauthenticated))
+        );
+        assert_eq!(auth.rejected(), 3);
+        assert!(keys().signer(KeyId::Shop(1)).is_err());
+    }
+
+    #[test]
+    fn test03_replayed_messages_are_rejected() {
+        let signer = keys().signer(KeyId::Shop(0)).expect("There is no key");
+        let auth = Authenticator::new(keys());
+        let message = signer.sign("DOWN");
+        let window = AUTH_WINDOW.as_millis() as u64;
+
+        assert!(auth.verify(&message, any).is_ok());
+        assert_eq!(
+            auth.verify(&message, any),
+            Err(Error::Transport(TransportError::ReplayedMessage))
+        );
+        assert_eq!(
+            auth.verify_at(&signer.sign("UP"), any, now_millis() + 2 * window),
+            Err(Error::Transport(TransportError::ReplayedMessage))
+        );
+    }
+
+    #[test]
+    fn test04_key_ids_round_trip() {
+        for id in [KeyId::Shop(3), KeyId::Machine(2, 1), KeyId::Operator(7)] {
+            assert_eq!(id.to_string().parse::<KeyId>(), Ok(id));
+        }
+        assert!("machine:2".parse::<KeyId>().is_err());
+    }
+
+    #[test]
+    fn test05_messages_are_verified_from_many_threads() {
+        let signer = keys().signer(KeyId::Shop(0)).expect("There is no key");
+        let auth = Arc::new(Authenticator::new(keys()));
+        let messages: Vec<String> = (0..100)
+            .map(|i| signer.sign(&format!("block {} 0", i)))
+            .collect();
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let (auth, messages) = (auth.clone(), messages.clone());
+                thread::spawn(move || {
+                    messages
+                        .iter()
+                        .filter(|message| auth.verify(message, any).is_ok())
+                        .count()
+                })
+            })
+            .collect();
+        let accepted: usize = threads
+            .into_iter()
+            .map(|thread| thread.join().expect("Error joining the thread"))
+            .sum();
+
+        assert_eq!(accepted, 100);
+        assert_eq!(auth.rejected(), 300);
+    }
+}
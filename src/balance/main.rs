@@ -0,0 +1,46 @@
+use std::{env, process::ExitCode};
+
+use tp2::{
+    action::Action,
+    errors::{self, ConfigError, Error, OrderError},
+    local_server::server::operator_addr,
+    message_parser::MessageParser,
+    operator,
+};
+
+const USAGE: &str = "balance <shop_id> <client_id> [max_lag]";
+
+fn main() -> ExitCode {
+    errors::report(run())
+}
+
+fn run() -> Result<(), Error> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 || args.len() > 4 {
+        return Err(ConfigError::Usage(USAGE).into());
+    }
+    let shop_id = match args[1].parse::<u32>() {
+        Ok(shop_id) => shop_id,
+        Err(_) => return Err(ConfigError::Usage(USAGE).into()),
+    };
+
+    let query = format!("balance {}", args[2..].join(" "));
+    if !matches!(MessageParser::parse(query.clone()), Ok(Action::Balance(..))) {
+        return Err(ConfigError::Usage(USAGE).into());
+    }
+    let answer = operator::query(operator_addr(shop_id), &query)?;
+    match MessageParser::parse(answer.clone()) {
+        Ok(Action::BalanceState(client_id, points, blocked, index, lag)) => {
+            println!(
+                "[BALANCE]: client {} has {} points{}, read at index {} with {} entries of lag",
+                client_id,
+                points,
+                if blocked { " (blocked)" } else { "" },
+                index,
+                lag
+            );
+            Ok(())
+        }
+        _ => Err(OrderError::UnexpectedAnswer(answer).into()),
+    }
+}
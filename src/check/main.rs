@@ -0,0 +1,135 @@
+use std::{env, fs, process::ExitCode};
+
+use tp2::{
+    errors::{self, ConfigError, Error, StorageError},
+    local_server::{
+        consistency::{divergences, repair_plan, ServerState},
+        operation_log::recover,
+        server::operator_addr,
+        snapshot::Snapshot,
+        sync::install_snapshot,
+    },
+    operator::request_state,
+    wire::{Account, ServerStatus},
+};
+
+const USAGE: &str = "check <shop_amount> [--plan] [--save <accounts.txt>]";
+
+/// Exit code when the servers do not agree on the accounts.
+const DIVERGENT: u8 = 1;
+
+/// Returns the accounts that result from applying the log of the leader,
+/// read from the files of the leader in the current directory.
+fn leader_accounts(leader: &ServerState) -> Result<Vec<Account>, Error> {
+    let path = format!("log_{}.txt", leader.shop);
+    // Recovering a missing log would create it
+    if let Err(err) = fs::metadata(&path) {
+        return Err(StorageError::FileNotFound {
+            path,
+            cause: err.into(),
+        }
+        .into());
+    }
+    let (log, points) = recover(&path, &format!("snapshot_{}.txt", leader.shop))?;
+    Ok(Snapshot::take(log.next_index(), &points).accounts)
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(DIVERGENT),
+        Err(err) => errors::report(Err(err)),
+    }
+}
+
+/// Compares the accounts of every server. Returns false if any client is divergent.
+fn run() -> Result<bool, Error> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        return Err(ConfigError::Usage(USAGE).into());
+    }
+    let shop_amount = match args[1].parse::<u32>() {
+        Ok(shop_amount) => shop_amount,
+        Err(_) => return Err(ConfigError::Usage(USAGE).into()),
+    };
+    let mut plan = false;
+    let mut save = None;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--plan" => plan = true,
+            "--save" => match options.next() {
+                Some(path) => save = Some(path.clone()),
+                None => return Err(ConfigError::Usage(USAGE).into()),
+            },
+            _ => return Err(ConfigError::Usage(USAGE).into()),
+        }
+    }
+
+    let mut states = vec![];
+    for shop_id in 0..shop_amount {
+        match request_state(operator_addr(shop_id)) {
+            Ok(state) => {
+                println!(
+                    "[CHECK]: shop {} ({}) at index {} with {} accounts",
+                    state.shop,
+                    state.status,
+                    state.index,
+                    state.accounts.len()
+                );
+                for (client, debt) in install_snapshot(&state.accounts).debts() {
+                    println!(
+                        "[DEBT]: shop {}: client {} owes {} points",
+                        state.shop, client, debt
+                    );
+                }
+                states.push(state);
+            }
+            Err(err) => println!("[CHECK]: shop {} did not answer: {}", shop_id, err),
+        }
+    }
+
+    let divergences = divergences(&states);
+    for divergence in &divergences {
+        println!("[DIVERGENT]: {}", divergence);
+    }
+    println!(
+        "[CHECK]: {} divergent clients in {} servers",
+        divergences.len(),
+        states.len()
+    );
+
+    if plan {
+        match states.iter().find(|s| s.status == ServerStatus::Leader) {
+            Some(leader) => {
+                let steps = repair_plan(&leader_accounts(leader)?, &states);
+                for step in &steps {
+                    println!("[PLAN]: {}", step);
+                }
+                println!(
+                    "[PLAN]: {} changes to match the log of the leader {}",
+                    steps.len(),
+                    leader.shop
+                );
+            }
+            None => println!("[PLAN]: no leader answered, there is no log to follow"),
+        }
+    }
+    if let Some(path) = save {
+        match states.iter().find(|s| s.status == ServerStatus::Leader) {
+            Some(leader) => {
+                let accounts = Snapshot {
+                    index: leader.index,
+                    accounts: leader.accounts.clone(),
+                };
+                accounts.write_atomic(&path)?;
+                println!(
+                    "[SAVE]: accounts of the leader {} at index {} saved in {}",
+                    leader.shop, leader.index, path
+                );
+            }
+            None => println!("[SAVE]: no leader answered, there are no accounts to save"),
+        }
+    }
+    Ok(divergences.is_empty())
+}
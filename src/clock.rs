@@ -0,0 +1,17 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Returns the milliseconds elapsed since the unix epoch.
+pub fn now_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+/// Returns the nanoseconds elapsed since the unix epoch.
+pub fn now_nanos() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_nanos() as u64,
+        Err(_) => 0,
+    }
+}
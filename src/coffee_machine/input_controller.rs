@@ -1,6 +1,9 @@
 use std::path::Path;
 
-use crate::{coffee_machine::orders::Order, errors::Error};
+use crate::{
+    coffee_machine::orders::Order,
+    errors::{ConfigError, Error, ParseError, StorageError},
+};
 
 #[derive(Clone, Debug)]
 pub struct InputController {
@@ -20,15 +23,21 @@ impl InputController {
     ) -> Result<InputController, Error> {
         let file = match file_input {
             Some(file) => file,
-            None => return Err(Error::NotFileInput),
+            None => return Err(ConfigError::MissingArgument("orders_file").into()),
         };
 
         let shop_id = match shop_id_input {
             Some(shop_id) => match shop_id.parse::<u32>() {
                 Ok(shop_id) => shop_id,
-                Err(_) => return Err(Error::InvalidShopId),
+                Err(_) => {
+                    return Err(ConfigError::InvalidArgument {
+                        name: "shop_id",
+                        value: shop_id,
+                    }
+                    .into())
+                }
             },
-            None => return Err(Error::NotShopIdInput),
+            None => return Err(ConfigError::MissingArgument("shop_id").into()),
         };
 
         Ok(InputController {
@@ -42,7 +51,7 @@ impl InputController {
     pub fn deserialize(self, orders: &str) -> Result<Vec<Order>, Error> {
         let result = match serde_json::from_str::<Vec<Order>>(orders) {
             Ok(orders) => orders,
-            Err(_) => return Err(Error::WrongFileFormat),
+            Err(err) => return Err(ParseError::InvalidJson(err.into()).into()),
         };
 
         Ok(result)
@@ -55,19 +64,28 @@ impl InputController {
         let file = Path::new(&self.filename);
         let path = dir.join(file);
 
-        let orders = match std::fs::read_to_string(path) {
+        let orders = match std::fs::read_to_string(&path) {
             Ok(orders) => orders,
-            Err(_e) => return Err(Error::FileNotFound),
+            Err(err) => {
+                return Err(StorageError::FileNotFound {
+                    path: path.display().to_string(),
+                    cause: err.into(),
+                }
+                .into())
+            }
         };
 
-        Ok(self.deserialize(&orders))?
+        self.deserialize(&orders)
     }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use crate::{coffee_machine::input_controller::InputController, errors::Error};
+    use crate::{
+        coffee_machine::input_controller::InputController,
+        errors::{ConfigError, Error, ParseError, StorageError},
+    };
 
     #[test]
     fn test01_get_a_valid_filename_and_shop_id() {
@@ -87,7 +105,7 @@ mod tests {
     fn test02_not_get_a_filename() {
         let result = InputController::new(None, Some("0".to_string()))
             .expect_err("You must enter a filename of the orders file");
-        let err_expected = Error::NotFileInput;
+        let err_expected = Error::Config(ConfigError::MissingArgument("orders_file"));
 
         assert_eq!(result, err_expected);
     }
@@ -96,7 +114,7 @@ mod tests {
     fn test03_not_get_a_shop_id() {
         let result = InputController::new(Some("orders.json".to_string()), None)
             .expect_err("You must enter a filename of the orders file");
-        let err_expected = Error::NotShopIdInput;
+        let err_expected = Error::Config(ConfigError::MissingArgument("shop_id"));
 
         assert_eq!(result, err_expected);
     }
@@ -109,9 +127,10 @@ mod tests {
         let result = controller
             .get_orders()
             .expect_err("The filename was not found");
-        let err_expected = Error::FileNotFound;
-
-        assert_eq!(result, err_expected);
+        assert!(matches!(
+            result,
+            Error::Storage(StorageError::FileNotFound { path, .. }) if path == "resources/pedidos.json"
+        ));
     }
 
     #[test]
@@ -123,9 +142,7 @@ mod tests {
         let result = controller
             .deserialize(&orders)
             .expect_err("The order doesnt have all the ingredients");
-        let err_expected = Error::WrongFileFormat;
-
-        assert_eq!(result, err_expected);
+        assert!(matches!(result, Error::Parse(ParseError::InvalidJson(_))));
     }
 
     #[test]
@@ -134,7 +151,10 @@ mod tests {
             InputController::new(Some("pedidos.json".to_string()), Some("aaaa".to_string()))
                 .expect_err("You must enter a valid shop id");
 
-        let err_expected = Error::InvalidShopId;
+        let err_expected = Error::Config(ConfigError::InvalidArgument {
+            name: "shop_id",
+            value: "aaaa".to_string(),
+        });
 
         assert_eq!(result, err_expected);
     }
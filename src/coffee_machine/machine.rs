@@ -4,10 +4,23 @@ use std::{
     net::{SocketAddr, UdpSocket},
     sync::Arc,
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
+use tracing::info;
 
-use crate::{coffee_machine::orders::Order, errors::Error, message_sender::MessageSender};
+use crate::{
+    auth::Signer,
+    clock::now_millis,
+    coffee_machine::orders::Order,
+    errors::{Error, OrderError},
+    linearizability::{self, Call, Operation, Reply},
+    logging::COFFEE_MACHINE,
+    message_sender::MessageSender,
+    metadata::{self, MACHINE, ORDER},
+    metrics::{message_type, registry, ORDER_LATENCY},
+    payment_method::Method,
+    trace::{Kind, Span, TraceContext},
+};
 
 const POINTS: &str = "points";
 const COMPLETED: bool = true;
@@ -25,6 +38,10 @@ pub struct CoffeeMachine {
     pub server_addr: SocketAddr,
     pub socket: Arc<UdpSocket>,
     pub shop_id: u32,
+    /// Signs the messages with the key of the coffee machine.
+    pub signer: Signer,
+    /// Context of the trace of the order being processed.
+    pub trace: Option<TraceContext>,
 }
 
 impl Actor for CoffeeMachine {
@@ -35,35 +52,91 @@ impl Handler<ProcessOrder> for CoffeeMachine {
     type Result = Result<(), Error>;
 
     fn handle(&mut self, msg: ProcessOrder, _ctx: &mut Self::Context) -> Self::Result {
-        let coffee_machine = self.clone();
-        let order = msg.order;
+        let started_at = Instant::now();
+        let mut span = Span::root("order", Kind::Internal);
+        span.attribute("shop.id", &self.shop_id.to_string());
+        span.attribute("machine.id", &self.id.to_string());
+        span.attribute("order.id", &msg.order.id.to_string());
+        self.trace = span.context().cloned();
+        let processed = self.process(msg.order);
+        let result = if processed.is_ok() { "ok" } else { "error" };
+        if processed.is_err() {
+            span.fail();
+        }
+        span.end();
+        registry().observe(
+            &ORDER_LATENCY,
+            &[("machine", &self.id.to_string()), ("result", result)],
+            started_at.elapsed().as_secs_f64(),
+        );
+        processed
+    }
+}
 
+impl CoffeeMachine {
+    /// Processes the order, blocking the account first if it is paid with points.
+    fn process(&mut self, order: Order) -> Result<(), Error> {
+        let id = self.id;
         if self.pay_with_points(order.clone()) {
-            self.handle_block_message(order.clone(), coffee_machine.id)?;
+            self.handle_block_message(order.clone(), id)?;
         }
 
-        self.handle_process_order(order, coffee_machine.id)?;
+        self.handle_process_order(order, id)?;
 
         Ok(())
     }
-}
 
-impl CoffeeMachine {
     /// Handles messages to server.
-    fn send_message(&mut self, message: String, id: u32) -> Result<(), Error> {
-        match MessageSender::send(
+    /// The message carries the ids of the coffee machine and the order,
+    /// and the context of the trace of the order, as metadata.
+    /// The call and its reply are recorded in the history of the process.
+    fn send_message(
+        &mut self,
+        message: String,
+        call: Call,
+        order: &Order,
+        id: u32,
+    ) -> Result<(), Error> {
+        let mut span = match &self.trace {
+            Some(order) => Span::child("send", Kind::Client, order),
+            None => Span::root("send", Kind::Client),
+        };
+        span.attribute("message.type", message_type(&message));
+        let message = metadata::with(&message, MACHINE, &id.to_string());
+        let message = metadata::with(&message, ORDER, &order.id.to_string());
+        let invoke = now_millis();
+        let sent = MessageSender::send(
             self.socket.clone(),
             self.server_addr,
-            message,
+            span.inject(&message),
             None,
             Some(Duration::new(5, 0)),
             id,
-        ) {
-            Ok(_) => (),
-            Err(err) => return Err(err),
+            &self.signer,
+        );
+        if let Err(err) = &sent {
+            span.attribute("error", &err.to_string());
+            span.fail();
         }
+        span.end();
+        let reply = match &sent {
+            Ok(true) => Some(Reply::Ack),
+            Err(Error::Order(OrderError::NotEnoughPoints(_))) => Some(Reply::NotEnoughPoints),
+            Err(Error::Order(OrderError::ClientAlreadyBlocked(_))) => Some(Reply::AlreadyBlocked),
+            _ => None,
+        };
+        linearizability::record(&Operation {
+            shop: self.shop_id,
+            machine: id,
+            order: order.id,
+            client: order.customer_id,
+            call,
+            invoke,
+            complete: now_millis(),
+            reply,
+        });
 
-        Ok(())
+        sent.map(|_| ())
     }
 
     /// Returns true if order's payment method is points.
@@ -85,14 +158,14 @@ impl CoffeeMachine {
     /// Handles BLOCK message.
     fn handle_block_message(&mut self, order: Order, id: u32) -> Result<(), Error> {
         let block_message = format!("block {} {}", order.customer_id, self.shop_id);
-        match self.send_message(block_message, id) {
+        match self.send_message(block_message, Call::Block, &order, id) {
             Ok(_) => (),
             Err(err) => match err {
-                Error::ClientAlreadyBlocked => {
+                Error::Order(OrderError::ClientAlreadyBlocked(_)) => {
                     sleep(Duration::from_secs(10));
                     self.handle_client_already_blocked(order, id)?;
                 }
-                _ => return Err(Error::InvalidMessage),
+                _ => return Err(err),
             },
         }
 
@@ -114,9 +187,12 @@ impl CoffeeMachine {
     /// Handles process order.
     fn handle_process_order(&mut self, order: Order, id: u32) -> Result<(), Error> {
         sleep(Duration::from_secs(3));
-        println!(
-            "[COFFEE MACHINE {}]: order {:?} already processed",
-            id, order.id
+        info!(
+            target: COFFEE_MACHINE,
+            shop = self.shop_id,
+            machine = id,
+            "order {} already processed",
+            order.id
         );
 
         if self.is_completed() {
@@ -134,7 +210,11 @@ impl CoffeeMachine {
             "complete {} {} cash {}",
             order.customer_id, order.price, self.shop_id
         );
-        self.send_message(complete_message, id)?;
+        let call = Call::Complete {
+            price: order.price,
+            method: Method::Cash,
+        };
+        self.send_message(complete_message, call, &order, id)?;
 
         Ok(())
     }
@@ -145,10 +225,21 @@ impl CoffeeMachine {
             "complete {} {} {} {}",
             order.customer_id, order.price, order.payment_method, self.shop_id
         );
-        match self.send_message(complete_message, id) {
+        let method = if self.pay_with_points(order.clone()) {
+            Method::Points
+        } else {
+            Method::Cash
+        };
+        let call = Call::Complete {
+            price: order.price,
+            method,
+        };
+        match self.send_message(complete_message, call, &order, id) {
             Ok(_) => (),
             Err(err) => match err {
-                Error::NotEnoughPoints => self.handle_not_enough_points(order, id)?,
+                Error::Order(OrderError::NotEnoughPoints(_)) => {
+                    self.handle_not_enough_points(order, id)?
+                }
                 _ => return Err(err),
             },
         }
@@ -159,7 +250,7 @@ impl CoffeeMachine {
     /// Handles FAIL message.
     fn handle_fail_message(&mut self, order: Order, id: u32) -> Result<(), Error> {
         let fail_message = format!("fail {} {}", order.customer_id, self.shop_id);
-        self.send_message(fail_message, id)?;
+        self.send_message(fail_message, Call::Fail, &order, id)?;
 
         Ok(())
     }
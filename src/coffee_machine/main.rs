@@ -2,38 +2,48 @@ use actix::{Actor, Addr};
 use actix_rt::System;
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    process::ExitCode,
     sync::Arc,
 };
 use tp2::{
+    auth::{KeyId, KeyStore},
     coffee_machine::{
         input_controller::InputController,
         machine::{CoffeeMachine, ProcessOrder},
     },
-    constants::COFFEE_MACHINES,
-    errors::Error,
+    constants::{COFFEE_MACHINES, COFFEE_MACHINE_METRICS_PORT, KEYS_FILE, RECORD_VAR},
+    errors::{self, Error, TransportError},
+    linearizability,
+    logging::{self, COFFEE_MACHINE},
+    metrics, trace,
 };
+use tracing::info;
 
 /// Creates a list of [`CoffeeMachine`].
+/// Each coffee machine signs its messages with its own key.
 fn get_coffee_machines(
     socket: Arc<UdpSocket>,
     addr: SocketAddr,
     shop_id: u32,
-) -> Vec<Addr<CoffeeMachine>> {
+    keys: &KeyStore,
+) -> Result<Vec<Addr<CoffeeMachine>>, Error> {
     let mut coffee_makers = Vec::new();
     for i in 0..COFFEE_MACHINES {
-        println!("[COFFEE MACHINE {:?}]: starting", i);
+        info!(target: COFFEE_MACHINE, shop = shop_id, machine = i, "starting");
         coffee_makers.push(
             CoffeeMachine {
                 id: i,
                 server_addr: addr,
                 socket: socket.clone(),
                 shop_id,
+                signer: keys.signer(KeyId::Machine(shop_id, i))?,
+                trace: None,
             }
             .start(),
         );
     }
 
-    coffee_makers
+    Ok(coffee_makers)
 }
 
 pub fn id_to_dataaddr(id: usize) -> SocketAddr {
@@ -41,37 +51,64 @@ pub fn id_to_dataaddr(id: usize) -> SocketAddr {
     SocketAddr::from(([127, 0, 0, 1], port))
 }
 
-fn main() -> Result<(), Error> {
-    System::new().block_on(async {
-        let controller = InputController::new(std::env::args().nth(1), std::env::args().nth(2))?;
-        let shop_id = controller.shop_id;
-        let orders = controller.get_orders()?;
+fn main() -> ExitCode {
+    errors::report(System::new().block_on(run()))
+}
+
+async fn run() -> Result<(), Error> {
+    logging::init()?;
+    let controller = InputController::new(std::env::args().nth(1), std::env::args().nth(2))?;
+    let shop_id = controller.shop_id;
+    let orders = controller.get_orders()?;
+    let keys = KeyStore::from_file(KEYS_FILE)?;
+    trace::init(
+        &format!("coffee_machine-{}", shop_id),
+        &format!("traces_coffee_machine_{}.jsonl", shop_id),
+    )?;
+    if std::env::var(RECORD_VAR).is_ok() {
+        linearizability::init(&format!("operations_coffee_machine_{}.jsonl", shop_id))?;
+    }
+    let metrics_port = COFFEE_MACHINE_METRICS_PORT + shop_id as u16;
+    metrics::serve(SocketAddr::from(([127, 0, 0, 1], metrics_port)), || ())?;
 
-        let ip_addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-        let port = 8000 + shop_id as u16;
-        let addr = SocketAddr::new(ip_addr, port);
+    let ip_addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    let port = 8000 + shop_id as u16;
+    let addr = SocketAddr::new(ip_addr, port);
 
-        let socket =
-            Arc::new(UdpSocket::bind(addr).expect("Error when binding coffee machines socket"));
-        let server_addr = id_to_dataaddr(shop_id as usize);
+    let socket = match UdpSocket::bind(addr) {
+        Ok(socket) => Arc::new(socket),
+        Err(err) => {
+            return Err(TransportError::CantBindSocket {
+                addr: addr.to_string(),
+                cause: err.into(),
+            }
+            .into())
+        }
+    };
+    let server_addr = id_to_dataaddr(shop_id as usize);
 
-        // Start coffee machines
-        let coffee_machines = get_coffee_machines(socket.clone(), server_addr, shop_id);
-        for (idx, order) in orders.into_iter().enumerate() {
-            let id = idx % coffee_machines.len();
-            let coffee_machine = coffee_machines[id].clone();
-            match coffee_machine
-                .send(ProcessOrder {
-                    order: order.clone(),
-                })
-                .await
-            {
-                Ok(_) => (),
-                Err(_) => return Err(Error::CantSendMessage),
+    // Start coffee machines
+    let coffee_machines = get_coffee_machines(socket.clone(), server_addr, shop_id, &keys)?;
+    for (idx, order) in orders.into_iter().enumerate() {
+        let id = idx % coffee_machines.len();
+        let coffee_machine = coffee_machines[id].clone();
+        match coffee_machine
+            .send(ProcessOrder {
+                order: order.clone(),
+            })
+            .await
+        {
+            Ok(_) => (),
+            Err(err) => {
+                return Err(TransportError::CantSendMessage {
+                    to: format!("coffee machine {}", id),
+                    cause: err.into(),
+                }
+                .into())
             }
         }
+    }
 
-        System::current().stop();
-        Ok(())
-    })
+    System::current().stop();
+    Ok(())
 }
@@ -1,4 +1,27 @@
 use std::time::Duration;
 
 pub const TIMEOUT: Duration = Duration::from_millis(500);
+/// Size of the buffers that receive the messages, longer datagrams are cut off.
+pub const MESSAGE_BYTES: usize = 1024;
 pub const COFFEE_MACHINES: u32 = 2;
+pub const PROXY_OFFSET_VAR: &str = "TP2_PROXY_OFFSET";
+pub const PROXY_CONTROL_PORT: u16 = 5000;
+pub const TRANSFER_TIMEOUT: Duration = Duration::from_secs(3);
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+pub const SYNC_TIMEOUT: Duration = Duration::from_secs(1);
+pub const SYNC_MAX_RETRIES: u32 = 10;
+pub const SYNC_CHUNK_ENTRIES: usize = 64;
+pub const SYNC_SNAPSHOT_THRESHOLD: u64 = 1000;
+pub const PIPELINE_QUEUE_CAPACITY: usize = 256;
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+pub const MEMBER_TIMEOUT: Duration = Duration::from_secs(2);
+pub const GOSSIP_INTERVAL: Duration = Duration::from_secs(1);
+pub const ANTI_ENTROPY_INTERVAL: Duration = Duration::from_secs(2);
+pub const KEYS_FILE: &str = "keys.json";
+pub const AUTH_WINDOW: Duration = Duration::from_secs(30);
+pub const OPERATOR_VAR: &str = "TP2_OPERATOR";
+pub const SERVER_METRICS_PORT: u16 = 9234;
+pub const COFFEE_MACHINE_METRICS_PORT: u16 = 9334;
+pub const RECORD_VAR: &str = "TP2_RECORD";
+pub const LOG_VAR: &str = "TP2_LOG";
+pub const LOG_FORMAT_VAR: &str = "TP2_LOG_FORMAT";
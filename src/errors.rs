@@ -1,27 +1,369 @@
-#[derive(Debug, PartialEq)]
+use std::{error, fmt, process::ExitCode, sync::Arc};
+
+/// Underlying error of an [`Error`], such as an I/O or a JSON error.
+/// Causes are compared by their message, so errors can still be compared in the tests.
+#[derive(Debug, Clone)]
+pub struct Cause(Arc<dyn error::Error + Send + Sync>);
+
+impl PartialEq for Cause {
+    fn eq(&self, other: &Cause) -> bool {
+        self.0.to_string() == other.0.to_string()
+    }
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<E: error::Error + Send + Sync + 'static> From<E> for Cause {
+    fn from(err: E) -> Cause {
+        Cause(Arc::new(err))
+    }
+}
+
+/// Errors of the crate, grouped by the subsystem that raises them.
+#[derive(Debug, Clone, PartialEq)]
 pub enum Error {
-    NotFileInput,
-    FileNotFound,
-    WrongFileFormat,
-    NoMoreOrders,
-    CantSendMessage,
-    CantCloneSocket,
+    Parse(ParseError),
+    Transport(TransportError),
+    Storage(StorageError),
+    Election(ElectionError),
+    Order(OrderError),
+    Config(ConfigError),
+    /// A lock was poisoned by a thread that panicked while holding it.
+    Lock,
+    /// A thread panicked.
+    CantJoinThread,
+}
+
+/// Errors parsing messages, files and log entries.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// The message is not a valid action, answer or command.
+    InvalidMessage(String),
+    /// The line of a snapshot or history file has a wrong format.
+    InvalidLine(String),
+    /// The JSON of a file or of a log entry does not match its format.
+    InvalidJson(Cause),
+}
+
+/// Errors sending and receiving messages.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransportError {
+    CantBindSocket {
+        addr: String,
+        cause: Cause,
+    },
+    CantSendMessage {
+        to: String,
+        cause: Cause,
+    },
+    CantReceiveMessage(Cause),
+    CantSetReadTimeout(Cause),
+    /// No answer arrived in time.
     Timeout,
-    NotShopIdInput,
-    InvalidShopId,
-    CantLockLeaderId,
-    CantGetLeaderId,
-    CantParseMessage,
-    CantGetShopId,
-    CantReceiveMessage,
-    NotEnoughPoints,
-    InvalidMessageFormat,
+    /// The signature of the message is missing or wrong, or its key is not allowed.
+    Unauthenticated,
+    /// The message was already received.
+    ReplayedMessage,
+}
+
+/// Errors reading and writing the files of the servers and the tools.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageError {
+    FileNotFound {
+        path: String,
+        cause: Cause,
+    },
+    CantRead {
+        path: String,
+        cause: Cause,
+    },
+    CantWrite {
+        path: String,
+        cause: Cause,
+    },
+    /// The entries from the index were compacted into a snapshot.
+    Compacted(u64),
+    /// The checksum of the log entry does not match its fields.
+    InvalidChecksum(u64),
+}
+
+/// Errors of the election of the leader.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ElectionError {
+    /// The leader can't be read.
+    UnknownLeader,
+    /// The election message is truncated or has an unknown format.
     InvalidMessage,
-    CantSetReadTimeout,
-    ClientAlreadyBlocked,
-    UserAlreadyBlocked,
-    UserAlreadyUnblocked,
+    /// No other node answered the election message.
+    NoAnswer,
+}
+
+/// Errors processing the orders, the payments and the adjustments of the accounts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderError {
+    NotEnoughPoints(u32),
+    ClientAlreadyBlocked(u32),
+    AlreadyRefunded(u32),
+    UnknownOrder(u32),
+    /// The points of the client would not fit in its account.
+    PointsOutOfRange(u32),
+    /// The answer of the server does not match the message sent.
+    UnexpectedAnswer(String),
+    /// The server is down and does not process the message.
     Down,
+    /// The server is synchronizing and does not process the message.
     Sync,
-    Lock,
+}
+
+/// Errors in the arguments, the configuration and the setup of the processes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// The arguments of the binary do not match its usage.
+    Usage(&'static str),
+    MissingArgument(&'static str),
+    InvalidArgument {
+        name: &'static str,
+        value: String,
+    },
+    /// The keys file has no key for the shop, coffee machine or operator.
+    MissingKey(String),
+    InvalidLogConfig(String),
+    CantWriteTraces {
+        path: String,
+        cause: Cause,
+    },
+    CantRegisterSignal(Cause),
+}
+
+impl Error {
+    /// Exit code of the binaries that fail with this error, one per subsystem.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Config(_) => 2,
+            Error::Parse(_) => 3,
+            Error::Transport(_) => 4,
+            Error::Storage(_) => 5,
+            Error::Election(_) => 6,
+            Error::Order(_) => 7,
+            Error::Lock | Error::CantJoinThread => 8,
+        }
+    }
+}
+
+/// Prints the error of a binary and returns the exit code of the process.
+pub fn report(result: Result<(), Error>) -> ExitCode {
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            let mut source = error::Error::source(&err);
+            while let Some(cause) = source {
+                eprintln!("  caused by: {}", cause);
+                source = cause.source();
+            }
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(err) => write!(f, "parse error: {}", err),
+            Error::Transport(err) => write!(f, "transport error: {}", err),
+            Error::Storage(err) => write!(f, "storage error: {}", err),
+            Error::Election(err) => write!(f, "election error: {}", err),
+            Error::Order(err) => write!(f, "order error: {}", err),
+            Error::Config(err) => write!(f, "configuration error: {}", err),
+            Error::Lock => write!(f, "a lock was poisoned"),
+            Error::CantJoinThread => write!(f, "a thread panicked"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::InvalidMessage(message) => write!(f, "invalid message \"{}\"", message),
+            ParseError::InvalidLine(line) => write!(f, "invalid line \"{}\"", line),
+            ParseError::InvalidJson(_) => write!(f, "invalid JSON"),
+        }
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::CantBindSocket { addr, .. } => {
+                write!(f, "can't bind socket to {}", addr)
+            }
+            TransportError::CantSendMessage { to, .. } => write!(f, "can't send message to {}", to),
+            TransportError::CantReceiveMessage(_) => write!(f, "can't receive message"),
+            TransportError::CantSetReadTimeout(_) => write!(f, "can't set the read timeout"),
+            TransportError::Timeout => write!(f, "timed out waiting for an answer"),
+            TransportError::Unauthenticated => write!(f, "message not authenticated"),
+            TransportError::ReplayedMessage => write!(f, "replayed message"),
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::FileNotFound { path, .. } => write!(f, "can't open {}", path),
+            StorageError::CantRead { path, .. } => write!(f, "can't read {}", path),
+            StorageError::CantWrite { path, .. } => write!(f, "can't write {}", path),
+            StorageError::Compacted(index) => write!(f, "entry {} was compacted", index),
+            StorageError::InvalidChecksum(seq) => write!(f, "wrong checksum in entry {}", seq),
+        }
+    }
+}
+
+impl fmt::Display for ElectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElectionError::UnknownLeader => write!(f, "can't get the leader"),
+            ElectionError::InvalidMessage => write!(f, "invalid election message"),
+            ElectionError::NoAnswer => write!(f, "no node answered"),
+        }
+    }
+}
+
+impl fmt::Display for OrderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderError::NotEnoughPoints(client) => {
+                write!(f, "client {} has not enough points", client)
+            }
+            OrderError::ClientAlreadyBlocked(client) => {
+                write!(f, "client {} is already blocked", client)
+            }
+            OrderError::AlreadyRefunded(order) => write!(f, "order {} was already refunded", order),
+            OrderError::UnknownOrder(order) => write!(f, "unknown order {}", order),
+            OrderError::PointsOutOfRange(client) => {
+                write!(f, "the points of client {} are out of range", client)
+            }
+            OrderError::UnexpectedAnswer(answer) => write!(f, "unexpected answer \"{}\"", answer),
+            OrderError::Down => write!(f, "the server is down"),
+            OrderError::Sync => write!(f, "the server is synchronizing"),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Usage(usage) => write!(f, "usage: {}", usage),
+            ConfigError::MissingArgument(name) => write!(f, "missing argument <{}>", name),
+            ConfigError::InvalidArgument { name, value } => {
+                write!(f, "invalid <{}> \"{}\"", name, value)
+            }
+            ConfigError::MissingKey(id) => write!(f, "no key for {}", id),
+            ConfigError::InvalidLogConfig(config) => write!(f, "invalid log config \"{}\"", config),
+            ConfigError::CantWriteTraces { path, .. } => {
+                write!(f, "can't write traces to {}", path)
+            }
+            ConfigError::CantRegisterSignal(_) => write!(f, "can't register the signal handlers"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        let cause = match self {
+            Error::Parse(ParseError::InvalidJson(cause)) => cause,
+            Error::Transport(TransportError::CantBindSocket { cause, .. })
+            | Error::Transport(TransportError::CantSendMessage { cause, .. })
+            | Error::Transport(TransportError::CantReceiveMessage(cause))
+            | Error::Transport(TransportError::CantSetReadTimeout(cause)) => cause,
+            Error::Storage(StorageError::FileNotFound { cause, .. })
+            | Error::Storage(StorageError::CantRead { cause, .. })
+            | Error::Storage(StorageError::CantWrite { cause, .. }) => cause,
+            Error::Config(ConfigError::CantWriteTraces { cause, .. })
+            | Error::Config(ConfigError::CantRegisterSignal(cause)) => cause,
+            _ => return None,
+        };
+        Some(&*cause.0)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Error {
+        Error::Parse(err)
+    }
+}
+
+impl From<TransportError> for Error {
+    fn from(err: TransportError) -> Error {
+        Error::Transport(err)
+    }
+}
+
+impl From<StorageError> for Error {
+    fn from(err: StorageError) -> Error {
+        Error::Storage(err)
+    }
+}
+
+impl From<ElectionError> for Error {
+    fn from(err: ElectionError) -> Error {
+        Error::Election(err)
+    }
+}
+
+impl From<OrderError> for Error {
+    fn from(err: OrderError) -> Error {
+        Error::Order(err)
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(err: ConfigError) -> Error {
+        Error::Config(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn test01_errors_keep_their_source() {
+        let cause = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        let err = Error::from(StorageError::FileNotFound {
+            path: "log_0.txt".to_string(),
+            cause: cause.into(),
+        });
+
+        assert_eq!(err.to_string(), "storage error: can't open log_0.txt");
+        assert_eq!(
+            error::Error::source(&err).map(|cause| cause.to_string()),
+            Some("no such file".to_string())
+        );
+    }
+
+    #[test]
+    fn test02_each_subsystem_has_its_exit_code() {
+        let errors = [
+            Error::from(ConfigError::MissingArgument("shop_id")),
+            Error::from(ParseError::InvalidMessage("block".to_string())),
+            Error::from(TransportError::Timeout),
+            Error::from(StorageError::Compacted(3)),
+            Error::from(ElectionError::UnknownLeader),
+            Error::from(OrderError::Down),
+            Error::Lock,
+        ];
+        let mut codes: Vec<u8> = errors.iter().map(|err| err.exit_code()).collect();
+        codes.sort();
+        codes.dedup();
+
+        assert_eq!(codes.len(), errors.len());
+        assert!(!codes.contains(&0) && !codes.contains(&1));
+    }
 }
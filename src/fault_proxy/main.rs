@@ -0,0 +1,40 @@
+use std::{env, path::Path, process::ExitCode};
+
+use tp2::{
+    errors::{self, ConfigError, Error, StorageError},
+    fault_proxy::{proxy::FaultProxy, rules::FaultConfig},
+    logging::{self, FAULT_PROXY},
+};
+use tracing::info;
+
+fn main() -> ExitCode {
+    errors::report(run())
+}
+
+fn run() -> Result<(), Error> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        return Err(ConfigError::MissingArgument("config_file").into());
+    }
+
+    logging::init()?;
+    let path = Path::new("resources/").join(&args[1]);
+    let config = match std::fs::read_to_string(&path) {
+        Ok(config) => FaultConfig::from_json(&config)?,
+        Err(err) => {
+            return Err(StorageError::FileNotFound {
+                path: path.display().to_string(),
+                cause: err.into(),
+            }
+            .into())
+        }
+    };
+    info!(
+        target: FAULT_PROXY,
+        "{} shops, servers listening with offset {}",
+        config.shops,
+        config.offset
+    );
+
+    FaultProxy::new(config).run()
+}
@@ -0,0 +1,19 @@
+pub mod proxy;
+pub mod rules;
+
+use std::{env, net::SocketAddr};
+
+use crate::constants::PROXY_OFFSET_VAR;
+
+/// Returns the address a socket has to be bound to.
+/// When the servers run behind the fault proxy, the proxy owns `addr`
+/// and the real socket listens on `addr` plus the configured offset.
+pub fn bind_addr(addr: SocketAddr) -> SocketAddr {
+    let offset = env::var(PROXY_OFFSET_VAR)
+        .ok()
+        .and_then(|offset| offset.parse::<u16>().ok())
+        .unwrap_or(0);
+    let mut bind = addr;
+    bind.set_port(addr.port() + offset);
+    bind
+}
@@ -0,0 +1,377 @@
+use std::{
+    cmp::Ordering as CmpOrdering,
+    collections::{BinaryHeap, HashMap},
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tracing::{debug, info};
+
+use crate::{
+    constants::{MESSAGE_BYTES, PROXY_CONTROL_PORT},
+    errors::{Error, ParseError, TransportError},
+    fault_proxy::rules::{parse_partitions, Channel, FaultConfig, Verdict},
+    local_server::{
+        leader_election::id_to_ctrladdr,
+        server::{coffee_machine_addr, id_to_dataaddr},
+    },
+    logging::FAULT_PROXY,
+};
+
+/// A packet waiting to be delivered.
+struct Delivery {
+    at: Instant,
+    seq: u64,
+    socket: Arc<UdpSocket>,
+    to: SocketAddr,
+    payload: Vec<u8>,
+}
+
+impl PartialEq for Delivery {
+    fn eq(&self, other: &Self) -> bool {
+        self.at == other.at && self.seq == other.seq
+    }
+}
+
+impl Eq for Delivery {}
+
+impl PartialOrd for Delivery {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delivery {
+    // Reversed so the binary heap pops the earliest delivery first.
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+/// Delivers packets once their delay is over.
+struct Scheduler {
+    queue: Mutex<BinaryHeap<Delivery>>,
+    cvar: Condvar,
+    seq: AtomicU64,
+}
+
+impl Scheduler {
+    fn schedule(
+        &self,
+        socket: Arc<UdpSocket>,
+        to: SocketAddr,
+        payload: Vec<u8>,
+        verdict: &Verdict,
+    ) {
+        if let Ok(mut queue) = self.queue.lock() {
+            for _ in 0..verdict.copies {
+                queue.push(Delivery {
+                    at: Instant::now() + verdict.delay,
+                    seq: self.seq.fetch_add(1, Ordering::SeqCst),
+                    socket: socket.clone(),
+                    to,
+                    payload: payload.clone(),
+                });
+            }
+        }
+        self.cvar.notify_all();
+    }
+
+    fn run(&self) -> Result<(), Error> {
+        let mut queue = self.queue.lock().map_err(|_| Error::Lock)?;
+        loop {
+            let now = Instant::now();
+            match queue.peek() {
+                None => {
+                    queue = self.cvar.wait(queue).map_err(|_| Error::Lock)?;
+                }
+                Some(next) if next.at > now => {
+                    let wait = next.at - now;
+                    queue = self
+                        .cvar
+                        .wait_timeout(queue, wait)
+                        .map_err(|_| Error::Lock)?
+                        .0;
+                }
+                Some(_) => {
+                    if let Some(delivery) = queue.pop() {
+                        let _ = delivery.socket.send_to(&delivery.payload, delivery.to);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Upstream sockets indexed by the front address and the client that sent the packet.
+type Sessions = HashMap<(SocketAddr, SocketAddr), Arc<UdpSocket>>;
+
+/// UDP proxy that sits between coffee machines, servers and election sockets
+/// and injects faults according to a [`FaultConfig`].
+pub struct FaultProxy {
+    config: Arc<RwLock<FaultConfig>>,
+    scheduler: Arc<Scheduler>,
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+impl FaultProxy {
+    /// Creates an instance of [`FaultProxy`].
+    pub fn new(config: FaultConfig) -> FaultProxy {
+        FaultProxy {
+            config: Arc::new(RwLock::new(config)),
+            scheduler: Arc::new(Scheduler {
+                queue: Mutex::new(BinaryHeap::new()),
+                cvar: Condvar::new(),
+                seq: AtomicU64::new(0),
+            }),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Listens on the addresses of every shop and forwards the packets to the real servers.
+    pub fn run(self) -> Result<(), Error> {
+        let (shops, offset) = {
+            let config = self.config.read().map_err(|_| Error::Lock)?;
+            (config.shops, config.offset)
+        };
+        let mut threads_handler: Vec<JoinHandle<Result<(), Error>>> = vec![];
+
+        let scheduler = self.scheduler.clone();
+        threads_handler.push(thread::spawn(move || scheduler.run()));
+
+        for shop in 0..shops {
+            let fronts = [
+                (id_to_dataaddr(shop as usize), Channel::Data),
+                (id_to_dataaddr(shop as usize + 1000), Channel::CoffeeMachine),
+                (id_to_ctrladdr(shop as usize), Channel::Election),
+            ];
+            for (front, channel) in fronts {
+                let socket = match UdpSocket::bind(front) {
+                    Ok(socket) => Arc::new(socket),
+                    Err(err) => {
+                        return Err(TransportError::CantBindSocket {
+                            addr: front.to_string(),
+                            cause: err.into(),
+                        }
+                        .into())
+                    }
+                };
+                let mut upstream = front;
+                upstream.set_port(front.port() + offset);
+                info!(
+                    target: FAULT_PROXY,
+                    shop,
+                    "{:?} from {} to {}",
+                    channel,
+                    front,
+                    upstream
+                );
+                let proxy = self.clone();
+                threads_handler.push(thread::spawn(move || {
+                    proxy.forward(socket, shop, channel, upstream)
+                }));
+            }
+        }
+
+        let proxy = self.clone();
+        threads_handler.push(thread::spawn(move || proxy.control()));
+
+        for thread in threads_handler {
+            thread.join().map_err(|_| Error::CantJoinThread)??;
+        }
+        Ok(())
+    }
+
+    /// Forwards the packets received on the front socket of a shop to its upstream address.
+    fn forward(
+        &self,
+        front: Arc<UdpSocket>,
+        shop: u32,
+        channel: Channel,
+        upstream: SocketAddr,
+    ) -> Result<(), Error> {
+        let mut rng = StdRng::from_entropy();
+        let mut buf = [0u8; MESSAGE_BYTES];
+        loop {
+            let (size, from) = match front.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) => return Err(TransportError::CantReceiveMessage(err.into()).into()),
+            };
+            let session = self.session(front.clone(), from, shop, channel)?;
+            let from_shop = self.shop_of(from);
+            self.deliver(
+                &mut rng,
+                buf[..size].to_vec(),
+                from_shop,
+                shop,
+                channel,
+                session,
+                upstream,
+            );
+        }
+    }
+
+    /// Returns the socket used to talk to the upstream address on behalf of `client`.
+    /// The first time it is asked, a thread that forwards the replies back to the client is spawned.
+    fn session(
+        &self,
+        front: Arc<UdpSocket>,
+        client: SocketAddr,
+        shop: u32,
+        channel: Channel,
+    ) -> Result<Arc<UdpSocket>, Error> {
+        let front_addr = match front.local_addr() {
+            Ok(addr) => addr,
+            Err(err) => return Err(TransportError::CantReceiveMessage(err.into()).into()),
+        };
+        let mut sessions = self.sessions.lock().map_err(|_| Error::Lock)?;
+        if let Some(socket) = sessions.get(&(front_addr, client)) {
+            return Ok(socket.clone());
+        }
+
+        let socket = match UdpSocket::bind("127.0.0.1:0") {
+            Ok(socket) => Arc::new(socket),
+            Err(err) => {
+                return Err(TransportError::CantBindSocket {
+                    addr: "127.0.0.1:0".to_string(),
+                    cause: err.into(),
+                }
+                .into())
+            }
+        };
+        sessions.insert((front_addr, client), socket.clone());
+
+        let proxy = self.clone();
+        let replies = socket.clone();
+        thread::spawn(move || {
+            let mut rng = StdRng::from_entropy();
+            let mut buf = [0u8; MESSAGE_BYTES];
+            let client_shop = proxy.shop_of(client);
+            while let Ok((size, _)) = replies.recv_from(&mut buf) {
+                let payload = buf[..size].to_vec();
+                match client_shop {
+                    Some(client_shop) => proxy.deliver(
+                        &mut rng,
+                        payload,
+                        Some(shop),
+                        client_shop,
+                        channel,
+                        front.clone(),
+                        client,
+                    ),
+                    None => {
+                        let _ = front.send_to(&payload, client);
+                    }
+                }
+            }
+        });
+        Ok(socket)
+    }
+
+    /// Applies the fault rules to a packet and schedules its delivery.
+    #[allow(clippy::too_many_arguments)]
+    fn deliver(
+        &self,
+        rng: &mut StdRng,
+        mut payload: Vec<u8>,
+        from: Option<u32>,
+        to: u32,
+        channel: Channel,
+        socket: Arc<UdpSocket>,
+        addr: SocketAddr,
+    ) {
+        let verdict = match self.config.read() {
+            Ok(config) => config.decide(from, to, channel, rng),
+            Err(_) => return,
+        };
+        if verdict.copies == 0 {
+            debug!(
+                target: FAULT_PROXY,
+                "drop {:?} packet from {:?} to {}",
+                channel,
+                from,
+                to
+            );
+            return;
+        }
+        if verdict.corrupt && !payload.is_empty() {
+            let pos = rng.gen_range(0..payload.len());
+            payload[pos] ^= rng.gen_range(1..=u8::MAX);
+        }
+        self.scheduler.schedule(socket, addr, payload, &verdict);
+    }
+
+    /// Returns the shop that owns the socket at `addr`, if any.
+    fn shop_of(&self, addr: SocketAddr) -> Option<u32> {
+        let (shops, offset) = match self.config.read() {
+            Ok(config) => (config.shops, config.offset),
+            Err(_) => return None,
+        };
+        (0..shops).find(|shop| {
+            let id = *shop as usize;
+            [
+                id_to_dataaddr(id).port() + offset,
+                id_to_dataaddr(id + 1000).port() + offset,
+                id_to_ctrladdr(id).port() + offset,
+                coffee_machine_addr(*shop).port(),
+            ]
+            .contains(&addr.port())
+        })
+    }
+
+    /// Handles the commands sent to the control port:
+    /// - `PARTITION <shops> <shops> ...`: splits the shops in groups, e.g. `PARTITION 0,1 2`.
+    /// - `HEAL`: removes every partition.
+    fn control(&self) -> Result<(), Error> {
+        let addr = SocketAddr::from(([127, 0, 0, 1], PROXY_CONTROL_PORT));
+        let socket = match UdpSocket::bind(addr) {
+            Ok(socket) => socket,
+            Err(err) => {
+                return Err(TransportError::CantBindSocket {
+                    addr: addr.to_string(),
+                    cause: err.into(),
+                }
+                .into())
+            }
+        };
+        let mut buf = [0u8; MESSAGE_BYTES];
+        loop {
+            let (size, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) => return Err(TransportError::CantReceiveMessage(err.into()).into()),
+            };
+            let message = String::from_utf8_lossy(&buf[..size]).into_owned();
+            info!(target: FAULT_PROXY, %from, "get {}", message);
+            let words: Vec<&str> = message.split_whitespace().collect();
+            let partitions = match words.first() {
+                Some(&"PARTITION") => parse_partitions(&words[1..]),
+                Some(&"HEAL") => Ok(vec![]),
+                _ => Err(ParseError::InvalidMessage(message.clone()).into()),
+            };
+            let answer = match partitions {
+                Ok(partitions) => {
+                    let mut config = self.config.write().map_err(|_| Error::Lock)?;
+                    config.partitions = partitions;
+                    "ACK"
+                }
+                Err(_) => "ERROR",
+            };
+            let _ = socket.send_to(answer.as_bytes(), from);
+        }
+    }
+
+    /// Creates a clone instance of [`FaultProxy`].
+    fn clone(&self) -> FaultProxy {
+        FaultProxy {
+            config: self.config.clone(),
+            scheduler: self.scheduler.clone(),
+            sessions: self.sessions.clone(),
+        }
+    }
+}
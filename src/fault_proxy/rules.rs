@@ -0,0 +1,273 @@
+use std::time::Duration;
+
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::errors::{Error, ParseError};
+
+/// Kind of socket a packet is addressed to.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    /// Server to server messages.
+    Data,
+    /// Leader election messages.
+    Election,
+    /// Coffee machine to server messages.
+    CoffeeMachine,
+}
+
+/// A fault rule. Every rule whose filters match a packet is applied to it.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Rule {
+    /// Shops that send the packet. Any shop if missing.
+    #[serde(default)]
+    pub from: Option<Vec<u32>>,
+    /// Shops that receive the packet. Any shop if missing.
+    #[serde(default)]
+    pub to: Option<Vec<u32>>,
+    /// Channel of the packet. Any channel if missing.
+    #[serde(default)]
+    pub channel: Option<Channel>,
+    /// Probability of dropping the packet.
+    #[serde(default)]
+    pub drop: f64,
+    /// Probability of delivering the packet twice.
+    #[serde(default)]
+    pub duplicate: f64,
+    /// Probability of holding the packet back so later packets overtake it.
+    #[serde(default)]
+    pub reorder: f64,
+    /// Probability of flipping a byte of the packet.
+    #[serde(default)]
+    pub corrupt: f64,
+    /// Fixed delay added to the packet.
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// Random delay, between 0 and this value, added to the packet.
+    #[serde(default)]
+    pub jitter_ms: u64,
+}
+
+/// Configuration of the fault injection proxy.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FaultConfig {
+    /// Amount of shops of the local.
+    pub shops: u32,
+    /// Offset added to the ports the servers really listen on.
+    pub offset: u16,
+    /// Groups of shops that can only talk to shops of the same group.
+    #[serde(default)]
+    pub partitions: Vec<Vec<u32>>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+/// What the proxy has to do with a packet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    /// Amount of copies to deliver. Zero means the packet is dropped.
+    pub copies: usize,
+    pub delay: Duration,
+    pub corrupt: bool,
+}
+
+/// Max delay added to a packet that has to be reordered.
+const REORDER_DELAY_MS: u64 = 200;
+
+impl Rule {
+    fn matches(&self, from: Option<u32>, to: u32, channel: Channel) -> bool {
+        let from_matches = match (&self.from, from) {
+            (None, _) => true,
+            (Some(shops), Some(from)) => shops.contains(&from),
+            (Some(_), None) => false,
+        };
+        let to_matches = match &self.to {
+            None => true,
+            Some(shops) => shops.contains(&to),
+        };
+        let channel_matches = match self.channel {
+            None => true,
+            Some(c) => c == channel,
+        };
+        from_matches && to_matches && channel_matches
+    }
+}
+
+impl FaultConfig {
+    /// Parses a [`FaultConfig`] from its json representation.
+    pub fn from_json(config: &str) -> Result<FaultConfig, Error> {
+        match serde_json::from_str::<FaultConfig>(config) {
+            Ok(config) => Ok(config),
+            Err(err) => Err(ParseError::InvalidJson(err.into()).into()),
+        }
+    }
+
+    /// Returns true if the shops are in different partitions.
+    /// Packets without a known sender are never partitioned.
+    pub fn is_partitioned(&self, from: Option<u32>, to: u32) -> bool {
+        let from = match from {
+            Some(from) => from,
+            None => return false,
+        };
+        if from == to || self.partitions.is_empty() {
+            return false;
+        }
+        let group_of = |shop: u32| self.partitions.iter().position(|g| g.contains(&shop));
+        group_of(from) != group_of(to)
+    }
+
+    /// Decides what to do with a packet sent by `from` to `to` through `channel`.
+    pub fn decide<R: Rng>(
+        &self,
+        from: Option<u32>,
+        to: u32,
+        channel: Channel,
+        rng: &mut R,
+    ) -> Verdict {
+        let mut verdict = Verdict {
+            copies: 1,
+            delay: Duration::ZERO,
+            corrupt: false,
+        };
+        if self.is_partitioned(from, to) {
+            verdict.copies = 0;
+            return verdict;
+        }
+
+        for rule in self.rules.iter().filter(|r| r.matches(from, to, channel)) {
+            if rng.gen_bool(rule.drop.clamp(0.0, 1.0)) {
+                verdict.copies = 0;
+                return verdict;
+            }
+            if rng.gen_bool(rule.duplicate.clamp(0.0, 1.0)) {
+                verdict.copies += 1;
+            }
+            if rng.gen_bool(rule.corrupt.clamp(0.0, 1.0)) {
+                verdict.corrupt = true;
+            }
+            let mut delay = rule.delay_ms;
+            if rule.jitter_ms > 0 {
+                delay += rng.gen_range(0..=rule.jitter_ms);
+            }
+            if rng.gen_bool(rule.reorder.clamp(0.0, 1.0)) {
+                delay += rng.gen_range(1..=REORDER_DELAY_MS);
+            }
+            verdict.delay += Duration::from_millis(delay);
+        }
+        verdict
+    }
+}
+
+/// Parses the partition of a control command like `0,1 2`.
+pub fn parse_partitions(groups: &[&str]) -> Result<Vec<Vec<u32>>, Error> {
+    let mut partitions = vec![];
+    for group in groups {
+        let mut shops = vec![];
+        for shop in group.split(',') {
+            match shop.parse::<u32>() {
+                Ok(shop) => shops.push(shop),
+                Err(_) => return Err(ParseError::InvalidMessage(group.to_string()).into()),
+            }
+        }
+        partitions.push(shops);
+    }
+    Ok(partitions)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn config(rules: Vec<Rule>, partitions: Vec<Vec<u32>>) -> FaultConfig {
+        FaultConfig {
+            shops: 3,
+            offset: 10000,
+            partitions,
+            rules,
+        }
+    }
+
+    #[test]
+    fn test01_packets_pass_without_rules() {
+        let config = config(vec![], vec![]);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let verdict = config.decide(Some(0), 1, Channel::Data, &mut rng);
+
+        assert_eq!(verdict.copies, 1);
+        assert_eq!(verdict.delay, Duration::ZERO);
+        assert!(!verdict.corrupt);
+    }
+
+    #[test]
+    fn test02_packets_between_partitions_are_dropped() {
+        let config = config(vec![], vec![vec![0], vec![1, 2]]);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        assert_eq!(config.decide(Some(0), 1, Channel::Data, &mut rng).copies, 0);
+        assert_eq!(config.decide(Some(1), 2, Channel::Data, &mut rng).copies, 1);
+        assert_eq!(config.decide(None, 1, Channel::Data, &mut rng).copies, 1);
+    }
+
+    #[test]
+    fn test03_rules_only_apply_to_matching_packets() {
+        let rule = Rule {
+            from: Some(vec![0]),
+            channel: Some(Channel::Election),
+            drop: 1.0,
+            ..Default::default()
+        };
+        let config = config(vec![rule], vec![]);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        assert_eq!(
+            config
+                .decide(Some(0), 1, Channel::Election, &mut rng)
+                .copies,
+            0
+        );
+        assert_eq!(config.decide(Some(0), 1, Channel::Data, &mut rng).copies, 1);
+        assert_eq!(
+            config
+                .decide(Some(1), 0, Channel::Election, &mut rng)
+                .copies,
+            1
+        );
+    }
+
+    #[test]
+    fn test04_rules_add_delay_duplicates_and_corruption() {
+        let rule = Rule {
+            duplicate: 1.0,
+            corrupt: 1.0,
+            delay_ms: 50,
+            ..Default::default()
+        };
+        let config = config(vec![rule], vec![]);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let verdict = config.decide(Some(0), 1, Channel::Data, &mut rng);
+
+        assert_eq!(verdict.copies, 2);
+        assert_eq!(verdict.delay, Duration::from_millis(50));
+        assert!(verdict.corrupt);
+    }
+
+    #[test]
+    fn test05_parse_config_and_partitions() {
+        let config = FaultConfig::from_json(
+            "{\"shops\": 2, \"offset\": 10000, \"rules\": [{\"channel\": \"coffee_machine\", \"drop\": 0.5}]}",
+        )
+        .expect("The config is invalid");
+
+        assert_eq!(config.rules[0].channel, Some(Channel::CoffeeMachine));
+        assert_eq!(
+            parse_partitions(&["0,1", "2"]).expect("Invalid partitions"),
+            vec![vec![0, 1], vec![2]]
+        );
+        assert!(parse_partitions(&["a"]).is_err());
+    }
+}
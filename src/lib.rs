@@ -1,9 +1,19 @@
 pub mod action;
+pub mod auth;
+pub mod clock;
 pub mod coffee_machine;
 pub mod constants;
 pub mod errors;
+pub mod fault_proxy;
+pub mod linearizability;
 pub mod local_server;
+pub mod logging;
 pub mod message_parser;
 pub mod message_sender;
+pub mod metadata;
+pub mod metrics;
+pub mod operator;
 pub mod payment_method;
 pub mod points_handler;
+pub mod trace;
+pub mod wire;
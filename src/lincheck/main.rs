@@ -0,0 +1,63 @@
+use std::{env, fs, process::ExitCode};
+
+use tp2::{
+    errors::{self, ConfigError, Error, StorageError},
+    linearizability::{check, read_history},
+    local_server::snapshot::Snapshot,
+};
+
+const USAGE: &str =
+    "lincheck [--accounts <accounts.txt>] <operations.jsonl> [<operations.jsonl> ...]";
+
+/// Exit code when the history is not linearizable.
+const NOT_LINEARIZABLE: u8 = 1;
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(NOT_LINEARIZABLE),
+        Err(err) => errors::report(Err(err)),
+    }
+}
+
+/// Checks the histories recorded by the coffee machines of every shop together,
+/// starting from the accounts saved with `check --save`, or from empty accounts.
+/// Returns false if the operations of any client can't be linearized.
+fn run() -> Result<bool, Error> {
+    let mut paths: Vec<String> = env::args().skip(1).collect();
+    let accounts = match paths.first().map(|arg| arg.as_str()) {
+        Some("--accounts") if paths.len() > 2 => {
+            // A missing file would be read as empty accounts
+            if let Err(err) = fs::metadata(&paths[1]) {
+                return Err(StorageError::FileNotFound {
+                    path: paths[1].clone(),
+                    cause: err.into(),
+                }
+                .into());
+            }
+            let accounts = Snapshot::read(&paths[1])?.accounts;
+            paths.drain(..2);
+            accounts
+        }
+        Some("--accounts") | None => return Err(ConfigError::Usage(USAGE).into()),
+        Some(_) => vec![],
+    };
+    let mut history = vec![];
+    for path in &paths {
+        history.extend(read_history(path)?);
+    }
+
+    let violations = check(&history, &accounts);
+    for violation in &violations {
+        println!("[VIOLATION]: {}", violation);
+        for operation in &violation.operations {
+            println!("[VIOLATION]:     {}", operation);
+        }
+    }
+    println!(
+        "[LINCHECK]: {} operations, {} clients not linearizable",
+        history.len(),
+        violations.len()
+    );
+    Ok(violations.is_empty())
+}
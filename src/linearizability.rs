@@ -0,0 +1,396 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    sync::{Mutex, OnceLock},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{Error, ParseError, StorageError},
+    payment_method::Method,
+    points_handler::PointsHandler,
+    wire::Account,
+};
+
+/// Message a coffee machine sends to its server about the account of a client.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "call")]
+pub enum Call {
+    Block,
+    Complete { price: u32, method: Method },
+    Fail,
+}
+
+/// Answer of the server to a [`Call`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reply {
+    Ack,
+    NotEnoughPoints,
+    AlreadyBlocked,
+}
+
+/// Call of a coffee machine and its reply, with the milliseconds since the unix epoch
+/// when it was sent and when the coffee machine got the reply or gave up waiting.
+/// Without a reply the server may apply the call at any time after it was sent, or never.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Operation {
+    pub shop: u32,
+    pub machine: u32,
+    pub order: u32,
+    pub client: u32,
+    #[serde(flatten)]
+    pub call: Call,
+    pub invoke: u64,
+    pub complete: u64,
+    pub reply: Option<Reply>,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let call = match self.call {
+            Call::Block => "block".to_string(),
+            Call::Complete { price, method } => format!("complete {} {:?}", price, method),
+            Call::Fail => "fail".to_string(),
+        };
+        let reply = match self.reply {
+            Some(reply) => format!("{:?}", reply),
+            None => "no reply".to_string(),
+        };
+        write!(
+            f,
+            "[{}, {}] machine {}.{} order {}: {} -> {}",
+            self.invoke, self.complete, self.shop, self.machine, self.order, call, reply
+        )
+    }
+}
+
+/// Applies the call to the account of the client as the leader does, with a [`PointsHandler`]
+/// that only has that account. Returns the account after the call and the reply of the server.
+pub fn step(account: (i32, bool), client_id: u32, call: Call) -> ((i32, bool), Reply) {
+    let mut handler = PointsHandler {
+        points: HashMap::from([(client_id, account)]),
+    };
+    let reply = match call {
+        Call::Block => match handler.block(client_id) {
+            Ok(_) => Reply::Ack,
+            Err(_) => Reply::AlreadyBlocked,
+        },
+        Call::Complete { price, method } => {
+            let points = match method {
+                Method::Cash => price as i32,
+                Method::Points => -(price as i32),
+            };
+            let reply = match handler.update_points(client_id, points) {
+                Ok(_) => Reply::Ack,
+                Err(_) => Reply::NotEnoughPoints,
+            };
+            handler.unblock(client_id);
+            reply
+        }
+        Call::Fail => {
+            handler.unblock(client_id);
+            Reply::Ack
+        }
+    };
+    let account = handler.points.get(&client_id).copied().unwrap_or(account);
+    (account, reply)
+}
+
+/// Client whose operations can't be ordered so the replies match the ones of a single
+/// server that applies them one at a time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub client: u32,
+    /// Largest amount of operations that could be ordered.
+    pub linearized: usize,
+    pub operations: Vec<Operation>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "client {}: only {} of its {} operations can be linearized",
+            self.client,
+            self.linearized,
+            self.operations.len()
+        )
+    }
+}
+
+/// Checks that the history is linearizable: that there is an order of the operations
+/// that respects their times and where each reply is the one of [`step`].
+/// Operations on different clients don't affect each other, so each client is checked
+/// separately. Each account starts as in `accounts`, or empty and unblocked if it is not there.
+pub fn check(history: &[Operation], accounts: &[Account]) -> Vec<Violation> {
+    let initial: HashMap<u32, (i32, bool)> = accounts
+        .iter()
+        .map(|(client_id, points, blocked)| (*client_id, (*points, *blocked)))
+        .collect();
+    let mut clients: BTreeMap<u32, Vec<Operation>> = BTreeMap::new();
+    for operation in history {
+        clients
+            .entry(operation.client)
+            .or_default()
+            .push(operation.clone());
+    }
+    let mut violations = vec![];
+    for (client, mut operations) in clients {
+        operations.sort_by_key(|operation| operation.invoke);
+        let account = initial.get(&client).copied().unwrap_or_default();
+        if let Err(linearized) = linearize(client, account, &operations) {
+            violations.push(Violation {
+                client,
+                linearized,
+                operations,
+            });
+        }
+    }
+    violations
+}
+
+/// Searches an order of the operations of the client, extending the orders found so far
+/// with the operations that were sent before every pending one got its reply.
+/// Orders that reach the same operations and account are only extended once.
+/// Returns the largest amount of operations ordered if none orders all the replied ones.
+fn linearize(client_id: u32, account: (i32, bool), operations: &[Operation]) -> Result<(), usize> {
+    let mut seen = HashSet::new();
+    let mut pending = vec![(vec![false; operations.len()], account, 0)];
+    let mut linearized = 0;
+    while let Some((done, account, amount)) = pending.pop() {
+        linearized = linearized.max(amount);
+        let remaining = || {
+            operations
+                .iter()
+                .zip(&done)
+                .filter(|(_, done)| !**done)
+                .map(|(operation, _)| operation)
+        };
+        if remaining().all(|operation| operation.reply.is_none()) {
+            return Ok(());
+        }
+        let deadline = remaining()
+            .filter(|operation| operation.reply.is_some())
+            .map(|operation| operation.complete)
+            .min()
+            .unwrap_or(u64::MAX);
+        for (i, operation) in operations.iter().enumerate() {
+            if done[i] || operation.invoke > deadline {
+                continue;
+            }
+            let (next, reply) = step(account, client_id, operation.call);
+            if operation.reply.is_some_and(|recorded| recorded != reply) {
+                continue;
+            }
+            let mut next_done = done.clone();
+            next_done[i] = true;
+            if seen.insert((next_done.clone(), next)) {
+                pending.push((next_done, next, amount + 1));
+            }
+        }
+    }
+    Err(linearized)
+}
+
+/// Reads a history written by [`record`], one operation per line.
+pub fn read_history(path: &str) -> Result<Vec<Operation>, Error> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) => {
+            return Err(StorageError::CantRead {
+                path: path.to_string(),
+                cause: err.into(),
+            }
+            .into())
+        }
+    };
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| match serde_json::from_str(line) {
+            Ok(operation) => Ok(operation),
+            Err(err) => Err(ParseError::InvalidJson(err.into()).into()),
+        })
+        .collect()
+}
+
+static HISTORY: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Records the operations of the process in the file at "path".
+/// Operations are not recorded until this is called.
+pub fn init(path: &str) -> Result<(), Error> {
+    let file = match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            return Err(StorageError::CantWrite {
+                path: path.to_string(),
+                cause: err.into(),
+            }
+            .into())
+        }
+    };
+    match HISTORY.set(Mutex::new(file)) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(StorageError::CantWrite {
+            path: path.to_string(),
+            cause: io::Error::new(io::ErrorKind::AlreadyExists, "history already recorded").into(),
+        }
+        .into()),
+    }
+}
+
+/// Appends the operation to the history of the process, if it is recorded.
+pub fn record(operation: &Operation) {
+    if let Some(history) = HISTORY.get() {
+        if let (Ok(mut file), Ok(line)) = (history.lock(), serde_json::to_string(operation)) {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operation(
+        machine: u32,
+        call: Call,
+        invoke: u64,
+        complete: u64,
+        reply: Option<Reply>,
+    ) -> Operation {
+        Operation {
+            shop: 0,
+            machine,
+            order: 0,
+            client: 1,
+            call,
+            invoke,
+            complete,
+            reply,
+        }
+    }
+
+    fn complete(price: u32, method: Method) -> Call {
+        Call::Complete { price, method }
+    }
+
+    #[test]
+    fn test01_concurrent_operations_can_be_reordered() {
+        let history = vec![
+            operation(0, complete(10, Method::Cash), 0, 10, Some(Reply::Ack)),
+            operation(1, Call::Block, 5, 20, Some(Reply::Ack)),
+            operation(1, complete(10, Method::Points), 25, 30, Some(Reply::Ack)),
+            operation(0, Call::Block, 40, 50, Some(Reply::Ack)),
+            operation(
+                0,
+                complete(5, Method::Points),
+                55,
+                60,
+                Some(Reply::NotEnoughPoints),
+            ),
+        ];
+
+        assert_eq!(check(&history, &[]), vec![]);
+    }
+
+    #[test]
+    fn test02_double_spend_is_a_violation() {
+        let history = vec![
+            operation(0, complete(10, Method::Cash), 0, 10, Some(Reply::Ack)),
+            operation(0, complete(10, Method::Points), 20, 30, Some(Reply::Ack)),
+            operation(1, complete(10, Method::Points), 25, 35, Some(Reply::Ack)),
+        ];
+
+        let violations = check(&history, &[]);
+
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].linearized, 2);
+        assert_eq!(
+            violations[0].to_string(),
+            "client 1: only 2 of its 3 operations can be linearized"
+        );
+    }
+
+    #[test]
+    fn test03_lost_update_and_double_block_are_violations() {
+        let lost_update = vec![
+            operation(0, complete(10, Method::Cash), 0, 10, Some(Reply::Ack)),
+            operation(
+                1,
+                complete(5, Method::Points),
+                20,
+                30,
+                Some(Reply::NotEnoughPoints),
+            ),
+        ];
+        let double_block = vec![
+            operation(0, Call::Block, 0, 10, Some(Reply::Ack)),
+            operation(1, Call::Block, 20, 30, Some(Reply::Ack)),
+        ];
+
+        assert_eq!(check(&lost_update, &[]).len(), 1);
+        assert_eq!(check(&double_block, &[]).len(), 1);
+    }
+
+    #[test]
+    fn test04_operations_without_reply_may_apply_later_or_never() {
+        let applied_later = vec![
+            operation(0, complete(10, Method::Cash), 0, 10, None),
+            operation(1, complete(10, Method::Points), 100, 110, Some(Reply::Ack)),
+        ];
+        let never_applied = vec![
+            operation(0, complete(10, Method::Cash), 0, 10, None),
+            operation(
+                1,
+                complete(10, Method::Points),
+                100,
+                110,
+                Some(Reply::NotEnoughPoints),
+            ),
+        ];
+        let applied_before_sent = vec![
+            operation(1, complete(10, Method::Points), 0, 10, Some(Reply::Ack)),
+            operation(0, complete(10, Method::Cash), 20, 30, None),
+        ];
+
+        assert_eq!(check(&applied_later, &[]), vec![]);
+        assert_eq!(check(&never_applied, &[]), vec![]);
+        assert_eq!(check(&applied_before_sent, &[]).len(), 1);
+    }
+
+    #[test]
+    fn test05_operations_are_recorded_as_json_lines() {
+        let recorded = operation(1, complete(10, Method::Points), 0, 10, None);
+
+        let line = serde_json::to_string(&recorded).expect("Error serializing the operation");
+        let parsed: Operation = serde_json::from_str(&line).expect("Error parsing the operation");
+
+        assert_eq!(parsed, recorded);
+        assert!(line.contains("\"call\":\"complete\""));
+        assert!(line.contains("\"method\":\"points\""));
+    }
+
+    #[test]
+    fn test06_accounts_start_with_the_given_balance() {
+        let redemption = vec![
+            operation(0, Call::Block, 0, 10, Some(Reply::Ack)),
+            operation(0, complete(30, Method::Points), 20, 30, Some(Reply::Ack)),
+        ];
+        let blocked = vec![operation(
+            0,
+            Call::Block,
+            0,
+            10,
+            Some(Reply::AlreadyBlocked),
+        )];
+
+        assert_eq!(check(&redemption, &[]).len(), 1);
+        assert_eq!(check(&redemption, &[(1, 40, false), (2, 5, false)]), vec![]);
+        assert_eq!(check(&blocked, &[]).len(), 1);
+        assert_eq!(check(&blocked, &[(1, 0, true)]), vec![]);
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    local_server::partitions::hash,
+    wire::{Account, DIGEST_RANGES},
+};
+
+/// Returns the range of the client id.
+pub fn range_of(client_id: u32) -> u32 {
+    client_id % DIGEST_RANGES
+}
+
+/// Returns the digest of the accounts of each range, a hash of its accounts sorted by client id.
+/// Servers with the same accounts in a range have the same digest of the range.
+pub fn digests(accounts: &[Account]) -> Vec<u64> {
+    let mut sorted = accounts.to_vec();
+    sorted.sort();
+    let mut ranges = vec![String::new(); DIGEST_RANGES as usize];
+    for (client_id, points, blocked) in sorted {
+        let range = &mut ranges[range_of(client_id) as usize];
+        range.push_str(&format!("{}:{}:{},", client_id, points, blocked as u8));
+    }
+    ranges.iter().map(|range| hash(range.as_bytes())).collect()
+}
+
+/// Compares the state of a follower with the digests the leader sends periodically.
+/// Writes in flight make a range differ for a moment, so a range is only repaired
+/// when it differs in two rounds in a row.
+#[derive(Debug, Default)]
+pub struct AntiEntropy {
+    /// Ranges that differed from the leader in the last round.
+    suspects: BTreeSet<u32>,
+    /// Index of the log of the leader in the last round.
+    last_index: Option<u64>,
+    /// The log of the follower was ahead of the leader in the last round.
+    ahead: bool,
+    /// Ranges of the repair in progress, their accounts are replaced by the ones of the leader.
+    repairing: Vec<u32>,
+}
+
+impl AntiEntropy {
+    /// Creates the comparison of a follower that has not received any digest.
+    pub fn new() -> AntiEntropy {
+        AntiEntropy::default()
+    }
+
+    /// Compares the digests of the leader at "index" with the ones of the follower at
+    /// "local_index". Returns the ranges to repair, or None if there is nothing to repair.
+    /// A log position that did not reach the index of the previous round lost entries,
+    /// so the ranges that differ are repaired right away, even if there are none,
+    /// and the log of the follower moves to the position of the leader.
+    pub fn compare(
+        &mut self,
+        index: u64,
+        local_index: u64,
+        leader: &[u64],
+        local: &[u64],
+    ) -> Option<Vec<u32>> {
+        let previous = self.last_index.replace(index);
+        let differ: BTreeSet<u32> = (0..DIGEST_RANGES)
+            .filter(|range| leader.get(*range as usize) != local.get(*range as usize))
+            .collect();
+        let was_ahead = std::mem::replace(&mut self.ahead, local_index > index);
+        let lost_entries = matches!(previous, Some(previous) if local_index < previous);
+        if lost_entries || (self.ahead && was_ahead) {
+            self.suspects.clear();
+            self.ahead = false;
+            return Some(differ.into_iter().collect());
+        }
+        if local_index != index {
+            self.suspects.clear();
+            return None;
+        }
+        let confirmed: Vec<u32> = differ.intersection(&self.suspects).copied().collect();
+        self.suspects = differ;
+        if confirmed.is_empty() {
+            return None;
+        }
+        self.suspects.retain(|range| !confirmed.contains(range));
+        Some(confirmed)
+    }
+
+    /// Registers the ranges of a repair requested to the leader.
+    pub fn start_repair(&mut self, ranges: Vec<u32>) {
+        self.repairing = ranges;
+    }
+
+    /// Returns true if the client is in a range of the repair in progress.
+    pub fn is_repairing(&self, client_id: u32) -> bool {
+        self.repairing.contains(&range_of(client_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::{digests_to_text, parse_digests};
+
+    #[test]
+    fn test01_digests_only_differ_in_the_ranges_that_changed() {
+        let accounts = vec![(1, 10, false), (2, 5, true), (17, 3, false)];
+        let mut changed = accounts.clone();
+        changed[2] = (17, 4, false);
+
+        let before = digests(&accounts);
+        let after = digests(&changed);
+
+        assert_eq!(before.len(), DIGEST_RANGES as usize);
+        let differ: Vec<usize> = (0..before.len())
+            .filter(|r| before[*r] != after[*r])
+            .collect();
+        assert_eq!(differ, vec![range_of(17) as usize]);
+        assert_eq!(parse_digests(&digests_to_text(&before)), Some(before));
+        assert_eq!(parse_digests("00ff"), None);
+    }
+
+    #[test]
+    fn test02_ranges_are_repaired_when_they_differ_twice() {
+        let leader = digests(&[(1, 10, false)]);
+        let local = digests(&[(1, 0, false)]);
+        let mut anti_entropy = AntiEntropy::new();
+
+        assert_eq!(anti_entropy.compare(4, 4, &leader, &local), None);
+        assert_eq!(anti_entropy.compare(5, 4, &leader, &local), None);
+        assert_eq!(anti_entropy.compare(5, 5, &leader, &local), None);
+        assert_eq!(
+            anti_entropy.compare(5, 5, &leader, &local),
+            Some(vec![range_of(1)])
+        );
+        assert_eq!(anti_entropy.compare(5, 5, &leader, &leader), None);
+    }
+
+    #[test]
+    fn test03_lost_entries_repair_the_log_position() {
+        let leader = digests(&[(1, 10, false)]);
+        let mut anti_entropy = AntiEntropy::new();
+
+        assert_eq!(anti_entropy.compare(5, 4, &leader, &leader), None);
+        assert_eq!(anti_entropy.compare(5, 4, &leader, &leader), Some(vec![]));
+        assert_eq!(anti_entropy.compare(5, 6, &leader, &leader), None);
+        assert_eq!(anti_entropy.compare(5, 6, &leader, &leader), Some(vec![]));
+
+        anti_entropy.start_repair(vec![range_of(1)]);
+        assert!(anti_entropy.is_repairing(1 + DIGEST_RANGES));
+        assert!(!anti_entropy.is_repairing(2));
+    }
+}
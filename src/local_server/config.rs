@@ -0,0 +1,148 @@
+use std::{path::Path, thread};
+
+use serde::Deserialize;
+
+use crate::{
+    constants::KEYS_FILE,
+    errors::{Error, ParseError, StorageError},
+};
+
+/// Configuration of a shop server.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Points each customer can redeem while the shop is down.
+    pub offline_allowance: u32,
+    /// Entries logged between snapshots of the log. Zero disables the compaction.
+    pub compaction_interval: u64,
+    /// Entries kept in the log after a snapshot, so lagging servers can catch up from them.
+    pub retained_entries: u64,
+    /// File of the resources directory with the keys of the shops and coffee machines.
+    pub keys_file: String,
+    /// Threads of the leader that apply the orders of the coffee machines, and shards of the accounts.
+    pub workers: usize,
+    /// Partitions of the client ids among the shops. Zero sends every write to the leader.
+    pub partitions: u32,
+    /// Shops that keep each partition, its primary included.
+    pub replication_factor: usize,
+    /// Entries a follower can be behind the leader and still answer a balance lookup,
+    /// unless the lookup sets its own bound.
+    pub max_read_lag: u64,
+    /// Accrues the cash payments in a counter of each shop merged by gossip, instead of
+    /// sending them to the leader. Only used if the accounts are not partitioned.
+    pub crdt_accruals: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            offline_allowance: 0,
+            compaction_interval: 1000,
+            retained_entries: 100,
+            keys_file: KEYS_FILE.to_string(),
+            workers: thread::available_parallelism().map_or(1, |cores| cores.get()),
+            partitions: 0,
+            replication_factor: 2,
+            max_read_lag: 0,
+            crdt_accruals: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Parses a [`ServerConfig`] from its json representation.
+    pub fn from_json(config: &str) -> Result<ServerConfig, Error> {
+        match serde_json::from_str::<ServerConfig>(config) {
+            Ok(config) => Ok(config),
+            Err(err) => Err(ParseError::InvalidJson(err.into()).into()),
+        }
+    }
+
+    /// Reads the configuration from a file of the resources directory.
+    pub fn from_file(filename: &str) -> Result<ServerConfig, Error> {
+        let path = Path::new("resources/").join(filename);
+        match std::fs::read_to_string(&path) {
+            Ok(config) => ServerConfig::from_json(&config),
+            Err(err) => Err(StorageError::FileNotFound {
+                path: path.display().to_string(),
+                cause: err.into(),
+            }
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test01_missing_fields_take_default_values() {
+        let config = ServerConfig::from_json("{}").expect("The config is invalid");
+
+        assert_eq!(config, ServerConfig::default());
+    }
+
+    #[test]
+    fn test02_parse_offline_allowance() {
+        let config =
+            ServerConfig::from_json("{\"offline_allowance\": 20}").expect("The config is invalid");
+
+        assert_eq!(config.offline_allowance, 20);
+    }
+
+    #[test]
+    fn test03_parse_retention_policy() {
+        let config =
+            ServerConfig::from_json("{\"compaction_interval\": 50, \"retained_entries\": 0}")
+                .expect("The config is invalid");
+
+        assert_eq!(config.compaction_interval, 50);
+        assert_eq!(config.retained_entries, 0);
+        assert_eq!(config.offline_allowance, 0);
+    }
+
+    #[test]
+    fn test04_invalid_config() {
+        let err = ServerConfig::from_json("{\"offline_allowance\": -1}")
+            .expect_err("The config is valid");
+
+        assert!(matches!(err, Error::Parse(ParseError::InvalidJson(_))));
+    }
+
+    #[test]
+    fn test05_parse_workers() {
+        let config = ServerConfig::from_json("{\"workers\": 3}").expect("The config is invalid");
+
+        assert_eq!(config.workers, 3);
+        assert!(ServerConfig::default().workers >= 1);
+    }
+
+    #[test]
+    fn test06_partitions_are_disabled_by_default() {
+        let config = ServerConfig::from_json("{\"partitions\": 64, \"replication_factor\": 3}")
+            .expect("The config is invalid");
+
+        assert_eq!(config.partitions, 64);
+        assert_eq!(config.replication_factor, 3);
+        assert_eq!(ServerConfig::default().partitions, 0);
+    }
+
+    #[test]
+    fn test07_parse_max_read_lag() {
+        let config =
+            ServerConfig::from_json("{\"max_read_lag\": 5}").expect("The config is invalid");
+
+        assert_eq!(config.max_read_lag, 5);
+        assert_eq!(ServerConfig::default().max_read_lag, 0);
+    }
+
+    #[test]
+    fn test08_crdt_accruals_are_disabled_by_default() {
+        let config =
+            ServerConfig::from_json("{\"crdt_accruals\": true}").expect("The config is invalid");
+
+        assert!(config.crdt_accruals);
+        assert!(!ServerConfig::default().crdt_accruals);
+    }
+}
@@ -0,0 +1,219 @@
+use std::{collections::BTreeSet, fmt};
+
+use crate::wire::{Account, ServerStatus};
+
+/// Accounts of a server and the index of its log, as it answers the state command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerState {
+    pub shop: u32,
+    pub index: u64,
+    pub status: ServerStatus,
+    pub accounts: Vec<Account>,
+}
+
+impl ServerState {
+    /// Returns the points and the block flag of the client in the server, if it has an account.
+    pub fn account(&self, client_id: u32) -> Option<(i32, bool)> {
+        self.accounts
+            .iter()
+            .find(|(client, _, _)| *client == client_id)
+            .map(|(_, points, blocked)| (*points, *blocked))
+    }
+}
+
+/// Account of a client that is not the same in every server, with its value in each one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub client: u32,
+    pub values: Vec<(u32, Option<(i32, bool)>)>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let values: Vec<String> = self
+            .values
+            .iter()
+            .map(|(shop, account)| format!("shop {} has {}", shop, describe(*account)))
+            .collect();
+        write!(f, "client {}: {}", self.client, values.join(", "))
+    }
+}
+
+/// Change of the account of a client in a server so it matches the log of the leader.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepairStep {
+    pub shop: u32,
+    pub client: u32,
+    pub current: Option<(i32, bool)>,
+    pub expected: (i32, bool),
+}
+
+impl fmt::Display for RepairStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "shop {}: set client {} to {} (has {})",
+            self.shop,
+            self.client,
+            describe(Some(self.expected)),
+            describe(self.current)
+        )
+    }
+}
+
+fn describe(account: Option<(i32, bool)>) -> String {
+    match account {
+        Some((points, true)) => format!("{} points (blocked)", points),
+        Some((points, false)) => format!("{} points", points),
+        None => "no account".to_string(),
+    }
+}
+
+/// Returns the clients whose account is not the same in every server.
+/// A missing account is the same as an empty one, since every write reaches every server,
+/// unless the accounts are partitioned: then only the servers with the account are compared.
+pub fn divergences(states: &[ServerState]) -> Vec<Divergence> {
+    let partitioned = states
+        .iter()
+        .any(|state| state.status == ServerStatus::Partitioned);
+    let clients: BTreeSet<u32> = states
+        .iter()
+        .flat_map(|state| state.accounts.iter().map(|(client, _, _)| *client))
+        .collect();
+    let mut divergences = vec![];
+    for client in clients {
+        let values: Vec<(u32, Option<(i32, bool)>)> = states
+            .iter()
+            .map(|state| (state.shop, state.account(client)))
+            .collect();
+        let compared: BTreeSet<(i32, bool)> = values
+            .iter()
+            .filter_map(|(_, account)| match account {
+                None if partitioned => None,
+                account => Some(account.unwrap_or((0, false))),
+            })
+            .collect();
+        if compared.len() > 1 {
+            divergences.push(Divergence { client, values });
+        }
+    }
+    divergences
+}
+
+/// Returns the changes that make the accounts of each server match the "expected" ones,
+/// the accounts that result from applying the log of the leader.
+pub fn repair_plan(expected: &[Account], states: &[ServerState]) -> Vec<RepairStep> {
+    let mut steps = vec![];
+    for state in states {
+        let clients: BTreeSet<u32> = expected
+            .iter()
+            .chain(state.accounts.iter())
+            .map(|(client, _, _)| *client)
+            .collect();
+        for client in clients {
+            let expected = expected
+                .iter()
+                .find(|(c, _, _)| *c == client)
+                .map_or((0, false), |(_, points, blocked)| (*points, *blocked));
+            let current = state.account(client);
+            if current.unwrap_or((0, false)) != expected {
+                steps.push(RepairStep {
+                    shop: state.shop,
+                    client,
+                    current,
+                    expected,
+                });
+            }
+        }
+    }
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(shop: u32, status: ServerStatus, accounts: Vec<Account>) -> ServerState {
+        ServerState {
+            shop,
+            index: 10,
+            status,
+            accounts,
+        }
+    }
+
+    #[test]
+    fn test01_divergent_clients_are_reported() {
+        let states = vec![
+            state(0, ServerStatus::Leader, vec![(1, 10, false), (2, 5, false)]),
+            state(
+                1,
+                ServerStatus::Follower,
+                vec![(1, 8, false), (3, 0, false)],
+            ),
+        ];
+
+        let divergences = divergences(&states);
+
+        assert_eq!(
+            divergences,
+            vec![
+                Divergence {
+                    client: 1,
+                    values: vec![(0, Some((10, false))), (1, Some((8, false)))]
+                },
+                Divergence {
+                    client: 2,
+                    values: vec![(0, Some((5, false))), (1, None)]
+                },
+            ]
+        );
+        assert_eq!(
+            divergences[1].to_string(),
+            "client 2: shop 0 has 5 points, shop 1 has no account"
+        );
+    }
+
+    #[test]
+    fn test02_partitioned_servers_only_compare_their_accounts() {
+        let states = vec![
+            state(0, ServerStatus::Partitioned, vec![(1, 10, false)]),
+            state(
+                1,
+                ServerStatus::Partitioned,
+                vec![(1, 10, false), (2, 5, true)],
+            ),
+            state(2, ServerStatus::Partitioned, vec![(2, 5, false)]),
+        ];
+
+        let divergences = divergences(&states);
+
+        assert_eq!(divergences.len(), 1);
+        assert_eq!(divergences[0].client, 2);
+    }
+
+    #[test]
+    fn test03_repair_plan_follows_the_expected_accounts() {
+        let states = vec![
+            state(0, ServerStatus::Leader, vec![(1, 10, false)]),
+            state(1, ServerStatus::Follower, vec![(1, 8, true), (2, 0, false)]),
+        ];
+
+        let plan = repair_plan(&[(1, 10, false)], &states);
+
+        assert_eq!(
+            plan,
+            vec![RepairStep {
+                shop: 1,
+                client: 1,
+                current: Some((8, true)),
+                expected: (10, false)
+            }]
+        );
+        assert_eq!(
+            plan[0].to_string(),
+            "shop 1: set client 1 to 10 points (has 8 points (blocked))"
+        );
+        assert_eq!("syncing".parse::<ServerStatus>(), Ok(ServerStatus::Syncing));
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+use crate::{
+    errors::{Error, ParseError, StorageError},
+    local_server::sync::CHUNK_BYTES,
+    wire::{parse_counters, PNCounter},
+};
+
+/// Points accrued by each client with cash payments, as a [`PNCounter`] for each client.
+/// Each shop increments its own total when it accrues points, and the leader decrements
+/// its own total when it settles the accrued points into the account of the client.
+/// The counters are stored in a file, rewritten after each change.
+pub struct Accruals {
+    path: String,
+    counters: HashMap<u32, PNCounter>,
+}
+
+impl Accruals {
+    /// Opens the counters of the given path. There are no counters if the file does not exist.
+    pub fn open(path: &str) -> Result<Accruals, Error> {
+        let mut counters = HashMap::new();
+        let file = match File::open(path) {
+            Ok(file) => Some(file),
+            Err(_) if !Path::new(path).exists() => None,
+            Err(err) => {
+                return Err(StorageError::CantRead {
+                    path: path.to_string(),
+                    cause: err.into(),
+                }
+                .into())
+            }
+        };
+        for line in file
+            .into_iter()
+            .flat_map(|file| BufReader::new(file).lines())
+        {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    return Err(StorageError::CantRead {
+                        path: path.to_string(),
+                        cause: err.into(),
+                    }
+                    .into())
+                }
+            };
+            match parse_counters(&line).as_deref() {
+                Some([(client_id, counter)]) => {
+                    counters.insert(*client_id, counter.clone());
+                }
+                _ => return Err(ParseError::InvalidLine(line).into()),
+            }
+        }
+        Ok(Accruals {
+            path: path.to_string(),
+            counters,
+        })
+    }
+
+    /// Returns the points accrued by the client and not settled yet.
+    pub fn points(&self, client_id: u32) -> i64 {
+        self.counters.get(&client_id).map_or(0, |c| c.value())
+    }
+
+    /// Accrues the points of a cash payment of the client in the total of the shop.
+    pub fn accrue(&mut self, client_id: u32, shop: u32, points: u32) -> Result<(), Error> {
+        self.counters
+            .entry(client_id)
+            .or_default()
+            .increment(shop, points as u64);
+        self.save()
+    }
+
+    /// Settles the points accrued by the client, decrementing them in the total of the shop.
+    /// Returns the points settled and the new total decremented by the shop,
+    /// or None if the client has no points to settle.
+    /// At most the points that fit in an account are settled, the rest stays accrued.
+    pub fn settle(&mut self, client_id: u32, shop: u32) -> Result<Option<(u32, u64)>, Error> {
+        let counter = match self.counters.get_mut(&client_id) {
+            Some(counter) if counter.value() > 0 => counter,
+            _ => return Ok(None),
+        };
+        let points = counter.value().min(i32::MAX as i64) as u32;
+        counter.decrement(shop, points as u64);
+        let total = counter.decrements_of(shop);
+        self.save()?;
+        Ok(Some((points, total)))
+    }
+
+    /// Applies a settlement made by the shop, which decremented "total" points of the client.
+    /// Applying it more than once, or after receiving it by gossip, does not change the counter.
+    pub fn apply_settlement(&mut self, client_id: u32, shop: u32, total: u64) -> Result<(), Error> {
+        let mut settled = PNCounter::new();
+        settled.decrement(shop, total);
+        self.merge(vec![(client_id, settled)]).map(|_| ())
+    }
+
+    /// Merges the counters received from another shop. Returns true if any counter changed.
+    pub fn merge(&mut self, counters: Vec<(u32, PNCounter)>) -> Result<bool, Error> {
+        let mut changed = false;
+        for (client_id, counter) in counters {
+            changed |= self.counters.entry(client_id).or_default().merge(&counter);
+        }
+        if changed {
+            self.save()?;
+        }
+        Ok(changed)
+    }
+
+    /// Returns the counters split in parts that fit in a message.
+    /// Each part is a list of `client:counter` separated by commas.
+    pub fn parts(&self) -> Vec<String> {
+        let mut clients: Vec<&u32> = self.counters.keys().collect();
+        clients.sort();
+        let mut parts = vec![];
+        let mut part = String::new();
+        for client_id in clients {
+            let counter = format!("{}:{}", client_id, self.counters[client_id].to_text());
+            if !part.is_empty() && part.len() + counter.len() + 1 > CHUNK_BYTES {
+                parts.push(part);
+                part = String::new();
+            }
+            if !part.is_empty() {
+                part.push(',');
+            }
+            part.push_str(&counter);
+        }
+        if !part.is_empty() {
+            parts.push(part);
+        }
+        parts
+    }
+
+    /// Writes the counters in a temporary file and renames it,
+    /// so the file always holds every counter.
+    fn save(&self) -> Result<(), Error> {
+        let tmp_path = format!("{}.tmp", self.path);
+        let mut content = String::new();
+        for part in self.parts() {
+            for counter in part.split(',') {
+                content.push_str(counter);
+                content.push('\n');
+            }
+        }
+        let written = File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(content.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp_path, &self.path));
+        match written {
+            Ok(_) => Ok(()),
+            Err(err) => Err(StorageError::CantWrite {
+                path: self.path.clone(),
+                cause: err.into(),
+            }
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use super::*;
+
+    fn accruals_path(name: &str) -> String {
+        let path = temp_dir().join(format!("accruals_{}_{}.txt", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path.display().to_string()
+    }
+
+    #[test]
+    fn test01_settlements_are_idempotent() {
+        let path = accruals_path("settle");
+        let mut leader = Accruals::open(&path).expect("Error opening the accruals");
+        leader.accrue(123, 1, 10).expect("Error accruing");
+        leader.accrue(123, 0, 5).expect("Error accruing");
+
+        assert_eq!(leader.settle(123, 0), Ok(Some((15, 15))));
+        assert_eq!(leader.points(123), 0);
+        assert_eq!(leader.settle(123, 0), Ok(None));
+
+        let mut follower = Accruals::open(&accruals_path("follower")).expect("Error opening");
+        follower.accrue(123, 1, 10).expect("Error accruing");
+        follower
+            .apply_settlement(123, 0, 15)
+            .expect("Error applying the settlement");
+        follower
+            .apply_settlement(123, 0, 15)
+            .expect("Error applying the settlement");
+        assert_eq!(follower.points(123), -5);
+
+        let parts = leader.parts();
+        let counters = parse_counters(&parts[0]).expect("Invalid part");
+        follower.merge(counters).expect("Error merging");
+        assert_eq!(follower.points(123), 0);
+    }
+
+    #[test]
+    fn test02_counters_are_recovered_from_the_file() {
+        let path = accruals_path("recover");
+        let mut accruals = Accruals::open(&path).expect("Error opening the accruals");
+        accruals.accrue(7, 2, 40).expect("Error accruing");
+        accruals.accrue(8, 2, 10).expect("Error accruing");
+
+        let recovered = Accruals::open(&path).expect("Error opening the accruals");
+
+        assert_eq!(recovered.points(7), 40);
+        assert_eq!(recovered.points(8), 10);
+        assert_eq!(recovered.parts(), accruals.parts());
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+};
+
+use tracing::warn;
+
+use crate::{
+    action::Action,
+    errors::{Error, OrderError, StorageError},
+    logging::SERVER,
+    message_parser::MessageParser,
+    payment_method::Method,
+    wire::{EntryKind, HistoryEntry, ENTRY_SEPARATOR},
+};
+
+use super::{log_record::LogRecord, sync::CHUNK_BYTES};
+
+/// Max bytes of history entries sent in a single message.
+const PART_BYTES: usize = CHUNK_BYTES;
+
+impl HistoryEntry {
+    /// Returns the movement of points of a record of the log, if the operation changed the balance.
+    pub fn from_record(record: &LogRecord) -> Option<HistoryEntry> {
+        if record.result != "ACK" {
+            return None;
+        }
+        let mut order = record.order;
+        let (kind, points) = match MessageParser::parse(record.operation.clone()).ok()? {
+            Action::CompleteOrder(_, price, Method::Cash, _) => (EntryKind::Accrual, price as i32),
+            Action::CompleteOrder(_, price, Method::Points, _) => {
+                (EntryKind::Redemption, -(price as i32))
+            }
+            Action::OfflineRedeem(_, price, _) => (EntryKind::Redemption, -(price as i32)),
+            Action::Grant(_, amount, _, _, _) => (EntryKind::Adjustment, amount as i32),
+            Action::Deduct(_, amount, _, _, _) => (EntryKind::Adjustment, -(amount as i32)),
+            Action::Reverse(_, order_id, _, _, amount, _) => {
+                order = Some(order_id);
+                (EntryKind::Adjustment, amount)
+            }
+            _ => return None,
+        };
+        Some(HistoryEntry {
+            client: record.client,
+            kind,
+            points,
+            order,
+            shop: record.shop,
+            timestamp: record.timestamp,
+        })
+    }
+}
+
+/// Movements of the points of every client, stored as json lines in a file.
+pub struct History {
+    path: String,
+    file: File,
+    entries: HashMap<u32, Vec<HistoryEntry>>,
+}
+
+impl History {
+    /// Opens the history of the given path and loads its entries.
+    /// The file is created if it does not exist.
+    pub fn open(path: &str) -> Result<History, Error> {
+        let mut entries: HashMap<u32, Vec<HistoryEntry>> = HashMap::new();
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(err) => {
+                        return Err(StorageError::CantRead {
+                            path: path.to_string(),
+                            cause: err.into(),
+                        }
+                        .into())
+                    }
+                };
+                match serde_json::from_str::<HistoryEntry>(&line) {
+                    Ok(entry) => entries.entry(entry.client).or_default().push(entry),
+                    Err(_) => warn!(target: SERVER, "invalid history entry: {}", line),
+                }
+            }
+        }
+        let file = match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                return Err(StorageError::CantWrite {
+                    path: path.to_string(),
+                    cause: err.into(),
+                }
+                .into())
+            }
+        };
+        Ok(History {
+            path: path.to_string(),
+            file,
+            entries,
+        })
+    }
+
+    /// Stores a new entry.
+    pub fn record(&mut self, entry: HistoryEntry) -> Result<(), Error> {
+        let mut line = serde_json::to_string(&entry).unwrap_or_default();
+        line.push('\n');
+        if let Err(err) = self.file.write_all(line.as_bytes()) {
+            return Err(self.write_error(err));
+        }
+        self.entries.entry(entry.client).or_default().push(entry);
+        Ok(())
+    }
+
+    /// Writes the entries to the disk.
+    pub fn sync(&self) -> Result<(), Error> {
+        match self.file.sync_all() {
+            Ok(_) => Ok(()),
+            Err(err) => Err(self.write_error(err)),
+        }
+    }
+
+    fn write_error(&self, err: io::Error) -> Error {
+        StorageError::CantWrite {
+            path: self.path.clone(),
+            cause: err.into(),
+        }
+        .into()
+    }
+
+    /// Returns the points that reverse the order of the client made in the shop:
+    /// the points accrued are taken back and the points redeemed are restored.
+    /// Returns error if the order has no movements or it was already refunded.
+    pub fn refund_points(&self, client: u32, shop: u32, order: u32) -> Result<i32, Error> {
+        let movements: Vec<&HistoryEntry> = match self.entries.get(&client) {
+            Some(entries) => entries
+                .iter()
+                .filter(|entry| entry.shop == shop && entry.order == Some(order))
+                .collect(),
+            None => vec![],
+        };
+        if movements
+            .iter()
+            .any(|entry| entry.kind == EntryKind::Adjustment)
+        {
+            return Err(OrderError::AlreadyRefunded(order).into());
+        }
+        if movements.is_empty() {
+            return Err(OrderError::UnknownOrder(order).into());
+        }
+        Ok(-movements.iter().map(|entry| entry.points).sum::<i32>())
+    }
+
+    /// Returns the entries of the client between the times `from` and `to`, both included.
+    pub fn query(&self, client: u32, from: Option<u64>, to: Option<u64>) -> Vec<HistoryEntry> {
+        match self.entries.get(&client) {
+            Some(entries) => entries
+                .iter()
+                .filter(|entry| from.is_none_or(|from| entry.timestamp >= from))
+                .filter(|entry| to.is_none_or(|to| entry.timestamp <= to))
+                .cloned()
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+/// Returns the HISTORY messages with the entries of the client:
+/// `HISTORY client part parts entry|entry|...`. There is always at least one part.
+pub fn history_parts(client: u32, entries: &[HistoryEntry]) -> Vec<String> {
+    let mut parts: Vec<String> = vec![];
+    let mut part = String::new();
+    for entry in entries {
+        let text = entry.to_text();
+        if !part.is_empty() && part.len() + text.len() + 1 > PART_BYTES {
+            parts.push(part);
+            part = String::new();
+        }
+        if !part.is_empty() {
+            part.push(ENTRY_SEPARATOR);
+        }
+        part.push_str(&text);
+    }
+    parts.push(part);
+
+    let amount = parts.len();
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, part)| {
+            format!("HISTORY {} {} {} {}", client, i, amount, part)
+                .trim_end()
+                .to_string()
+        })
+        .collect()
+}
+
+/// Returns the statement of the entries as csv, with the points accumulated after each entry.
+pub fn statement_csv(entries: &[HistoryEntry]) -> String {
+    let mut csv = "timestamp,kind,points,total,shop,order\n".to_string();
+    let mut total = 0;
+    for entry in entries {
+        total += entry.points;
+        let order = match entry.order {
+            Some(order) => order.to_string(),
+            None => String::new(),
+        };
+        csv.push_str(&format!(
+            "{},{},{},{},{},{}\n",
+            entry.timestamp, entry.kind, entry.points, total, entry.shop, order
+        ));
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use super::*;
+
+    fn entry(client: u32, points: i32, timestamp: u64) -> HistoryEntry {
+        HistoryEntry {
+            client,
+            kind: EntryKind::Accrual,
+            points,
+            order: Some(3),
+            shop: 0,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn test01_entries_of_the_records_that_moved_points() {
+        let accrual =
+            LogRecord::new("complete 123 10 cash 0 @order=4", "ACK", 1).expect("Invalid record");
+        let rejected =
+            LogRecord::new("complete 123 10 points 0", "notEnough 123", 1).expect("Invalid record");
+        let redeem = LogRecord::new("offlineRedeem 123 5 1", "ACK", 1).expect("Invalid record");
+        let block = LogRecord::new("block 123 0", "ACK", 1).expect("Invalid record");
+
+        let got = HistoryEntry::from_record(&accrual).expect("There is no entry");
+
+        assert_eq!(
+            (got.kind, got.points, got.order),
+            (EntryKind::Accrual, 10, Some(4))
+        );
+        assert_eq!(HistoryEntry::from_record(&rejected), None);
+        assert_eq!(
+            HistoryEntry::from_record(&redeem).map(|e| (e.kind, e.points, e.shop)),
+            Some((EntryKind::Redemption, -5, 1))
+        );
+        assert_eq!(HistoryEntry::from_record(&block), None);
+    }
+
+    #[test]
+    fn test02_history_is_stored_and_queried_by_time() {
+        let path = temp_dir()
+            .join(format!("tp2_history_{}.txt", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let mut history = History::open(&path).expect("Error opening history");
+        for e in [entry(1, 10, 100), entry(1, -5, 200), entry(2, 7, 150)] {
+            history.record(e).expect("Error recording entry");
+        }
+
+        let reopened = History::open(&path).expect("Error opening history");
+
+        assert_eq!(reopened.query(1, None, None).len(), 2);
+        assert_eq!(reopened.query(1, Some(150), None), vec![entry(1, -5, 200)]);
+        assert_eq!(reopened.query(1, None, Some(150)), vec![entry(1, 10, 100)]);
+        assert_eq!(reopened.query(3, None, None), vec![]);
+    }
+
+    #[test]
+    fn test03_refund_reverses_the_order_once() {
+        let path = temp_dir()
+            .join(format!("tp2_history_refund_{}.txt", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let mut history = History::open(&path).expect("Error opening history");
+        history
+            .record(entry(1, 10, 100))
+            .expect("Error recording entry");
+
+        let points = history.refund_points(1, 0, 3);
+        let record =
+            LogRecord::new("reverse 1 3 0 7 -10 cold coffee", "ACK", 1).expect("Invalid record");
+        let reverse = HistoryEntry::from_record(&record).expect("There is no entry");
+        history.record(reverse).expect("Error recording entry");
+
+        assert_eq!(points, Ok(-10));
+        assert_eq!(
+            history.refund_points(1, 0, 3),
+            Err(Error::Order(OrderError::AlreadyRefunded(3)))
+        );
+        assert_eq!(
+            history.refund_points(1, 1, 3),
+            Err(Error::Order(OrderError::UnknownOrder(3)))
+        );
+    }
+
+    #[test]
+    fn test04_history_parts_round_trip() {
+        let entries: Vec<HistoryEntry> = (0..100).map(|i| entry(1, i, 1000 + i as u64)).collect();
+
+        let parts = history_parts(1, &entries);
+        let parsed: Vec<HistoryEntry> = parts
+            .iter()
+            .flat_map(|part| {
+                part.splitn(5, ' ')
+                    .nth(4)
+                    .unwrap_or("")
+                    .split(ENTRY_SEPARATOR)
+            })
+            .map(|text| HistoryEntry::from_text(1, text).expect("Invalid entry"))
+            .collect();
+
+        assert!(parts.len() > 1);
+        assert!(parts[0].starts_with(&format!("HISTORY 1 0 {} ", parts.len())));
+        assert_eq!(parsed, entries);
+        assert_eq!(history_parts(1, &[]), vec!["HISTORY 1 0 1".to_string()]);
+    }
+
+    #[test]
+    fn test05_statement_accumulates_the_points() {
+        let mut redemption = entry(1, -4, 200);
+        redemption.kind = EntryKind::Redemption;
+        redemption.order = None;
+
+        let csv = statement_csv(&[entry(1, 10, 100), redemption]);
+
+        assert_eq!(
+            csv,
+            "timestamp,kind,points,total,shop,order\n100,accrual,10,10,0,3\n200,redemption,-4,6,0,\n"
+        );
+    }
+}
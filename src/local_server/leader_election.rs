@@ -1,13 +1,23 @@
 use std::{
     mem::size_of,
     net::{SocketAddr, UdpSocket},
-    sync::{Arc, Condvar, Mutex},
-    thread, vec,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+    vec,
 };
 
+use tracing::{info, warn};
+
 use crate::constants::TIMEOUT;
 use crate::errors;
-use errors::Error;
+use crate::fault_proxy::bind_addr;
+use crate::logging::ELECTION;
+use crate::metrics::{registry, ELECTIONS, ELECTION_DURATION};
+use errors::{ElectionError, Error, TransportError};
 
 /// Returns socket address of leader node
 pub fn id_to_ctrladdr(id: usize) -> SocketAddr {
@@ -17,36 +27,50 @@ pub fn id_to_ctrladdr(id: usize) -> SocketAddr {
 
 pub struct LeaderElection {
     id: usize,
-    socket: UdpSocket,
+    socket: Arc<UdpSocket>,
     leader_id: Arc<(Mutex<Option<usize>>, Condvar)>,
     got_ack: Arc<(Mutex<Option<usize>>, Condvar)>,
     stop: Arc<(Mutex<bool>, Condvar)>,
     shops_amount: u32,
+    // Amount of leaders elected since the server started
+    term: Arc<AtomicU64>,
+    // Time this server was the leader before its current term, and when the current one started
+    led: Arc<Mutex<(Duration, Option<Instant>)>>,
 }
 
 impl LeaderElection {
-    pub fn new(id: usize, shops_amount: u32) -> LeaderElection {
+    pub fn new(id: usize, shops_amount: u32) -> Result<LeaderElection, Error> {
+        let addr = bind_addr(id_to_ctrladdr(id));
+        let socket = match UdpSocket::bind(addr) {
+            Ok(socket) => Arc::new(socket),
+            Err(err) => {
+                return Err(TransportError::CantBindSocket {
+                    addr: addr.to_string(),
+                    cause: err.into(),
+                }
+                .into())
+            }
+        };
         let mut leader = LeaderElection {
             id,
-            socket: UdpSocket::bind(id_to_ctrladdr(id)).expect("Error when binding server socket"),
+            socket,
             leader_id: Arc::new((Mutex::new(Some(id)), Condvar::new())),
             got_ack: Arc::new((Mutex::new(None), Condvar::new())),
             stop: Arc::new((Mutex::new(false), Condvar::new())),
             shops_amount,
+            term: Arc::new(AtomicU64::new(0)),
+            led: Arc::new(Mutex::new((Duration::ZERO, None))),
         };
         let mut clone = leader.clone_leader_election();
         thread::spawn(move || clone.run());
 
         // Find new leader
         leader.find_new();
-        leader
+        Ok(leader)
     }
 
     pub fn am_i_leader(&self) -> Result<bool, Error> {
-        match self.get_leader_id() {
-            Ok(leader_id) => Ok(leader_id == self.id),
-            Err(_) => Err(Error::CantGetShopId),
-        }
+        Ok(self.get_leader_id()? == self.id)
     }
 
     pub fn get_leader_id(&self) -> Result<usize, Error> {
@@ -60,13 +84,26 @@ impl LeaderElection {
                     if let Some(leader_id) = *leader_id_guard {
                         Ok(leader_id)
                     } else {
-                        Err(Error::CantGetLeaderId)
+                        Err(ElectionError::UnknownLeader.into())
                     }
                 }
-                Err(_) => Err(Error::CantGetLeaderId),
+                Err(_) => Err(Error::Lock),
             }
         } else {
-            Err(Error::CantLockLeaderId)
+            Err(Error::Lock)
+        }
+    }
+
+    // Get the amount of leaders this server has seen elected
+    pub fn term(&self) -> u64 {
+        self.term.load(Ordering::SeqCst)
+    }
+
+    // Get the time this server has been the leader
+    pub fn time_as_leader(&self) -> Duration {
+        match self.led.lock() {
+            Ok(led) => led.0 + led.1.map_or(Duration::ZERO, |since| since.elapsed()),
+            Err(_) => Duration::ZERO,
         }
     }
 
@@ -78,8 +115,20 @@ impl LeaderElection {
     // Set leader id value
     fn set_leader_id(&mut self, value: Option<usize>) {
         if let Ok(mut leader_id_lock) = self.leader_id.0.lock() {
+            if value.is_some() {
+                self.term.fetch_add(1, Ordering::SeqCst);
+            }
+            if let Ok(mut led) = self.led.lock() {
+                match (led.1, value == Some(self.id)) {
+                    (None, true) => led.1 = Some(Instant::now()),
+                    (Some(since), false) => *led = (led.0 + since.elapsed(), None),
+                    _ => (),
+                }
+            }
             *leader_id_lock = value
         }
+        // Wakes up the threads waiting for a leader
+        self.leader_id.1.notify_all();
     }
 
     // Find new leader
@@ -89,9 +138,9 @@ impl LeaderElection {
                 return;
             }
         }
-        print!("\x1b[34m");
-        println!("[SERVER OF SHOP {}]: Finding new leader", self.id);
-        print!("\x1b[0m");
+        info!(target: ELECTION, shop = self.id, "finding new leader");
+        registry().inc(&ELECTIONS, &[]);
+        let started_at = Instant::now();
         self.set_leader_id(None);
 
         // Send ELECTION message to all shops
@@ -111,6 +160,7 @@ impl LeaderElection {
                 self.set_leader_id(Some(self.id));
             }
         }
+        registry().observe(&ELECTION_DURATION, &[], started_at.elapsed().as_secs_f64());
     }
 
     fn ids_to_msg(&self, header: u8, ids: &[usize]) -> Vec<u8> {
@@ -132,30 +182,57 @@ impl LeaderElection {
     fn safe_send_next(&self, msg: &[u8], id: usize) -> Result<(), Error> {
         let next_id = self.next(id);
         if next_id == self.id {
-            return Err(Error::Timeout);
+            return Err(ElectionError::NoAnswer.into());
         }
         self.clone_leader_election().set_got_ack(None);
 
-        let _ = self.socket.send_to(msg, id_to_ctrladdr(next_id));
+        // A failed send is handled as a lost message: the next node is skipped when the ack times out
+        let _ = self.send(msg, id_to_ctrladdr(next_id));
 
-        if let Ok(got_ack_lock) = self.got_ack.0.lock() {
-            let got_ack = self
-                .got_ack
-                .1
-                .wait_timeout_while(got_ack_lock, TIMEOUT, |got_it| {
-                    got_it.is_none() || got_it.unwrap() != next_id
-                });
-            if got_ack.unwrap().1.timed_out() {
-                match self.safe_send_next(msg, next_id) {
-                    Ok(_) => (),
-                    Err(_) => return Err(Error::Timeout),
+        let timed_out = match self.got_ack.0.lock() {
+            Ok(got_ack_lock) => {
+                match self
+                    .got_ack
+                    .1
+                    .wait_timeout_while(got_ack_lock, TIMEOUT, |got_it| *got_it != Some(next_id))
+                {
+                    Ok((_, wait)) => wait.timed_out(),
+                    Err(_) => return Err(Error::Lock),
                 }
             }
+            Err(_) => return Err(Error::Lock),
+        };
+        if timed_out {
+            match self.safe_send_next(msg, next_id) {
+                Ok(_) => (),
+                Err(_) => return Err(ElectionError::NoAnswer.into()),
+            }
         }
 
         Ok(())
     }
 
+    /// Sends the message to the node at "to", reporting the error if it fails.
+    fn send(&self, msg: &[u8], to: SocketAddr) -> Result<(), Error> {
+        match self.socket.send_to(msg, to) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                warn!(
+                    target: ELECTION,
+                    shop = self.id,
+                    to = %to,
+                    "error sending election message: {}",
+                    err
+                );
+                Err(TransportError::CantSendMessage {
+                    to: to.to_string(),
+                    cause: err.into(),
+                }
+                .into())
+            }
+        }
+    }
+
     // Returns a buffer to receive messages
     fn get_buffer(self) -> Vec<u8> {
         let vec_capacity =
@@ -170,79 +247,104 @@ impl LeaderElection {
 
     fn parse_message(&self, buf: &[u8]) -> Result<(u8, Vec<usize>), Error> {
         let mut ids = vec![];
-
-        if let Ok(value) = buf[1..1 + size_of::<usize>()].try_into() {
-            let count = usize::from_le_bytes(value);
-            let mut pos = 1 + size_of::<usize>();
-            for _id in 0..count {
-                if let Ok(value) = buf[pos..pos + size_of::<usize>()].try_into() {
-                    ids.push(usize::from_le_bytes(value));
-                    pos += size_of::<usize>();
-                }
+        let read = |pos: usize| -> Result<usize, Error> {
+            match buf
+                .get(pos..pos + size_of::<usize>())
+                .map(|bytes| bytes.try_into())
+            {
+                Some(Ok(value)) => Ok(usize::from_le_bytes(value)),
+                _ => Err(ElectionError::InvalidMessage.into()),
             }
+        };
 
-            Ok((buf[0], ids))
-        } else {
-            Err(Error::CantParseMessage)
+        let header = match buf.first() {
+            Some(header) => *header,
+            None => return Err(ElectionError::InvalidMessage.into()),
+        };
+        let count = read(1)?;
+        let mut pos = 1 + size_of::<usize>();
+        for _id in 0..count {
+            ids.push(read(pos)?);
+            pos += size_of::<usize>();
         }
+
+        Ok((header, ids))
     }
 
     fn receive_message(&mut self, buf: &mut [u8]) -> Result<SocketAddr, Error> {
-        let (_size, from) = if let Ok(resp) = self.socket.recv_from(buf) {
-            resp
-        } else {
-            return Err(Error::CantReceiveMessage);
-        };
-        Ok(from)
+        match self.socket.recv_from(buf) {
+            Ok((_size, from)) => Ok(from),
+            Err(err) => Err(TransportError::CantReceiveMessage(err.into()).into()),
+        }
     }
 
-    fn add_id_to_got_ack(&mut self, ids: &[usize]) {
+    fn add_id_to_got_ack(&mut self, id: usize) {
         if let Ok(mut got_ack_lock) = self.got_ack.0.lock() {
-            *got_ack_lock = Some(ids[0]);
+            *got_ack_lock = Some(id);
         }
         self.got_ack.1.notify_all();
     }
 
-    fn run(&mut self) -> Result<(), Error> {
+    /// Handles the election messages of the other nodes. Messages that can't be
+    /// received or parsed are reported and dropped, so the thread keeps running.
+    fn run(&mut self) {
         loop {
             let mut buf = self.clone_leader_election().get_buffer();
-            let from = self.clone_leader_election().receive_message(&mut buf)?;
-            if let Ok(stop_lock) = self.stop.0.lock() {
-                if *stop_lock {
+            let from = match self.clone_leader_election().receive_message(&mut buf) {
+                Ok(from) => from,
+                Err(err) => {
+                    warn!(target: ELECTION, shop = self.id, "error receiving election message: {}", err);
+                    continue;
+                }
+            };
+            let (msg_type, mut ids) = match self.parse_message(&buf) {
+                Ok(message) => message,
+                Err(err) => {
+                    warn!(target: ELECTION, shop = self.id, from = %from, "invalid election message: {}", err);
                     continue;
                 }
+            };
+            // A stopped server still takes the acks of the messages it sends
+            if msg_type != b'A' && self.is_stopped() {
+                continue;
             }
-            let (msg_type, mut ids) = self.parse_message(&buf)?;
             match msg_type {
                 b'A' => {
-                    self.clone_leader_election().add_id_to_got_ack(&ids);
+                    if let Some(id) = ids.first() {
+                        self.clone_leader_election().add_id_to_got_ack(*id);
+                    }
                 }
                 b'E' => {
-                    self.socket
-                        .send_to(&self.ids_to_msg(b'A', &[self.id]), from)
-                        .expect("Error when sending message");
+                    let _ = self.send(&self.ids_to_msg(b'A', &[self.id]), from);
                     if ids.contains(&self.id) {
                         // Message has been sent to all nodes, send COORDINATOR message
                         if let Some(winner) = ids.iter().max() {
-                            self.socket
-                                .send_to(&self.ids_to_msg(b'C', &[*winner]), from)
-                                .expect("Error when sending message");
+                            let _ = self.send(&self.ids_to_msg(b'C', &[*winner]), from);
                         }
                     } else {
                         // Message has not been sent to all nodes, send ELECTION message to next shop
                         ids.push(self.id);
                         let msg = self.ids_to_msg(b'E', &ids);
-                        let clone = self.clone_leader_election();
-                        thread::spawn(move || clone.safe_send_next(&msg, clone.id));
+                        let mut clone = self.clone_leader_election();
+                        thread::spawn(move || {
+                            // If no other node answers, it is the only one left and becomes leader
+                            if clone.safe_send_next(&msg, clone.id).is_err() {
+                                clone.set_leader_id(Some(clone.id));
+                            }
+                        });
                     }
                 }
                 b'C' => {
-                    let winner_id = Some(ids[0]);
+                    let winner_id = match ids.first() {
+                        Some(winner_id) => Some(*winner_id),
+                        None => {
+                            warn!(target: ELECTION, shop = self.id, from = %from, "coordinator message without winner");
+                            continue;
+                        }
+                    };
                     self.clone_leader_election().set_leader_id(winner_id);
                     self.leader_id.1.notify_all();
-                    self.socket
-                        .send_to(&self.ids_to_msg(b'A', &[self.id]), from)
-                        .expect("Error when sending message");
+                    let _ = self.send(&self.ids_to_msg(b'A', &[self.id]), from);
                     if !ids[1..].contains(&self.id) {
                         ids.push(self.id);
                         let msg = self.ids_to_msg(b'C', &ids);
@@ -251,7 +353,7 @@ impl LeaderElection {
                     }
                 }
                 _ => {
-                    println!("[{}] ??? {:?}", self.id, ids);
+                    warn!(target: ELECTION, shop = self.id, "unknown election message {:?}", ids);
                 }
             }
         }
@@ -264,6 +366,35 @@ impl LeaderElection {
         }
     }
 
+    fn is_stopped(&self) -> bool {
+        match self.stop.0.lock() {
+            Ok(stop_lock) => *stop_lock,
+            Err(_) => false,
+        }
+    }
+
+    /// Stops taking part in the elections and starts one among the rest of the servers,
+    /// so they elect a new leader without waiting for this one to time out.
+    pub fn resign(&mut self) {
+        self.stop();
+        if let Ok(mut led) = self.led.lock() {
+            if let Some(since) = led.1 {
+                *led = (led.0 + since.elapsed(), None);
+            }
+        }
+        info!(target: ELECTION, shop = self.id, "handing off the leadership");
+        let _ = self.safe_send_next(&self.ids_to_msg(b'E', &[]), self.id);
+    }
+
+    /// Becomes the leader and announces it to the rest of the servers with a coordinator message.
+    pub fn take_over(&mut self) {
+        info!(target: ELECTION, shop = self.id, "taking over the leadership");
+        self.set_leader_id(Some(self.id));
+        let msg = self.ids_to_msg(b'C', &[self.id]);
+        let clone = self.clone_leader_election();
+        thread::spawn(move || clone.safe_send_next(&msg, clone.id));
+    }
+
     pub fn up(&mut self) {
         let (stop_lock, _) = &*self.stop;
         if let Ok(mut stop_lock) = stop_lock.lock() {
@@ -274,14 +405,13 @@ impl LeaderElection {
     pub fn clone_leader_election(&self) -> LeaderElection {
         LeaderElection {
             id: self.id,
-            socket: self
-                .socket
-                .try_clone()
-                .expect("Error when trying to clone udp socket"),
+            socket: self.socket.clone(),
             leader_id: self.leader_id.clone(),
             got_ack: self.got_ack.clone(),
             stop: self.stop.clone(),
             shops_amount: self.shops_amount,
+            term: self.term.clone(),
+            led: self.led.clone(),
         }
     }
 }
@@ -0,0 +1,346 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Lines},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    action::Action,
+    clock::now_millis,
+    errors::{Error, ParseError, StorageError},
+    message_parser::MessageParser,
+    metadata::{self, MACHINE, OFFLINE, ORDER},
+};
+
+/// An operation of the log of a server, written as a json line.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    /// Index of the record in its log.
+    pub seq: u64,
+    /// Milliseconds since the unix epoch when the server logged the operation.
+    pub timestamp: u64,
+    /// Amount of leaders the server had seen elected when it logged the operation.
+    pub term: u64,
+    /// Shop where the operation was made.
+    pub shop: u32,
+    /// Coffee machine that sent the operation, if it is known.
+    pub machine: Option<u32>,
+    /// Order of the operation, if it is known.
+    #[serde(default)]
+    pub order: Option<u32>,
+    /// Milliseconds since the unix epoch when the server went down, only in the down log.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub down_since: Option<u64>,
+    /// Id of the offline operation, if the leader merged it when reconciling a shop.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offline: Option<String>,
+    pub client: u32,
+    /// Message of the operation, without metadata.
+    pub operation: String,
+    /// Answer of the server to the operation.
+    pub result: String,
+    /// CRC32 of the other fields.
+    pub checksum: u32,
+}
+
+impl LogRecord {
+    /// Creates the record of an operation message and its result.
+    /// Returns error if the message is not an operation of the log.
+    pub fn new(message: &str, result: &str, term: u64) -> Result<LogRecord, Error> {
+        let (client, shop) = match MessageParser::parse(message.to_string())? {
+            Action::Block(client, shop) => (client, shop),
+            Action::CompleteOrder(client, _, _, shop) => (client, shop),
+            Action::FailOrder(client, shop) => (client, shop),
+            Action::OfflineRedeem(client, _, shop) => (client, shop),
+            Action::Grant(client, _, shop, _, _) => (client, shop),
+            Action::Deduct(client, _, shop, _, _) => (client, shop),
+            Action::Reverse(client, _, shop, _, _, _) => (client, shop),
+            Action::Settle(client, _, shop, _) => (client, shop),
+            _ => return Err(ParseError::InvalidMessage(message.to_string()).into()),
+        };
+        let words: Vec<&str> = message.split(' ').collect();
+        let mut record = LogRecord {
+            seq: 0,
+            timestamp: now_millis(),
+            term,
+            shop,
+            machine: metadata::get(message, MACHINE).and_then(|m| m.parse::<u32>().ok()),
+            order: metadata::get(message, ORDER).and_then(|o| o.parse::<u32>().ok()),
+            down_since: None,
+            offline: metadata::get(message, OFFLINE),
+            client,
+            operation: metadata::strip(&words).join(" "),
+            result: result.to_string(),
+            checksum: 0,
+        };
+        record.seal();
+        Ok(record)
+    }
+
+    /// Returns the message of the operation with its metadata, as it is replicated.
+    pub fn message(&self) -> String {
+        let mut message = self.operation.clone();
+        if let Some(machine) = self.machine {
+            message = metadata::with(&message, MACHINE, &machine.to_string());
+        }
+        if let Some(order) = self.order {
+            message = metadata::with(&message, ORDER, &order.to_string());
+        }
+        if let Some(id) = &self.offline {
+            message = metadata::with(&message, OFFLINE, id);
+        }
+        message
+    }
+
+    /// Sets the sequence number of the record and updates its checksum.
+    pub fn set_seq(&mut self, seq: u64) {
+        self.seq = seq;
+        self.seal();
+    }
+
+    /// Sets when the server went down, for the records of the down log, and updates its checksum.
+    pub fn set_down_since(&mut self, since: u64) {
+        self.down_since = Some(since);
+        self.seal();
+    }
+
+    /// Returns true if the checksum matches the fields of the record.
+    pub fn verify(&self) -> bool {
+        self.checksum == self.compute_checksum()
+    }
+
+    /// Returns the json line of the record.
+    pub fn to_line(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// Parses a record from its json line.
+    /// Returns error if the line is not a record or its checksum does not match.
+    pub fn from_line(line: &str) -> Result<LogRecord, Error> {
+        let record = match serde_json::from_str::<LogRecord>(line) {
+            Ok(record) => record,
+            Err(err) => return Err(ParseError::InvalidJson(err.into()).into()),
+        };
+        if !record.verify() {
+            return Err(StorageError::InvalidChecksum(record.seq).into());
+        }
+        Ok(record)
+    }
+
+    fn seal(&mut self) {
+        self.checksum = self.compute_checksum();
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        let mut fields = format!(
+            "{}|{}|{}|{}|{:?}|{:?}|{}|{}|{}",
+            self.seq,
+            self.timestamp,
+            self.term,
+            self.shop,
+            self.machine,
+            self.order,
+            self.client,
+            self.operation,
+            self.result
+        );
+        if let Some(since) = self.down_since {
+            fields.push_str(&format!("|{}", since));
+        }
+        if let Some(id) = &self.offline {
+            fields.push_str(&format!("|{}", id));
+        }
+        crc32fast::hash(fields.as_bytes())
+    }
+}
+
+/// Reads the records of a log file, skipping its header lines.
+/// Each item is the line number and the record read from it.
+pub struct LogReader {
+    path: String,
+    lines: Lines<BufReader<File>>,
+    line: usize,
+}
+
+impl LogReader {
+    /// Opens the log file of the given path.
+    pub fn open(path: &str) -> Result<LogReader, Error> {
+        match File::open(path) {
+            Ok(file) => Ok(LogReader {
+                path: path.to_string(),
+                lines: BufReader::new(file).lines(),
+                line: 0,
+            }),
+            Err(err) => Err(StorageError::FileNotFound {
+                path: path.to_string(),
+                cause: err.into(),
+            }
+            .into()),
+        }
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = (usize, Result<LogRecord, Error>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.line += 1;
+            match self.lines.next()? {
+                Ok(line) if line.starts_with('#') => continue,
+                Ok(line) => return Some((self.line, LogRecord::from_line(&line))),
+                Err(err) => {
+                    let err = StorageError::CantRead {
+                        path: self.path.clone(),
+                        cause: err.into(),
+                    };
+                    return Some((self.line, Err(err.into())));
+                }
+            }
+        }
+    }
+}
+
+/// Filter of the records of a log. Every field that is set has to match.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecordFilter {
+    pub client: Option<u32>,
+    pub shop: Option<u32>,
+    /// First millisecond of the time range.
+    pub from: Option<u64>,
+    /// Last millisecond of the time range.
+    pub to: Option<u64>,
+}
+
+impl RecordFilter {
+    /// Returns true if the record matches the filter.
+    pub fn matches(&self, record: &LogRecord) -> bool {
+        self.client.is_none_or(|client| record.client == client)
+            && self.shop.is_none_or(|shop| record.shop == shop)
+            && self.from.is_none_or(|from| record.timestamp >= from)
+            && self.to.is_none_or(|to| record.timestamp <= to)
+    }
+}
+
+/// Checks every record of a log and returns the problems found:
+/// lines that are not records, wrong checksums and gaps in the sequence numbers.
+pub fn verify(reader: LogReader) -> Vec<String> {
+    let mut problems = vec![];
+    let mut expected: Option<u64> = None;
+    for (line, record) in reader {
+        match record {
+            Ok(record) => {
+                if let Some(seq) = expected.filter(|seq| *seq != record.seq) {
+                    problems.push(format!(
+                        "line {}: expected seq {} but found {}",
+                        line, seq, record.seq
+                    ));
+                }
+                expected = Some(record.seq + 1);
+            }
+            Err(Error::Storage(StorageError::InvalidChecksum(_))) => {
+                problems.push(format!("line {}: wrong checksum", line))
+            }
+            Err(_) => problems.push(format!("line {}: invalid record", line)),
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env::temp_dir, fs};
+
+    use super::*;
+
+    #[test]
+    fn test01_record_of_an_operation() {
+        let message = "complete 123 10 cash 2 @machine=1 @order=8";
+        let record = LogRecord::new(message, "ACK", 3).expect("Invalid record");
+
+        assert_eq!(record.client, 123);
+        assert_eq!(record.shop, 2);
+        assert_eq!(record.machine, Some(1));
+        assert_eq!(record.order, Some(8));
+        assert_eq!(record.operation, "complete 123 10 cash 2");
+        assert_eq!(record.message(), message);
+        assert_eq!(LogRecord::from_line(&record.to_line()), Ok(record));
+        assert!(LogRecord::new("ACK", "ACK", 3).is_err());
+    }
+
+    #[test]
+    fn test02_tampered_record_fails_the_checksum() {
+        let mut record = LogRecord::new("block 123 0", "ACK", 1).expect("Invalid record");
+        record.set_seq(7);
+        assert!(record.verify());
+
+        record.client = 124;
+
+        assert!(!record.verify());
+        assert_eq!(
+            LogRecord::from_line(&record.to_line()),
+            Err(Error::Storage(StorageError::InvalidChecksum(7)))
+        );
+    }
+
+    #[test]
+    fn test03_filter_records() {
+        let mut record = LogRecord::new("fail 123 1", "ACK", 1).expect("Invalid record");
+        record.timestamp = 100;
+
+        let filter = RecordFilter {
+            client: Some(123),
+            from: Some(50),
+            to: Some(150),
+            ..Default::default()
+        };
+
+        assert!(filter.matches(&record));
+        assert!(!RecordFilter {
+            shop: Some(0),
+            ..Default::default()
+        }
+        .matches(&record));
+    }
+
+    #[test]
+    fn test04_verify_reports_gaps_and_bad_lines() {
+        let path = temp_dir()
+            .join(format!("tp2_records_{}.txt", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let mut lines = vec!["#base 4".to_string()];
+        for seq in [4, 5, 7] {
+            let mut record = LogRecord::new("block 123 0", "ACK", 1).expect("Invalid record");
+            record.set_seq(seq);
+            lines.push(record.to_line());
+        }
+        lines.push("block 123 0".to_string());
+        fs::write(&path, lines.join("\n")).expect("Error writing log");
+
+        let problems = verify(LogReader::open(&path).expect("Error opening log"));
+
+        assert_eq!(
+            problems,
+            vec![
+                "line 4: expected seq 6 but found 7".to_string(),
+                "line 5: invalid record".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test05_records_keep_the_id_of_merged_offline_operations() {
+        let message = "offlineRedeem 123 10 0 @offline=0-10-3";
+        let mut record = LogRecord::new(message, "ACK", 1).expect("Invalid record");
+
+        assert_eq!(record.offline, Some("0-10-3".to_string()));
+        assert_eq!(record.message(), message);
+        assert_eq!(LogRecord::from_line(&record.to_line()), Ok(record.clone()));
+
+        record.offline = Some("0-10-4".to_string());
+
+        assert!(!record.verify());
+    }
+}
@@ -1,32 +1,44 @@
-use std::{env, process};
+use std::{env, process::ExitCode};
 
-use tp2::{errors::Error, local_server::server::Server};
+use tp2::{
+    errors::{self, ConfigError, Error},
+    local_server::{config::ServerConfig, server::Server},
+    logging::{self, SERVER},
+};
+use tracing::info;
 
-fn id_missing() -> i32 {
-    println!("Number of shop must be specified");
-    -1
+fn parse_arg(args: &[String], id: usize, name: &'static str) -> Result<u32, Error> {
+    match args.get(id) {
+        None => Err(ConfigError::MissingArgument(name).into()),
+        Some(value) => match value.parse::<u32>() {
+            Ok(parsed_value) => Ok(parsed_value),
+            Err(_) => Err(ConfigError::InvalidArgument {
+                name,
+                value: value.clone(),
+            }
+            .into()),
+        },
+    }
 }
 
-fn parse_arg(args: Vec<String>, id: usize) -> Result<u32, Error> {
-    if let Ok(parsed_value) = args[id].parse::<u32>() {
-        Ok(parsed_value)
-    } else {
-        Err(Error::CantGetShopId)
-    }
+fn main() -> ExitCode {
+    errors::report(run())
 }
 
-fn main() -> Result<(), Error> {
+fn run() -> Result<(), Error> {
     let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        process::exit(id_missing());
-    }
+    let shop_id = parse_arg(&args, 1, "shop_id")?;
+    let shop_amount = parse_arg(&args, 2, "shops_amount")?;
 
-    let shop_id = parse_arg(args.clone(), 1)?;
-    let shop_amount = parse_arg(args, 2)?;
-    println!("Nº OF SHOPS: {}", shop_amount);
+    logging::init()?;
+    info!(target: SERVER, shop = shop_id, "Nº OF SHOPS: {}", shop_amount);
+    let config = match args.get(3) {
+        Some(filename) => ServerConfig::from_file(filename)?,
+        None => ServerConfig::default(),
+    };
 
     // Start shop server
-    let server = Server::new(shop_id, shop_amount);
+    let server = Server::new(shop_id, shop_amount, config)?;
     server.run()?;
 
     Ok(())
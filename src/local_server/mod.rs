@@ -1,2 +1,17 @@
+pub mod anti_entropy;
+pub mod config;
+pub mod consistency;
+pub mod crdt;
+pub mod history;
 pub mod leader_election;
+pub mod log_record;
+pub mod offline_credit;
+pub mod operation_log;
+pub mod partitions;
+pub mod pipeline;
+pub mod reconciliation;
+pub mod roles;
 pub mod server;
+pub mod shards;
+pub mod snapshot;
+pub mod sync;
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+/// Tracks the points redeemed by each customer while the shop is down.
+#[derive(Debug, Clone, Default)]
+pub struct OfflineCredit {
+    allowance: u32,
+    redeemed: HashMap<u32, u32>,
+}
+
+impl OfflineCredit {
+    /// Creates a new instance of [`OfflineCredit`] with the points each customer can redeem.
+    pub fn new(allowance: u32) -> OfflineCredit {
+        OfflineCredit {
+            allowance,
+            redeemed: HashMap::new(),
+        }
+    }
+
+    /// Redeems the points if the customer does not go over its allowance
+    /// nor over the last known balance of its account.
+    /// Returns false if the points can not be redeemed.
+    pub fn try_redeem(&mut self, client_id: u32, points: u32, last_balance: i32) -> bool {
+        let redeemed = self.redeemed.get(&client_id).copied().unwrap_or(0);
+        let total = match redeemed.checked_add(points) {
+            Some(total) => total,
+            None => return false,
+        };
+        if total > self.allowance || total as i64 > last_balance as i64 {
+            return false;
+        }
+        self.redeemed.insert(client_id, total);
+        true
+    }
+
+    /// Forgets the points redeemed, called each time the shop goes down.
+    pub fn reset(&mut self) {
+        self.redeemed.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OfflineCredit;
+
+    #[test]
+    fn test01_redeem_up_to_the_allowance() {
+        let mut credit = OfflineCredit::new(20);
+
+        assert!(credit.try_redeem(123, 10, 100));
+        assert!(credit.try_redeem(123, 10, 100));
+        assert!(!credit.try_redeem(123, 10, 100));
+        assert!(credit.try_redeem(124, 10, 100));
+    }
+
+    #[test]
+    fn test02_redeem_up_to_the_last_known_balance() {
+        let mut credit = OfflineCredit::new(20);
+
+        assert!(!credit.try_redeem(123, 10, 5));
+        assert!(credit.try_redeem(123, 5, 5));
+    }
+
+    #[test]
+    fn test03_without_allowance_nothing_is_redeemed() {
+        let mut credit = OfflineCredit::new(0);
+
+        assert!(!credit.try_redeem(123, 10, 100));
+    }
+
+    #[test]
+    fn test04_reset_restores_the_allowance() {
+        let mut credit = OfflineCredit::new(10);
+        assert!(credit.try_redeem(123, 10, 100));
+
+        credit.reset();
+
+        assert!(credit.try_redeem(123, 10, 100));
+    }
+
+    #[test]
+    fn test05_overflowing_redemption_is_refused() {
+        let mut credit = OfflineCredit::new(u32::MAX);
+        assert!(credit.try_redeem(123, 10, 100));
+
+        assert!(!credit.try_redeem(123, u32::MAX, i32::MAX));
+        assert!(credit.try_redeem(123, 10, 100));
+    }
+}
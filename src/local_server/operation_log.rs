@@ -0,0 +1,432 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+};
+
+use tracing::warn;
+
+use crate::{
+    errors::{Error, ParseError, StorageError},
+    logging::SERVER,
+    points_handler::PointsHandler,
+};
+
+use super::{
+    log_record::LogRecord,
+    snapshot::{apply_entry, Snapshot},
+};
+
+/// Prefix of the first line of the file, holding the index of its first entry.
+const BASE_HEADER: &str = "#base ";
+
+/// Append only log of the operations applied by a server.
+/// Each entry is a [`LogRecord`] in a line of the file, and is identified by its index.
+/// The entries before the index of the snapshot can be compacted into it.
+pub struct OperationLog {
+    path: String,
+    snapshot_path: String,
+    file: File,
+    base_index: u64,
+    next_index: u64,
+    snapshot_index: u64,
+}
+
+impl OperationLog {
+    /// Creates an empty log in the given path, discarding its previous snapshot.
+    pub fn create(path: &str, snapshot_path: &str) -> Result<OperationLog, Error> {
+        Snapshot::default().write_atomic(snapshot_path)?;
+        let file = write_file(path, 0, &[])?;
+        Ok(OperationLog {
+            path: path.to_string(),
+            snapshot_path: snapshot_path.to_string(),
+            file,
+            base_index: 0,
+            next_index: 0,
+            snapshot_index: 0,
+        })
+    }
+
+    /// Opens the log of the given path, creating it if it does not exist.
+    /// A torn last entry is dropped. Returns error if any other entry is invalid.
+    pub fn open(path: &str, snapshot_path: &str) -> Result<OperationLog, Error> {
+        drop_torn_record(path)?;
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return OperationLog::create(path, snapshot_path),
+        };
+        let mut base_index = 0;
+        let mut entries = 0;
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            match line {
+                Ok(line) if i == 0 && line.starts_with(BASE_HEADER) => {
+                    base_index = parse_header(&line)?;
+                }
+                Ok(line) => {
+                    LogRecord::from_line(&line)?;
+                    entries += 1;
+                }
+                Err(err) => return Err(read_error(path, err)),
+            }
+        }
+        let file = match OpenOptions::new().append(true).open(path) {
+            Ok(file) => file,
+            Err(err) => return Err(write_error(path, err)),
+        };
+        Ok(OperationLog {
+            path: path.to_string(),
+            snapshot_path: snapshot_path.to_string(),
+            file,
+            base_index,
+            next_index: base_index + entries,
+            snapshot_index: Snapshot::read(snapshot_path)?.index,
+        })
+    }
+
+    /// Returns the index of the first entry kept in the file.
+    pub fn base_index(&self) -> u64 {
+        self.base_index
+    }
+
+    /// Returns the index the next entry will have, which is the amount of entries ever logged.
+    pub fn next_index(&self) -> u64 {
+        self.next_index
+    }
+
+    /// Returns the index of the last snapshot.
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    /// Returns the size in bytes of the log file.
+    pub fn file_size(&self) -> u64 {
+        self.file.metadata().map_or(0, |metadata| metadata.len())
+    }
+
+    /// Appends a record and returns its index, which becomes its sequence number.
+    pub fn append(&mut self, mut record: LogRecord) -> Result<u64, Error> {
+        record.set_seq(self.next_index);
+        let mut line = record.to_line();
+        line.push('\n');
+        if let Err(err) = self.file.write_all(line.as_bytes()) {
+            return Err(write_error(&self.path, err));
+        }
+        self.next_index += 1;
+        Ok(self.next_index - 1)
+    }
+
+    /// Writes the entries to the disk.
+    pub fn sync(&self) -> Result<(), Error> {
+        match self.file.sync_all() {
+            Ok(_) => Ok(()),
+            Err(err) => Err(write_error(&self.path, err)),
+        }
+    }
+
+    /// Returns up to `max` entries starting at the index `from`.
+    /// Returns error if the entries before `from` are no longer in the file.
+    pub fn read_from(&self, from: u64, max: usize) -> Result<Vec<LogRecord>, Error> {
+        if from < self.base_index {
+            return Err(StorageError::Compacted(from).into());
+        }
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) => return Err(read_error(&self.path, err)),
+        };
+        let mut entries = vec![];
+        for line in BufReader::new(file)
+            .lines()
+            .filter(|line| !matches!(line, Ok(line) if line.starts_with(BASE_HEADER)))
+            .skip((from - self.base_index) as usize)
+            .take(max)
+        {
+            match line {
+                Ok(line) => entries.push(LogRecord::from_line(&line)?),
+                Err(err) => return Err(read_error(&self.path, err)),
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Returns the state of the accounts after applying every entry:
+    /// the last snapshot plus the entries logged after it.
+    pub fn state(&self) -> Result<Snapshot, Error> {
+        let snapshot = Snapshot::read(&self.snapshot_path)?;
+        let mut points = snapshot.points();
+        for record in self.read_from(snapshot.index, usize::MAX)? {
+            if apply_entry(&mut points, &record.operation).is_err() {
+                warn!(target: SERVER, "invalid log entry: {}", record.operation);
+            }
+        }
+        Ok(Snapshot::take(self.next_index, &points))
+    }
+
+    /// Writes a snapshot of the current state and removes the entries it covers,
+    /// except for the last `retained` ones, kept so lagging servers can catch up from them.
+    pub fn compact(&mut self, retained: u64) -> Result<(), Error> {
+        let snapshot = self.state()?;
+        snapshot.write_atomic(&self.snapshot_path)?;
+        self.snapshot_index = snapshot.index;
+
+        let base_index = self
+            .next_index
+            .saturating_sub(retained)
+            .max(self.base_index);
+        let entries = self.read_from(base_index, usize::MAX)?;
+        self.file = write_file(&self.path, base_index, &entries)?;
+        self.base_index = base_index;
+        Ok(())
+    }
+
+    /// Replaces the state with a snapshot received from another server and discards every entry,
+    /// the next one will have the index of the snapshot.
+    pub fn reset(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        snapshot.write_atomic(&self.snapshot_path)?;
+        self.file = write_file(&self.path, snapshot.index, &[])?;
+        self.base_index = snapshot.index;
+        self.next_index = snapshot.index;
+        self.snapshot_index = snapshot.index;
+        Ok(())
+    }
+}
+
+/// Returns the accounts recovered from the log and the snapshot of the given paths.
+pub fn recover(path: &str, snapshot_path: &str) -> Result<(OperationLog, PointsHandler), Error> {
+    let log = OperationLog::open(path, snapshot_path)?;
+    let points = log.state()?.points();
+    Ok((log, points))
+}
+
+/// Drops the last line of the file if it is not a valid record, which is what a crash
+/// in the middle of an append leaves. Later records are appended after the previous one.
+pub fn drop_torn_record(path: &str) -> Result<(), Error> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(read_error(path, err)),
+    };
+    let lines = content.strip_suffix(b"\n").unwrap_or(&content);
+    if lines.is_empty() {
+        return Ok(());
+    }
+    let start = lines
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |i| i + 1);
+    let last = String::from_utf8_lossy(&lines[start..]);
+    if last.starts_with(BASE_HEADER) || LogRecord::from_line(&last).is_ok() {
+        return Ok(());
+    }
+    warn!(target: SERVER, "dropped the torn last record of {}: {}", path, last);
+    match OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(start as u64))
+    {
+        Ok(_) => Ok(()),
+        Err(err) => Err(write_error(path, err)),
+    }
+}
+
+fn parse_header(line: &str) -> Result<u64, Error> {
+    match line[BASE_HEADER.len()..].parse::<u64>() {
+        Ok(index) => Ok(index),
+        Err(_) => Err(ParseError::InvalidLine(line.to_string()).into()),
+    }
+}
+
+/// Writes the log file atomically and returns it opened to append new entries.
+fn write_file(path: &str, base_index: u64, entries: &[LogRecord]) -> Result<File, Error> {
+    let tmp_path = format!("{}.tmp", path);
+    let mut content = format!("{}{}\n", BASE_HEADER, base_index);
+    for entry in entries {
+        content.push_str(&entry.to_line());
+        content.push('\n');
+    }
+    let written = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(content.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .and_then(|_| OpenOptions::new().append(true).open(path));
+    match written {
+        Ok(file) => Ok(file),
+        Err(err) => Err(write_error(path, err)),
+    }
+}
+
+fn read_error(path: &str, err: io::Error) -> Error {
+    StorageError::CantRead {
+        path: path.to_string(),
+        cause: err.into(),
+    }
+    .into()
+}
+
+fn write_error(path: &str, err: io::Error) -> Error {
+    StorageError::CantWrite {
+        path: path.to_string(),
+        cause: err.into(),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use super::*;
+
+    fn log_path(name: &str) -> String {
+        temp_dir()
+            .join(format!("tp2_{}_{}.txt", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn record(message: &str) -> LogRecord {
+        LogRecord::new(message, "ACK", 1).expect("Invalid record")
+    }
+
+    fn operations(records: Result<Vec<LogRecord>, Error>) -> Vec<String> {
+        records
+            .expect("Error reading log")
+            .into_iter()
+            .map(|record| record.operation)
+            .collect()
+    }
+
+    fn create_log(name: &str) -> OperationLog {
+        OperationLog::create(&log_path(name), &log_path(&format!("{}_snapshot", name)))
+            .expect("Error creating log")
+    }
+
+    #[test]
+    fn test01_append_returns_the_index_of_each_entry() {
+        let mut log = create_log("append");
+
+        assert_eq!(log.append(record("block 123 0")), Ok(0));
+        assert_eq!(log.append(record("fail 123 0")), Ok(1));
+        assert_eq!(log.next_index(), 2);
+    }
+
+    #[test]
+    fn test02_read_from_an_index() {
+        let mut log = create_log("read");
+        for entry in ["block 123 0", "fail 123 0", "complete 124 10 cash 0"] {
+            log.append(record(entry)).expect("Error appending entry");
+        }
+
+        let entries = operations(log.read_from(1, 10));
+
+        assert_eq!(entries, vec!["fail 123 0", "complete 124 10 cash 0"]);
+        assert_eq!(operations(log.read_from(0, 1)), vec!["block 123 0"]);
+        assert_eq!(log.read_from(3, 10), Ok(vec![]));
+        assert_eq!(log.read_from(2, 1).map(|r| r[0].seq), Ok(2));
+    }
+
+    #[test]
+    fn test03_reset_discards_previous_entries() {
+        let mut log = create_log("reset");
+        log.append(record("block 123 0"))
+            .expect("Error appending entry");
+        let snapshot = Snapshot {
+            index: 10,
+            accounts: vec![(123, 50, false)],
+        };
+
+        log.reset(&snapshot).expect("Error resetting log");
+        log.append(record("fail 123 0"))
+            .expect("Error appending entry");
+
+        assert_eq!(
+            log.read_from(5, 10),
+            Err(Error::Storage(StorageError::Compacted(5)))
+        );
+        assert_eq!(operations(log.read_from(10, 10)), vec!["fail 123 0"]);
+        assert_eq!(log.state().map(|s| s.accounts), Ok(vec![(123, 50, false)]));
+    }
+
+    #[test]
+    fn test04_compaction_keeps_the_retained_entries() {
+        let mut log = create_log("compact");
+        for _ in 0..10 {
+            log.append(record("complete 123 10 cash 0"))
+                .expect("Error appending entry");
+        }
+
+        log.compact(3).expect("Error compacting log");
+        log.append(record("complete 123 5 points 0"))
+            .expect("Error appending entry");
+
+        assert_eq!(log.base_index(), 7);
+        assert_eq!(log.snapshot_index(), 10);
+        assert_eq!(
+            log.read_from(6, 10),
+            Err(Error::Storage(StorageError::Compacted(6)))
+        );
+        assert_eq!(log.read_from(7, 10).map(|e| e.len()), Ok(4));
+        assert_eq!(log.state().map(|s| s.accounts), Ok(vec![(123, 95, false)]));
+    }
+
+    #[test]
+    fn test05_recover_from_snapshot_and_tail() {
+        let (path, snapshot_path) = (log_path("recover"), log_path("recover_snapshot"));
+        let mut log = OperationLog::create(&path, &snapshot_path).expect("Error creating log");
+        for entry in ["complete 1 20 cash 0", "block 2 0", "complete 1 5 points 0"] {
+            log.append(record(entry)).expect("Error appending entry");
+        }
+        log.compact(1).expect("Error compacting log");
+        log.append(record("complete 1 7 cash 0"))
+            .expect("Error appending entry");
+        drop(log);
+
+        let (log, points) = recover(&path, &snapshot_path).expect("Error recovering log");
+
+        assert_eq!(log.base_index(), 2);
+        assert_eq!(log.next_index(), 4);
+        assert_eq!(points.points.get(&1), Some(&(22, false)));
+        assert_eq!(points.points.get(&2), Some(&(0, true)));
+    }
+
+    #[test]
+    fn test06_torn_last_entry_is_dropped() {
+        let (path, snapshot_path) = (log_path("torn"), log_path("torn_snapshot"));
+        let mut log = OperationLog::create(&path, &snapshot_path).expect("Error creating log");
+        for entry in ["complete 1 20 cash 0", "block 2 0"] {
+            log.append(record(entry)).expect("Error appending entry");
+        }
+        drop(log);
+        let line = record("complete 1 5 cash 0").to_line();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .expect("Error opening log");
+        file.write_all(&line.as_bytes()[..line.len() / 2])
+            .expect("Error writing log");
+
+        let (mut log, points) = recover(&path, &snapshot_path).expect("Error recovering log");
+        log.append(record("fail 2 0"))
+            .expect("Error appending entry");
+
+        assert_eq!(points.points.get(&1), Some(&(20, false)));
+        assert_eq!(
+            operations(log.read_from(0, 10)),
+            vec!["complete 1 20 cash 0", "block 2 0", "fail 2 0"]
+        );
+    }
+
+    #[test]
+    fn test07_invalid_entry_in_the_middle_is_an_error() {
+        let (path, snapshot_path) = (log_path("corrupt"), log_path("corrupt_snapshot"));
+        let mut log = OperationLog::create(&path, &snapshot_path).expect("Error creating log");
+        for entry in ["complete 1 20 cash 0", "block 2 0"] {
+            log.append(record(entry)).expect("Error appending entry");
+        }
+        drop(log);
+        let content = fs::read_to_string(&path).expect("Error reading log");
+        fs::write(&path, content.replacen("20", "21", 1)).expect("Error writing log");
+
+        assert!(recover(&path, &snapshot_path).is_err());
+    }
+}
@@ -0,0 +1,423 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use crate::wire::Account;
+
+/// Points of each shop in the ring, so the partitions are spread evenly among the shops.
+const VIRTUAL_NODES: u32 = 32;
+
+/// Returns the FNV-1a hash of the bytes, which is the same in every server. The result is
+/// mixed afterwards, since keys that only differ in the last bytes get close FNV-1a hashes.
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Consistent hash ring of the shops. Adding or removing a shop only moves
+/// the keys between that shop and its neighbours in the ring.
+pub struct HashRing {
+    nodes: BTreeMap<u64, u32>,
+}
+
+impl HashRing {
+    /// Creates a ring with [`VIRTUAL_NODES`] points for each shop.
+    pub fn new(shops: &BTreeSet<u32>) -> HashRing {
+        let mut nodes = BTreeMap::new();
+        for shop in shops {
+            for node in 0..VIRTUAL_NODES {
+                nodes.insert(hash(format!("shop-{}-{}", shop, node).as_bytes()), *shop);
+            }
+        }
+        HashRing { nodes }
+    }
+
+    /// Returns up to "amount" different shops, walking the ring clockwise from "key".
+    pub fn successors(&self, key: u64, amount: usize) -> Vec<u32> {
+        let mut shops = vec![];
+        for shop in self
+            .nodes
+            .range(key..)
+            .chain(self.nodes.range(..key))
+            .map(|(_, shop)| *shop)
+        {
+            if shops.len() == amount {
+                break;
+            }
+            if !shops.contains(&shop) {
+                shops.push(shop);
+            }
+        }
+        shops
+    }
+}
+
+/// Assignment of the partitions of the client ids to the live shops. The first owner
+/// of a partition is its primary, which processes its writes, and the rest are its replicas.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionMap {
+    replication: usize,
+    live: BTreeSet<u32>,
+    owners: Vec<Vec<u32>>,
+}
+
+impl PartitionMap {
+    /// Creates the map of "partitions" partitions with "replication" owners each.
+    pub fn new(partitions: u32, replication: usize, live: BTreeSet<u32>) -> PartitionMap {
+        let mut map = PartitionMap {
+            replication: replication.max(1),
+            live: BTreeSet::new(),
+            owners: vec![vec![]; partitions.max(1) as usize],
+        };
+        map.rebalance(live);
+        map
+    }
+
+    /// Returns the partition of the client.
+    pub fn partition_of(&self, client_id: u32) -> u32 {
+        (hash(&client_id.to_be_bytes()) % self.owners.len() as u64) as u32
+    }
+
+    /// Returns the owners of the partition, its primary first.
+    pub fn owners(&self, partition: u32) -> &[u32] {
+        match self.owners.get(partition as usize) {
+            Some(owners) => owners,
+            None => &[],
+        }
+    }
+
+    /// Returns the primary of the partition.
+    pub fn primary(&self, partition: u32) -> Option<u32> {
+        self.owners(partition).first().copied()
+    }
+
+    /// Returns the partitions the shop owns, as primary or as replica.
+    pub fn owned_by(&self, shop: u32) -> Vec<u32> {
+        (0..self.owners.len() as u32)
+            .filter(|partition| self.owners(*partition).contains(&shop))
+            .collect()
+    }
+
+    /// Returns the shops the map was built for.
+    pub fn live(&self) -> &BTreeSet<u32> {
+        &self.live
+    }
+
+    /// Assigns the partitions to the live shops and returns the partitions whose owners
+    /// changed, with their previous owners.
+    pub fn rebalance(&mut self, live: BTreeSet<u32>) -> Vec<(u32, Vec<u32>)> {
+        let ring = HashRing::new(&live);
+        let mut moved = vec![];
+        for (partition, owners) in self.owners.iter_mut().enumerate() {
+            let key = hash(format!("partition-{}", partition).as_bytes());
+            let new_owners = ring.successors(key, self.replication);
+            if *owners != new_owners {
+                moved.push((partition as u32, std::mem::replace(owners, new_owners)));
+            }
+        }
+        self.live = live;
+        moved
+    }
+}
+
+/// Shops that sent a heartbeat recently.
+pub struct Membership {
+    last_seen: HashMap<u32, Instant>,
+    timeout: Duration,
+}
+
+impl Membership {
+    /// Creates an instance of [`Membership`] that considers every shop alive,
+    /// until it does not send a heartbeat for "timeout".
+    pub fn new(shops_amount: u32, timeout: Duration) -> Membership {
+        let mut membership = Membership {
+            last_seen: HashMap::new(),
+            timeout,
+        };
+        membership.reset(shops_amount);
+        membership
+    }
+
+    /// Considers every shop alive again.
+    pub fn reset(&mut self, shops_amount: u32) {
+        let now = Instant::now();
+        self.last_seen = (0..shops_amount).map(|shop| (shop, now)).collect();
+    }
+
+    /// Registers a heartbeat of the shop.
+    pub fn seen(&mut self, shop: u32) {
+        self.last_seen.insert(shop, Instant::now());
+    }
+
+    /// Returns the shops alive at "now", including "me".
+    pub fn live(&self, me: u32, now: Instant) -> BTreeSet<u32> {
+        let mut live: BTreeSet<u32> = self
+            .last_seen
+            .iter()
+            .filter(|(_, seen)| now.saturating_duration_since(**seen) < self.timeout)
+            .map(|(shop, _)| *shop)
+            .collect();
+        live.insert(me);
+        live
+    }
+}
+
+/// Partition owned by the server whose accounts are being received from another owner.
+pub struct PendingPartition {
+    /// Owners that can send the accounts, the first one is asked first.
+    pub sources: Vec<u32>,
+    pub accounts: Vec<Account>,
+    pub parts: BTreeSet<u32>,
+    pub requested_at: Option<Instant>,
+    pub retries: u32,
+}
+
+/// Where a message of a client has to be processed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    /// The server is the primary of the partition of the client.
+    Local,
+    /// Another shop is the primary of the partition of the client.
+    Forward(u32),
+    /// The server is the primary but it is still receiving the accounts of the partition.
+    Pending,
+}
+
+/// State of a server that shares the accounts with the others by partitions.
+pub struct Partitioning {
+    pub map: PartitionMap,
+    pub membership: Membership,
+    pub pending: HashMap<u32, PendingPartition>,
+    /// Set while a server back from a fall waits for its partitions to send its down log.
+    pub rejoining: bool,
+}
+
+impl Partitioning {
+    /// Creates the partitions of "shops_amount" shops, all of them alive.
+    pub fn new(
+        partitions: u32,
+        replication: usize,
+        shops_amount: u32,
+        timeout: Duration,
+    ) -> Partitioning {
+        let live = (0..shops_amount).collect();
+        Partitioning {
+            map: PartitionMap::new(partitions, replication, live),
+            membership: Membership::new(shops_amount, timeout),
+            pending: HashMap::new(),
+            rejoining: false,
+        }
+    }
+
+    /// Returns where the message of the client has to be processed by "me".
+    /// Messages without a client are processed where they are received.
+    pub fn route(&self, client_id: Option<u32>, me: u32) -> Route {
+        let partition = match client_id {
+            Some(client_id) => self.map.partition_of(client_id),
+            None => return Route::Local,
+        };
+        match self.map.primary(partition) {
+            Some(primary) if primary != me => Route::Forward(primary),
+            _ if self.pending.contains_key(&partition) => Route::Pending,
+            _ => Route::Local,
+        }
+    }
+
+    /// Returns the replicas of the partition of the client, the owners other than "me".
+    pub fn replicas(&self, client_id: u32, me: u32) -> Vec<u32> {
+        let partition = self.map.partition_of(client_id);
+        self.map
+            .owners(partition)
+            .iter()
+            .copied()
+            .filter(|shop| *shop != me)
+            .collect()
+    }
+
+    /// Returns true if "me" is waiting for the accounts of the partition of the client.
+    pub fn is_pending(&self, client_id: u32) -> bool {
+        self.pending.contains_key(&self.map.partition_of(client_id))
+    }
+
+    /// Marks the partitions as pending, to be received from their owners other than "me".
+    /// The previous primary is asked first, since it has the latest writes.
+    pub fn receive(&mut self, partitions: Vec<(u32, Vec<u32>)>, me: u32) {
+        for (partition, previous) in partitions {
+            let mut sources: Vec<u32> = previous.into_iter().filter(|shop| *shop != me).collect();
+            for owner in self.map.owners(partition) {
+                if *owner != me && !sources.contains(owner) {
+                    sources.push(*owner);
+                }
+            }
+            self.pending.insert(
+                partition,
+                PendingPartition {
+                    sources,
+                    accounts: vec![],
+                    parts: BTreeSet::new(),
+                    requested_at: None,
+                    retries: 0,
+                },
+            );
+        }
+    }
+
+    /// Reassigns the partitions to the shops alive at "now" if they changed, and marks the
+    /// partitions that "me" now owns and did not own before as pending.
+    /// Returns true if the partitions were rebalanced.
+    pub fn refresh(&mut self, me: u32, now: Instant) -> bool {
+        let live = self.membership.live(me, now);
+        if &live == self.map.live() {
+            return false;
+        }
+        let moved = self.map.rebalance(live.clone());
+        let received = moved
+            .into_iter()
+            .filter(|(partition, previous)| {
+                !previous.contains(&me) && self.map.owners(*partition).contains(&me)
+            })
+            .collect();
+        self.receive(received, me);
+        for pending in self.pending.values_mut() {
+            pending.sources.retain(|shop| live.contains(shop));
+        }
+        true
+    }
+
+    /// Considers every shop alive again, as when the server starts, and marks every
+    /// partition of "me" as pending. Used when the server comes back from a fall.
+    pub fn rejoin(&mut self, shops_amount: u32, me: u32) {
+        self.membership.reset(shops_amount);
+        self.map.rebalance((0..shops_amount).collect());
+        self.pending.clear();
+        self.receive_all(me);
+        self.rejoining = true;
+    }
+
+    /// Marks every partition of "me" as pending, to be received from its other owners.
+    pub fn receive_all(&mut self, me: u32) {
+        let partitions = self
+            .map
+            .owned_by(me)
+            .into_iter()
+            .map(|partition| (partition, vec![]))
+            .collect();
+        self.receive(partitions, me);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shops(ids: &[u32]) -> BTreeSet<u32> {
+        ids.iter().copied().collect()
+    }
+
+    #[test]
+    fn test01_each_partition_has_distinct_owners() {
+        let map = PartitionMap::new(64, 2, shops(&[0, 1, 2]));
+
+        for partition in 0..64 {
+            let owners = map.owners(partition);
+            assert_eq!(owners.len(), 2);
+            assert_ne!(owners[0], owners[1]);
+        }
+        for shop in 0..3 {
+            assert!(!map.owned_by(shop).is_empty());
+        }
+    }
+
+    #[test]
+    fn test02_a_client_always_maps_to_the_same_partition() {
+        let map = PartitionMap::new(16, 1, shops(&[0, 1]));
+        let other = PartitionMap::new(16, 3, shops(&[0, 1, 2, 3]));
+
+        for client_id in 0..100 {
+            assert_eq!(map.partition_of(client_id), other.partition_of(client_id));
+            assert!(map.partition_of(client_id) < 16);
+        }
+    }
+
+    #[test]
+    fn test03_removing_a_shop_only_moves_its_partitions() {
+        let mut map = PartitionMap::new(64, 1, shops(&[0, 1, 2, 3]));
+        let before = map.clone();
+
+        let moved = map.rebalance(shops(&[0, 1, 3]));
+
+        assert!(!moved.is_empty());
+        for (partition, previous) in moved {
+            assert_eq!(previous, vec![2]);
+            assert_ne!(map.primary(partition), Some(2));
+        }
+        for partition in 0..64 {
+            if before.primary(partition) != Some(2) {
+                assert_eq!(map.primary(partition), before.primary(partition));
+            }
+        }
+    }
+
+    #[test]
+    fn test04_replication_is_limited_by_the_live_shops() {
+        let map = PartitionMap::new(8, 3, shops(&[5]));
+
+        assert_eq!(map.owners(0), &[5]);
+        assert_eq!(map.owned_by(5).len(), 8);
+    }
+
+    #[test]
+    fn test05_shops_without_heartbeats_are_not_alive() {
+        let mut membership = Membership::new(3, Duration::from_secs(1));
+        let later = Instant::now() + Duration::from_secs(2);
+
+        assert_eq!(membership.live(0, Instant::now()), shops(&[0, 1, 2]));
+        assert_eq!(membership.live(0, later), shops(&[0]));
+        membership.seen(2);
+        assert_eq!(membership.live(0, Instant::now()), shops(&[0, 1, 2]));
+    }
+
+    #[test]
+    fn test06_route_clients_to_the_primary_of_their_partition() {
+        let mut partitioning = Partitioning::new(16, 2, 2, Duration::from_secs(1));
+        let client_id = (0..100)
+            .find(|client_id| {
+                let partition = partitioning.map.partition_of(*client_id);
+                partitioning.map.primary(partition) == Some(1)
+            })
+            .expect("Shop 1 is not a primary");
+
+        assert_eq!(partitioning.route(Some(client_id), 0), Route::Forward(1));
+        assert_eq!(partitioning.route(Some(client_id), 1), Route::Local);
+        assert_eq!(partitioning.route(None, 0), Route::Local);
+        assert_eq!(partitioning.replicas(client_id, 1), vec![0]);
+
+        partitioning.receive_all(1);
+        assert_eq!(partitioning.route(Some(client_id), 1), Route::Pending);
+        assert!(partitioning.is_pending(client_id));
+    }
+
+    #[test]
+    fn test07_new_owners_receive_the_partitions_from_the_previous_ones() {
+        let mut partitioning = Partitioning::new(16, 1, 2, Duration::from_secs(1));
+        partitioning.membership = Membership::new(2, Duration::ZERO);
+        partitioning.membership.seen(0);
+
+        assert!(partitioning.refresh(0, Instant::now()));
+        assert!(!partitioning.pending.is_empty());
+        for pending in partitioning.pending.values() {
+            // Shop 1 is not alive, so there is no one to receive the partitions from
+            assert!(pending.sources.is_empty());
+        }
+        assert!(!partitioning.refresh(0, Instant::now()));
+    }
+}
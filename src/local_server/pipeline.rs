@@ -0,0 +1,194 @@
+use std::{
+    net::SocketAddr,
+    sync::mpsc::{self, Receiver, Sender, SyncSender},
+    thread::{self, JoinHandle},
+};
+
+use crate::errors::Error;
+
+/// Message received by the pipeline or answer to be sent, with the address of the peer.
+pub type Request = (String, SocketAddr);
+
+/// Request pipeline of the leader: the receiver dispatches each request to the queue of a worker
+/// chosen by its key, the workers handle the requests in parallel, and a sender thread sends
+/// their answers. Requests with the same key are handled by the same worker in arrival order.
+pub struct Pipeline {
+    queues: Vec<SyncSender<Request>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Pipeline {
+    /// Starts "workers" threads, each one handling its requests with the handler returned by
+    /// "handler" for its index, and a thread that sends the answers with "send".
+    /// The queue of each worker holds up to "capacity" requests, the receiver waits when it is full.
+    pub fn start<H, W, S>(workers: usize, capacity: usize, mut handler: H, mut send: S) -> Pipeline
+    where
+        H: FnMut(usize) -> W,
+        W: FnMut(String, SocketAddr) -> Option<Request> + Send + 'static,
+        S: FnMut(String, SocketAddr) + Send + 'static,
+    {
+        let (answers, sent): (Sender<Request>, Receiver<Request>) = mpsc::channel();
+        let mut queues = vec![];
+        let mut threads = vec![];
+        for worker in 0..workers.max(1) {
+            let (queue, requests) = mpsc::sync_channel::<Request>(capacity);
+            let answers = answers.clone();
+            let mut handle = handler(worker);
+            threads.push(thread::spawn(move || {
+                for (message, from) in requests {
+                    if let Some(answer) = handle(message, from) {
+                        let _ = answers.send(answer);
+                    }
+                }
+            }));
+            queues.push(queue);
+        }
+        // The sender stops once every worker dropped its end of the channel
+        drop(answers);
+        threads.push(thread::spawn(move || {
+            for (answer, to) in sent {
+                send(answer, to);
+            }
+        }));
+        Pipeline { queues, threads }
+    }
+
+    /// Returns the amount of workers of the pipeline.
+    pub fn workers(&self) -> usize {
+        self.queues.len()
+    }
+
+    /// Queues the request in the worker of "key".
+    /// Returns error if the worker stopped because it panicked, the request is not handled.
+    pub fn dispatch(&self, key: u32, message: String, from: SocketAddr) -> Result<(), Error> {
+        let worker = key as usize % self.queues.len();
+        match self.queues[worker].send((message, from)) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::CantJoinThread),
+        }
+    }
+
+    /// Waits until the queued requests are handled and their answers sent.
+    pub fn stop(self) -> Result<(), Error> {
+        drop(self.queues);
+        for thread in self.threads {
+            if thread.join().is_err() {
+                return Err(Error::CantJoinThread);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        thread::ThreadId,
+    };
+
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 8000))
+    }
+
+    #[test]
+    fn test01_requests_with_the_same_key_keep_their_order() {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let answers = sent.clone();
+        let pipeline = Pipeline::start(
+            4,
+            8,
+            |_| |message: String, from| Some((message, from)),
+            move |answer, _| answers.lock().expect("Error locking").push(answer),
+        );
+
+        for i in 0..100 {
+            pipeline
+                .dispatch(i % 10, format!("{} {}", i % 10, i), addr())
+                .expect("Error dispatching");
+        }
+        pipeline.stop().expect("Error stopping the pipeline");
+
+        let sent = sent.lock().expect("Error locking");
+        assert_eq!(sent.len(), 100);
+        for key in 0..10 {
+            let order: Vec<u32> = sent
+                .iter()
+                .filter(|answer| answer.starts_with(&format!("{} ", key)))
+                .map(|answer| answer[2..].parse().expect("Invalid answer"))
+                .collect();
+            let expected: Vec<u32> = (0..10).map(|i| i * 10 + key).collect();
+            assert_eq!(order, expected);
+        }
+    }
+
+    #[test]
+    fn test02_each_key_is_handled_by_a_single_worker() {
+        let handled: Arc<Mutex<Vec<(u32, ThreadId)>>> = Arc::new(Mutex::new(vec![]));
+        let workers = handled.clone();
+        let pipeline = Pipeline::start(
+            3,
+            8,
+            move |_| {
+                let handled = workers.clone();
+                move |message: String, _| {
+                    let key = message.parse().expect("Invalid request");
+                    let id = thread::current().id();
+                    handled.lock().expect("Error locking").push((key, id));
+                    None
+                }
+            },
+            |_, _| (),
+        );
+
+        assert_eq!(pipeline.workers(), 3);
+        for i in 0..30 {
+            pipeline
+                .dispatch(i, (i % 6).to_string(), addr())
+                .expect("Error dispatching");
+        }
+        pipeline.stop().expect("Error stopping the pipeline");
+
+        let handled = handled.lock().expect("Error locking");
+        assert_eq!(handled.len(), 30);
+        for (key, id) in handled.iter() {
+            assert!(handled
+                .iter()
+                .filter(|(other, _)| other % 3 == key % 3)
+                .all(|(_, other_id)| other_id == id));
+        }
+    }
+
+    #[test]
+    fn test03_requests_for_a_stopped_worker_fail() {
+        let pipeline = Pipeline::start(
+            2,
+            8,
+            |_| {
+                |message: String, from| match message.as_str() {
+                    "panic" => panic!("worker panicked"),
+                    _ => Some((message, from)),
+                }
+            },
+            |_, _| (),
+        );
+
+        pipeline
+            .dispatch(0, "panic".to_string(), addr())
+            .expect("Error dispatching");
+        let mut dispatched = Ok(());
+        for _ in 0..100 {
+            dispatched = pipeline.dispatch(0, "order".to_string(), addr());
+            if dispatched.is_err() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        assert_eq!(dispatched, Err(Error::CantJoinThread));
+        assert!(pipeline.dispatch(1, "order".to_string(), addr()).is_ok());
+        assert_eq!(pipeline.stop(), Err(Error::CantJoinThread));
+    }
+}
@@ -0,0 +1,466 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+};
+
+use tracing::error;
+
+use crate::{
+    action::Action,
+    errors::{Error, StorageError},
+    logging::SYNC,
+    message_parser::MessageParser,
+    payment_method::Method,
+    points_handler::PointsHandler,
+    wire::OfflineOperation,
+};
+
+use super::log_record::LogRecord;
+
+impl OfflineOperation {
+    /// Returns the operation of a record of the down log of `shop_id`.
+    /// The id keeps the outage when the record was written, so it is the same
+    /// every time the record is sent.
+    pub fn from_record(shop_id: u32, record: &LogRecord) -> OfflineOperation {
+        OfflineOperation {
+            id: format!(
+                "{}-{}-{}",
+                shop_id,
+                record.down_since.unwrap_or_default(),
+                record.seq
+            ),
+            timestamp: record.timestamp,
+            message: record.message(),
+        }
+    }
+
+    /// Returns the message the leader replicates when the operation is merged.
+    /// Offline redemptions are replicated as such so the replicas accept a negative balance.
+    pub fn replicated_message(&self) -> String {
+        match MessageParser::parse(self.message.clone()) {
+            Ok(Action::CompleteOrder(client_id, price, Method::Points, shop_id)) => {
+                format!("offlineRedeem {} {} {}", client_id, price, shop_id)
+            }
+            _ => self.message.clone(),
+        }
+    }
+}
+
+/// Result of reconciling an offline operation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Merged,
+    /// The offline redemption was merged leaving the client with the given debt.
+    MergedWithDebt(u32),
+    Discarded(String),
+    Rejected(String),
+}
+
+impl Outcome {
+    /// Returns true if the operation was applied.
+    pub fn is_merged(&self) -> bool {
+        matches!(self, Outcome::Merged | Outcome::MergedWithDebt(_))
+    }
+}
+
+/// Operations of a shop reconciled after it was down.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconciliationReport {
+    pub shop_id: u32,
+    pub since: u64,
+    pub entries: Vec<(OfflineOperation, Outcome)>,
+}
+
+impl ReconciliationReport {
+    /// Returns the amount of entries with the same kind of outcome.
+    pub fn count(&self, merged: bool) -> usize {
+        self.entries
+            .iter()
+            .filter(|(_, outcome)| outcome.is_merged() == merged)
+            .count()
+    }
+}
+
+impl fmt::Display for ReconciliationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Reconciliation of shop {} (down since {}): {} merged, {} not merged",
+            self.shop_id,
+            self.since,
+            self.count(true),
+            self.count(false)
+        )?;
+        for (operation, outcome) in &self.entries {
+            let outcome = match outcome {
+                Outcome::Merged => "MERGED".to_string(),
+                Outcome::MergedWithDebt(debt) => format!("MERGED WITH DEBT ({} points)", debt),
+                Outcome::Discarded(reason) => format!("DISCARDED ({})", reason),
+                Outcome::Rejected(reason) => format!("REJECTED ({})", reason),
+            };
+            writeln!(
+                f,
+                "{} {} {}: {}",
+                operation.id, operation.timestamp, operation.message, outcome
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the history needed to reconcile offline operations.
+///
+/// Policy applied to every offline operation:
+/// - An operation whose id was already reconciled is rejected as a duplicate.
+/// - Cash payments are commutative, so their points are always merged.
+/// - Points payments are merged. If the cluster spent the points in the meantime,
+///   the balance becomes negative and the outcome records the debt of the client.
+/// - Blocks and failures only protected the account while the shop was offline,
+///   so they are discarded. If the cluster also blocked the client during that time,
+///   the report says the block was taken on both sides.
+///
+/// The ids of the decided operations are written in a file, so they are still rejected
+/// as duplicates after a restart. Replicas only learn the ids of the merged ones, so a new
+/// leader decides again an operation that was not merged, which never applies it twice.
+#[derive(Debug, Default)]
+pub struct Reconciler {
+    applied: HashSet<String>,
+    /// Last time the cluster blocked each client. It only changes the reason of a discarded
+    /// block in the report, so it is not written in the file.
+    last_block: HashMap<u32, u64>,
+    reports: HashMap<u32, ReconciliationReport>,
+    /// File of the ids of the decided operations, None if they are only kept in memory.
+    file: Option<File>,
+}
+
+impl Reconciler {
+    /// Creates a new instance of [`Reconciler`].
+    pub fn new() -> Reconciler {
+        Reconciler::default()
+    }
+
+    /// Opens the file of the decided operations of the given path and loads their ids.
+    /// The file is created if it does not exist.
+    pub fn open(path: &str) -> Result<Reconciler, Error> {
+        let mut applied = HashSet::new();
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines() {
+                match line {
+                    Ok(id) => applied.insert(id),
+                    Err(err) => {
+                        return Err(StorageError::CantRead {
+                            path: path.to_string(),
+                            cause: err.into(),
+                        }
+                        .into())
+                    }
+                };
+            }
+        }
+        let file = match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                return Err(StorageError::CantWrite {
+                    path: path.to_string(),
+                    cause: err.into(),
+                }
+                .into())
+            }
+        };
+        Ok(Reconciler {
+            applied,
+            file: Some(file),
+            ..Reconciler::default()
+        })
+    }
+
+    /// Registers an operation that the leader merged, so it is rejected if it is sent again.
+    pub fn remember(&mut self, id: &str) {
+        if self.applied.insert(id.to_string()) {
+            self.persist(id);
+        }
+    }
+
+    /// Registers that the cluster blocked the client at the given time.
+    pub fn record_block(&mut self, client_id: u32, timestamp: u64) {
+        self.last_block.insert(client_id, timestamp);
+    }
+
+    /// Reconciles an operation accepted by `shop_id` while it was down since `since`.
+    /// Merged operations are applied to the points handler.
+    pub fn reconcile(
+        &mut self,
+        shop_id: u32,
+        since: u64,
+        operation: OfflineOperation,
+        points: &mut PointsHandler,
+    ) -> Outcome {
+        let outcome = if self.applied.contains(&operation.id) {
+            Outcome::Rejected("duplicate operation".to_string())
+        } else {
+            self.apply(since, &operation, points)
+        };
+        if self.applied.insert(operation.id.clone()) {
+            self.persist(&operation.id);
+        }
+
+        self.reports
+            .entry(shop_id)
+            .or_insert(ReconciliationReport {
+                shop_id,
+                since,
+                entries: vec![],
+            })
+            .entries
+            .push((operation, outcome.clone()));
+        outcome
+    }
+
+    /// Returns the report of the shop and starts a new one.
+    pub fn finish(&mut self, shop_id: u32) -> Option<ReconciliationReport> {
+        self.reports.remove(&shop_id)
+    }
+
+    fn persist(&mut self, id: &str) {
+        if let Some(file) = &mut self.file {
+            if let Err(err) = writeln!(file, "{}", id) {
+                error!(target: SYNC, "error writing the reconciled operation {}: {}", id, err);
+            }
+        }
+    }
+
+    fn apply(
+        &self,
+        since: u64,
+        operation: &OfflineOperation,
+        points: &mut PointsHandler,
+    ) -> Outcome {
+        match MessageParser::parse(operation.message.clone()) {
+            Ok(Action::CompleteOrder(_, price, _, _)) if i32::try_from(price).is_err() => {
+                Outcome::Rejected("price out of range".to_string())
+            }
+            Ok(Action::CompleteOrder(client_id, price, Method::Cash, _)) => {
+                match points.update_points(client_id, price as i32) {
+                    Ok(_) => Outcome::Merged,
+                    Err(_) => Outcome::Rejected("points could not be updated".to_string()),
+                }
+            }
+            Ok(Action::CompleteOrder(client_id, price, Method::Points, _)) => {
+                if points
+                    .force_update_points(client_id, -(price as i32))
+                    .is_err()
+                {
+                    return Outcome::Rejected("points out of range".to_string());
+                }
+                match points.balance(client_id) {
+                    balance if balance < 0 => Outcome::MergedWithDebt(balance.unsigned_abs()),
+                    _ => Outcome::Merged,
+                }
+            }
+            Ok(Action::Block(client_id, _)) => match self.last_block.get(&client_id) {
+                Some(timestamp) if *timestamp >= since => {
+                    Outcome::Discarded("block taken on both sides".to_string())
+                }
+                _ => Outcome::Discarded("offline block released".to_string()),
+            },
+            Ok(Action::FailOrder(_, _)) => Outcome::Discarded("offline block released".to_string()),
+            _ => Outcome::Rejected("unknown operation".to_string()),
+        }
+    }
+}
+
+/// Operations of the down log sent to each server, until it acknowledges them.
+#[derive(Debug, Default)]
+pub struct DownLogAcks {
+    since: u64,
+    expected: HashMap<u32, usize>,
+}
+
+impl DownLogAcks {
+    /// Waits for the servers to reconcile the operations of the down log sent to them
+    /// after the server was down since `since`.
+    pub fn new(since: u64, expected: HashMap<u32, usize>) -> DownLogAcks {
+        DownLogAcks { since, expected }
+    }
+
+    /// Registers that `shop_id` reconciled `reconciled` operations. Once every server
+    /// reconciled all the operations sent to it, returns when the server went down,
+    /// since the records of the down log up to then are no longer needed.
+    pub fn acknowledge(&mut self, shop_id: u32, reconciled: usize) -> Option<u64> {
+        if self.expected.get(&shop_id) != Some(&reconciled) {
+            return None;
+        }
+        self.expected.remove(&shop_id);
+        match self.expected.is_empty() {
+            true => Some(self.since),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operation(id: &str, timestamp: u64, message: &str) -> OfflineOperation {
+        OfflineOperation {
+            id: id.to_string(),
+            timestamp,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn test01_cash_payments_are_merged_once() {
+        let mut reconciler = Reconciler::new();
+        let mut points = PointsHandler::new();
+        let op = operation("0-10-1", 15, "complete 123 10 cash 0");
+
+        let first = reconciler.reconcile(0, 10, op.clone(), &mut points);
+        let second = reconciler.reconcile(0, 10, op, &mut points);
+
+        assert_eq!(first, Outcome::Merged);
+        assert_eq!(second, Outcome::Rejected("duplicate operation".to_string()));
+        assert_eq!(points.points.get(&123), Some(&(10, false)));
+    }
+
+    #[test]
+    fn test02_block_taken_on_both_sides_is_reported() {
+        let mut reconciler = Reconciler::new();
+        let mut points = PointsHandler::new();
+        reconciler.record_block(123, 12);
+
+        let conflict =
+            reconciler.reconcile(0, 10, operation("0-10-1", 15, "block 123 0"), &mut points);
+        let released =
+            reconciler.reconcile(0, 10, operation("0-10-2", 16, "block 124 0"), &mut points);
+
+        assert_eq!(
+            conflict,
+            Outcome::Discarded("block taken on both sides".to_string())
+        );
+        assert_eq!(
+            released,
+            Outcome::Discarded("offline block released".to_string())
+        );
+    }
+
+    #[test]
+    fn test03_report_lists_every_operation() {
+        let mut reconciler = Reconciler::new();
+        let mut points = PointsHandler::new();
+        reconciler.reconcile(
+            0,
+            10,
+            operation("0-10-1", 15, "complete 123 10 cash 0"),
+            &mut points,
+        );
+        reconciler.reconcile(0, 10, operation("0-10-2", 16, "block 123 0"), &mut points);
+
+        let report = reconciler.finish(0).expect("There is no report");
+
+        assert_eq!(report.count(true), 1);
+        assert_eq!(report.count(false), 1);
+        assert!(reconciler.finish(0).is_none());
+    }
+
+    #[test]
+    fn test04_over_redemption_is_recorded_as_debt() {
+        let mut reconciler = Reconciler::new();
+        let mut points = PointsHandler::new();
+        points
+            .update_points(123, 15)
+            .expect("Error when adding points");
+        let op = operation("0-10-1", 15, "complete 123 10 points 0");
+
+        let first = reconciler.reconcile(0, 10, op.clone(), &mut points);
+        let second = reconciler.reconcile(0, 10, operation("0-10-2", 16, &op.message), &mut points);
+
+        assert_eq!(first, Outcome::Merged);
+        assert_eq!(second, Outcome::MergedWithDebt(5));
+        assert_eq!(points.debts(), vec![(123, 5)]);
+        assert_eq!(op.replicated_message(), "offlineRedeem 123 10 0");
+    }
+
+    #[test]
+    fn test05_prices_out_of_range_are_rejected() {
+        let mut reconciler = Reconciler::new();
+        let mut points = PointsHandler::new();
+        let price = i32::MAX as u32 + 10;
+
+        let cash = format!("complete 123 {} cash 0", price);
+        let redemption = format!("complete 123 {} points 0", price);
+        let outcomes = [
+            reconciler.reconcile(0, 10, operation("0-10-1", 15, &cash), &mut points),
+            reconciler.reconcile(0, 10, operation("0-10-2", 16, &redemption), &mut points),
+        ];
+
+        let rejected = Outcome::Rejected("price out of range".to_string());
+        assert_eq!(outcomes, [rejected.clone(), rejected]);
+        assert_eq!(points.balance(123), 0);
+    }
+
+    #[test]
+    fn test06_records_of_an_earlier_outage_keep_their_id() {
+        let mut reconciler = Reconciler::new();
+        let mut points = PointsHandler::new();
+        let mut record =
+            LogRecord::new("complete 123 10 cash 0", "ACK", 1).expect("Invalid record");
+        record.set_seq(3);
+        record.set_down_since(10);
+
+        let read = LogRecord::from_line(&record.to_line()).expect("Invalid line");
+        let op = OfflineOperation::from_record(0, &read);
+        let first = reconciler.reconcile(0, 10, op.clone(), &mut points);
+        let resent =
+            reconciler.reconcile(0, 20, OfflineOperation::from_record(0, &read), &mut points);
+
+        assert_eq!(op.id, "0-10-3");
+        assert_eq!(first, Outcome::Merged);
+        assert_eq!(resent, Outcome::Rejected("duplicate operation".to_string()));
+        assert_eq!(points.balance(123), 10);
+    }
+
+    #[test]
+    fn test07_decided_operations_are_rejected_after_a_restart() {
+        let path = std::env::temp_dir()
+            .join(format!("tp2_reconciled_{}.txt", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&path);
+        let mut points = PointsHandler::new();
+        let merged = operation("0-10-1", 15, "complete 123 10 cash 0");
+        let discarded = operation("0-10-2", 16, "block 123 0");
+        let mut reconciler = Reconciler::open(&path).expect("Error opening the reconciled ids");
+        reconciler.reconcile(0, 10, merged.clone(), &mut points);
+        reconciler.reconcile(0, 10, discarded.clone(), &mut points);
+        reconciler.remember("1-20-1");
+
+        let mut restarted = Reconciler::open(&path).expect("Error opening the reconciled ids");
+
+        let duplicate = Outcome::Rejected("duplicate operation".to_string());
+        assert_eq!(restarted.reconcile(0, 10, merged, &mut points), duplicate);
+        assert_eq!(
+            restarted.reconcile(0, 10, discarded, &mut points),
+            duplicate
+        );
+        assert_eq!(
+            restarted.reconcile(1, 20, operation("1-20-1", 25, "block 124 1"), &mut points),
+            duplicate
+        );
+        assert_eq!(points.balance(123), 10);
+    }
+
+    #[test]
+    fn test08_down_log_is_acknowledged_once_every_server_reconciled_it() {
+        let mut acks = DownLogAcks::new(10, HashMap::from([(1, 2), (2, 1)]));
+
+        assert_eq!(acks.acknowledge(1, 1), None);
+        assert_eq!(acks.acknowledge(1, 2), None);
+        assert_eq!(acks.acknowledge(3, 0), None);
+        assert_eq!(acks.acknowledge(2, 1), Some(10));
+        assert_eq!(acks.acknowledge(2, 1), None);
+    }
+}
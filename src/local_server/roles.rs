@@ -0,0 +1,101 @@
+use crate::{action::Action, auth::KeyId};
+
+/// Role of the sender of a message. Each socket of a server receives the messages of one role.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Coffee machines of the shop, on the coffee machines socket.
+    CoffeeMachine,
+    /// Servers of the other shops, on the servers socket.
+    PeerServer,
+    /// Operators and their tools, on the control socket.
+    Operator,
+}
+
+impl Role {
+    /// Returns the role of the senders that sign with the key.
+    pub fn of(id: KeyId) -> Role {
+        match id {
+            KeyId::Machine(..) => Role::CoffeeMachine,
+            KeyId::Shop(_) => Role::PeerServer,
+            KeyId::Operator(_) => Role::Operator,
+        }
+    }
+
+    /// Returns the name of the socket of the role, used to label the metrics.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Role::CoffeeMachine => "coffee_machine",
+            Role::PeerServer => "servers",
+            Role::Operator => "control",
+        }
+    }
+
+    /// Returns true if a sender with the role can send the action.
+    /// Coffee machines only send the operations of their orders, operators send the commands
+    /// of the control plane, and servers send the rest, including forwarded admin operations.
+    pub fn allows(&self, action: &Action) -> bool {
+        match self {
+            Role::CoffeeMachine => matches!(
+                action,
+                Action::Block(..) | Action::CompleteOrder(..) | Action::FailOrder(..)
+            ),
+            Role::PeerServer => !matches!(
+                action,
+                Action::Up
+                    | Action::Down
+                    | Action::History(..)
+                    | Action::HistoryPart(..)
+                    | Action::State
+            ),
+            Role::Operator => matches!(
+                action,
+                Action::Up
+                    | Action::Down
+                    | Action::History(..)
+                    | Action::Balance(..)
+                    | Action::State
+                    | Action::Grant(..)
+                    | Action::Deduct(..)
+                    | Action::Refund(..)
+                    | Action::Transfer(_)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::payment_method::Method;
+
+    use super::*;
+
+    #[test]
+    fn test01_each_role_allows_its_messages() {
+        let complete = Action::CompleteOrder(123, 10, Method::Cash, 0);
+        let grant = Action::Grant(123, 10, 0, 7, "gift".to_string());
+
+        assert!(Role::CoffeeMachine.allows(&complete));
+        assert!(!Role::CoffeeMachine.allows(&Action::Down));
+        assert!(!Role::CoffeeMachine.allows(&grant));
+        assert!(Role::PeerServer.allows(&Action::Sync(4)));
+        assert!(Role::PeerServer.allows(&grant));
+        assert!(!Role::PeerServer.allows(&Action::Up));
+        assert!(Role::Operator.allows(&Action::Down));
+        assert!(Role::Operator.allows(&grant));
+        assert!(Role::Operator.allows(&Action::Transfer(1)));
+        assert!(Role::Operator.allows(&Action::Balance(123, None)));
+        assert!(!Role::CoffeeMachine.allows(&Action::Balance(123, None)));
+        assert!(!Role::Operator.allows(&Action::TakeOver(4)));
+        assert!(!Role::CoffeeMachine.allows(&Action::TakeOver(4)));
+        assert!(Role::Operator.allows(&Action::State));
+        assert!(!Role::PeerServer.allows(&Action::State));
+        assert!(!Role::Operator.allows(&complete));
+    }
+
+    #[test]
+    fn test02_role_of_a_key() {
+        assert_eq!(Role::of(KeyId::Machine(0, 1)), Role::CoffeeMachine);
+        assert_eq!(Role::of(KeyId::Shop(2)), Role::PeerServer);
+        assert_eq!(Role::of(KeyId::Operator(7)), Role::Operator);
+    }
+}
@@ -1,20 +1,62 @@
+use rand::Rng;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    flag,
+};
 use std::{
-    collections::VecDeque,
-    fs::File,
+    collections::{HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
     io::{BufRead, BufReader, Write},
     net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc, Mutex,
     },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    action::Action, constants::TIMEOUT, errors::Error,
-    local_server::leader_election::LeaderElection, message_parser::MessageParser,
-    payment_method::Method, points_handler::PointsHandler,
+    action::Action,
+    auth::{Authenticator, KeyId, KeyStore, Signer},
+    clock::now_millis,
+    constants::{
+        ANTI_ENTROPY_INTERVAL, GOSSIP_INTERVAL, HEARTBEAT_INTERVAL, MEMBER_TIMEOUT, MESSAGE_BYTES,
+        PIPELINE_QUEUE_CAPACITY, SERVER_METRICS_PORT, SHUTDOWN_TIMEOUT, SYNC_CHUNK_ENTRIES,
+        SYNC_MAX_RETRIES, SYNC_SNAPSHOT_THRESHOLD, SYNC_TIMEOUT, TIMEOUT, TRANSFER_TIMEOUT,
+    },
+    errors::{ConfigError, Error, OrderError, StorageError, TransportError},
+    fault_proxy::bind_addr,
+    local_server::{
+        anti_entropy::{digests, range_of, AntiEntropy},
+        config::ServerConfig,
+        crdt::Accruals,
+        history::{history_parts, History},
+        leader_election::LeaderElection,
+        log_record::LogRecord,
+        offline_credit::OfflineCredit,
+        operation_log::{drop_torn_record, recover, OperationLog},
+        partitions::{Partitioning, Route},
+        pipeline::{Pipeline, Request},
+        reconciliation::{DownLogAcks, Reconciler},
+        roles::Role,
+        shards::ShardedPoints,
+        snapshot::{apply_entry, checked_points, points_answer, Snapshot},
+        sync::{encode_chunk, snapshot_parts, SyncState},
+    },
+    logging::{ELECTION, SERVER, SYNC},
+    message_parser::MessageParser,
+    metadata::{self, INDEX, OFFLINE, PRIMARY, REPLY},
+    metrics::{
+        self, message_type, registry, ANTI_ENTROPY_REPAIRS, BLOCKED_ACCOUNTS, GOSSIP_MERGES,
+        LEADER_SECONDS, LOCAL_ACCRUALS, LOG_BYTES, LOG_ENTRIES, MESSAGES_RECEIVED, MESSAGES_SENT,
+        PARSE_FAILURES, PARTITIONS, READS, READ_LAG, REBALANCES, REJECTED_MESSAGES,
+    },
+    payment_method::Method,
+    points_handler::PointsHandler,
+    trace::{self, Kind, Span},
+    wire::{digests_to_text, Account, HistoryEntry, OfflineOperation, ServerStatus},
 };
 
 pub fn id_to_dataaddr(id: usize) -> SocketAddr {
@@ -26,241 +68,1042 @@ pub struct Server {
     pub addr: SocketAddr,
     pub socket: Arc<UdpSocket>,
     pub coffee_machine_socket: Arc<UdpSocket>,
+    /// Socket of the control plane, receives the commands of the operators.
+    pub control_socket: Arc<UdpSocket>,
     pub shop_id: u32,
     pub shops_amount: u32,
-    pub points_handler: Arc<Mutex<PointsHandler>>,
+    /// Accounts of the clients, sharded by client id.
+    pub points_handler: Arc<ShardedPoints>,
     pub down: Arc<AtomicBool>,
-    pub log: File,
-    pub log_down: File,
+    pub log: Arc<Mutex<OperationLog>>,
+    pub log_down: Arc<Mutex<File>>,
     pub shop_leader: LeaderElection,
     pub sync: Arc<AtomicBool>,
+    pub sync_state: Arc<Mutex<SyncState>>,
+    /// Accounts when the server went down, matching the entries of its log.
+    pub synced_points: Arc<Mutex<Option<PointsHandler>>>,
+    pub history: Arc<Mutex<History>>,
     pub msg_queue: VecDeque<(String, Action)>,
+    /// Write that the leader is processing and the servers that replicate it, sent to them
+    /// when it is logged so they receive the writes in the order of the log.
+    pub fan_out: Option<(String, Vec<u32>)>,
+    pub down_since: Arc<AtomicU64>,
+    pub offline_seq: Arc<AtomicU64>,
+    pub reconciler: Arc<Mutex<Reconciler>>,
+    /// Operations of the down log sent to other servers that they did not acknowledge yet.
+    pub down_acks: Arc<Mutex<DownLogAcks>>,
+    pub offline_credit: Arc<Mutex<OfflineCredit>>,
+    pub config: ServerConfig,
+    pub auth: Arc<Authenticator>,
+    /// Signs the messages sent to other servers with the key of the shop.
+    pub signer: Signer,
+    /// Set when the server receives SIGINT or SIGTERM.
+    pub shutdown: Arc<AtomicBool>,
+    /// Operations forwarded to the leader whose answer was not received yet,
+    /// or queued in the pipeline of the leader.
+    pub in_flight: Arc<AtomicU64>,
+    /// Set while the leader transfers the leadership to another server.
+    pub transferring: Arc<AtomicBool>,
+    /// Writes received during the transfer of the leadership, with their sender.
+    pub held: Arc<Mutex<Vec<(String, SocketAddr, Role)>>>,
+    /// Set when the server has to take over the leadership once it is synchronized.
+    pub takeover: Arc<AtomicBool>,
+    /// Partitions of the accounts among the shops, None if every write goes through the leader.
+    pub partitioning: Option<Arc<Mutex<Partitioning>>>,
+    /// Latest index of the log of the leader the server knows of.
+    pub leader_index: Arc<AtomicU64>,
+    /// Index the log of the server has to reach to answer a read, so it sees the writes it forwarded.
+    pub read_floor: Arc<AtomicU64>,
+    /// Comparison of the accounts of the server with the digests of the leader.
+    pub anti_entropy: Arc<Mutex<AntiEntropy>>,
+    /// Points accrued with cash by each client, None if the accruals go through the leader.
+    pub accruals: Option<Arc<Mutex<Accruals>>>,
+    /// Socket of the gossip of the accruals with the other servers.
+    pub gossip_socket: Option<Arc<UdpSocket>>,
 }
 
 impl Server {
     /// Creates an instance of [`Server`].
-    pub fn new(shop_id: u32, shops_amount: u32) -> Server {
+    /// Fails if a socket can't be bound or the files of the server can't be opened.
+    pub fn new(shop_id: u32, shops_amount: u32, config: ServerConfig) -> Result<Server, Error> {
         let addr = id_to_dataaddr(shop_id as usize);
-        let socket = Arc::new(UdpSocket::bind(addr).expect("Error when binding server socket"));
+        let socket = Arc::new(bind(bind_addr(addr))?);
         let addr_cm = id_to_dataaddr(shop_id as usize + 1000);
-        let coffee_machine_socket =
-            Arc::new(UdpSocket::bind(addr_cm).expect("Error when binding coffee_machine socket"));
+        let coffee_machine_socket = Arc::new(bind(bind_addr(addr_cm))?);
+        let control_socket = Arc::new(bind(operator_addr(shop_id))?);
 
-        println!(
-            "[SERVER OF SHOP {}]: listening on port {}",
-            shop_id,
-            addr.port()
-        );
-        let points_handler = PointsHandler::new();
+        info!(target: SERVER, shop = shop_id, "listening on port {}", addr.port());
         let log_file_name = format!("log_{}.txt", shop_id);
-        let log_file = File::create(log_file_name).expect("Error creating de log file");
+        let snapshot_file_name = format!("snapshot_{}.txt", shop_id);
+        let (log, points_handler) = recover(&log_file_name, &snapshot_file_name)?;
+        info!(
+            target: SERVER,
+            shop = shop_id,
+            "recovered {} accounts up to index {}",
+            points_handler.points.len(),
+            log.next_index()
+        );
+        let history = History::open(&format!("history_{}.txt", shop_id))?;
+        let reconciler = Reconciler::open(&format!("reconciled_{}.txt", shop_id))?;
+        let keys = KeyStore::from_file(&config.keys_file)?;
+        let signer = keys.signer(KeyId::Shop(shop_id))?;
         let log_down_file_name = format!("log_down_{}.txt", shop_id);
-        let log_down_file = File::create(log_down_file_name).expect("Error creating de log file");
+        // Operations accepted offline and not reconciled yet are kept across restarts
+        drop_torn_record(&log_down_file_name)?;
+        let log_down_file = match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_down_file_name)
+        {
+            Ok(file) => file,
+            Err(err) => {
+                return Err(StorageError::CantWrite {
+                    path: log_down_file_name,
+                    cause: err.into(),
+                }
+                .into())
+            }
+        };
+        let shop_leader = LeaderElection::new(shop_id as usize, shops_amount)?;
+        let (accruals, gossip_socket) = if config.crdt_accruals && config.partitions == 0 {
+            let accruals = Accruals::open(&format!("accruals_{}.txt", shop_id))?;
+            let gossip_socket = bind(gossip_addr(shop_id))?;
+            (
+                Some(Arc::new(Mutex::new(accruals))),
+                Some(Arc::new(gossip_socket)),
+            )
+        } else {
+            (None, None)
+        };
+        let partitioning = match config.partitions {
+            0 => None,
+            partitions => Some(Arc::new(Mutex::new(Partitioning::new(
+                partitions,
+                config.replication_factor,
+                shops_amount,
+                MEMBER_TIMEOUT,
+            )))),
+        };
 
-        Server {
+        Ok(Server {
             addr,
             socket,
             coffee_machine_socket,
+            control_socket,
             shop_id,
             shops_amount,
-            points_handler: Arc::new(Mutex::new(points_handler)),
+            points_handler: Arc::new(ShardedPoints::new(config.workers, points_handler)),
             down: Arc::new(AtomicBool::new(false)),
-            log: log_file,
-            log_down: log_down_file,
-            shop_leader: LeaderElection::new(shop_id as usize, shops_amount),
+            log: Arc::new(Mutex::new(log)),
+            log_down: Arc::new(Mutex::new(log_down_file)),
+            shop_leader,
             sync: Arc::new(AtomicBool::new(false)),
+            sync_state: Arc::new(Mutex::new(SyncState::new())),
+            synced_points: Arc::new(Mutex::new(None)),
+            history: Arc::new(Mutex::new(history)),
             msg_queue: VecDeque::new(),
-        }
+            fan_out: None,
+            down_since: Arc::new(AtomicU64::new(0)),
+            offline_seq: Arc::new(AtomicU64::new(0)),
+            reconciler: Arc::new(Mutex::new(reconciler)),
+            down_acks: Arc::new(Mutex::new(DownLogAcks::default())),
+            offline_credit: Arc::new(Mutex::new(OfflineCredit::new(config.offline_allowance))),
+            config,
+            auth: Arc::new(Authenticator::new(keys)),
+            signer,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            in_flight: Arc::new(AtomicU64::new(0)),
+            transferring: Arc::new(AtomicBool::new(false)),
+            held: Arc::new(Mutex::new(vec![])),
+            takeover: Arc::new(AtomicBool::new(false)),
+            partitioning,
+            leader_index: Arc::new(AtomicU64::new(0)),
+            read_floor: Arc::new(AtomicU64::new(0)),
+            anti_entropy: Arc::new(Mutex::new(AntiEntropy::new())),
+            accruals,
+            gossip_socket,
+        })
     }
 
-    /// Handles messages from other shop servers and coffee machines.
+    /// Handles messages from other shop servers and coffee machines until it receives
+    /// SIGINT or SIGTERM, then shuts down gracefully.
     pub fn run(self) -> Result<(), Error> {
         let mut coffee_machine = self.clone();
         let mut server = self.clone();
+        let mut operators = self.clone();
+        let scraped = self.clone();
         let mut threads_handler: Vec<JoinHandle<Result<(), Error>>> = vec![];
 
-        threads_handler.push(thread::spawn(move || loop {
-            if coffee_machine.shop_leader.am_i_leader()? {
-                if coffee_machine
-                    .receive_from_coffee_machines_leader()
-                    .is_err()
-                {
-                    continue;
+        trace::init(
+            &format!("server-{}", self.shop_id),
+            &format!("traces_{}.jsonl", self.shop_id),
+        )?;
+
+        let metrics_addr =
+            SocketAddr::from(([127, 0, 0, 1], SERVER_METRICS_PORT + self.shop_id as u16));
+        metrics::serve(metrics_addr, move || scraped.refresh_metrics())?;
+        // A second signal exits right away if the graceful shutdown gets stuck
+        for signal in [SIGINT, SIGTERM] {
+            let registered = flag::register_conditional_shutdown(signal, 1, self.shutdown.clone())
+                .and_then(|_| flag::register(signal, self.shutdown.clone()));
+            if let Err(err) = registered {
+                return Err(ConfigError::CantRegisterSignal(err.into()).into());
+            }
+        }
+
+        // Stops accepting requests of the coffee machines as soon as the shutdown starts,
+        // the requests already queued in the pipeline are handled before the thread ends
+        threads_handler.push(thread::spawn(move || {
+            let pipeline = coffee_machine.start_pipeline();
+            loop {
+                if coffee_machine.shutdown.load(Ordering::SeqCst) {
+                    return pipeline.stop();
+                }
+                let partitioned = coffee_machine.partitioning.is_some();
+                let received = match coffee_machine.shop_leader.am_i_leader() {
+                    _ if partitioned => {
+                        coffee_machine.receive_from_coffee_machines_leader(&pipeline)
+                    }
+                    Ok(true) => coffee_machine.receive_from_coffee_machines_leader(&pipeline),
+                    Ok(false) => coffee_machine.receive_from_coffee_machines_local_server(),
+                    Err(err) => Err(err),
+                };
+                match received {
+                    Ok(_)
+                    | Err(Error::Transport(TransportError::Timeout))
+                    | Err(Error::Order(OrderError::Sync)) => (),
+                    Err(err) => warn!(
+                        target: SERVER,
+                        shop = coffee_machine.shop_id,
+                        "error handling coffee machine message: {}",
+                        err
+                    ),
                 }
-            } else if coffee_machine
-                .receive_from_coffee_machines_local_server()
-                .is_err()
-            {
-                continue;
             }
         }));
 
-        threads_handler.push(thread::spawn(move || loop {
-            if server.shop_leader.am_i_leader()? {
-                if let Err(_rr) = server.receive_from_servers() {
+        // Keeps serving the other servers until the operations in flight are drained
+        threads_handler.push(thread::spawn(move || {
+            server.reconcile_left_down_log();
+            let mut deadline = None;
+            loop {
+                let idle = if server.partitioning.is_some() {
+                    matches!(
+                        server.receive_partitioned(),
+                        Err(Error::Transport(TransportError::Timeout))
+                    )
+                } else if let Ok(true) = server.shop_leader.am_i_leader() {
+                    matches!(
+                        server.receive_from_servers(),
+                        Err(Error::Transport(TransportError::Timeout))
+                    )
+                } else {
+                    match server.receive_from_leader() {
+                        Ok(_) => false,
+                        Err(_) if deadline.is_some() => true,
+                        // The leader may have handed off the leadership to this server meanwhile
+                        Err(_) if matches!(server.shop_leader.am_i_leader(), Ok(true)) => false,
+                        Err(_) => {
+                            server.shop_leader.find_new();
+                            false
+                        }
+                    }
+                };
+                if !server.shutdown.load(Ordering::SeqCst) {
                     continue;
                 }
-            } else if server.receive_from_leader().is_err() {
-                server.shop_leader.find_new();
-            };
+                let deadline = *deadline.get_or_insert_with(|| Instant::now() + SHUTDOWN_TIMEOUT);
+                let drained = idle && server.in_flight.load(Ordering::SeqCst) == 0;
+                if drained || Instant::now() >= deadline {
+                    return server.shut_down();
+                }
+            }
+        }));
+
+        threads_handler.push(thread::spawn(move || loop {
+            if operators.shutdown.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            operators.receive_from_operators();
         }));
 
+        if self.partitioning.is_some() {
+            let mut partitions = self.clone();
+            threads_handler.push(thread::spawn(move || {
+                partitions.start_partitions();
+                while !partitions.shutdown.load(Ordering::SeqCst) {
+                    partitions.tick_partitions();
+                    thread::sleep(HEARTBEAT_INTERVAL);
+                }
+                Ok(())
+            }));
+        }
+
+        if self.partitioning.is_none() {
+            let anti_entropy = self.clone();
+            threads_handler.push(thread::spawn(move || {
+                while !anti_entropy.shutdown.load(Ordering::SeqCst) {
+                    thread::sleep(ANTI_ENTROPY_INTERVAL);
+                    anti_entropy.send_digests();
+                }
+                Ok(())
+            }));
+        }
+
+        if self.accruals.is_some() {
+            let gossip = self.clone();
+            threads_handler.push(thread::spawn(move || {
+                let mut next_round = Instant::now();
+                while !gossip.shutdown.load(Ordering::SeqCst) {
+                    if Instant::now() >= next_round {
+                        gossip.send_gossip();
+                        next_round = Instant::now() + GOSSIP_INTERVAL;
+                    }
+                    gossip.receive_gossip();
+                }
+                Ok(())
+            }));
+        }
+
         for thread in threads_handler {
-            thread.join().expect("Error joining threads")?;
+            match thread.join() {
+                Ok(result) => result?,
+                Err(_) => return Err(Error::CantJoinThread),
+            }
         }
+        info!(target: SERVER, shop = self.shop_id, "shut down");
         Ok(())
     }
 
-    /// Receives messages from the coffee machines and handle it like a leader.
-    fn receive_from_coffee_machines_leader(&mut self) -> Result<(), Error> {
-        let mut buf = [0u8; 1024];
+    /// Hands off the leadership if it is the leader and writes the logs to the disk.
+    fn shut_down(&mut self) -> Result<(), Error> {
+        let pending = self.in_flight.load(Ordering::SeqCst);
+        if pending > 0 {
+            warn!(
+                target: SERVER,
+                shop = self.shop_id,
+                "shutting down with {} operations in flight",
+                pending
+            );
+        }
+        if let Ok(true) = self.shop_leader.am_i_leader() {
+            self.shop_leader.resign();
+        } else {
+            self.shop_leader.stop();
+        }
+        if let Ok(log) = self.log.lock() {
+            log.sync()?;
+        }
+        if let Ok(history) = self.history.lock() {
+            history.sync()?;
+        }
+        match self.log_down.lock() {
+            Ok(log_down) => match log_down.sync_all() {
+                Ok(_) => Ok(()),
+                Err(err) => Err(StorageError::CantWrite {
+                    path: format!("log_down_{}.txt", self.shop_id),
+                    cause: err.into(),
+                }
+                .into()),
+            },
+            Err(_) => Err(Error::Lock),
+        }
+    }
+
+    /// Updates the metrics read from the state of the server before they are scraped.
+    fn refresh_metrics(&self) {
+        let metrics = registry();
+        let blocked = self.points_handler.blocked();
+        metrics.set(&BLOCKED_ACCOUNTS, &[], blocked as f64);
+        if let Ok(log) = self.log.lock() {
+            metrics.set(
+                &LOG_ENTRIES,
+                &[],
+                (log.next_index() - log.base_index()) as f64,
+            );
+            metrics.set(&LOG_BYTES, &[], log.file_size() as f64);
+        }
+        let led = self.shop_leader.time_as_leader().as_secs_f64();
+        metrics.set(&LEADER_SECONDS, &[], led);
+        metrics.set(&READ_LAG, &[], self.read_lag() as f64);
+        if let Some(Ok(partitioning)) = self.partitioning.as_ref().map(|p| p.lock()) {
+            let owned = partitioning.map.owned_by(self.shop_id);
+            let primary = owned
+                .iter()
+                .filter(|partition| partitioning.map.primary(**partition) == Some(self.shop_id))
+                .count();
+            metrics.set(&PARTITIONS, &[("role", "primary")], primary as f64);
+            metrics.set(
+                &PARTITIONS,
+                &[("role", "replica")],
+                (owned.len() - primary) as f64,
+            );
+        }
+    }
+
+    /// Receives the commands of the operators on the control socket.
+    /// Admin operations have to be signed with the key of the operator they name.
+    fn receive_from_operators(&mut self) {
+        let mut buf = [0u8; MESSAGE_BYTES];
+        let _ = self.control_socket.set_read_timeout(Some(TIMEOUT));
+        let (size, from) = match self.control_socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(_) => return,
+        };
+        let message = String::from_utf8_lossy(&buf[..size]).into_owned();
+        let (signer, message) = match self.authenticate(message, from, Role::Operator) {
+            Some(authenticated) => authenticated,
+            None => return,
+        };
+        debug!(
+            target: SERVER,
+            shop = self.shop_id,
+            %from,
+            "get {}",
+            message
+        );
+        let act = match MessageParser::parse(message.clone()) {
+            Ok(act) => act,
+            Err(_) => return,
+        };
+        match act {
+            Action::Grant(_, _, _, operator, _)
+            | Action::Deduct(_, _, _, operator, _)
+            | Action::Refund(_, _, _, operator, _) => {
+                if signer != KeyId::Operator(operator) {
+                    let reason = format!("signed by {}", signer);
+                    self.reject(&message, from, Role::Operator, "wrong_operator", &reason);
+                } else {
+                    self.forward_admin_operation(message, act, from);
+                }
+            }
+            Action::Transfer(_) => self.forward_admin_operation(message, act, from),
+            Action::Balance(client_id, max_lag) => {
+                self.read_balance(message, client_id, max_lag, from)
+            }
+            _ => {
+                self.handle_extra_messages(message, from);
+            }
+        }
+    }
+
+    /// Applies the admin operation or the transfer of the leadership if the server is the leader,
+    /// or forwards it to the leader with the address of the operator, so the leader answers to it.
+    fn forward_admin_operation(&mut self, message: String, act: Action, from: SocketAddr) {
+        let message = match metadata::get(&message, REPLY) {
+            Some(_) => message,
+            None => metadata::with(&message, REPLY, &from.to_string()),
+        };
+        if self.down.load(Ordering::SeqCst) || self.sync.load(Ordering::SeqCst) {
+            self.answer_operator("unavailable", from);
+        } else if self.partitioning.is_some() {
+            match self.route(&message) {
+                Route::Local => {
+                    if !self.hold(&message, from, Role::Operator) {
+                        self.process_action(message, act, from);
+                    }
+                }
+                Route::Forward(primary) => {
+                    self.send_to_server(&message, id_to_dataaddr(primary as usize))
+                }
+                Route::Pending => self.answer_operator("unavailable", from),
+            }
+        } else if let Ok(true) = self.shop_leader.am_i_leader() {
+            if !self.hold(&message, from, Role::Operator) {
+                self.process_action(message, act, from);
+            }
+        } else if self.resend_message_to_leader(message).is_err() {
+            self.answer_operator("unavailable", from);
+        }
+    }
+
+    /// Answers a balance lookup from the accounts of the server if it is the leader, or a follower
+    /// that is at most "max_lag" entries behind the leader and applied the writes it forwarded.
+    /// Otherwise the lookup is forwarded to the leader, or to the primary of the partition
+    /// of the client, which answers it to the operator.
+    fn read_balance(
+        &mut self,
+        message: String,
+        client_id: u32,
+        max_lag: Option<u64>,
+        from: SocketAddr,
+    ) {
+        let reply = metadata::get(&message, REPLY)
+            .and_then(|addr| addr.parse::<SocketAddr>().ok())
+            .unwrap_or(from);
+        let message = match metadata::get(&message, REPLY) {
+            Some(_) => message,
+            None => metadata::with(&message, REPLY, &from.to_string()),
+        };
+        if self.down.load(Ordering::SeqCst) || self.sync.load(Ordering::SeqCst) {
+            self.answer_operator("unavailable", reply);
+        } else if self.partitioning.is_some() {
+            match self.route(&message) {
+                Route::Local => self.answer_balance(client_id, 0, "primary", reply),
+                Route::Forward(primary) => {
+                    self.send_to_server(&message, id_to_dataaddr(primary as usize))
+                }
+                Route::Pending => self.answer_operator("unavailable", reply),
+            }
+        } else if let Ok(true) = self.shop_leader.am_i_leader() {
+            self.answer_balance(client_id, 0, "leader", reply);
+        } else {
+            let lag = self.read_lag();
+            let max_lag = max_lag.unwrap_or(self.config.max_read_lag);
+            let read_your_writes = self.log_index() >= self.read_floor.load(Ordering::SeqCst);
+            if lag <= max_lag && read_your_writes {
+                self.answer_balance(client_id, lag, "follower", reply);
+            } else if self.resend_message_to_leader(message).is_ok() {
+                registry().inc(&READS, &[("source", "forwarded")]);
+            } else {
+                self.answer_operator("unavailable", reply);
+            }
+        }
+    }
+
+    /// Sends the balance of the client to the operator at "to", with the index of the log
+    /// of the server and the entries it is behind the leader.
+    fn answer_balance(&self, client_id: u32, lag: u64, source: &str, to: SocketAddr) {
+        let account = match self.points_handler.lock(client_id) {
+            Ok(points) => points.points.get(&client_id).copied(),
+            Err(_) => {
+                self.answer_operator("Error", to);
+                return;
+            }
+        };
+        let (points, blocked) = account.unwrap_or((0, false));
+        let points = match self.accrued(client_id).and_then(|a| points.checked_add(a)) {
+            Some(points) => points,
+            None => {
+                self.answer_operator("Error", to);
+                return;
+            }
+        };
+        registry().inc(&READS, &[("source", source)]);
+        let msg = format!(
+            "BALANCE {} {} {} {} {}",
+            client_id,
+            points,
+            blocked as u8,
+            self.log_index(),
+            lag
+        );
+        self.answer_operator(&msg, to);
+    }
+
+    /// Returns the entries of the log of the leader the server knows of and has not applied.
+    fn read_lag(&self) -> u64 {
+        match self.shop_leader.am_i_leader() {
+            Ok(true) => 0,
+            _ => self
+                .leader_index
+                .load(Ordering::SeqCst)
+                .saturating_sub(self.log_index()),
+        }
+    }
+
+    /// Registers the index of the log of the leader carried by its message. If the message is
+    /// the answer to a write forwarded by the server, its reads have to wait for that index.
+    fn observe_leader_index(&self, message: &str) {
+        let index = match metadata::get(message, INDEX).and_then(|i| i.parse::<u64>().ok()) {
+            Some(index) => index,
+            None => return,
+        };
+        self.leader_index.fetch_max(index, Ordering::SeqCst);
+        if let Ok(Action::Ack | Action::NotEnoughPoints(_) | Action::ClientAlreadyBlocked(_)) =
+            MessageParser::parse(message.to_string())
+        {
+            self.read_floor.fetch_max(index, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns the message with the index of the log of the server as the leader,
+    /// so the followers know how far behind they are. Partitioned logs have no common index.
+    fn with_leader_index(&self, message: &str) -> String {
+        match self.partitioning {
+            Some(_) => message.to_string(),
+            None => metadata::with(message, INDEX, &self.log_index().to_string()),
+        }
+    }
+
+    /// Sends the answer of the leader to an operation that the server forwarded
+    /// for a coffee machine of its shop.
+    fn answer_forwarded(&self, answer: &str) {
+        let _ = self
+            .in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        self.answer_coffee_machine(answer, coffee_machine_addr(self.shop_id));
+    }
+
+    /// Sends the answer to an operation to the coffee machine at "to".
+    fn answer_coffee_machine(&self, answer: &str, to: SocketAddr) {
+        let labels = [
+            ("socket", Role::CoffeeMachine.label()),
+            ("type", message_type(answer)),
+        ];
+        registry().inc(&MESSAGES_SENT, &labels);
+        // The coffee machine resends the order if the answer is lost
+        if let Err(err) = self.coffee_machine_socket.send_to(answer.as_bytes(), to) {
+            warn!(
+                target: SERVER,
+                shop = self.shop_id,
+                to = %to,
+                "error sending {} to coffee machine: {}",
+                answer,
+                err
+            );
+        }
+    }
+
+    /// Sends the answer to a command to the operator at "to".
+    fn answer_operator(&self, answer: &str, to: SocketAddr) {
+        let labels = [
+            ("socket", Role::Operator.label()),
+            ("type", message_type(answer)),
+        ];
+        registry().inc(&MESSAGES_SENT, &labels);
+        debug!(
+            target: SERVER,
+            shop = self.shop_id,
+            to = %to,
+            "send {}",
+            answer
+        );
+        if let Err(err) = self.control_socket.send_to(answer.as_bytes(), to) {
+            warn!(
+                target: SERVER,
+                shop = self.shop_id,
+                to = %to,
+                "error sending {} to operator: {}",
+                answer,
+                err
+            );
+        }
+    }
+
+    /// Receives messages from the coffee machines and queues them in the pipeline of the leader.
+    /// The messages of a client go to the same worker, so they are applied in order.
+    fn receive_from_coffee_machines_leader(&mut self, pipeline: &Pipeline) -> Result<(), Error> {
+        let mut buf = [0u8; MESSAGE_BYTES];
         let _ = self.coffee_machine_socket.set_read_timeout(Some(TIMEOUT));
         if !self.sync.load(Ordering::SeqCst) {
             match self.coffee_machine_socket.recv_from(&mut buf) {
                 Ok((size, from)) => {
                     let message = String::from_utf8_lossy(&buf[..size]).into_owned();
-                    println!(
-                        "[SERVER FROM SHOP {}]: get {} from {}",
-                        self.shop_id, message, from
-                    );
-                    let extra = self.handle_extra_messages(message.clone());
-
-                    if let Some(msg) = self.answer_leader(message.clone(), from) {
-                        if !self.down.load(Ordering::SeqCst) && extra != Some(Action::Up) {
-                            self.resend_to_servers(message)
-                        };
-                        println!(
-                            "[SERVER FROM SHOP {}]: send {} to {}",
-                            self.shop_id, msg, from
+                    let client_id = match MessageParser::parse(message.clone()) {
+                        Ok(act) => act.client_id().unwrap_or(0),
+                        Err(_) => 0,
+                    };
+                    self.in_flight.fetch_add(1, Ordering::SeqCst);
+                    if let Err(err) = pipeline.dispatch(client_id, message, from) {
+                        let _ =
+                            self.in_flight
+                                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                                    n.checked_sub(1)
+                                });
+                        error!(
+                            target: SERVER,
+                            shop = self.shop_id,
+                            "the worker of client {} stopped: {}",
+                            client_id,
+                            err
                         );
-                        self.socket
-                            .send_to(msg.as_bytes(), from)
-                            .expect("Error sending message to server");
+                        self.reply_coffee_machine("Error", from);
+                    }
+                }
+                Err(_) => return Err(TransportError::Timeout.into()),
+            }
+        }
+
+        Err(OrderError::Sync.into())
+    }
+
+    /// Starts the pipeline of the leader for the messages of the coffee machines,
+    /// with a worker for each shard of the accounts.
+    fn start_pipeline(&self) -> Pipeline {
+        let sender = self.clone();
+        Pipeline::start(
+            self.config.workers,
+            PIPELINE_QUEUE_CAPACITY,
+            |_| {
+                let mut worker = self.clone();
+                move |message, from| worker.handle_coffee_machine_request(message, from)
+            },
+            move |answer, to| sender.reply_coffee_machine(&answer, to),
+        )
+    }
+
+    /// Authenticates and processes a message of a coffee machine in a worker of the pipeline.
+    /// Returns the answer to the coffee machine.
+    fn handle_coffee_machine_request(
+        &mut self,
+        message: String,
+        from: SocketAddr,
+    ) -> Option<Request> {
+        let answer = match self.authenticate(message, from, Role::CoffeeMachine) {
+            Some((_, message)) => {
+                debug!(
+                    target: SERVER,
+                    shop = self.shop_id,
+                    %from,
+                    "get {}",
+                    message
+                );
+                if let Some(answer) = self.accrue_locally(&message) {
+                    return Some((answer, from));
+                }
+                match self.route(&message) {
+                    Route::Local => self.lead_coffee_machine_message(message, from),
+                    Route::Forward(primary) => {
+                        self.forward_to_primary(message, primary);
+                        None
                     }
+                    // The coffee machine resends it once the partition is received
+                    Route::Pending => None,
+                }
+            }
+            None => None,
+        };
+        let _ = self
+            .in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        answer.map(|answer| (answer, from))
+    }
+
+    /// Processes the message of a coffee machine of the shop as the leader
+    /// and returns the answer to the coffee machine.
+    fn lead_coffee_machine_message(&mut self, message: String, from: SocketAddr) -> Option<String> {
+        if self.hold(&message, from, Role::CoffeeMachine) {
+            return None;
+        }
+        let (msg, unsent) = self.process_traced(message, from)?;
+        if let Some(message) = unsent {
+            if !self.down.load(Ordering::SeqCst) {
+                self.resend_to_servers(message)
+            };
+        }
+        Some(msg)
+    }
+
+    /// Sends the answer of the leader to the coffee machine at "to".
+    fn reply_coffee_machine(&self, answer: &str, to: SocketAddr) {
+        debug!(
+            target: SERVER,
+            shop = self.shop_id,
+            to = %to,
+            "send {}",
+            answer
+        );
+        self.answer_coffee_machine(answer, to);
+    }
+
+    /// Processes the message of another server as the leader.
+    fn lead_server_message(&mut self, message: String, from: SocketAddr) {
+        if self.hold(&message, from, Role::PeerServer) {
+            return;
+        }
+        if let Some((msg, unsent)) = self.process_traced(message, from) {
+            if let Some(message) = unsent {
+                if !self.sync.load(Ordering::SeqCst) {
+                    self.resend_to_servers(message)
+                };
+            }
+            let msg = self.with_leader_index(&msg);
+            debug!(
+                target: SERVER,
+                shop = self.shop_id,
+                to = %from,
+                "send {}",
+                msg
+            );
+            self.send_to_server(&msg, from);
+        }
+    }
+
+    /// Holds the writes that the leader receives while it transfers the leadership,
+    /// they are handled when the transfer ends. Returns true if the message was held.
+    fn hold(&self, message: &str, from: SocketAddr, role: Role) -> bool {
+        if !self.transferring.load(Ordering::SeqCst) {
+            return false;
+        }
+        match MessageParser::parse(message.to_string()) {
+            Ok(act) if is_replicated(&act) || matches!(act, Action::Refund(..)) => {
+                if let Ok(mut held) = self.held.lock() {
+                    held.push((message.to_string(), from, role));
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Starts transferring the leadership to the server of "target" in another thread,
+    /// so the leader keeps answering the synchronization requests of the target.
+    fn start_transfer(&mut self, message: &str, target: u32, from: SocketAddr) {
+        let reply = metadata::get(message, REPLY)
+            .and_then(|addr| addr.parse::<SocketAddr>().ok())
+            .unwrap_or(from);
+        if target == self.shop_id || target >= self.shops_amount {
+            self.answer_operator("invalidTarget", reply);
+            return;
+        }
+        if self.transferring.swap(true, Ordering::SeqCst) {
+            self.answer_operator("transferring", reply);
+            return;
+        }
+        let mut server = self.clone();
+        thread::spawn(move || server.transfer_leadership(target, reply));
+    }
+
+    /// Asks the target to take over the leadership until it is the leader, then hands it
+    /// the writes held meanwhile. If the target does not take over in time, the server
+    /// keeps the leadership and handles the held writes itself.
+    fn transfer_leadership(&mut self, target: u32, reply: SocketAddr) {
+        let index = self.log_index();
+        info!(
+            target: ELECTION,
+            shop = self.shop_id,
+            "transferring the leadership to shop {} at index {}",
+            target,
+            index
+        );
+        let deadline = Instant::now() + TRANSFER_TIMEOUT;
+        let mut next_request = Instant::now();
+        let mut transferred = false;
+        while Instant::now() < deadline {
+            if let Ok(leader_id) = self.shop_leader.get_leader_id() {
+                if leader_id == target as usize {
+                    transferred = true;
+                    break;
+                }
+            }
+            if Instant::now() >= next_request {
+                let msg = format!("TAKEOVER {}", index);
+                self.send_to_server(&msg, id_to_dataaddr(target as usize));
+                next_request = Instant::now() + TIMEOUT;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        if !transferred {
+            warn!(
+                target: ELECTION,
+                shop = self.shop_id,
+                "shop {} did not take over the leadership",
+                target
+            );
+            self.transferring.store(false, Ordering::SeqCst);
+        }
+        loop {
+            let held = match self.held.lock() {
+                Ok(mut held) => std::mem::take(&mut *held),
+                Err(_) => vec![],
+            };
+            if held.is_empty() {
+                break;
+            }
+            for (message, from, role) in held {
+                self.release(message, from, role, transferred);
+            }
+        }
+        self.transferring.store(false, Ordering::SeqCst);
+        let answer = if transferred { "ACK" } else { "transferFailed" };
+        self.answer_operator(answer, reply);
+    }
+
+    /// Handles a write held during a transfer of the leadership: it is forwarded
+    /// to the new leader, or processed if the server is still the leader.
+    fn release(&mut self, message: String, from: SocketAddr, role: Role, transferred: bool) {
+        match (role, transferred) {
+            (Role::CoffeeMachine, true) => self.forward_coffee_machine_message(message),
+            (Role::Operator, true) => {
+                if self.resend_message_to_leader(message).is_err() {
+                    self.answer_operator("unavailable", from);
+                }
+            }
+            (Role::PeerServer, true) => {
+                if let Err(err) = self.resend_message_to_leader(message) {
+                    warn!(target: SERVER, shop = self.shop_id, "error forwarding held message: {}", err);
+                }
+            }
+            (Role::CoffeeMachine, false) => {
+                if let Some(answer) = self.lead_coffee_machine_message(message, from) {
+                    self.reply_coffee_machine(&answer, from);
+                }
+            }
+            (Role::PeerServer, false) => self.lead_server_message(message, from),
+            (Role::Operator, false) => {
+                if let Ok(act) = MessageParser::parse(message.clone()) {
+                    self.process_action(message, act, from);
                 }
-                Err(_) => return Err(Error::Timeout),
             }
         }
+    }
 
-        Err(Error::Sync)
+    /// Takes over the leadership once its log reaches the index of the leader,
+    /// synchronizing with the leader first if it is behind.
+    fn take_over(&mut self, index: u64, from: SocketAddr) {
+        if self.down.load(Ordering::SeqCst) || self.sync.load(Ordering::SeqCst) {
+            return;
+        }
+        if self.log_index() >= index {
+            self.shop_leader.take_over();
+            return;
+        }
+        self.takeover.store(true, Ordering::SeqCst);
+        self.sync.store(true, Ordering::SeqCst);
+        if let Ok(mut state) = self.sync_state.lock() {
+            state.started_at = Some(Instant::now());
+        }
+        self.request_sync(format!("SYNC {}", self.log_index()), from);
+        let mut watcher = self.clone();
+        thread::spawn(move || watcher.watch_sync());
     }
 
     /// Receives messages from other servers.
     fn receive_from_servers(&mut self) -> Result<(), Error> {
-        let mut buf = [0u8; 1024];
+        let mut buf = [0u8; MESSAGE_BYTES];
         let _ = self.socket.set_read_timeout(Some(TIMEOUT));
         match self.socket.recv_from(&mut buf) {
             Ok((size, from)) => {
                 if !self.down.load(Ordering::SeqCst) {
                     let message = String::from_utf8_lossy(&buf[..size]).into_owned();
-                    println!(
-                        "[SERVER FROM SHOP {}]: get {} from {}",
-                        self.shop_id, message, from
+                    let message = match self.authenticate(message, from, Role::PeerServer) {
+                        Some((_, message)) => message,
+                        None => return Ok(()),
+                    };
+                    debug!(
+                        target: SERVER,
+                        shop = self.shop_id,
+                        %from,
+                        "get {}",
+                        message
                     );
-                    if let Some(msg) = self.answer_leader(message.clone(), from) {
-                        if !self.sync.load(Ordering::SeqCst) {
-                            self.resend_to_servers(message)
-                        };
-                        println!(
-                            "[SERVER FROM SHOP {}]: send {} to {}",
-                            self.shop_id, msg, from
-                        );
-                        self.socket
-                            .send_to(msg.as_bytes(), from)
-                            .expect("Error sending message to server");
-                    }
+                    self.lead_server_message(message, from);
                     return Ok(());
                 }
             }
-            Err(_) => return Err(Error::Timeout),
+            Err(_) => return Err(TransportError::Timeout.into()),
         }
 
-        Err(Error::Down)
+        Err(OrderError::Down.into())
     }
 
     /// Receives messages from leader server.
     fn receive_from_leader(&mut self) -> Result<String, Error> {
-        let mut buf = [0u8; 1024];
+        let mut buf = [0u8; MESSAGE_BYTES];
         let _ = self.socket.set_read_timeout(Some(Duration::new(3, 0)));
 
         match self.socket.recv_from(&mut buf) {
             Ok((size, from)) => {
                 if !self.down.load(Ordering::SeqCst) {
                     let message = String::from_utf8_lossy(&buf[..size]).into_owned();
-                    println!(
-                        "[SERVER FROM SHOP {}]: get {} from {}",
-                        self.shop_id, message, from
+                    let message = match self.authenticate(message, from, Role::PeerServer) {
+                        Some((_, message)) => message,
+                        // A rejected message does not mean the leader is gone
+                        None => return Ok(String::new()),
+                    };
+                    self.observe_leader_index(&message);
+                    debug!(
+                        target: SERVER,
+                        shop = self.shop_id,
+                        %from,
+                        "get {}",
+                        message
                     );
-                    if let Some(msg) = self.answer_local_server(message.clone(), from) {
-                        println!(
-                            "[SERVER FROM SHOP {}]: send {} to {}",
-                            self.shop_id, msg, from
+                    if let Some(msg) = self.answer_local_server_traced(message.clone(), from) {
+                        debug!(
+                            target: SERVER,
+                            shop = self.shop_id,
+                            to = %from,
+                            "send {}",
+                            msg
                         );
-                        self.socket
-                            .send_to(msg.as_bytes(), from)
-                            .expect("Error sending message");
+                        self.send_to_server(&msg, from);
                     }
                     return Ok(message);
                 }
-                Err(Error::Down)
+                Err(OrderError::Down.into())
             }
-            Err(_) => Err(Error::Timeout),
+            Err(_) => Err(TransportError::Timeout.into()),
         }
     }
 
     /// Receives messages from the coffees machines and handle it like a local server.
     fn receive_from_coffee_machines_local_server(&mut self) -> Result<(), Error> {
-        let mut buf = [0u8; 1024];
+        let mut buf = [0u8; MESSAGE_BYTES];
         let _ = self.coffee_machine_socket.set_read_timeout(Some(TIMEOUT));
         match self.coffee_machine_socket.recv_from(&mut buf) {
             Ok((size, from)) => {
                 if !self.sync.load(Ordering::SeqCst) {
                     let message = String::from_utf8_lossy(&buf[..size]).into_owned();
-                    println!(
-                        "[SERVER FROM SHOP {}]: get {} from {}",
-                        self.shop_id, message, from
+                    let message = match self.authenticate(message, from, Role::CoffeeMachine) {
+                        Some((_, message)) => message,
+                        None => return Ok(()),
+                    };
+                    debug!(
+                        target: SERVER,
+                        shop = self.shop_id,
+                        %from,
+                        "get {}",
+                        message
                     );
-                    let extra = self.handle_extra_messages(message.clone());
-
-                    if !self.down.load(Ordering::SeqCst) {
-                        self.resend_message_to_leader(message);
-                        return Ok(());
-                    } else if extra != Some(Action::Up) {
-                        if let Some(msg) = self.answer_local_server(message, from) {
-                            println!(
-                                "[SERVER FROM SHOP {}]: send {} to {}",
-                                self.shop_id, msg, from
-                            );
-                            self.coffee_machine_socket
-                                .send_to(msg.as_bytes(), from)
-                                .expect("Error sending message");
-                        }
-                        return Ok(());
+                    if let Some(msg) = self.accrue_locally(&message) {
+                        self.answer_coffee_machine(&msg, from);
+                    } else if !self.down.load(Ordering::SeqCst) {
+                        self.forward_coffee_machine_message(message);
+                    } else if let Some(msg) = self.answer_local_server_traced(message, from) {
+                        debug!(
+                            target: SERVER,
+                            shop = self.shop_id,
+                            to = %from,
+                            "send {}",
+                            msg
+                        );
+                        self.answer_coffee_machine(&msg, from);
                     }
                 }
                 Ok(())
             }
-            Err(_) => Err(Error::Timeout),
+            Err(_) => Err(TransportError::Timeout.into()),
         }
     }
 
-    /// Handles extra messages DOWN and UP.
-    fn handle_extra_messages(&mut self, message: String) -> Option<Action> {
+    /// Handles the commands DOWN and UP and the history queries of the operators.
+    fn handle_extra_messages(&mut self, message: String, from: SocketAddr) -> Option<Action> {
         if let Ok(msg) = MessageParser::parse(message) {
             match msg {
+                Action::History(client_id, since, until) => {
+                    self.send_history(client_id, since, until, from);
+                    return Some(msg);
+                }
+                Action::State => {
+                    self.send_state(from);
+                    return Some(msg);
+                }
+                Action::Up if self.partitioning.is_some() => {
+                    info!(target: SERVER, shop = self.shop_id, "Im UP");
+                    self.rejoin();
+                    return Some(msg);
+                }
                 Action::Up => {
-                    print!("\x1b[32m");
-                    println!("[SERVER FROM SHOP {}]: Im UP", self.shop_id);
-                    print!("\x1b[0m");
+                    info!(target: SERVER, shop = self.shop_id, "Im UP");
                     self.sync.store(true, Ordering::SeqCst);
                     self.sync_with_leader();
                     return Some(msg);
                 }
                 Action::Down => {
-                    print!("\x1b[31m");
-                    println!("[SERVER FROM SHOP {}]: Im DOWN", self.shop_id);
-                    print!("\x1b[0m");
+                    warn!(target: SERVER, shop = self.shop_id, "Im DOWN");
                     self.shop_leader.stop();
+                    self.down_since.store(now_millis(), Ordering::SeqCst);
+                    if let (Ok(points), Ok(mut synced)) =
+                        (self.points_handler.merged(), self.synced_points.lock())
+                    {
+                        *synced = Some(points);
+                    }
+                    if let Ok(mut credit) = self.offline_credit.lock() {
+                        credit.reset();
+                    }
                     self.down.store(true, Ordering::SeqCst);
                     return Some(msg);
                 }
@@ -271,109 +1114,654 @@ impl Server {
     }
 
     /// Starts synchronization with the leader after being down.
+    /// The server asks for the log entries it missed, starting at the index of its next entry.
+    /// The accounts go back to their state before going down, since the operations accepted
+    /// offline come back through the log once they are reconciled.
     fn sync_with_leader(&mut self) {
+        if let Ok(mut state) = self.sync_state.lock() {
+            state.started_at = Some(std::time::Instant::now());
+        }
+        self.restore_synced_points();
         self.shop_leader.up();
         self.shop_leader.find_new();
-        let log_name = format!("log_{}.txt", self.shop_id);
-        let reader = BufReader::new(File::open(log_name).expect("Error opening the log file"));
-        let line_count = reader.lines().count();
-        let msg = format!("SYNC {}", line_count);
+        let msg = format!("SYNC {}", self.log_index());
+        self.down.store(false, Ordering::SeqCst);
         if let Ok(leader) = self.shop_leader.am_i_leader() {
             if leader {
-                if let Ok(addr) = self.broadcast() {
-                    self.send_down_log_broadcast();
-
-                    self.resend_message(msg, addr);
+                self.reconcile_down_log();
+                match self.broadcast() {
+                    Ok(addr) => self.request_sync(msg, addr),
+                    Err(_) => self.sync.store(false, Ordering::SeqCst),
                 };
-            } else {
-                let leader_addr = id_to_dataaddr(self.shop_leader.get_leader_id().unwrap());
-                self.resend_message(msg, leader_addr);
+            } else if let Ok(leader_id) = self.shop_leader.get_leader_id() {
+                let leader_addr = id_to_dataaddr(leader_id);
+                self.send_down_log(leader_id as u32, leader_addr);
+                self.request_sync(msg, leader_addr);
+            }
+        }
+
+        let mut watcher = self.clone();
+        thread::spawn(move || watcher.watch_sync());
+    }
 
-                self.send_down_log(leader_addr);
+    /// Restores the accounts to their state before going down.
+    fn restore_synced_points(&mut self) {
+        if let Ok(mut synced) = self.synced_points.lock() {
+            if let Some(synced) = synced.take() {
+                if self.points_handler.replace(synced).is_err() {
+                    error!(target: SYNC, shop = self.shop_id, "error restoring the accounts");
+                }
             }
         }
-        self.down.store(false, Ordering::SeqCst);
     }
 
-    /// Send all servers the accumulated log while it was down.
-    fn send_down_log_broadcast(&mut self) {
-        let log_name = format!("log_down_{}.txt", self.shop_id);
-        let reader = BufReader::new(File::open(log_name).expect("Error when opening the log file"));
-        for line in reader.lines() {
-            match line {
-                Ok(line) => {
-                    for i in 0..self.shops_amount {
-                        let addr = id_to_dataaddr(i as usize);
-                        if i != self.shop_id {
-                            println!(
-                                "[SERVER FROM SHOP {}]: send {} to {}",
-                                self.shop_id, line, addr
-                            );
-                            self.socket
-                                .send_to(line.as_bytes(), addr)
-                                .expect("Error when sending message");
-                        }
+    /// Sends a synchronization request and registers it so it is resent if it is not answered.
+    fn request_sync(&mut self, message: String, to: SocketAddr) {
+        if let Ok(mut state) = self.sync_state.lock() {
+            state.request(message.clone(), to);
+        }
+        self.resend_message(message, to);
+    }
+
+    /// Resends the last synchronization request while there are no answers,
+    /// so the synchronization resumes from the last entry applied.
+    fn watch_sync(&mut self) {
+        while self.sync.load(Ordering::SeqCst) {
+            thread::sleep(SYNC_TIMEOUT);
+            let request = match self.sync_state.lock() {
+                Ok(mut state) => {
+                    if !self.sync.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    if state.updated_at.elapsed() < SYNC_TIMEOUT {
+                        continue;
+                    }
+                    state.retries += 1;
+                    state.updated_at = std::time::Instant::now();
+                    if state.retries > SYNC_MAX_RETRIES {
+                        state.finish("failed");
+                        self.takeover.store(false, Ordering::SeqCst);
+                        None
+                    } else {
+                        state.last_request.clone()
                     }
                 }
-                Err(err) => {
-                    println!("Error when reading line: {}", err);
+                Err(_) => None,
+            };
+            match request {
+                Some((message, to)) => self.resend_message(message, to),
+                None => {
+                    warn!(
+                        target: SYNC,
+                        shop = self.shop_id,
+                        "synchronization with the leader failed"
+                    );
+                    self.sync.store(false, Ordering::SeqCst);
                     return;
                 }
             }
         }
     }
 
-    /// Send TRY message to all servers.
-    fn broadcast(&mut self) -> Result<SocketAddr, Error> {
-        for i in 0..self.shops_amount {
-            let addr = id_to_dataaddr(i as usize);
-            if i != self.shop_id {
-                self.socket.send_to("TRY".as_bytes(), addr).unwrap();
-            }
+    /// Applies the entries of a chunk if they start at the next index of the log
+    /// and asks for the following ones.
+    fn apply_chunk(&mut self, start: u64, entries: Vec<String>, from: SocketAddr) {
+        if !self.sync.load(Ordering::SeqCst) {
+            return;
         }
-        let mut buf = [0u8; 1024];
-        self.socket
-            .set_read_timeout(Some(Duration::from_secs(3)))
-            .expect("Error setting timeout");
-        if let Ok((size, from)) = self.socket.recv_from(&mut buf) {
-            let message = String::from_utf8_lossy(&buf[..size]).into_owned();
-            println!(
-                "[SERVER FROM SHOP {}]: get {} from {}",
-                self.shop_id, message, from
-            );
-            return Ok(from);
+        if start == self.log_index() {
+            for entry in entries {
+                self.apply_entry(entry);
+            }
         }
-        Err(Error::Timeout)
+        let msg = format!("SYNC {}", self.log_index());
+        self.request_sync(msg, from);
     }
 
-    /// Send the log file to the server at the specified address
-    fn send_down_log(&mut self, addr: SocketAddr) {
+    /// Applies an entry of the leader's log received during the synchronization.
+    fn apply_entry(&mut self, entry: String) {
+        if let Ok(Action::Settle(client_id, _, shop_id, total)) =
+            MessageParser::parse(entry.clone())
+        {
+            self.apply_settlement(client_id, shop_id, total);
+        }
+        match self.points_handler.apply_entry(&entry) {
+            Ok(result) => self.write_log(entry, &result),
+            Err(_) => warn!(target: SYNC, shop = self.shop_id, "invalid log entry: {}", entry),
+        }
+    }
+
+    /// Stores a part of the snapshot sent by the leader and asks for the next one.
+    /// Once every part is received, the snapshot replaces the accounts and the log of the server.
+    fn apply_snapshot_part(
+        &mut self,
+        index: u64,
+        part: u32,
+        parts: u32,
+        accounts: Vec<Account>,
+        from: SocketAddr,
+    ) {
+        if !self.sync.load(Ordering::SeqCst) {
+            return;
+        }
+        let complete = match self.sync_state.lock() {
+            Ok(mut state) => {
+                if part == 0 {
+                    state.snapshot.clear();
+                }
+                state.snapshot.extend(accounts);
+                if part + 1 == parts {
+                    Some(std::mem::take(&mut state.snapshot))
+                } else {
+                    None
+                }
+            }
+            Err(_) => return,
+        };
+        let msg = match complete {
+            Some(accounts) => {
+                let snapshot = Snapshot { index, accounts };
+                if self.points_handler.replace(snapshot.points()).is_err() {
+                    error!(target: SYNC, shop = self.shop_id, "error installing the snapshot");
+                }
+                if let Ok(mut log) = self.log.lock() {
+                    if log.reset(&snapshot).is_err() {
+                        error!(target: SYNC, shop = self.shop_id, "error resetting the log file");
+                    }
+                }
+                info!(
+                    target: SYNC,
+                    shop = self.shop_id,
+                    "installed snapshot at index {}",
+                    index
+                );
+                format!("SYNC {}", index)
+            }
+            None => format!("SYNCSNAP {} {}", index, part + 1),
+        };
+        self.request_sync(msg, from);
+    }
+
+    /// Ends the synchronization with the leader.
+    fn finish_sync(&mut self, index: u64) {
+        if !self.sync.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Ok(mut state) = self.sync_state.lock() {
+            state.last_request = None;
+            state.finish("ok");
+            self.sync.store(false, Ordering::SeqCst);
+        }
+        // The indexes known from a previous leader do not apply to the log of the current one
+        self.leader_index.store(index, Ordering::SeqCst);
+        self.read_floor.fetch_min(index, Ordering::SeqCst);
+        if self.takeover.swap(false, Ordering::SeqCst) {
+            self.shop_leader.take_over();
+        }
+        info!(
+            target: SYNC,
+            shop = self.shop_id,
+            "synchronized up to index {}",
+            index
+        );
+    }
+
+    /// Sends the index of the log and the digests of the accounts of the leader to the
+    /// other servers, so they find out if they lost any of its writes.
+    fn send_digests(&self) {
+        let idle = !self.down.load(Ordering::SeqCst)
+            && !self.sync.load(Ordering::SeqCst)
+            && !self.transferring.load(Ordering::SeqCst);
+        if !idle || !matches!(self.shop_leader.am_i_leader(), Ok(true)) {
+            return;
+        }
+        let accounts = match self.points_handler.accounts(|_| true) {
+            Ok(accounts) => accounts,
+            Err(_) => return,
+        };
+        let msg = format!(
+            "DIGEST {} {}",
+            self.log_index(),
+            digests_to_text(&digests(&accounts))
+        );
+        for shop_id in (0..self.shops_amount).filter(|id| *id != self.shop_id) {
+            self.send_to_server(&msg, id_to_dataaddr(shop_id as usize));
+        }
+    }
+
+    /// Compares the accounts and the log position of the server with the digests of the leader
+    /// at "index", and asks the leader for the accounts of the ranges to repair.
+    fn check_digests(&mut self, index: u64, leader: Vec<u64>, from: SocketAddr) {
+        if self.down.load(Ordering::SeqCst) || self.sync.load(Ordering::SeqCst) {
+            return;
+        }
+        let local_index = self.log_index();
+        let local = match self.points_handler.accounts(|_| true) {
+            Ok(accounts) => digests(&accounts),
+            Err(_) => return,
+        };
+        let ranges = match self.anti_entropy.lock() {
+            Ok(mut anti_entropy) => {
+                let ranges = anti_entropy.compare(index, local_index, &leader, &local);
+                if let Some(ranges) = &ranges {
+                    anti_entropy.start_repair(ranges.clone());
+                }
+                ranges
+            }
+            Err(_) => return,
+        };
+        let ranges = match ranges {
+            Some(ranges) => ranges,
+            None => return,
+        };
+        warn!(
+            target: SYNC,
+            shop = self.shop_id,
+            "diverged from the leader at index {} (local {}), repairing ranges {:?}",
+            index,
+            local_index,
+            ranges
+        );
+        registry().inc(&ANTI_ENTROPY_REPAIRS, &[]);
+        let ranges: Vec<String> = ranges.iter().map(|range| range.to_string()).collect();
+        let msg = format!("REPAIR {}", ranges.join(","));
+        self.send_to_server(msg.trim_end(), from);
+    }
+
+    /// Sends to the server "from" the accounts of the leader in the ranges to repair,
+    /// with the index of its log, split in as many parts as needed.
+    fn send_repair(&self, ranges: Vec<u32>, from: SocketAddr) {
+        let accounts = match self
+            .points_handler
+            .accounts(|client_id| ranges.contains(&range_of(client_id)))
+        {
+            Ok(accounts) => accounts,
+            Err(_) => return,
+        };
+        let index = self.log_index();
+        let parts = snapshot_parts(&accounts);
+        for (part, accounts) in parts.iter().enumerate() {
+            let msg = format!(
+                "REPAIRSTATE {} {} {} {}",
+                index,
+                part,
+                parts.len(),
+                accounts
+            );
+            self.resend_message(msg.trim_end().to_string(), from);
+        }
+    }
+
+    /// Replaces the accounts of the ranges to repair with the ones of the leader. Once every part
+    /// is received, the log of the server moves to the index of the log of the leader.
+    fn apply_repair_part(&mut self, index: u64, part: u32, parts: u32, accounts: Vec<Account>) {
+        let replaced = match self.anti_entropy.lock() {
+            // The accounts missing in the leader are dropped with the first part
+            Ok(anti_entropy) if part == 0 => self
+                .points_handler
+                .replace_accounts(|client_id| anti_entropy.is_repairing(client_id), &accounts),
+            Ok(_) => self.points_handler.replace_accounts(|_| false, &accounts),
+            Err(_) => Err(Error::Lock),
+        };
+        if replaced.is_err() {
+            error!(target: SYNC, shop = self.shop_id, "error repairing the accounts");
+            return;
+        }
+        if part + 1 < parts || self.log_index() == index {
+            return;
+        }
+        let snapshot = match self.points_handler.accounts(|_| true) {
+            Ok(accounts) => Snapshot { index, accounts },
+            Err(_) => return,
+        };
+        if let Ok(mut log) = self.log.lock() {
+            if log.reset(&snapshot).is_err() {
+                error!(target: SYNC, shop = self.shop_id, "error resetting the log file");
+                return;
+            }
+        }
+        self.leader_index.fetch_max(index, Ordering::SeqCst);
+        self.read_floor.fetch_min(index, Ordering::SeqCst);
+        info!(
+            target: SYNC,
+            shop = self.shop_id,
+            "moved the log to the index {} of the leader",
+            index
+        );
+    }
+
+    /// Reconciles the operations accumulated while it was down with the state of the cluster
+    /// and sends the merged ones to all servers.
+    fn reconcile_down_log(&mut self) {
+        let since = self.down_since.load(Ordering::SeqCst);
+        for operation in self.read_down_log() {
+            self.reconcile_operation(self.shop_id, since, operation);
+        }
+        self.finish_reconciliation(self.shop_id);
+        self.truncate_down_log(since);
+    }
+
+    /// Reconciles the operations left in the down log by an earlier run of the server,
+    /// accepted offline and not reconciled before it stopped. They are reconciled as if
+    /// the server came back from the outage of the last of them.
+    fn reconcile_left_down_log(&mut self) {
+        let records = self.read_down_records();
+        let since = match records.iter().map(|r| r.down_since.unwrap_or(0)).max() {
+            Some(since) => since,
+            None => return,
+        };
+        info!(
+            target: SYNC,
+            shop = self.shop_id,
+            "reconciling {} operations left in the down log",
+            records.len()
+        );
+        self.down_since.store(since, Ordering::SeqCst);
+        if let Some(partitioning) = &self.partitioning {
+            // They are sent to their primaries once the partitions are received
+            if let Ok(mut partitioning) = partitioning.lock() {
+                partitioning.rejoining = true;
+            }
+        } else if let Ok(true) = self.shop_leader.am_i_leader() {
+            self.reconcile_down_log();
+        } else if let Ok(leader_id) = self.shop_leader.get_leader_id() {
+            self.send_down_log(leader_id as u32, id_to_dataaddr(leader_id));
+        }
+    }
+
+    /// Returns the operations of the down log.
+    fn read_down_log(&self) -> Vec<OfflineOperation> {
+        self.read_down_records()
+            .iter()
+            .map(|record| OfflineOperation::from_record(self.shop_id, record))
+            .collect()
+    }
+
+    /// Returns the records of the down log.
+    fn read_down_records(&self) -> Vec<LogRecord> {
         let log_name = format!("log_down_{}.txt", self.shop_id);
-        let reader = BufReader::new(File::open(log_name).expect("Error when opening the log file"));
+        let reader = match File::open(&log_name) {
+            Ok(file) => BufReader::new(file),
+            Err(err) => {
+                error!(target: SERVER, shop = self.shop_id, "error opening {}: {}", log_name, err);
+                return vec![];
+            }
+        };
+        let mut records = vec![];
         for line in reader.lines() {
             match line {
-                Ok(line) => {
-                    self.socket
-                        .send_to(line.as_bytes(), addr)
-                        .expect("Error sending message");
-                }
+                Ok(line) => match LogRecord::from_line(&line) {
+                    Ok(record) => records.push(record),
+                    Err(_) => warn!(
+                        target: SERVER,
+                        shop = self.shop_id,
+                        "invalid line in down log: {}",
+                        line
+                    ),
+                },
                 Err(err) => {
-                    println!("Error when reading line: {}", err);
-                    return;
+                    error!(target: SERVER, shop = self.shop_id, "error reading the down log: {}", err);
+                    break;
+                }
+            }
+        }
+        records
+    }
+
+    /// Drops the records of the down log written while the server was down until `since`,
+    /// once they are reconciled. The records of a later outage are kept.
+    /// The records kept are written to a temporary file that replaces the down log,
+    /// so a crash while it is written leaves the whole down log.
+    fn truncate_down_log(&self, since: u64) {
+        let log_name = format!("log_down_{}.txt", self.shop_id);
+        let tmp_name = format!("{}.tmp", log_name);
+        let mut log_down = match self.log_down.lock() {
+            Ok(log_down) => log_down,
+            Err(_) => return,
+        };
+        let kept: Vec<LogRecord> = self
+            .read_down_records()
+            .into_iter()
+            .filter(|record| record.down_since > Some(since))
+            .collect();
+        let written = File::create(&tmp_name)
+            .and_then(|mut file| {
+                for record in &kept {
+                    writeln!(file, "{}", record.to_line())?;
                 }
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp_name, &log_name))
+            .and_then(|_| OpenOptions::new().append(true).open(&log_name));
+        match written {
+            Ok(file) => {
+                *log_down = file;
+                info!(
+                    target: SYNC,
+                    shop = self.shop_id,
+                    "dropped the reconciled operations of the down log, {} left",
+                    kept.len()
+                );
+            }
+            Err(err) => {
+                error!(target: SYNC, shop = self.shop_id, "error truncating the down log: {}", err)
+            }
+        }
+    }
+
+    /// Drops the reconciled records of the down log once every server that got them
+    /// acknowledged them. Otherwise they are sent again after the next outage.
+    fn acknowledge_down_log(&self, shop_id: u32, reconciled: usize) {
+        let since = match self.down_acks.lock() {
+            Ok(mut acks) => acks.acknowledge(shop_id, reconciled),
+            Err(_) => return,
+        };
+        if let Some(since) = since {
+            self.truncate_down_log(since);
+        }
+    }
+
+    /// Reconciles an operation that "shop_id" accepted while it was down.
+    /// Merged operations are logged and forwarded to the others servers with their id,
+    /// so every server rejects them if they are sent again.
+    fn reconcile_operation(&mut self, shop_id: u32, since: u64, operation: OfflineOperation) {
+        let message = metadata::with(&operation.replicated_message(), OFFLINE, &operation.id);
+        let servers = self.replication_targets(&message);
+        let outcome = match (
+            self.reconciler.lock(),
+            self.points_handler.lock_for(&operation.message),
+        ) {
+            (Ok(mut reconciler), Ok(mut points)) => {
+                reconciler.reconcile(shop_id, since, operation, &mut points)
+            }
+            _ => return,
+        };
+        if outcome.is_merged() {
+            self.write_log_replicated(message, "ACK", servers);
+        }
+    }
+
+    /// Ends the reconciliation of "shop_id" and acknowledges the operations reconciled to it.
+    fn end_reconciliation(&mut self, shop_id: u32, from: SocketAddr) {
+        let reconciled = self.finish_reconciliation(shop_id);
+        let msg = format!("offlineAck {} {}", self.shop_id, reconciled);
+        self.send_to_server(&msg, from);
+    }
+
+    /// Writes the reconciliation report of "shop_id" in the reconciliation file.
+    /// Returns the amount of operations reconciled.
+    fn finish_reconciliation(&mut self, shop_id: u32) -> usize {
+        let report = match self.reconciler.lock() {
+            Ok(mut reconciler) => match reconciler.finish(shop_id) {
+                Some(report) => report,
+                None => return 0,
+            },
+            Err(_) => return 0,
+        };
+        info!(
+            target: SYNC,
+            shop = self.shop_id,
+            "reconciled shop {}: {} merged, {} not merged",
+            shop_id,
+            report.count(true),
+            report.count(false)
+        );
+        let file_name = format!("reconciliation_{}.txt", shop_id);
+        match OpenOptions::new().create(true).append(true).open(file_name) {
+            Ok(mut file) => {
+                if let Err(err) = file.write_all(report.to_string().as_bytes()) {
+                    error!(target: SYNC, shop = self.shop_id, "error writing reconciliation report: {}", err);
+                }
+            }
+            Err(err) => {
+                error!(target: SYNC, shop = self.shop_id, "error opening reconciliation report: {}", err)
+            }
+        }
+        report.entries.len()
+    }
+
+    /// Send TRY message to all servers.
+    fn broadcast(&mut self) -> Result<SocketAddr, Error> {
+        for i in 0..self.shops_amount {
+            let addr = id_to_dataaddr(i as usize);
+            if i != self.shop_id {
+                self.send_to_server("TRY", addr);
             }
         }
+        let mut buf = [0u8; MESSAGE_BYTES];
+        if let Err(err) = self.socket.set_read_timeout(Some(Duration::from_secs(3))) {
+            return Err(TransportError::CantSetReadTimeout(err.into()).into());
+        }
+        if let Ok((size, from)) = self.socket.recv_from(&mut buf) {
+            let message = String::from_utf8_lossy(&buf[..size]).into_owned();
+            let message = match self.authenticate(message, from, Role::PeerServer) {
+                Some((_, message)) => message,
+                None => return Err(TransportError::Unauthenticated.into()),
+            };
+            debug!(
+                target: SERVER,
+                shop = self.shop_id,
+                %from,
+                "get {}",
+                message
+            );
+            return Ok(from);
+        }
+        Err(TransportError::Timeout.into())
+    }
+
+    /// Send the operations accumulated while it was down to the server "shop_id" at the specified
+    /// address so it can reconcile them.
+    fn send_down_log(&mut self, shop_id: u32, addr: SocketAddr) {
+        let since = self.down_since.load(Ordering::SeqCst);
+        let operations = self.read_down_log();
+        if let Ok(mut acks) = self.down_acks.lock() {
+            *acks = DownLogAcks::new(since, HashMap::from([(shop_id, operations.len())]));
+        }
+        for operation in operations {
+            let msg = format!("offline {} {} {}", self.shop_id, since, operation.to_line());
+            self.send_to_server(&msg, addr);
+        }
+        let msg = format!("offlineEnd {}", self.shop_id);
+        self.send_to_server(&msg, addr);
     }
 
     /// Forward the "message" to "from".
     fn resend_message(&self, message: String, from: SocketAddr) {
-        println!(
-            "[SERVER FROM SHOP {}] resend {} to {}",
-            self.shop_id, message, from
+        debug!(
+            target: SERVER,
+            shop = self.shop_id,
+            to = %from,
+            "resend {}",
+            message
+        );
+        self.send_to_server(&message, from);
+    }
+
+    /// Signs the message with the key of the shop and sends it to the server at "to".
+    fn send_to_server(&self, message: &str, to: SocketAddr) {
+        let labels = [
+            ("socket", Role::PeerServer.label()),
+            ("type", message_type(message)),
+        ];
+        registry().inc(&MESSAGES_SENT, &labels);
+        // Lost messages are recovered by the resends and the synchronization of the servers
+        if let Err(err) = self
+            .socket
+            .send_to(self.signer.sign(message).as_bytes(), to)
+        {
+            warn!(
+                target: SERVER,
+                shop = self.shop_id,
+                to = %to,
+                "error sending {} to server: {}",
+                message,
+                err
+            );
+        }
+    }
+
+    /// Returns the key that signed the message and the message without its signature,
+    /// or None if it is rejected. The socket that received the message only accepts
+    /// the keys and the messages of its role: the coffee machines of the shop,
+    /// the servers of the other shops or the operators.
+    fn authenticate(
+        &self,
+        message: String,
+        from: SocketAddr,
+        role: Role,
+    ) -> Option<(KeyId, String)> {
+        let (shop_id, shops_amount) = (self.shop_id, self.shops_amount);
+        let allowed = |id: KeyId| {
+            Role::of(id) == role
+                && match id {
+                    KeyId::Machine(shop, _) => shop == shop_id,
+                    KeyId::Shop(shop) => shop != shop_id && shop < shops_amount,
+                    KeyId::Operator(_) => true,
+                }
+        };
+        match self.auth.verify(&message, allowed) {
+            Ok((id, message)) => match MessageParser::parse(message.clone()) {
+                Ok(act) if !role.allows(&act) => {
+                    let reason = format!("not allowed for {:?} {}", role, id);
+                    self.reject(&message, from, role, "not_allowed", &reason);
+                    None
+                }
+                parsed => {
+                    let labels = [("socket", role.label()), ("type", message_type(&message))];
+                    registry().inc(&MESSAGES_RECEIVED, &labels);
+                    if parsed.is_err() {
+                        registry().inc(&PARSE_FAILURES, &[("socket", role.label())]);
+                    }
+                    Some((id, message))
+                }
+            },
+            Err(err) => {
+                let kind = match err {
+                    Error::Transport(TransportError::ReplayedMessage) => "replayed",
+                    _ => "unauthenticated",
+                };
+                let reason = format!("{}, {} rejected", err, self.auth.rejected());
+                self.reject(&message, from, role, kind, &reason);
+                None
+            }
+        }
+    }
+
+    /// Logs a rejected message with its source address and the reason, and counts it by kind.
+    fn reject(&self, message: &str, from: SocketAddr, role: Role, kind: &str, reason: &str) {
+        registry().inc(
+            &REJECTED_MESSAGES,
+            &[("socket", role.label()), ("reason", kind)],
+        );
+        warn!(
+            target: SERVER,
+            shop = self.shop_id,
+            %from,
+            reason,
+            "rejected message: {}",
+            message
         );
-        self.socket
-            .send_to(message.as_bytes(), from)
-            .expect("Error sending message");
     }
 
     /// Processes the message received by the leader and returns the message to be sent.
@@ -386,26 +1774,31 @@ impl Server {
         match act {
             Action::Block(client_id, _) => {
                 if !self.down.load(Ordering::SeqCst) {
-                    self.write_log(message);
+                    if let Ok(mut reconciler) = self.reconciler.lock() {
+                        reconciler.record_block(client_id, now_millis());
+                    }
 
                     let msg = self.block_client(client_id);
+                    self.write_log(message, &msg);
                     return Some(msg);
                 } else {
-                    self.write_down_log(message);
                     let msg = self.block_client(client_id);
+                    self.write_down_log(message, &msg);
                     return Some(msg);
                 }
             }
             Action::CompleteOrder(client_id, price, method, _) => {
                 if !self.down.load(Ordering::SeqCst) {
-                    self.write_log(message);
-
+                    if method == Method::Points {
+                        self.settle_accruals(client_id);
+                    }
                     let msg = self.complete_order(client_id, price, method);
+                    self.write_log(message, &msg);
                     return Some(msg);
                 } else {
-                    let msg = self.accumulate_points(client_id, method);
-                    if msg.is_some() {
-                        self.write_down_log(message);
+                    let msg = self.accumulate_points(client_id, price, method);
+                    if let Some(result) = &msg {
+                        self.write_down_log(message, result);
                         return msg;
                     } else {
                         return Some(format!("notEnough {}", client_id));
@@ -414,15 +1807,15 @@ impl Server {
             }
             Action::FailOrder(client_id, _) => {
                 if !self.down.load(Ordering::SeqCst) {
-                    self.write_log(message);
-                    if let Ok(mut lock) = self.points_handler.lock() {
+                    self.write_log(message, "ACK");
+                    if let Ok(mut lock) = self.points_handler.lock(client_id) {
                         lock.unblock(client_id);
                     }
                     return Some("ACK".to_string());
                 } else {
-                    self.write_down_log(message);
+                    self.write_down_log(message, "ACK");
 
-                    if let Ok(mut lock) = self.points_handler.lock() {
+                    if let Ok(mut lock) = self.points_handler.lock(client_id) {
                         lock.unblock(client_id);
                     }
                     return Some("ACK".to_string());
@@ -431,14 +1824,140 @@ impl Server {
             Action::Try => {
                 return Some("ACK".to_string());
             }
-            Action::Sync(lines) => {
-                self.send_sync(lines, from);
+            Action::Sync(index) => {
+                self.send_sync(index, from);
+            }
+            Action::SnapshotRequest(index, part) => {
+                self.send_snapshot_part(index, part, from);
+            }
+            Action::Offline(shop_id, since, operation) => {
+                self.reconcile_operation(shop_id, since, operation);
+            }
+            Action::OfflineEnd(shop_id) => {
+                self.end_reconciliation(shop_id, from);
+            }
+            Action::OfflineAck(shop_id, reconciled) => {
+                self.acknowledge_down_log(shop_id, reconciled);
+            }
+            Action::Grant(..) | Action::Deduct(..) | Action::Refund(..) => {
+                self.process_admin_operation(message, act, from);
+            }
+            Action::Transfer(target) => {
+                self.start_transfer(&message, target, from);
+            }
+            Action::Balance(client_id, max_lag) => {
+                self.read_balance(message, client_id, max_lag, from);
+            }
+            Action::Repair(ranges) => {
+                self.send_repair(ranges, from);
+            }
+            Action::Digest(..) => {
+                // Only the leader sends digests, another server also believes it leads
+                warn!(target: ELECTION, shop = self.shop_id, %from, "got the digests of another leader");
+                self.shop_leader.find_new();
             }
             _ => (),
         }
         None
     }
 
+    /// Applies an admin operation as the leader, replicates its entry and answers it
+    /// to the address of its reply metadata, or to "from" if it has none.
+    fn process_admin_operation(&mut self, message: String, act: Action, from: SocketAddr) {
+        let answer = if self.down.load(Ordering::SeqCst) {
+            "unavailable".to_string()
+        } else {
+            if let Action::Deduct(client_id, ..) = act {
+                self.settle_accruals(client_id);
+            }
+            // The accounts stay locked until the entry is logged, so a refund is resolved once.
+            // The partitions are not locked while the accounts are, so the servers are found first
+            let servers = self.replication_targets(&message);
+            let points_handler = self.points_handler.clone();
+            let lock = points_handler.lock_for(&message);
+            match lock {
+                Ok(mut points) => match self.admin_entry(&message, act) {
+                    Ok(entry) => match apply_entry(&mut points, &entry) {
+                        Ok(result) => {
+                            self.write_log_replicated(entry, &result, servers);
+                            result
+                        }
+                        Err(_) => "Error".to_string(),
+                    },
+                    Err(answer) => answer,
+                },
+                Err(_) => "Error".to_string(),
+            }
+        };
+        let reply = metadata::get(&message, REPLY)
+            .and_then(|addr| addr.parse::<SocketAddr>().ok())
+            .unwrap_or(from);
+        if reply != from && self.partitioning.is_none() {
+            // The server that forwarded the operation waits for its index to answer reads
+            self.send_to_server(&self.with_leader_index(&answer), from);
+        }
+        self.answer_operator(&answer, reply);
+    }
+
+    /// Returns the entry of the log of an admin operation.
+    /// A refund becomes the reverse of the movements of its order.
+    /// Returns the answer to the operation as error if it can not be applied.
+    fn admin_entry(&self, message: &str, act: Action) -> Result<String, String> {
+        match act {
+            Action::Refund(client_id, order_id, shop_id, operator, reason) => {
+                let refunded = match self.history.lock() {
+                    Ok(history) => history.refund_points(client_id, shop_id, order_id),
+                    Err(_) => Err(Error::Lock),
+                };
+                match refunded {
+                    Ok(points) => Ok(format!(
+                        "reverse {} {} {} {} {} {}",
+                        client_id, order_id, shop_id, operator, points, reason
+                    )),
+                    Err(Error::Order(OrderError::AlreadyRefunded(_))) => {
+                        Err(format!("alreadyRefunded {}", order_id))
+                    }
+                    Err(Error::Order(OrderError::UnknownOrder(_))) => {
+                        Err(format!("unknownOrder {}", order_id))
+                    }
+                    Err(_) => Err("Error".to_string()),
+                }
+            }
+            _ => {
+                let words: Vec<&str> = message.split(' ').collect();
+                Ok(metadata::strip(&words).join(" "))
+            }
+        }
+    }
+
+    /// Answers the message as the leader within a span of its trace.
+    /// Returns the answer and the message to replicate, which carries the context of the span,
+    /// unless it is a write that was already replicated when it was logged.
+    fn process_traced(
+        &mut self,
+        message: String,
+        from: SocketAddr,
+    ) -> Option<(String, Option<String>)> {
+        let mut span = Span::from_message("process_action", Kind::Server, &message);
+        span.attribute("shop.id", &self.shop_id.to_string());
+        span.attribute("message.type", message_type(&message));
+        let traced = span.inject(&message);
+        let write = MessageParser::parse(message.clone()).is_ok_and(|act| is_replicated(&act));
+        if write {
+            self.fan_out = Some((traced.clone(), self.replication_targets(&traced)));
+        }
+        let answer = self.answer_leader(message, from);
+        let unsent = match write {
+            true => self.fan_out.take().map(|(message, _)| message),
+            false => Some(traced),
+        };
+        if let Some(answer) = &answer {
+            span.attribute("answer", answer);
+        }
+        span.end();
+        answer.map(|answer| (answer, unsent))
+    }
+
     /// Parse the message received by the leader and decide what to do.
     pub fn answer_leader(&mut self, message: String, from: SocketAddr) -> Option<String> {
         let act = match MessageParser::parse(message.clone()) {
@@ -447,18 +1966,24 @@ impl Server {
         };
         if self.sync.load(Ordering::SeqCst) {
             match act {
-                Action::SyncStart => None,
-                Action::SyncEnd => {
+                Action::SyncChunk(start, entries) => {
+                    self.apply_chunk(start, entries, from);
+                    None
+                }
+                Action::Snapshot(index, part, parts, accounts) => {
+                    self.apply_snapshot_part(index, part, parts, accounts, from);
+                    None
+                }
+                Action::SyncEnd(index) => {
+                    self.finish_sync(index);
                     while !self.msg_queue.is_empty() {
                         let (message, action) = self.msg_queue.pop_front()?;
                         self.process_action(message, action, from);
                     }
-                    self.sync.store(false, Ordering::SeqCst);
                     None
                 }
-                _ => {
-                    self.msg_queue
-                        .push_back((message.clone(), MessageParser::parse(message).unwrap()));
+                act => {
+                    self.msg_queue.push_back((message, act));
                     Some("ACK".to_string())
                 }
             }
@@ -467,107 +1992,249 @@ impl Server {
         }
     }
 
-    /// Send the synchronization to the server "from".
-    fn send_sync(&mut self, lines: u32, from: SocketAddr) {
-        let msg_start: &str = "SYNCSTART";
-        self.socket
-            .send_to(msg_start.to_string().as_bytes(), from)
-            .expect("Error sending message");
-
-        let log_name = format!("log_{}.txt", self.shop_id);
-        let reader = BufReader::new(File::open(log_name).expect("Error opening the log file"));
-        let lines_to_skip = if lines > 0 { lines } else { 0 };
-        for (_, line) in reader.lines().skip(lines_to_skip as usize).enumerate() {
-            match line {
-                Ok(line) => {
-                    println!(
-                        "[SERVER FROM SHOP {}]: send {} to {}",
-                        self.shop_id, line, from
-                    );
-                    self.socket
-                        .send_to(line.as_bytes(), from)
-                        .expect("Error sendind message");
-                }
-                Err(err) => {
-                    println!("Error reading line: {}", err);
-                    return;
+    /// Answers a synchronization request of the server "from" that has the entries before "index".
+    /// Sends the next chunk of entries, the end of the synchronization if it is up to date,
+    /// or a snapshot if it is too far behind.
+    fn send_sync(&mut self, index: u64, from: SocketAddr) {
+        let (next_index, entries) = match self.log.lock() {
+            Ok(log) => {
+                let next_index = log.next_index();
+                let behind = next_index.saturating_sub(index);
+                if index > next_index || behind > SYNC_SNAPSHOT_THRESHOLD {
+                    (next_index, None)
+                } else {
+                    (next_index, log.read_from(index, SYNC_CHUNK_ENTRIES).ok())
                 }
             }
+            Err(_) => return,
+        };
+        let msg = match entries {
+            Some(_) if index == next_index => format!("SYNCEND {}", next_index),
+            Some(entries) if !entries.is_empty() => {
+                let entries: Vec<String> = entries.iter().map(|record| record.message()).collect();
+                encode_chunk(index, &entries).0
+            }
+            _ => {
+                self.send_snapshot_part(next_index, 0, from);
+                return;
+            }
+        };
+        self.resend_message(msg, from);
+    }
+
+    /// Sends a part of the snapshot taken at "index" to the server "from".
+    /// If that snapshot is no longer available, a new one is taken and its first part is sent.
+    fn send_snapshot_part(&mut self, index: u64, part: u32, from: SocketAddr) {
+        let mut state = match self.sync_state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        let available = match &state.served_snapshot {
+            Some((snapshot_index, parts)) => {
+                *snapshot_index == index && (part as usize) < parts.len()
+            }
+            None => false,
+        };
+        let part = if available {
+            part
+        } else {
+            let snapshot = match self.log.lock().map(|log| log.state()) {
+                Ok(Ok(snapshot)) => snapshot,
+                _ => return,
+            };
+            state.served_snapshot = Some((snapshot.index, snapshot_parts(&snapshot.accounts)));
+            0
+        };
+        let msg = match &state.served_snapshot {
+            Some((index, parts)) => format!(
+                "SNAPSHOT {} {} {} {}",
+                index,
+                part,
+                parts.len(),
+                parts[part as usize]
+            ),
+            None => return,
+        };
+        drop(state);
+        self.resend_message(msg.trim_end().to_string(), from);
+    }
+
+    /// Sends the movements of points of the client between the times "since" and "until"
+    /// to "from", split in as many messages as needed.
+    fn send_history(
+        &mut self,
+        client_id: u32,
+        since: Option<u64>,
+        until: Option<u64>,
+        from: SocketAddr,
+    ) {
+        let entries = match self.history.lock() {
+            Ok(history) => history.query(client_id, since, until),
+            Err(_) => return,
+        };
+        for part in history_parts(client_id, &entries) {
+            self.answer_operator(&part, from);
         }
+    }
 
-        let msg_end: &str = "SYNCEND";
-        self.socket
-            .send_to(msg_end.to_string().as_bytes(), from)
-            .expect("Error sending message");
+    /// Sends the accounts of the server, the index of its log and its status to the operator
+    /// at "from", split in as many messages as needed, so the servers can be compared.
+    fn send_state(&self, from: SocketAddr) {
+        let status = if self.down.load(Ordering::SeqCst) {
+            ServerStatus::Down
+        } else if self.sync.load(Ordering::SeqCst) {
+            ServerStatus::Syncing
+        } else if self.partitioning.is_some() {
+            ServerStatus::Partitioned
+        } else if let Ok(true) = self.shop_leader.am_i_leader() {
+            ServerStatus::Leader
+        } else {
+            ServerStatus::Follower
+        };
+        let accounts = match self.points_handler.accounts(|_| true) {
+            Ok(accounts) => accounts,
+            Err(_) => {
+                self.answer_operator("Error", from);
+                return;
+            }
+        };
+        let index = self.log_index();
+        let parts = snapshot_parts(&accounts);
+        for (part, accounts) in parts.iter().enumerate() {
+            let msg = format!(
+                "STATE {} {} {} {} {} {}",
+                self.shop_id,
+                index,
+                status,
+                part,
+                parts.len(),
+                accounts
+            );
+            self.answer_operator(msg.trim_end(), from);
+        }
+    }
+
+    /// Processes the message received by the server within a span of its trace.
+    fn answer_local_server_traced(&mut self, message: String, from: SocketAddr) -> Option<String> {
+        let mut span = Span::from_message("answer_local_server", Kind::Server, &message);
+        span.attribute("shop.id", &self.shop_id.to_string());
+        span.attribute("message.type", message_type(&message));
+        let answer = self.answer_local_server(message, from);
+        if let Some(answer) = &answer {
+            span.attribute("answer", answer);
+        }
+        span.end();
+        answer
     }
 
     /// Processes the message received by the server and returns the message to be sent.
     pub fn answer_local_server(&mut self, message: String, from: SocketAddr) -> Option<String> {
         if let Ok(msg) = MessageParser::parse(message.clone()) {
+            if self.sync.load(Ordering::SeqCst) && is_replicated(&msg) {
+                // The entry is in the leader's log, it is applied when the synchronization reaches it
+                return None;
+            }
             match msg {
                 Action::Block(client_id, shop_id) => {
+                    let msg = self.block_client(client_id);
                     if !self.down.load(Ordering::SeqCst) {
-                        self.write_log(message);
+                        self.write_log(message, &msg);
                     } else {
-                        self.write_down_log(message);
+                        self.write_down_log(message, &msg);
                     }
-                    let msg = self.block_client(client_id);
 
-                    if shop_id == self.shop_id {
-                        self.coffee_machine_socket
-                            .send_to(msg.as_bytes(), coffee_machine_addr(self.shop_id))
-                            .expect("Error sending message to coffee machine");
+                    if self.forwarded_by_me(shop_id) {
+                        self.answer_forwarded(&msg);
                     }
                     return Some(msg);
                 }
                 Action::CompleteOrder(client_id, price, method, shop_id) => {
                     if !self.down.load(Ordering::SeqCst) {
-                        self.write_log(message);
                         let msg = self.complete_order(client_id, price, method);
-                        if shop_id == self.shop_id {
-                            self.coffee_machine_socket
-                                .send_to(msg.as_bytes(), coffee_machine_addr(self.shop_id))
-                                .expect("Error sending message to coffee machine");
+                        self.write_log(message, &msg);
+                        if self.forwarded_by_me(shop_id) {
+                            self.answer_forwarded(&msg);
                         }
                         return Some(msg);
                     } else {
-                        let msg = match self.accumulate_points(client_id, method) {
+                        let msg = match self.accumulate_points(client_id, price, method) {
                             Some(msg) => {
-                                self.write_down_log(message);
+                                self.write_down_log(message, &msg);
                                 msg
                             }
                             None => format!("notEnough {}", client_id),
                         };
 
-                        if shop_id == self.shop_id {
-                            self.coffee_machine_socket
-                                .send_to(msg.as_bytes(), coffee_machine_addr(self.shop_id))
-                                .expect("Error sending message to coffee machine");
+                        if self.forwarded_by_me(shop_id) {
+                            self.answer_forwarded(&msg);
                         }
                         return Some(msg);
                     }
                 }
                 Action::FailOrder(client_id, shop_id) => {
                     if !self.down.load(Ordering::SeqCst) {
-                        self.write_log(message);
+                        self.write_log(message, "ACK");
                     } else {
-                        self.write_down_log(message)
+                        self.write_down_log(message, "ACK")
                     }
-                    if let Ok(mut lock) = self.points_handler.lock() {
+                    if let Ok(mut lock) = self.points_handler.lock(client_id) {
                         lock.unblock(client_id);
                     }
-                    if shop_id == self.shop_id {
-                        self.coffee_machine_socket
-                            .send_to("ACK".as_bytes(), coffee_machine_addr(self.shop_id))
-                            .expect("Error sending message to coffee machine");
+                    if self.forwarded_by_me(shop_id) {
+                        self.answer_forwarded("ACK");
                     }
                     return Some("ACK".to_string());
                 }
                 Action::Try => {
                     return Some("ACK".to_string());
                 }
-                Action::Sync(lines) => {
-                    self.send_sync(lines, from);
+                Action::Sync(index) => {
+                    self.send_sync(index, from);
+                }
+                Action::SnapshotRequest(index, part) => {
+                    self.send_snapshot_part(index, part, from);
+                }
+                Action::SyncChunk(start, entries) => {
+                    self.apply_chunk(start, entries, from);
+                }
+                Action::Snapshot(index, part, parts, accounts) => {
+                    self.apply_snapshot_part(index, part, parts, accounts, from);
+                }
+                Action::SyncEnd(index) => {
+                    self.finish_sync(index);
+                }
+                Action::OfflineAck(shop_id, reconciled) => {
+                    self.acknowledge_down_log(shop_id, reconciled);
+                }
+                Action::OfflineRedeem(client_id, points, _) => match i32::try_from(points) {
+                    Ok(points) => {
+                        let result = match self.points_handler.lock(client_id) {
+                            Ok(mut lock) => points_answer(
+                                lock.force_update_points(client_id, -points),
+                                client_id,
+                            ),
+                            Err(_) => "Error".to_string(),
+                        };
+                        self.write_log(message, &result);
+                    }
+                    Err(_) => {
+                        warn!(target: SERVER, shop = self.shop_id, "invalid redemption: {}", message)
+                    }
+                },
+                Action::Grant(..)
+                | Action::Deduct(..)
+                | Action::Reverse(..)
+                | Action::Settle(..) => {
+                    self.apply_entry(message);
+                }
+                Action::TakeOver(index) => {
+                    self.take_over(index, from);
+                }
+                Action::Digest(index, digests) => {
+                    self.check_digests(index, digests, from);
+                }
+                Action::RepairState(index, part, parts, accounts) => {
+                    self.apply_repair_part(index, part, parts, accounts);
                 }
                 _ => (),
             }
@@ -575,16 +2242,197 @@ impl Server {
         None
     }
 
-    /// Accumulate the points of the client_id
-    fn accumulate_points(&mut self, client_id: u32, method: Method) -> Option<String> {
+    /// Accrues the points of a cash payment of a coffee machine of the shop in the counters
+    /// of the accruals, without forwarding it to the leader. Works while the server is down.
+    /// Returns None if the accruals go through the leader or the message is not a cash payment.
+    fn accrue_locally(&mut self, message: &str) -> Option<String> {
+        let accruals = self.accruals.clone()?;
+        let (client_id, price) = match MessageParser::parse(message.to_string()) {
+            Ok(Action::CompleteOrder(client_id, price, Method::Cash, shop_id))
+                if shop_id == self.shop_id =>
+            {
+                (client_id, price)
+            }
+            _ => return None,
+        };
+        let accrued = match accruals.lock() {
+            Ok(mut accruals) => accruals.accrue(client_id, self.shop_id, price),
+            Err(_) => Err(Error::Lock),
+        };
+        if let Err(err) = accrued {
+            error!(target: SERVER, shop = self.shop_id, "error accruing points: {}", err);
+            return Some("Error".to_string());
+        }
+        if let Ok(mut points) = self.points_handler.lock(client_id) {
+            points.unblock(client_id);
+        }
+        // The log only has the settlements, the history keeps the order of the accrual
+        let record = LogRecord::new(message, "ACK", self.shop_leader.term()).ok();
+        if let Some(entry) = record.as_ref().and_then(HistoryEntry::from_record) {
+            if let Ok(mut history) = self.history.lock() {
+                if let Err(err) = history.record(entry) {
+                    error!(target: SERVER, shop = self.shop_id, "error writing the history file: {}", err);
+                }
+            }
+        }
+        registry().inc(&LOCAL_ACCRUALS, &[]);
+        Some("ACK".to_string())
+    }
+
+    /// Moves the points accrued by the client into its account before they are spent,
+    /// logging the settlement and sending it to the other servers. Only the leader settles.
+    fn settle_accruals(&mut self, client_id: u32) {
+        let settled = match &self.accruals {
+            Some(accruals) => match accruals.lock() {
+                Ok(mut accruals) => accruals.settle(client_id, self.shop_id),
+                Err(_) => Err(Error::Lock),
+            },
+            None => return,
+        };
+        let (points, total) = match settled {
+            Ok(Some(settled)) => settled,
+            Ok(None) => return,
+            Err(err) => {
+                error!(target: SERVER, shop = self.shop_id, "error settling points: {}", err);
+                return;
+            }
+        };
+        let result = match self.points_handler.lock(client_id) {
+            Ok(mut lock) => points_answer(
+                checked_points(client_id, points)
+                    .and_then(|points| lock.force_update_points(client_id, points)),
+                client_id,
+            ),
+            Err(_) => "Error".to_string(),
+        };
+        if result != "ACK" {
+            error!(target: SERVER, shop = self.shop_id, "error settling the points of client {}", client_id);
+        }
+        let entry = format!("settle {} {} {} {}", client_id, points, self.shop_id, total);
+        let servers = self.replication_targets(&entry);
+        self.write_log_replicated(entry, &result, servers);
+    }
+
+    /// Applies to the counters of the accruals a settlement made by the leader at shop_id.
+    fn apply_settlement(&self, client_id: u32, shop_id: u32, total: u64) {
+        if let Some(accruals) = &self.accruals {
+            let applied = match accruals.lock() {
+                Ok(mut accruals) => accruals.apply_settlement(client_id, shop_id, total),
+                Err(_) => Err(Error::Lock),
+            };
+            if let Err(err) = applied {
+                error!(target: SERVER, shop = self.shop_id, "error applying a settlement: {}", err);
+            }
+        }
+    }
+
+    /// Returns the points accrued by the client and not settled yet,
+    /// or None if they do not fit in an account.
+    fn accrued(&self, client_id: u32) -> Option<i32> {
+        match &self.accruals {
+            Some(accruals) => accruals
+                .lock()
+                .map_or(Some(0), |a| i32::try_from(a.points(client_id)).ok()),
+            None => Some(0),
+        }
+    }
+
+    /// Sends the counters of the accruals to a random server, so the accruals of the shops
+    /// converge even if a shop misses a settlement or is down for a while.
+    fn send_gossip(&self) {
+        if self.down.load(Ordering::SeqCst) || self.shops_amount < 2 {
+            return;
+        }
+        let parts = match &self.accruals {
+            Some(accruals) => match accruals.lock() {
+                Ok(accruals) => accruals.parts(),
+                Err(_) => return,
+            },
+            None => return,
+        };
+        let mut peer = rand::thread_rng().gen_range(0..self.shops_amount - 1);
+        if peer >= self.shop_id {
+            peer += 1;
+        }
+        for part in parts {
+            self.send_gossip_message(&format!("ACCRUALS {}", part), gossip_addr(peer));
+        }
+    }
+
+    /// Signs and sends a message through the gossip socket.
+    fn send_gossip_message(&self, message: &str, to: SocketAddr) {
+        if let Some(socket) = &self.gossip_socket {
+            let labels = [("socket", "gossip"), ("type", message_type(message))];
+            registry().inc(&MESSAGES_SENT, &labels);
+            let _ = socket.send_to(self.signer.sign(message).as_bytes(), to);
+        }
+    }
+
+    /// Receives the counters of the accruals of another server and merges them.
+    fn receive_gossip(&self) {
+        let socket = match &self.gossip_socket {
+            Some(socket) => socket,
+            None => return,
+        };
+        let mut buf = [0u8; 2048];
+        let _ = socket.set_read_timeout(Some(TIMEOUT));
+        let (size, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(_) => return,
+        };
+        if self.down.load(Ordering::SeqCst) {
+            return;
+        }
+        let message = String::from_utf8_lossy(&buf[..size]).into_owned();
+        let counters = match self.authenticate(message, from, Role::PeerServer) {
+            Some((_, message)) => match MessageParser::parse(message) {
+                Ok(Action::Accruals(counters)) => counters,
+                _ => return,
+            },
+            None => return,
+        };
+        let merged = match &self.accruals {
+            Some(accruals) => match accruals.lock() {
+                Ok(mut accruals) => accruals.merge(counters),
+                Err(_) => Err(Error::Lock),
+            },
+            None => return,
+        };
+        match merged {
+            Ok(changed) => {
+                let changed = if changed { "changed" } else { "unchanged" };
+                registry().inc(&GOSSIP_MERGES, &[("result", changed)]);
+            }
+            Err(err) => {
+                error!(target: SERVER, shop = self.shop_id, "error merging accruals: {}", err)
+            }
+        }
+    }
+
+    /// Accumulate the points of the client_id while the server is down.
+    /// Points payments are accepted up to the offline allowance of the client.
+    fn accumulate_points(&mut self, client_id: u32, price: u32, method: Method) -> Option<String> {
         match method {
             Method::Cash => {
-                if let Ok(mut lock) = self.points_handler.lock() {
+                if let Ok(mut lock) = self.points_handler.lock(client_id) {
                     lock.unblock(client_id);
                 }
                 Some("ACK".to_string())
             }
-            Method::Points => None,
+            Method::Points => {
+                let accrued = self.accrued(client_id);
+                let mut points = self.points_handler.lock(client_id).ok()?;
+                let mut credit = self.offline_credit.lock().ok()?;
+                // Points beyond the range of an account can pay any order
+                let available =
+                    accrued.map_or(i32::MAX, |a| points.balance(client_id).saturating_add(a));
+                if credit.try_redeem(client_id, price, available) {
+                    points.unblock(client_id);
+                    Some("ACK".to_string())
+                } else {
+                    None
+                }
+            }
         }
     }
 
@@ -592,13 +2440,10 @@ impl Server {
     /// Returns an ACK if the client account was successfully updated.
     /// Returns notEnough when the client does not has enough points to pay the order.
     fn complete_order(&mut self, client_id: u32, price: u32, method: Method) -> String {
-        let message = match self.update_points(client_id, price as i32, method) {
-            Ok(_) => "ACK".to_string(),
-            Err(_) => {
-                format!("notEnough {}", client_id)
-            }
-        };
-        if let Ok(mut lock) = self.points_handler.lock() {
+        let updated = checked_points(client_id, price)
+            .and_then(|price| self.update_points(client_id, price, method));
+        let message = points_answer(updated, client_id);
+        if let Ok(mut lock) = self.points_handler.lock(client_id) {
             lock.unblock(client_id);
         }
         message
@@ -608,14 +2453,14 @@ impl Server {
     fn update_points(&mut self, client_id: u32, points: i32, method: Method) -> Result<(), Error> {
         match method {
             Method::Cash => {
-                if let Ok(mut lock) = self.points_handler.lock() {
+                if let Ok(mut lock) = self.points_handler.lock(client_id) {
                     lock.update_points(client_id, points)
                 } else {
                     Err(Error::Lock)
                 }
             }
             Method::Points => {
-                if let Ok(mut lock) = self.points_handler.lock() {
+                if let Ok(mut lock) = self.points_handler.lock(client_id) {
                     lock.update_points(client_id, -points)
                 } else {
                     Err(Error::Lock)
@@ -624,29 +2469,114 @@ impl Server {
         }
     }
 
-    /// Writes the message in server's log file.
-    fn write_log(&mut self, message: String) {
-        let mut log_msg = message;
-        log_msg.push('\n');
-        self.log
-            .write_all(log_msg.as_bytes())
-            .expect("Error writing log file");
+    /// Writes the record of the message and its result in server's log file.
+    /// If it is the write the leader is processing, it is sent to the other servers
+    /// while the log is held, so they receive the writes in the order of the log.
+    fn write_log(&mut self, message: String, result: &str) {
+        let replicated = match self.down.load(Ordering::SeqCst) || self.sync.load(Ordering::SeqCst)
+        {
+            true => None,
+            false => self.fan_out.take(),
+        };
+        self.append_log(message, result, replicated);
     }
 
-    /// Writes the message in server's log_down file.
-    fn write_down_log(&mut self, message: String) {
-        let mut log_msg = message;
+    /// Writes the record of the message and its result in server's log file
+    /// and sends the message to "servers" while the log is held.
+    fn write_log_replicated(&mut self, message: String, result: &str, servers: Vec<u32>) {
+        self.append_log(message.clone(), result, Some((message, servers)));
+    }
+
+    /// Writes the record of the message and its result in server's log file,
+    /// sending the replicated message to its servers before releasing the log.
+    /// Compacts the log once it has `compaction_interval` entries after the last snapshot.
+    fn append_log(
+        &mut self,
+        message: String,
+        result: &str,
+        replicated: Option<(String, Vec<u32>)>,
+    ) {
+        let record = match LogRecord::new(&message, result, self.shop_leader.term()) {
+            Ok(record) => record,
+            Err(_) => {
+                warn!(target: SERVER, shop = self.shop_id, "invalid log entry: {}", message);
+                return;
+            }
+        };
+        if let Some(id) = &record.offline {
+            // Only merged operations lock the reconciler, which is locked before the accounts
+            if let Ok(mut reconciler) = self.reconciler.lock() {
+                reconciler.remember(id);
+            }
+        }
+        if let Some(entry) = HistoryEntry::from_record(&record) {
+            if let Ok(mut history) = self.history.lock() {
+                if let Err(err) = history.record(entry) {
+                    error!(target: SERVER, shop = self.shop_id, "error writing the history file: {}", err);
+                }
+            }
+        }
+        if let Ok(mut log) = self.log.lock() {
+            if let Err(err) = log.append(record) {
+                error!(target: SERVER, shop = self.shop_id, "error writing the log file: {}", err);
+                return;
+            }
+            if let Some((replicated, servers)) = replicated {
+                self.send_replicated(replicated, servers, log.next_index());
+            }
+            let interval = self.config.compaction_interval;
+            if interval > 0 && log.next_index() - log.snapshot_index() >= interval {
+                match log.compact(self.config.retained_entries) {
+                    Ok(_) => info!(
+                        target: SERVER,
+                        shop = self.shop_id,
+                        "compacted log up to index {}",
+                        log.snapshot_index()
+                    ),
+                    Err(_) => {
+                        error!(target: SERVER, shop = self.shop_id, "error compacting the log file")
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the index of the next entry of the server's log.
+    fn log_index(&self) -> u64 {
+        match self.log.lock() {
+            Ok(log) => log.next_index(),
+            Err(_) => 0,
+        }
+    }
+
+    /// Writes the record of the message and its result in server's log_down file.
+    /// The sequence number of the record and when the server went down identify the operation.
+    fn write_down_log(&mut self, message: String, result: &str) {
+        let mut record = match LogRecord::new(&message, result, self.shop_leader.term()) {
+            Ok(record) => record,
+            Err(_) => {
+                warn!(target: SERVER, shop = self.shop_id, "invalid log entry: {}", message);
+                return;
+            }
+        };
+        record.set_seq(self.offline_seq.fetch_add(1, Ordering::SeqCst));
+        record.set_down_since(self.down_since.load(Ordering::SeqCst));
+        let mut log_msg = record.to_line();
         log_msg.push('\n');
-        self.log_down
-            .write_all(log_msg.as_bytes())
-            .expect("Error writing log file");
+        let written = match self.log_down.lock() {
+            Ok(mut log_down) => log_down.write_all(log_msg.as_bytes()).is_ok(),
+            Err(_) => false,
+        };
+        if !written {
+            error!(target: SERVER, shop = self.shop_id, "error writing the down log: {}", message);
+        }
     }
 
     /// Block a client.
     /// Returns an ACK if the client accounts can be successfully blocked.
     /// Returns alreadyBlocked when the client account it is been used.
     pub fn block_client(&mut self, client_id: u32) -> String {
-        if let Ok(mut lock) = self.points_handler.lock() {
+        if let Ok(mut lock) = self.points_handler.lock(client_id) {
             match lock.block(client_id) {
                 Ok(_) => "ACK".to_string(),
                 Err(_) => format!("alreadyBlocked {}", client_id),
@@ -657,34 +2587,430 @@ impl Server {
     }
 
     /// Forward the message received to the leader server.
-    fn resend_message_to_leader(&mut self, message: String) {
-        let leader_id = self.shop_leader.get_leader_id().unwrap();
+    /// Fails if the leader is unknown.
+    fn resend_message_to_leader(&mut self, message: String) -> Result<(), Error> {
+        let leader_id = self.shop_leader.get_leader_id()?;
         let leader_addr = id_to_dataaddr(leader_id);
-        println!(
-            "[SERVER FROM SHOP {}]: send {} to {}",
-            self.shop_id, message, leader_addr
+        let mut span = Span::from_message("resend_message_to_leader", Kind::Client, &message);
+        span.attribute("shop.id", &self.shop_id.to_string());
+        span.attribute("leader.id", &leader_id.to_string());
+        let message = span.inject(&message);
+        debug!(
+            target: SERVER,
+            shop = self.shop_id,
+            to = %leader_addr,
+            "send {}",
+            message
         );
 
-        self.socket
-            .send_to(message.as_bytes(), leader_addr)
-            .expect("Error sending message to leader");
+        self.send_to_server(&message, leader_addr);
+        span.end();
+        Ok(())
+    }
+
+    /// Forwards the message of a coffee machine of the shop to the leader and waits
+    /// for its answer. If the leader is unknown the coffee machine resends the order.
+    fn forward_coffee_machine_message(&mut self, message: String) {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        if let Err(err) = self.resend_message_to_leader(message) {
+            let _ = self
+                .in_flight
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+            warn!(
+                target: SERVER,
+                shop = self.shop_id,
+                "error forwarding coffee machine message: {}",
+                err
+            );
+        }
     }
 
     /// Forward the message received to others server.
+    /// If the accounts are partitioned, it is sent to the replicas of the partition of its client.
     fn resend_to_servers(&mut self, message: String) {
-        for i in 0..self.shops_amount {
+        let servers = self.replication_targets(&message);
+        let index = self.log_index();
+        self.send_replicated(message, servers, index);
+    }
+
+    /// Sends a write to "servers", with the index of the log of the leader after the write.
+    /// Doesn't lock the log nor the partitions, so it can be called while the log is held.
+    fn send_replicated(&self, message: String, servers: Vec<u32>, index: u64) {
+        let mut span = Span::from_message("resend_to_servers", Kind::Client, &message);
+        span.attribute("shop.id", &self.shop_id.to_string());
+        span.attribute("servers", &servers.len().to_string());
+        let mut message = span.inject(&message);
+        match self.partitioning {
+            Some(_) => message = metadata::with(&message, PRIMARY, &self.shop_id.to_string()),
+            None => message = metadata::with(&message, INDEX, &index.to_string()),
+        }
+        for i in servers {
             let addr = id_to_dataaddr(i as usize);
+            debug!(
+                target: SERVER,
+                shop = self.shop_id,
+                to = i,
+                "send {}",
+                message
+            );
+            self.send_to_server(&message, addr);
+        }
+        span.end();
+    }
 
-            if i != self.shop_id {
-                println!(
-                    "[SERVER FROM SHOP {}]: send {} to shop {}",
-                    self.shop_id, message, i
+    /// Returns the servers a write is replicated to: the replicas of the partition of its
+    /// client if the accounts are partitioned, or every other server.
+    /// Locks the partitions, which are locked before the accounts and the log.
+    fn replication_targets(&self, message: &str) -> Vec<u32> {
+        let others = (0..self.shops_amount)
+            .filter(|i| *i != self.shop_id)
+            .collect();
+        let partitioning = match &self.partitioning {
+            Some(partitioning) => partitioning,
+            None => return others,
+        };
+        let client_id = MessageParser::parse(message.to_string())
+            .ok()
+            .and_then(|act| act.client_id());
+        match (client_id, partitioning.lock()) {
+            (Some(client_id), Ok(partitioning)) => partitioning.replicas(client_id, self.shop_id),
+            _ => others,
+        }
+    }
+
+    /// Returns true if the server answers the coffee machine when it applies a write replicated
+    /// by the leader, which happens for the writes it forwarded. The primary of a partition
+    /// answers the writes forwarded to it directly instead.
+    fn forwarded_by_me(&self, shop_id: u32) -> bool {
+        shop_id == self.shop_id && self.partitioning.is_none()
+    }
+
+    /// Returns where the message has to be processed. Every message is processed here if the
+    /// accounts are not partitioned or the server is down.
+    fn route(&self, message: &str) -> Route {
+        let partitioning = match &self.partitioning {
+            Some(partitioning) if !self.down.load(Ordering::SeqCst) => partitioning,
+            _ => return Route::Local,
+        };
+        let client_id = MessageParser::parse(message.to_string())
+            .ok()
+            .and_then(|act| act.client_id());
+        match partitioning.lock() {
+            Ok(partitioning) => partitioning.route(client_id, self.shop_id),
+            Err(_) => Route::Local,
+        }
+    }
+
+    /// Forwards the message of a coffee machine to the primary of the partition of its client,
+    /// which answers it back to this server.
+    fn forward_to_primary(&mut self, message: String, primary: u32) {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let primary_addr = id_to_dataaddr(primary as usize);
+        let mut span = Span::from_message("resend_message_to_primary", Kind::Client, &message);
+        span.attribute("shop.id", &self.shop_id.to_string());
+        span.attribute("primary.id", &primary.to_string());
+        let message = span.inject(&message);
+        debug!(
+            target: SERVER,
+            shop = self.shop_id,
+            to = %primary_addr,
+            "send {}",
+            message
+        );
+        self.send_to_server(&message, primary_addr);
+        span.end();
+    }
+
+    /// Receives the messages of the other servers when the accounts are partitioned: the writes
+    /// of the partitions it is the primary of, the writes it replicates, the answers to the
+    /// writes it forwarded, and the heartbeats and the partitions of the other servers.
+    fn receive_partitioned(&mut self) -> Result<(), Error> {
+        let mut buf = [0u8; MESSAGE_BYTES];
+        let _ = self.socket.set_read_timeout(Some(TIMEOUT));
+        let (size, from) = match self.socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(_) => return Err(TransportError::Timeout.into()),
+        };
+        if self.down.load(Ordering::SeqCst) {
+            return Err(OrderError::Down.into());
+        }
+        let message = String::from_utf8_lossy(&buf[..size]).into_owned();
+        let (signer, message) = match self.authenticate(message, from, Role::PeerServer) {
+            Some(authenticated) => authenticated,
+            None => return Ok(()),
+        };
+        debug!(
+            target: SERVER,
+            shop = self.shop_id,
+            %from,
+            "get {}",
+            message
+        );
+        let act = match MessageParser::parse(message.clone()) {
+            Ok(act) => act,
+            Err(_) => return Ok(()),
+        };
+        match act {
+            Action::Alive(shop_id) => {
+                if let Some(Ok(mut partitioning)) = self.partitioning.as_ref().map(|p| p.lock()) {
+                    partitioning.membership.seen(shop_id);
+                }
+            }
+            Action::PartitionSync(partition) => {
+                if let KeyId::Shop(shop_id) = signer {
+                    self.send_partition(partition, shop_id, from);
+                }
+            }
+            Action::PartitionState(partition, part, parts, accounts) => {
+                self.receive_partition_part(partition, part, parts, accounts);
+            }
+            Action::Ack | Action::NotEnoughPoints(_) | Action::ClientAlreadyBlocked(_) => {
+                self.answer_forwarded(&message);
+            }
+            Action::OfflineEnd(shop_id) => self.end_reconciliation(shop_id, from),
+            Action::OfflineAck(shop_id, reconciled) => {
+                self.acknowledge_down_log(shop_id, reconciled)
+            }
+            _ if metadata::get(&message, PRIMARY).is_some() => self.replicate(message, act, from),
+            _ => {
+                if self.route(&message) != Route::Pending {
+                    self.lead_server_message(message, from);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a write replicated by the primary of its partition, unless the server is
+    /// still receiving the accounts of the partition, which replace the ones it has.
+    fn replicate(&mut self, message: String, act: Action, from: SocketAddr) {
+        let pending = match (&self.partitioning, act.client_id()) {
+            (Some(partitioning), Some(client_id)) => partitioning
+                .lock()
+                .map(|partitioning| partitioning.is_pending(client_id))
+                .unwrap_or(false),
+            _ => false,
+        };
+        if !pending {
+            self.answer_local_server_traced(message, from);
+        }
+    }
+
+    /// Marks every partition of the server as pending, so it receives them from
+    /// their other owners in case it is restarting.
+    fn start_partitions(&mut self) {
+        if let Some(Ok(mut partitioning)) = self.partitioning.as_ref().map(|p| p.lock()) {
+            partitioning.receive_all(self.shop_id);
+        }
+    }
+
+    /// Sends a heartbeat to the other servers, rebalances the partitions if a server joined
+    /// or left, and asks for the accounts of the partitions it is waiting for.
+    fn tick_partitions(&mut self) {
+        if self.down.load(Ordering::SeqCst) {
+            return;
+        }
+        let alive = format!("ALIVE {}", self.shop_id);
+        for i in (0..self.shops_amount).filter(|i| *i != self.shop_id) {
+            self.send_to_server(&alive, id_to_dataaddr(i as usize));
+        }
+        let partitioning = match &self.partitioning {
+            Some(partitioning) => partitioning.clone(),
+            None => return,
+        };
+        let mut partitioning = match partitioning.lock() {
+            Ok(partitioning) => partitioning,
+            Err(_) => return,
+        };
+        if partitioning.refresh(self.shop_id, Instant::now()) {
+            registry().inc(&REBALANCES, &[]);
+            info!(
+                target: SERVER,
+                shop = self.shop_id,
+                "rebalanced the partitions among shops {:?}, {} owned",
+                partitioning.map.live(),
+                partitioning.map.owned_by(self.shop_id).len()
+            );
+        }
+        let mut requests = vec![];
+        let mut abandoned = vec![];
+        for (partition, pending) in partitioning.pending.iter_mut() {
+            if matches!(pending.requested_at, Some(at) if at.elapsed() < SYNC_TIMEOUT) {
+                continue;
+            }
+            if pending.sources.is_empty() || pending.retries >= SYNC_MAX_RETRIES {
+                abandoned.push((*partition, !pending.sources.is_empty()));
+                continue;
+            }
+            if pending.requested_at.is_some() {
+                pending.sources.rotate_left(1);
+                pending.retries += 1;
+            }
+            pending.accounts.clear();
+            pending.parts.clear();
+            pending.requested_at = Some(Instant::now());
+            requests.push((*partition, pending.sources[0]));
+        }
+        // With no other owner alive the server keeps the accounts it has
+        for (partition, failed) in abandoned {
+            partitioning.pending.remove(&partition);
+            if failed {
+                warn!(
+                    target: SYNC,
+                    shop = self.shop_id,
+                    "no owner sent partition {}, keeping its local accounts",
+                    partition
                 );
-                self.socket
-                    .send_to(message.as_bytes(), addr)
-                    .expect("Error sending message to server");
             }
         }
+        let rejoined = partitioning.rejoining && partitioning.pending.is_empty();
+        if rejoined {
+            partitioning.rejoining = false;
+        }
+        drop(partitioning);
+
+        for (partition, source) in requests {
+            let msg = format!("PARTSYNC {}", partition);
+            self.send_to_server(&msg, id_to_dataaddr(source as usize));
+        }
+        if rejoined {
+            self.send_down_log_to_primaries();
+        }
+    }
+
+    /// Sends the accounts of the partition to the server "shop_id" that became one of its owners.
+    /// A server that is still receiving the partition only sends it to the servers with
+    /// a higher id, so two servers waiting for the partition of each other do not get stuck.
+    fn send_partition(&mut self, partition: u32, shop_id: u32, from: SocketAddr) {
+        let map = match self.partitioning.as_ref().map(|p| p.lock()) {
+            Some(Ok(partitioning)) => {
+                if partitioning.pending.contains_key(&partition) && shop_id < self.shop_id {
+                    return;
+                }
+                partitioning.map.clone()
+            }
+            _ => return,
+        };
+        let accounts = match self
+            .points_handler
+            .accounts(|client_id| map.partition_of(client_id) == partition)
+        {
+            Ok(accounts) => accounts,
+            Err(_) => return,
+        };
+        let parts = snapshot_parts(&accounts);
+        for (part, accounts) in parts.iter().enumerate() {
+            let msg = format!(
+                "PARTSTATE {} {} {} {}",
+                partition,
+                part,
+                parts.len(),
+                accounts
+            );
+            self.resend_message(msg.trim_end().to_string(), from);
+        }
+    }
+
+    /// Stores a part of the accounts of a pending partition. Once every part is received,
+    /// they replace the accounts of the partition.
+    fn receive_partition_part(
+        &mut self,
+        partition: u32,
+        part: u32,
+        parts: u32,
+        accounts: Vec<Account>,
+    ) {
+        let partitioning = match &self.partitioning {
+            Some(partitioning) => partitioning.clone(),
+            None => return,
+        };
+        let mut partitioning = match partitioning.lock() {
+            Ok(partitioning) => partitioning,
+            Err(_) => return,
+        };
+        let pending = match partitioning.pending.get_mut(&partition) {
+            Some(pending) => pending,
+            None => return,
+        };
+        if !pending.parts.insert(part) {
+            return;
+        }
+        pending.accounts.extend(accounts);
+        if pending.parts.len() < parts as usize {
+            return;
+        }
+        let accounts = match partitioning.pending.remove(&partition) {
+            Some(pending) => pending.accounts,
+            None => return,
+        };
+        let map = &partitioning.map;
+        let replaced = self.points_handler.replace_accounts(
+            |client_id| map.partition_of(client_id) == partition,
+            &accounts,
+        );
+        match replaced {
+            Ok(_) => info!(
+                target: SYNC,
+                shop = self.shop_id,
+                "received partition {} with {} accounts",
+                partition,
+                accounts.len()
+            ),
+            Err(_) => {
+                error!(target: SYNC, shop = self.shop_id, "error installing partition {}", partition)
+            }
+        }
+    }
+
+    /// Rejoins the partitions after being down: the accounts go back to their state before
+    /// going down and the server receives its partitions from their other owners. Once they
+    /// are received, it sends the operations accepted offline to their primaries.
+    fn rejoin(&mut self) {
+        self.restore_synced_points();
+        if let Some(Ok(mut partitioning)) = self.partitioning.as_ref().map(|p| p.lock()) {
+            partitioning.rejoin(self.shops_amount, self.shop_id);
+        }
+        self.shop_leader.up();
+        self.shop_leader.find_new();
+        self.down.store(false, Ordering::SeqCst);
+    }
+
+    /// Sends each operation accepted while the server was down to the primary of the partition
+    /// of its client, or reconciles it if it is the primary, then ends the reconciliation.
+    fn send_down_log_to_primaries(&mut self) {
+        let since = self.down_since.load(Ordering::SeqCst);
+        let routed: Vec<(Route, OfflineOperation)> = self
+            .read_down_log()
+            .into_iter()
+            .map(|operation| (self.route(&operation.message), operation))
+            .collect();
+        let mut expected: HashMap<u32, usize> = HashMap::new();
+        for (route, _) in &routed {
+            if let Route::Forward(primary) = route {
+                *expected.entry(*primary).or_default() += 1;
+            }
+        }
+        let acknowledged = expected.is_empty();
+        if let Ok(mut acks) = self.down_acks.lock() {
+            *acks = DownLogAcks::new(since, expected);
+        }
+        for (route, operation) in routed {
+            match route {
+                Route::Forward(primary) => {
+                    let msg = format!("offline {} {} {}", self.shop_id, since, operation.to_line());
+                    self.send_to_server(&msg, id_to_dataaddr(primary as usize));
+                }
+                _ => self.reconcile_operation(self.shop_id, since, operation),
+            }
+        }
+        let msg = format!("offlineEnd {}", self.shop_id);
+        for i in (0..self.shops_amount).filter(|i| *i != self.shop_id) {
+            self.send_to_server(&msg, id_to_dataaddr(i as usize));
+        }
+        self.finish_reconciliation(self.shop_id);
+        if acknowledged {
+            self.truncate_down_log(since);
+        }
     }
 
     /// Creates an clone instance of [`Server`].
@@ -693,27 +3019,82 @@ impl Server {
             addr: self.addr,
             socket: self.socket.clone(),
             coffee_machine_socket: self.coffee_machine_socket.clone(),
+            control_socket: self.control_socket.clone(),
             shop_id: self.shop_id,
             shops_amount: self.shops_amount,
             points_handler: self.points_handler.clone(),
             down: self.down.clone(),
-            log: self
-                .log
-                .try_clone()
-                .expect("Error when trying to clone log file"),
-            log_down: self
-                .log_down
-                .try_clone()
-                .expect("Error when trying to clone log file"),
+            log: self.log.clone(),
+            log_down: self.log_down.clone(),
             shop_leader: self.shop_leader.clone_leader_election(),
             sync: self.sync.clone(),
+            sync_state: self.sync_state.clone(),
+            synced_points: self.synced_points.clone(),
+            history: self.history.clone(),
             msg_queue: VecDeque::new(),
+            fan_out: None,
+            down_since: self.down_since.clone(),
+            offline_seq: self.offline_seq.clone(),
+            reconciler: self.reconciler.clone(),
+            down_acks: self.down_acks.clone(),
+            offline_credit: self.offline_credit.clone(),
+            config: self.config.clone(),
+            auth: self.auth.clone(),
+            signer: self.signer.clone(),
+            shutdown: self.shutdown.clone(),
+            in_flight: self.in_flight.clone(),
+            transferring: self.transferring.clone(),
+            held: self.held.clone(),
+            takeover: self.takeover.clone(),
+            partitioning: self.partitioning.clone(),
+            leader_index: self.leader_index.clone(),
+            read_floor: self.read_floor.clone(),
+            anti_entropy: self.anti_entropy.clone(),
+            accruals: self.accruals.clone(),
+            gossip_socket: self.gossip_socket.clone(),
         }
     }
 }
 
+/// Returns true if the action is an entry of the log replicated by the leader.
+fn is_replicated(action: &Action) -> bool {
+    matches!(
+        action,
+        Action::Block(..)
+            | Action::CompleteOrder(..)
+            | Action::FailOrder(..)
+            | Action::OfflineRedeem(..)
+            | Action::Grant(..)
+            | Action::Deduct(..)
+            | Action::Reverse(..)
+            | Action::Settle(..)
+    )
+}
+
+fn bind(addr: SocketAddr) -> Result<UdpSocket, Error> {
+    match UdpSocket::bind(addr) {
+        Ok(socket) => Ok(socket),
+        Err(err) => Err(TransportError::CantBindSocket {
+            addr: addr.to_string(),
+            cause: err.into(),
+        }
+        .into()),
+    }
+}
+
+/// Returns the socket address of the control plane of the server with shop_id.
+/// It is not behind the fault proxy, so the operators can always reach the server.
+pub fn operator_addr(shop_id: u32) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 4234 + shop_id as u16))
+}
+
+/// Returns the socket address of the gossip of the accruals of the server with shop_id.
+pub fn gossip_addr(shop_id: u32) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 6234 + shop_id as u16))
+}
+
 /// Returns the socket address of the coffee machines that sends messages to the server with shop_id.
-fn coffee_machine_addr(shop_id: u32) -> SocketAddr {
+pub fn coffee_machine_addr(shop_id: u32) -> SocketAddr {
     let ip_addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
     let port = 8000 + shop_id as u16;
     SocketAddr::new(ip_addr, port)
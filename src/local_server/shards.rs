@@ -0,0 +1,225 @@
+use std::sync::{Mutex, MutexGuard};
+
+use crate::{
+    errors::Error, local_server::snapshot::apply_entry, message_parser::MessageParser,
+    points_handler::PointsHandler, wire::Account,
+};
+
+/// Accounts of the clients partitioned in shards by client id, each one behind its own lock,
+/// so the operations of clients of different shards are applied in parallel.
+pub struct ShardedPoints {
+    shards: Vec<Mutex<PointsHandler>>,
+}
+
+impl ShardedPoints {
+    /// Creates an instance of [`ShardedPoints`] with the accounts split in "shards" shards.
+    pub fn new(shards: usize, points: PointsHandler) -> ShardedPoints {
+        let mut parts = vec![PointsHandler::new(); shards.max(1)];
+        let amount = parts.len();
+        for (client_id, account) in points.points {
+            parts[client_id as usize % amount]
+                .points
+                .insert(client_id, account);
+        }
+        ShardedPoints {
+            shards: parts.into_iter().map(Mutex::new).collect(),
+        }
+    }
+
+    /// Locks the shard with the account of the client.
+    pub fn lock(&self, client_id: u32) -> Result<MutexGuard<'_, PointsHandler>, Error> {
+        let shard = client_id as usize % self.shards.len();
+        self.shards[shard].lock().map_err(|_| Error::Lock)
+    }
+
+    /// Locks the shard of the client the message refers to, or the first shard if it has none.
+    pub fn lock_for(&self, message: &str) -> Result<MutexGuard<'_, PointsHandler>, Error> {
+        let client_id = match MessageParser::parse(message.to_string()) {
+            Ok(act) => act.client_id().unwrap_or(0),
+            Err(_) => 0,
+        };
+        self.lock(client_id)
+    }
+
+    /// Applies an entry of the log to the account of its client and returns its answer.
+    pub fn apply_entry(&self, entry: &str) -> Result<String, Error> {
+        apply_entry(&mut *self.lock_for(entry)?, entry)
+    }
+
+    /// Returns a copy of every account. The shards are locked together,
+    /// so the copy does not mix accounts from before and after an operation.
+    pub fn merged(&self) -> Result<PointsHandler, Error> {
+        let shards = self.lock_all()?;
+        let mut points = PointsHandler::new();
+        for shard in shards.iter() {
+            points.points.extend(shard.points.iter());
+        }
+        Ok(points)
+    }
+
+    /// Replaces every account with the accounts of "points".
+    pub fn replace(&self, points: PointsHandler) -> Result<(), Error> {
+        let mut shards = self.lock_all()?;
+        let amount = shards.len();
+        for shard in shards.iter_mut() {
+            shard.points.clear();
+        }
+        for (client_id, account) in points.points {
+            shards[client_id as usize % amount]
+                .points
+                .insert(client_id, account);
+        }
+        Ok(())
+    }
+
+    /// Returns the accounts of the clients that match "filter", sorted by client id.
+    pub fn accounts(&self, filter: impl Fn(u32) -> bool) -> Result<Vec<Account>, Error> {
+        let shards = self.lock_all()?;
+        let mut accounts: Vec<Account> = shards
+            .iter()
+            .flat_map(|shard| shard.points.iter())
+            .filter(|(client_id, _)| filter(**client_id))
+            .map(|(client_id, (points, blocked))| (*client_id, *points, *blocked))
+            .collect();
+        accounts.sort();
+        Ok(accounts)
+    }
+
+    /// Replaces the accounts of the clients that match "filter" with "accounts".
+    pub fn replace_accounts(
+        &self,
+        filter: impl Fn(u32) -> bool,
+        accounts: &[Account],
+    ) -> Result<(), Error> {
+        let mut shards = self.lock_all()?;
+        let amount = shards.len();
+        for shard in shards.iter_mut() {
+            shard.points.retain(|client_id, _| !filter(*client_id));
+        }
+        for (client_id, points, blocked) in accounts {
+            shards[*client_id as usize % amount]
+                .points
+                .insert(*client_id, (*points, *blocked));
+        }
+        Ok(())
+    }
+
+    /// Returns the amount of blocked accounts.
+    pub fn blocked(&self) -> usize {
+        self.shards
+            .iter()
+            .filter_map(|shard| shard.lock().ok())
+            .map(|shard| {
+                shard
+                    .points
+                    .values()
+                    .filter(|(_, blocked)| *blocked)
+                    .count()
+            })
+            .sum()
+    }
+
+    /// Locks every shard, always in the same order.
+    fn lock_all(&self) -> Result<Vec<MutexGuard<'_, PointsHandler>>, Error> {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().map_err(|_| Error::Lock))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test01_operations_of_each_client_use_its_shard() {
+        let points = ShardedPoints::new(4, PointsHandler::new());
+
+        points
+            .lock(5)
+            .expect("Error locking")
+            .update_points(5, 10)
+            .expect("Error when adding points");
+        points
+            .lock(6)
+            .expect("Error locking")
+            .block(6)
+            .expect("Error when blocking");
+
+        assert_eq!(points.lock(5).expect("Error locking").balance(5), 10);
+        assert_eq!(
+            points.lock(1).expect("Error locking").points.get(&5),
+            Some(&(10, false))
+        );
+        assert_eq!(points.lock(2).expect("Error locking").points.get(&5), None);
+        assert_eq!(points.blocked(), 1);
+    }
+
+    #[test]
+    fn test02_merge_and_replace_every_account() {
+        let mut initial = PointsHandler::new();
+        for client_id in 0..20 {
+            initial
+                .update_points(client_id, client_id as i32)
+                .expect("Error when adding points");
+        }
+        let points = ShardedPoints::new(3, initial.clone());
+
+        assert_eq!(
+            points.merged().expect("Error merging").points,
+            initial.points
+        );
+
+        let mut replaced = PointsHandler::new();
+        replaced
+            .update_points(7, 70)
+            .expect("Error when adding points");
+        points.replace(replaced.clone()).expect("Error replacing");
+        assert_eq!(
+            points.merged().expect("Error merging").points,
+            replaced.points
+        );
+    }
+
+    #[test]
+    fn test03_replace_the_accounts_of_some_clients() {
+        let mut initial = PointsHandler::new();
+        for client_id in 0..10 {
+            initial
+                .update_points(client_id, 1)
+                .expect("Error when adding points");
+        }
+        let points = ShardedPoints::new(3, initial);
+        let last = |client_id: u32| client_id >= 5;
+
+        points
+            .replace_accounts(last, &[(6, 60, true), (12, 120, false)])
+            .expect("Error replacing");
+
+        assert_eq!(
+            points.accounts(last),
+            Ok(vec![(6, 60, true), (12, 120, false)])
+        );
+        assert_eq!(
+            points.accounts(|client_id| client_id == 3),
+            Ok(vec![(3, 1, false)])
+        );
+    }
+
+    #[test]
+    fn test04_apply_entry_to_the_shard_of_its_client() {
+        let points = ShardedPoints::new(2, PointsHandler::new());
+
+        assert_eq!(
+            points.apply_entry("complete 3 20 cash 0"),
+            Ok("ACK".to_string())
+        );
+        assert_eq!(
+            points.apply_entry("complete 3 30 points 0"),
+            Ok("notEnough 3".to_string())
+        );
+        assert_eq!(points.lock(3).expect("Error locking").balance(3), 20);
+        assert!(points.apply_entry("TRY").is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+};
+
+use crate::{
+    action::Action,
+    errors::{Error, OrderError, ParseError, StorageError},
+    message_parser::MessageParser,
+    payment_method::Method,
+    points_handler::PointsHandler,
+    wire::Account,
+};
+
+use super::sync::install_snapshot;
+
+/// Balances of every account after applying the log entries before `index`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Snapshot {
+    pub index: u64,
+    pub accounts: Vec<Account>,
+}
+
+impl Snapshot {
+    /// Returns the snapshot of the accounts of the points handler at the given index.
+    pub fn take(index: u64, points: &PointsHandler) -> Snapshot {
+        let mut accounts: Vec<Account> = points
+            .points
+            .iter()
+            .map(|(client_id, info)| (*client_id, info.0, info.1))
+            .collect();
+        accounts.sort();
+        Snapshot { index, accounts }
+    }
+
+    /// Returns a points handler with the accounts of the snapshot.
+    pub fn points(&self) -> PointsHandler {
+        install_snapshot(&self.accounts)
+    }
+
+    /// Reads the snapshot of a file. Returns an empty snapshot if the file does not exist.
+    pub fn read(path: &str) -> Result<Snapshot, Error> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) if !Path::new(path).exists() => return Ok(Snapshot::default()),
+            Err(err) => return Err(read_error(path, err)),
+        };
+        let mut lines = BufReader::new(file).lines();
+        let index = match lines.next() {
+            Some(Ok(line)) => match line.strip_prefix("index ").map(|i| i.parse::<u64>()) {
+                Some(Ok(index)) => index,
+                _ => return Err(ParseError::InvalidLine(line).into()),
+            },
+            Some(Err(err)) => return Err(read_error(path, err)),
+            None => return Err(ParseError::InvalidLine(String::new()).into()),
+        };
+        let mut accounts = vec![];
+        for line in lines {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => return Err(read_error(path, err)),
+            };
+            let fields: Vec<&str> = line.split(' ').collect();
+            if fields.len() != 3 {
+                return Err(ParseError::InvalidLine(line).into());
+            }
+            match (
+                fields[0].parse::<u32>(),
+                fields[1].parse::<i32>(),
+                fields[2].parse::<u8>(),
+            ) {
+                (Ok(client_id), Ok(points), Ok(blocked)) => {
+                    accounts.push((client_id, points, blocked == 1))
+                }
+                _ => return Err(ParseError::InvalidLine(line).into()),
+            }
+        }
+        Ok(Snapshot { index, accounts })
+    }
+
+    /// Writes the snapshot in a temporary file and renames it, so the file at `path`
+    /// always holds a complete snapshot.
+    pub fn write_atomic(&self, path: &str) -> Result<(), Error> {
+        let tmp_path = format!("{}.tmp", path);
+        let mut content = format!("index {}\n", self.index);
+        for (client_id, points, blocked) in &self.accounts {
+            content.push_str(&format!("{} {} {}\n", client_id, points, *blocked as u8));
+        }
+        let written = File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(content.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp_path, path));
+        match written {
+            Ok(_) => Ok(()),
+            Err(err) => Err(StorageError::CantWrite {
+                path: path.to_string(),
+                cause: err.into(),
+            }
+            .into()),
+        }
+    }
+}
+
+fn read_error(path: &str, err: io::Error) -> Error {
+    StorageError::CantRead {
+        path: path.to_string(),
+        cause: err.into(),
+    }
+    .into()
+}
+
+/// Returns the answer to an operation that updated the points of the client.
+/// Points that do not fit in the account are answered with an error and left unchanged.
+pub fn points_answer(updated: Result<(), Error>, client_id: u32) -> String {
+    match updated {
+        Ok(_) => "ACK".to_string(),
+        Err(Error::Order(OrderError::NotEnoughPoints(_))) => format!("notEnough {}", client_id),
+        Err(_) => "Error".to_string(),
+    }
+}
+
+/// Returns the points of an amount of an operation of the client.
+/// Returns error if they do not fit in an account.
+pub fn checked_points(client_id: u32, amount: u32) -> Result<i32, Error> {
+    match i32::try_from(amount) {
+        Ok(points) => Ok(points),
+        Err(_) => Err(OrderError::PointsOutOfRange(client_id).into()),
+    }
+}
+
+/// Applies an entry of the log to the accounts and returns the answer to the operation.
+/// Amounts that do not fit in an account are answered with an error.
+/// Returns error if the entry is not an operation of the log.
+pub fn apply_entry(points: &mut PointsHandler, entry: &str) -> Result<String, Error> {
+    let result = match MessageParser::parse(entry.to_string())? {
+        Action::Block(client_id, _) => match points.block(client_id) {
+            Ok(_) => "ACK".to_string(),
+            Err(_) => format!("alreadyBlocked {}", client_id),
+        },
+        Action::CompleteOrder(client_id, price, method, _) => {
+            let updated = checked_points(client_id, price).and_then(|price| match method {
+                Method::Cash => points.update_points(client_id, price),
+                Method::Points => points.update_points(client_id, -price),
+            });
+            let result = points_answer(updated, client_id);
+            points.unblock(client_id);
+            result
+        }
+        Action::FailOrder(client_id, _) => {
+            points.unblock(client_id);
+            "ACK".to_string()
+        }
+        Action::OfflineRedeem(client_id, price, _) => match i32::try_from(price) {
+            Ok(price) => points_answer(points.force_update_points(client_id, -price), client_id),
+            Err(_) => return Err(ParseError::InvalidMessage(entry.to_string()).into()),
+        },
+        Action::Grant(client_id, amount, _, _, _) => points_answer(
+            checked_points(client_id, amount)
+                .and_then(|amount| points.force_update_points(client_id, amount)),
+            client_id,
+        ),
+        Action::Deduct(client_id, amount, _, _, _) => points_answer(
+            checked_points(client_id, amount)
+                .and_then(|amount| points.update_points(client_id, -amount)),
+            client_id,
+        ),
+        Action::Reverse(client_id, _, _, _, amount, _) => {
+            points_answer(points.force_update_points(client_id, amount), client_id)
+        }
+        Action::Settle(client_id, settled, _, _) => points_answer(
+            checked_points(client_id, settled)
+                .and_then(|settled| points.force_update_points(client_id, settled)),
+            client_id,
+        ),
+        _ => return Err(ParseError::InvalidMessage(entry.to_string()).into()),
+    };
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use super::*;
+
+    #[test]
+    fn test01_snapshot_survives_a_write_and_read() {
+        let path = temp_dir()
+            .join(format!("tp2_snapshot_{}.txt", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let snapshot = Snapshot {
+            index: 42,
+            accounts: vec![(1, 10, false), (2, -5, true)],
+        };
+
+        snapshot
+            .write_atomic(&path)
+            .expect("Error writing snapshot");
+
+        assert_eq!(Snapshot::read(&path), Ok(snapshot));
+        assert_eq!(
+            Snapshot::read(&format!("{}.missing", path)),
+            Ok(Snapshot::default())
+        );
+    }
+
+    #[test]
+    fn test02_apply_entries_of_the_log() {
+        let mut points = PointsHandler::new();
+        for entry in [
+            "complete 1 30 cash 0",
+            "block 1 0",
+            "complete 1 10 points 0",
+            "offlineRedeem 1 25 1",
+            "block 2 0",
+        ] {
+            apply_entry(&mut points, entry).expect("Invalid entry");
+        }
+
+        assert_eq!(points.points.get(&1), Some(&(-5, false)));
+        assert_eq!(points.points.get(&2), Some(&(0, true)));
+        assert!(apply_entry(&mut points, "ACK").is_err());
+        assert_eq!(
+            apply_entry(&mut points, "deduct 1 1 0 7 wrong accrual"),
+            Ok("notEnough 1".to_string())
+        );
+        apply_entry(&mut points, "grant 1 20 0 7 gift").expect("Invalid entry");
+        apply_entry(&mut points, "reverse 1 4 0 7 -3 refund").expect("Invalid entry");
+        assert_eq!(points.balance(1), 12);
+        assert_eq!(
+            apply_entry(&mut points, "complete 2 5 points 0"),
+            Ok("notEnough 2".to_string())
+        );
+    }
+
+    #[test]
+    fn test03_amounts_out_of_range_are_answered_with_an_error() {
+        let mut points = PointsHandler::new();
+        apply_entry(&mut points, "complete 1 30 cash 0").expect("Invalid entry");
+
+        for entry in [
+            "complete 1 4294967295 cash 0",
+            "complete 1 2147483648 points 0",
+            "grant 1 2147483648 0 7 gift",
+            "deduct 1 4294967295 0 7 wrong accrual",
+            "settle 1 2147483648 0 2147483648",
+        ] {
+            assert_eq!(apply_entry(&mut points, entry), Ok("Error".to_string()));
+        }
+        assert_eq!(points.points.get(&1), Some(&(30, false)));
+    }
+}
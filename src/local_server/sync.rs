@@ -0,0 +1,210 @@
+use std::{net::SocketAddr, time::Instant};
+
+use crate::{
+    auth::SIGNATURE_BYTES,
+    constants::MESSAGE_BYTES,
+    metrics::{registry, SYNC_DURATION},
+    points_handler::PointsHandler,
+    wire::{Account, ENTRY_SEPARATOR},
+};
+
+/// Bytes of a message kept for its type, indexes and parts, and for metadata other than
+/// the signature, like the address of the operator of a forwarded answer.
+pub const HEADER_BYTES: usize = 128;
+
+/// Max bytes of log entries or accounts sent in a single message, so that the message
+/// still fits in the receive buffer once it has its header and it is signed.
+pub const CHUNK_BYTES: usize = MESSAGE_BYTES - SIGNATURE_BYTES - HEADER_BYTES;
+
+/// Progress of the synchronization of a server with the leader.
+pub struct SyncState {
+    /// Last request sent and the server it was sent to, resent if there is no answer.
+    pub last_request: Option<(String, SocketAddr)>,
+    pub updated_at: Instant,
+    pub retries: u32,
+    /// Accounts of the snapshot being received.
+    pub snapshot: Vec<Account>,
+    /// Parts of the last snapshot served to other servers and the index it was taken at.
+    pub served_snapshot: Option<(u64, Vec<String>)>,
+    /// When the synchronization in progress started.
+    pub started_at: Option<Instant>,
+}
+
+impl SyncState {
+    /// Creates a new instance of [`SyncState`].
+    pub fn new() -> SyncState {
+        SyncState {
+            last_request: None,
+            updated_at: Instant::now(),
+            retries: 0,
+            snapshot: vec![],
+            served_snapshot: None,
+            started_at: None,
+        }
+    }
+
+    /// Records the duration of the synchronization in progress with its result.
+    pub fn finish(&mut self, result: &str) {
+        if let Some(started_at) = self.started_at.take() {
+            registry().observe(
+                &SYNC_DURATION,
+                &[("result", result)],
+                started_at.elapsed().as_secs_f64(),
+            );
+        }
+    }
+
+    /// Registers a new request, sent because the previous one was answered.
+    pub fn request(&mut self, message: String, to: SocketAddr) {
+        self.last_request = Some((message, to));
+        self.updated_at = Instant::now();
+        self.retries = 0;
+    }
+}
+
+impl Default for SyncState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the SYNCCHUNK message with as many entries as fit in a message, starting at `start`,
+/// and the amount of entries it carries.
+pub fn encode_chunk(start: u64, entries: &[String]) -> (String, usize) {
+    let mut message = format!("SYNCCHUNK {} ", start);
+    let mut count = 0;
+    for entry in entries {
+        if count > 0 && message.len() + entry.len() + 1 > CHUNK_BYTES {
+            break;
+        }
+        if count > 0 {
+            message.push(ENTRY_SEPARATOR);
+        }
+        message.push_str(entry);
+        count += 1;
+    }
+    (message, count)
+}
+
+/// Returns the accounts split in the parts of a snapshot.
+/// Each part is a list of `client:points:blocked` separated by commas.
+pub fn snapshot_parts(accounts: &[Account]) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part = String::new();
+    for (client_id, points, blocked) in accounts {
+        let account = format!("{}:{}:{}", client_id, points, *blocked as u8);
+        if !part.is_empty() && part.len() + account.len() + 1 > CHUNK_BYTES {
+            parts.push(part);
+            part = String::new();
+        }
+        if !part.is_empty() {
+            part.push(',');
+        }
+        part.push_str(&account);
+    }
+    parts.push(part);
+    parts
+}
+
+/// Returns a points handler with the accounts of a snapshot.
+pub fn install_snapshot(accounts: &[Account]) -> PointsHandler {
+    let mut points = PointsHandler::new();
+    for (client_id, balance, blocked) in accounts {
+        points.points.insert(*client_id, (*balance, *blocked));
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{KeyId, KeyStore},
+        local_server::snapshot::Snapshot,
+        wire::parse_accounts,
+    };
+
+    #[test]
+    fn test01_chunk_carries_entries_until_it_is_full() {
+        let entries: Vec<String> = (0..200).map(|i| format!("block {} 0", i)).collect();
+
+        let (message, count) = encode_chunk(5, &entries);
+
+        assert!(message.starts_with("SYNCCHUNK 5 block 0 0|block 1 0"));
+        assert!(message.len() <= CHUNK_BYTES);
+        assert!(count > 1 && count < entries.len());
+    }
+
+    #[test]
+    fn test02_snapshot_parts_contain_every_account() {
+        let mut points = PointsHandler::new();
+        for client_id in 0..300 {
+            points
+                .update_points(client_id, client_id as i32)
+                .expect("Error when adding points");
+        }
+        points.block(7).expect("Error when blocking");
+
+        let parts = snapshot_parts(&Snapshot::take(0, &points).accounts);
+        let accounts: Vec<Account> = parts
+            .iter()
+            .flat_map(|part| parse_accounts(part).expect("Invalid part"))
+            .collect();
+
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|part| part.len() <= CHUNK_BYTES));
+        assert_eq!(install_snapshot(&accounts).points, points.points);
+    }
+
+    #[test]
+    fn test03_empty_snapshot_has_one_part() {
+        let parts = snapshot_parts(&[]);
+
+        assert_eq!(parts, vec![String::new()]);
+        assert_eq!(parse_accounts(&parts[0]), Some(vec![]));
+        assert_eq!(parse_accounts("1:a:0"), None);
+    }
+
+    #[test]
+    fn test04_signed_chunks_and_parts_fit_in_the_receive_buffer() {
+        let keys = KeyStore::from_json(
+            "{\"machines\": {\"4294967295\": {\"4294967295\": \"machine-key\"}}}",
+        )
+        .expect("The keys are invalid");
+        let signer = keys
+            .signer(KeyId::Machine(u32::MAX, u32::MAX))
+            .expect("There is no key");
+        let entries: Vec<String> = (0..200)
+            .map(|i| format!("complete {} 4294967295 points 4294967295", u32::MAX - i))
+            .collect();
+        let accounts: Vec<Account> = (0..300).map(|i| (u32::MAX - i, i32::MIN, true)).collect();
+
+        let (chunk, _) = encode_chunk(u64::MAX, &entries);
+        let parts = snapshot_parts(&accounts);
+        let messages = parts.iter().flat_map(|part| {
+            [
+                format!(
+                    "REPAIRSTATE {} {} {} {}",
+                    u64::MAX,
+                    u32::MAX,
+                    u32::MAX,
+                    part
+                ),
+                format!(
+                    "STATE {} {} partitioned {} {} {}",
+                    u32::MAX,
+                    u64::MAX,
+                    u32::MAX,
+                    u32::MAX,
+                    part
+                ),
+            ]
+        });
+
+        assert!(parts.len() > 1);
+        for message in messages.chain([chunk]) {
+            let signed = signer.sign(&message);
+            assert!(signed.len() <= MESSAGE_BYTES, "{} bytes", signed.len());
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::env;
+
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    constants::{LOG_FORMAT_VAR, LOG_VAR},
+    errors::{ConfigError, Error},
+};
+
+/// Targets of the components, used to filter their logs.
+pub const SERVER: &str = "server";
+pub const ELECTION: &str = "election";
+pub const SYNC: &str = "sync";
+pub const COFFEE_MACHINE: &str = "coffee_machine";
+pub const SENDER: &str = "sender";
+pub const FAULT_PROXY: &str = "fault_proxy";
+
+const DEFAULT_FILTER: &str = "info";
+
+#[derive(Debug, PartialEq)]
+pub enum Format {
+    Human,
+    Json,
+}
+
+impl Format {
+    fn parse(format: &str) -> Result<Format, Error> {
+        match format {
+            "human" => Ok(Format::Human),
+            "json" => Ok(Format::Json),
+            _ => Err(ConfigError::InvalidLogConfig(format.to_string()).into()),
+        }
+    }
+}
+
+/// Parses the filter of the logs, as in "info" or "warn,election=debug,sync=trace".
+fn filter(directives: &str) -> Result<EnvFilter, Error> {
+    match EnvFilter::try_new(directives) {
+        Ok(filter) => Ok(filter),
+        Err(_) => Err(ConfigError::InvalidLogConfig(directives.to_string()).into()),
+    }
+}
+
+/// Installs the logger of the process.
+/// The verbosity is taken from TP2_LOG and the format ("human" or "json") from TP2_LOG_FORMAT.
+pub fn init() -> Result<(), Error> {
+    let directives = env::var(LOG_VAR).unwrap_or_else(|_| DEFAULT_FILTER.to_string());
+    let format = match env::var(LOG_FORMAT_VAR) {
+        Ok(format) => Format::parse(&format)?,
+        Err(_) => Format::Human,
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter(&directives)?);
+    let installed = match format {
+        Format::Human => builder.try_init(),
+        Format::Json => builder.json().try_init(),
+    };
+    match installed {
+        Ok(_) => Ok(()),
+        Err(_) => Err(ConfigError::InvalidLogConfig("logger already installed".to_string()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test01_parses_the_formats() {
+        assert_eq!(Format::parse("human"), Ok(Format::Human));
+        assert_eq!(Format::parse("json"), Ok(Format::Json));
+        assert_eq!(
+            Format::parse("xml"),
+            Err(Error::Config(ConfigError::InvalidLogConfig(
+                "xml".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn test02_parses_per_target_filters() {
+        assert!(filter("info").is_ok());
+        assert!(filter("warn,election=debug,sync=trace").is_ok());
+        assert!(filter("server=loud").is_err());
+    }
+}
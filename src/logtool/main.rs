@@ -0,0 +1,81 @@
+use std::{env, process::ExitCode};
+
+use tp2::{
+    errors::{self, ConfigError, Error},
+    local_server::log_record::{verify, LogReader, RecordFilter},
+};
+
+const USAGE: &str = "logtool dump <log_file>
+       logtool filter <log_file> [--client <id>] [--shop <id>] [--from <ms>] [--to <ms>]
+       logtool verify <log_file>";
+
+/// Exit code of the verify command when the log has problems.
+const INVALID_LOG: u8 = 1;
+
+/// Parses the options of the filter command.
+fn parse_filter(options: &[String]) -> Result<RecordFilter, Error> {
+    let mut filter = RecordFilter::default();
+    for option in options.chunks(2) {
+        let value = match option.get(1).map(|v| v.parse::<u64>()) {
+            Some(Ok(value)) => value,
+            _ => return Err(ConfigError::Usage(USAGE).into()),
+        };
+        match option[0].as_str() {
+            "--client" => filter.client = Some(value as u32),
+            "--shop" => filter.shop = Some(value as u32),
+            "--from" => filter.from = Some(value),
+            "--to" => filter.to = Some(value),
+            _ => return Err(ConfigError::Usage(USAGE).into()),
+        }
+    }
+    Ok(filter)
+}
+
+/// Prints the records that match the filter and the lines that are not valid records.
+fn dump(reader: LogReader, filter: RecordFilter) {
+    for (line, record) in reader {
+        match record {
+            Ok(record) if filter.matches(&record) => println!("{}", record.to_line()),
+            Ok(_) => (),
+            Err(err) => println!("line {}: {}", line, err),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    if args.len() >= 3 && args[1] == "verify" {
+        return match LogReader::open(&args[2]) {
+            Ok(reader) => verify_log(reader, &args[2]),
+            Err(err) => errors::report(Err(err)),
+        };
+    }
+    errors::report(run(&args))
+}
+
+fn run(args: &[String]) -> Result<(), Error> {
+    if args.len() < 3 {
+        return Err(ConfigError::Usage(USAGE).into());
+    }
+    let reader = LogReader::open(&args[2])?;
+
+    match args[1].as_str() {
+        "dump" => dump(reader, RecordFilter::default()),
+        "filter" => dump(reader, parse_filter(&args[3..])?),
+        _ => return Err(ConfigError::Usage(USAGE).into()),
+    }
+    Ok(())
+}
+
+/// Prints the problems of the log, the process fails if there is any.
+fn verify_log(reader: LogReader, path: &str) -> ExitCode {
+    let problems = verify(reader);
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        return ExitCode::from(INVALID_LOG);
+    }
+    println!("{}: ok", path);
+    ExitCode::SUCCESS
+}
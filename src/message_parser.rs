@@ -1,4 +1,13 @@
-use crate::{action::*, errors::Error, payment_method::Method};
+use crate::{
+    action::*,
+    errors::{Error, ParseError},
+    metadata,
+    payment_method::Method,
+    wire::{
+        parse_accounts, parse_counters, parse_digests, HistoryEntry, OfflineOperation,
+        ServerStatus, DIGEST_RANGES, ENTRY_SEPARATOR,
+    },
+};
 
 const TYPE: usize = 0;
 const CLIENT_ID: usize = 1;
@@ -7,11 +16,56 @@ const METHOD: usize = 3;
 const SHOP_ID_BLOCK: usize = 2;
 const SHOP_ID_COMPLETE: usize = 4;
 const SHOP_ID_FAIL: usize = 2;
+const SHOP_ID_OFFLINE: usize = 1;
+const SINCE: usize = 2;
+const RECONCILED: usize = 2;
+const SHOP_ID_REDEEM: usize = 3;
+const INDEX: usize = 1;
+const ENTRIES: usize = 2;
+const PART: usize = 2;
+const PARTS: usize = 3;
+const ACCOUNTS: usize = 4;
+const OPERATION: usize = 3;
+const FROM: usize = 2;
+const TO: usize = 3;
+const HISTORY_ENTRIES: usize = 4;
+const AMOUNT: usize = 2;
+const SHOP_ID_ADMIN: usize = 3;
+const OPERATOR: usize = 4;
+const REASON: usize = 5;
+const REVERSE_POINTS: usize = 5;
+const REVERSE_REASON: usize = 6;
+const TARGET: usize = 1;
+const SHOP_ID_ALIVE: usize = 1;
+const PARTITION: usize = 1;
+const MAX_LAG: usize = 2;
+const BALANCE_POINTS: usize = 2;
+const BALANCE_BLOCKED: usize = 3;
+const BALANCE_INDEX: usize = 4;
+const BALANCE_LAG: usize = 5;
+const SETTLED: usize = 2;
+const SHOP_ID_SETTLE: usize = 3;
+const SETTLED_TOTAL: usize = 4;
+const COUNTERS: usize = 1;
+const DIGESTS: usize = 2;
+const RANGES: usize = 1;
+const SHOP_ID_STATE: usize = 1;
+const STATE_INDEX: usize = 2;
+const STATUS: usize = 3;
+const STATE_PART: usize = 4;
+const STATE_PARTS: usize = 5;
+const STATE_ACCOUNTS: usize = 6;
 pub struct MessageParser {}
 
 impl MessageParser {
     pub fn parse(s: String) -> Result<Action, Error> {
-        let words: Vec<&str> = s.split(' ').collect();
+        match MessageParser::parse_words(s.split(' ').collect()) {
+            Some(action) => Ok(action),
+            None => Err(ParseError::InvalidMessage(s).into()),
+        }
+    }
+
+    fn parse_words(words: Vec<&str>) -> Option<Action> {
         match words[TYPE] {
             "block" => MessageParser::parse_block(words),
             "complete" => MessageParser::parse_completion(words),
@@ -23,154 +77,638 @@ impl MessageParser {
             "DOWN" => MessageParser::parser_down(words),
             "UP" => MessageParser::parser_up(words),
             "SYNC" => MessageParser::parser_sync(words),
-            "SYNCSTART" => MessageParser::parse_sync_start(words),
+            "SYNCCHUNK" => MessageParser::parse_sync_chunk(words),
             "SYNCEND" => MessageParser::parse_sync_end(words),
-            _ => Err(Error::InvalidMessageFormat),
+            "SNAPSHOT" => MessageParser::parse_snapshot(words),
+            "SYNCSNAP" => MessageParser::parse_snapshot_request(words),
+            "offline" => MessageParser::parse_offline(words),
+            "offlineEnd" => MessageParser::parse_offline_end(words),
+            "offlineAck" => MessageParser::parse_offline_ack(words),
+            "offlineRedeem" => MessageParser::parse_offline_redeem(words),
+            "history" => MessageParser::parse_history(words),
+            "HISTORY" => MessageParser::parse_history_part(words),
+            "grant" | "deduct" | "refund" => MessageParser::parse_admin(words),
+            "reverse" => MessageParser::parse_reverse(words),
+            "TRANSFER" => MessageParser::parse_transfer(words),
+            "TAKEOVER" => MessageParser::parse_takeover(words),
+            "ALIVE" => MessageParser::parse_alive(words),
+            "PARTSYNC" => MessageParser::parse_partition_sync(words),
+            "PARTSTATE" => MessageParser::parse_partition_state(words),
+            "balance" => MessageParser::parse_balance(words),
+            "BALANCE" => MessageParser::parse_balance_state(words),
+            "settle" => MessageParser::parse_settle(words),
+            "ACCRUALS" => MessageParser::parse_accruals(words),
+            "DIGEST" => MessageParser::parse_digest(words),
+            "REPAIR" => MessageParser::parse_repair(words),
+            "REPAIRSTATE" => MessageParser::parse_repair_state(words),
+            "state" => MessageParser::parse_state(words),
+            "STATE" => MessageParser::parse_state_part(words),
+            _ => None,
         }
     }
 
-    fn parse_sync_start(words: Vec<&str>) -> Result<Action, Error> {
-        if words.len() != 1 {
-            return Err(Error::InvalidMessageFormat);
+    fn parse_offline(words: Vec<&str>) -> Option<Action> {
+        if words.len() < 6 {
+            return None;
+        }
+        let shop_id: u32 = match words[SHOP_ID_OFFLINE].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let since: u64 = match words[SINCE].parse::<u64>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let operation = OfflineOperation::from_line(&words[OPERATION..].join(" ")).ok()?;
+        match MessageParser::parse(operation.message.clone()).ok()? {
+            Action::Block(..) | Action::CompleteOrder(..) | Action::FailOrder(..) => {
+                Some(Action::Offline(shop_id, since, operation))
+            }
+            _ => None,
         }
-        Ok(Action::SyncStart)
     }
 
-    fn parse_sync_end(words: Vec<&str>) -> Result<Action, Error> {
-        if words.len() != 1 {
-            return Err(Error::InvalidMessageFormat);
+    fn parse_offline_end(words: Vec<&str>) -> Option<Action> {
+        if words.len() != 2 {
+            return None;
+        }
+        let shop_id: u32 = match words[SHOP_ID_OFFLINE].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        Some(Action::OfflineEnd(shop_id))
+    }
+
+    fn parse_offline_ack(words: Vec<&str>) -> Option<Action> {
+        if words.len() != 3 {
+            return None;
+        }
+        let shop_id: u32 = match words[SHOP_ID_OFFLINE].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let reconciled: usize = match words[RECONCILED].parse::<usize>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        Some(Action::OfflineAck(shop_id, reconciled))
+    }
+
+    /// Parses grant, deduct and refund, whose amount is the points or the order id.
+    fn parse_admin(words: Vec<&str>) -> Option<Action> {
+        let words = metadata::strip(&words);
+        if words.len() <= REASON {
+            return None;
+        }
+        let ids = MessageParser::parse_admin_ids(&words)?;
+        let reason = MessageParser::parse_reason(&words[REASON..])?;
+        let [client_id, amount, shop_id, operator] = ids;
+        match words[TYPE] {
+            "grant" => Some(Action::Grant(client_id, amount, shop_id, operator, reason)),
+            "deduct" => Some(Action::Deduct(client_id, amount, shop_id, operator, reason)),
+            _ => Some(Action::Refund(client_id, amount, shop_id, operator, reason)),
+        }
+    }
+
+    fn parse_reverse(words: Vec<&str>) -> Option<Action> {
+        let words = metadata::strip(&words);
+        if words.len() <= REVERSE_REASON {
+            return None;
+        }
+        let ids = MessageParser::parse_admin_ids(&words)?;
+        let points: i32 = match words[REVERSE_POINTS].parse::<i32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let reason = MessageParser::parse_reason(&words[REVERSE_REASON..])?;
+        let [client_id, order_id, shop_id, operator] = ids;
+        Some(Action::Reverse(
+            client_id, order_id, shop_id, operator, points, reason,
+        ))
+    }
+
+    /// Returns the client, the points or order, the shop and the operator of an admin operation.
+    fn parse_admin_ids(words: &[&str]) -> Option<[u32; 4]> {
+        let mut ids = [0u32; 4];
+        for (i, index) in [CLIENT_ID, AMOUNT, SHOP_ID_ADMIN, OPERATOR]
+            .into_iter()
+            .enumerate()
+        {
+            match words[index].parse::<u32>() {
+                Ok(id) => ids[i] = id,
+                Err(_) => return None,
+            }
+        }
+        Some(ids)
+    }
+
+    /// The reason of an admin operation can not contain the separator of the log entries.
+    fn parse_reason(words: &[&str]) -> Option<String> {
+        let reason = words.join(" ");
+        if reason.trim().is_empty() || reason.contains(ENTRY_SEPARATOR) {
+            return None;
+        }
+        Some(reason)
+    }
+
+    fn parse_offline_redeem(words: Vec<&str>) -> Option<Action> {
+        let words = metadata::strip(&words);
+        if words.len() != 4 {
+            return None;
+        }
+        let client_id: u32 = match words[CLIENT_ID].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let points: u32 = match words[PRICE].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let shop_id: u32 = match words[SHOP_ID_REDEEM].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        Some(Action::OfflineRedeem(client_id, points, shop_id))
+    }
+
+    fn parse_sync_chunk(words: Vec<&str>) -> Option<Action> {
+        if words.len() < 3 {
+            return None;
         }
-        Ok(Action::SyncEnd)
+        let start: u64 = match words[INDEX].parse::<u64>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let entries: Vec<String> = words[ENTRIES..]
+            .join(" ")
+            .split(ENTRY_SEPARATOR)
+            .map(|entry| entry.to_string())
+            .collect();
+        Some(Action::SyncChunk(start, entries))
     }
 
-    fn parser_sync(words: Vec<&str>) -> Result<Action, Error> {
+    fn parse_sync_end(words: Vec<&str>) -> Option<Action> {
         if words.len() != 2 {
-            return Err(Error::InvalidMessageFormat);
+            return None;
         }
-        let s: &str = words[CLIENT_ID];
-        let client_id: u32 = match s.parse::<u32>() {
+        let index: u64 = match words[INDEX].parse::<u64>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        Some(Action::SyncEnd(index))
+    }
+
+    fn parse_snapshot(words: Vec<&str>) -> Option<Action> {
+        if words.len() != 4 && words.len() != 5 {
+            return None;
+        }
+        let index: u64 = match words[INDEX].parse::<u64>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let part: u32 = match words[PART].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let parts: u32 = match words[PARTS].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let accounts = parse_accounts(words.get(ACCOUNTS).unwrap_or(&""))?;
+        if part >= parts {
+            return None;
+        }
+        Some(Action::Snapshot(index, part, parts, accounts))
+    }
+
+    fn parse_snapshot_request(words: Vec<&str>) -> Option<Action> {
+        if words.len() != 3 {
+            return None;
+        }
+        let index: u64 = match words[INDEX].parse::<u64>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let part: u32 = match words[PART].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        Some(Action::SnapshotRequest(index, part))
+    }
+
+    fn parse_history(words: Vec<&str>) -> Option<Action> {
+        if words.len() < 2 || words.len() > 4 {
+            return None;
+        }
+        let client_id: u32 = match words[CLIENT_ID].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let mut range = [None, None];
+        for (i, index) in [FROM, TO].into_iter().enumerate() {
+            if let Some(word) = words.get(index) {
+                match word.parse::<u64>() {
+                    Ok(time) => range[i] = Some(time),
+                    Err(_) => return None,
+                }
+            }
+        }
+        Some(Action::History(client_id, range[0], range[1]))
+    }
+
+    fn parse_history_part(words: Vec<&str>) -> Option<Action> {
+        if words.len() != 4 && words.len() != 5 {
+            return None;
+        }
+        let client_id: u32 = match words[CLIENT_ID].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let part: u32 = match words[PART].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let parts: u32 = match words[PARTS].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        if part >= parts {
+            return None;
+        }
+        let mut entries = vec![];
+        if let Some(text) = words.get(HISTORY_ENTRIES) {
+            for entry in text.split(ENTRY_SEPARATOR) {
+                entries.push(HistoryEntry::from_text(client_id, entry).ok()?);
+            }
+        }
+        Some(Action::HistoryPart(client_id, part, parts, entries))
+    }
+
+    fn parser_sync(words: Vec<&str>) -> Option<Action> {
+        if words.len() != 2 {
+            return None;
+        }
+        let index: u64 = match words[INDEX].parse::<u64>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        Some(Action::Sync(index))
+    }
+
+    /// The transfer of the leadership carries the address of the operator
+    /// when a server forwards it to the leader.
+    fn parse_transfer(words: Vec<&str>) -> Option<Action> {
+        let words = metadata::strip(&words);
+        if words.len() != 2 {
+            return None;
+        }
+        let target: u32 = match words[TARGET].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        Some(Action::Transfer(target))
+    }
+
+    fn parse_takeover(words: Vec<&str>) -> Option<Action> {
+        if words.len() != 2 {
+            return None;
+        }
+        let index: u64 = match words[INDEX].parse::<u64>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        Some(Action::TakeOver(index))
+    }
+
+    fn parse_alive(words: Vec<&str>) -> Option<Action> {
+        if words.len() != 2 {
+            return None;
+        }
+        let shop_id: u32 = match words[SHOP_ID_ALIVE].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        Some(Action::Alive(shop_id))
+    }
+
+    fn parse_partition_sync(words: Vec<&str>) -> Option<Action> {
+        if words.len() != 2 {
+            return None;
+        }
+        let partition: u32 = match words[PARTITION].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        Some(Action::PartitionSync(partition))
+    }
+
+    fn parse_partition_state(words: Vec<&str>) -> Option<Action> {
+        if words.len() != 4 && words.len() != 5 {
+            return None;
+        }
+        let partition: u32 = match words[PARTITION].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let part: u32 = match words[PART].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let parts: u32 = match words[PARTS].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let accounts = parse_accounts(words.get(ACCOUNTS).unwrap_or(&""))?;
+        if part >= parts {
+            return None;
+        }
+        Some(Action::PartitionState(partition, part, parts, accounts))
+    }
+
+    fn parse_balance(words: Vec<&str>) -> Option<Action> {
+        let words = metadata::strip(&words);
+        if words.len() != 2 && words.len() != 3 {
+            return None;
+        }
+        let client_id: u32 = match words[CLIENT_ID].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let max_lag = match words.get(MAX_LAG) {
+            Some(word) => match word.parse::<u64>() {
+                Ok(lag) => Some(lag),
+                Err(_) => return None,
+            },
+            None => None,
+        };
+        Some(Action::Balance(client_id, max_lag))
+    }
+
+    fn parse_balance_state(words: Vec<&str>) -> Option<Action> {
+        if words.len() != 6 {
+            return None;
+        }
+        let client_id: u32 = match words[CLIENT_ID].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let points: i32 = match words[BALANCE_POINTS].parse::<i32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let blocked = match words[BALANCE_BLOCKED] {
+            "0" => false,
+            "1" => true,
+            _ => return None,
+        };
+        let index: u64 = match words[BALANCE_INDEX].parse::<u64>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let lag: u64 = match words[BALANCE_LAG].parse::<u64>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        Some(Action::BalanceState(client_id, points, blocked, index, lag))
+    }
+
+    fn parse_settle(words: Vec<&str>) -> Option<Action> {
+        let words = metadata::strip(&words);
+        if words.len() != 5 {
+            return None;
+        }
+        let client_id: u32 = match words[CLIENT_ID].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let points: u32 = match words[SETTLED].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let shop_id: u32 = match words[SHOP_ID_SETTLE].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let total: u64 = match words[SETTLED_TOTAL].parse::<u64>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        Some(Action::Settle(client_id, points, shop_id, total))
+    }
+
+    fn parse_accruals(words: Vec<&str>) -> Option<Action> {
+        if words.len() != 2 {
+            return None;
+        }
+        Some(Action::Accruals(parse_counters(words[COUNTERS])?))
+    }
+
+    fn parse_digest(words: Vec<&str>) -> Option<Action> {
+        if words.len() != 3 {
+            return None;
+        }
+        let index: u64 = match words[INDEX].parse::<u64>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        Some(Action::Digest(index, parse_digests(words[DIGESTS])?))
+    }
+
+    fn parse_repair(words: Vec<&str>) -> Option<Action> {
+        let words = metadata::strip(&words);
+        if words.len() != 1 && words.len() != 2 {
+            return None;
+        }
+        let ranges = match words.get(RANGES) {
+            Some(ranges) => ranges
+                .split(',')
+                .map(|range| range.parse::<u32>().ok())
+                .collect::<Option<Vec<u32>>>()?,
+            None => vec![],
+        };
+        if ranges.iter().any(|range| *range >= DIGEST_RANGES) {
+            return None;
+        }
+        Some(Action::Repair(ranges))
+    }
+
+    fn parse_repair_state(words: Vec<&str>) -> Option<Action> {
+        match MessageParser::parse_snapshot(words)? {
+            Action::Snapshot(index, part, parts, accounts) => {
+                Some(Action::RepairState(index, part, parts, accounts))
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_state(words: Vec<&str>) -> Option<Action> {
+        let words = metadata::strip(&words);
+        if words.len() != 1 {
+            return None;
+        }
+        Some(Action::State)
+    }
+
+    fn parse_state_part(words: Vec<&str>) -> Option<Action> {
+        if words.len() != 6 && words.len() != 7 {
+            return None;
+        }
+        let shop_id: u32 = match words[SHOP_ID_STATE].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let index: u64 = match words[STATE_INDEX].parse::<u64>() {
             Ok(i) => i,
-            Err(_) => return Err(Error::InvalidMessageFormat),
+            Err(_) => return None,
         };
-        Ok(Action::Sync(client_id))
+        let status: ServerStatus = words[STATUS].parse().ok()?;
+        let part: u32 = match words[STATE_PART].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let parts: u32 = match words[STATE_PARTS].parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => return None,
+        };
+        let accounts = parse_accounts(words.get(STATE_ACCOUNTS).unwrap_or(&""))?;
+        if part >= parts {
+            return None;
+        }
+        Some(Action::StatePart(
+            shop_id, index, status, part, parts, accounts,
+        ))
     }
 
-    fn parser_ack(words: Vec<&str>) -> Result<Action, Error> {
+    fn parser_ack(words: Vec<&str>) -> Option<Action> {
+        let words = metadata::strip(&words);
         if words.len() != 1 {
-            return Err(Error::InvalidMessageFormat);
+            return None;
         }
-        Ok(Action::Ack)
+        Some(Action::Ack)
     }
 
-    fn parser_down(words: Vec<&str>) -> Result<Action, Error> {
+    fn parser_down(words: Vec<&str>) -> Option<Action> {
         if words.len() != 1 {
-            return Err(Error::InvalidMessageFormat);
+            return None;
         }
-        Ok(Action::Down)
+        Some(Action::Down)
     }
-    fn parser_up(words: Vec<&str>) -> Result<Action, Error> {
+    fn parser_up(words: Vec<&str>) -> Option<Action> {
         if words.len() != 1 {
-            return Err(Error::InvalidMessageFormat);
+            return None;
         }
-        Ok(Action::Up)
+        Some(Action::Up)
     }
 
-    fn parser_try(words: Vec<&str>) -> Result<Action, Error> {
+    fn parser_try(words: Vec<&str>) -> Option<Action> {
         if words.len() != 1 {
-            return Err(Error::InvalidMessageFormat);
+            return None;
         }
-        Ok(Action::Try)
+        Some(Action::Try)
     }
-    fn parse_block(words: Vec<&str>) -> Result<Action, Error> {
+    fn parse_block(words: Vec<&str>) -> Option<Action> {
+        let words = metadata::strip(&words);
         if words.len() != 3 {
-            return Err(Error::InvalidMessageFormat);
+            return None;
         }
         let s: &str = words[CLIENT_ID];
         let client_id: u32 = match s.parse::<u32>() {
             Ok(i) => i,
-            Err(_) => return Err(Error::InvalidMessageFormat),
+            Err(_) => return None,
         };
         let s: &str = words[SHOP_ID_BLOCK];
         let shop_id: u32 = match s.parse::<u32>() {
             Ok(i) => i,
-            Err(_) => return Err(Error::InvalidMessageFormat),
+            Err(_) => return None,
         };
 
-        Ok(Action::Block(client_id, shop_id))
+        Some(Action::Block(client_id, shop_id))
     }
 
-    fn parse_completion(words: Vec<&str>) -> Result<Action, Error> {
+    fn parse_completion(words: Vec<&str>) -> Option<Action> {
+        let words = metadata::strip(&words);
         if words.len() != 5 {
-            return Err(Error::InvalidMessageFormat);
+            return None;
         }
 
         let client_id: u32 = match words[CLIENT_ID].parse::<u32>() {
             Ok(i) => i,
-            Err(_) => return Err(Error::InvalidMessageFormat),
+            Err(_) => return None,
         };
         let price: u32 = match words[PRICE].parse::<u32>() {
             Ok(i) => i,
-            Err(_) => return Err(Error::InvalidMessageFormat),
+            Err(_) => return None,
         };
         let method: Method = match words[METHOD] {
             "cash" => Method::Cash,
             "points" => Method::Points,
-            _ => return Err(Error::InvalidMessageFormat),
+            _ => return None,
         };
         let shop_id: u32 = match words[SHOP_ID_COMPLETE].parse::<u32>() {
             Ok(i) => i,
-            Err(_) => return Err(Error::InvalidMessageFormat),
+            Err(_) => return None,
         };
-        Ok(Action::CompleteOrder(client_id, price, method, shop_id))
+        Some(Action::CompleteOrder(client_id, price, method, shop_id))
     }
 
-    fn parser_not_enough(words: Vec<&str>) -> Result<Action, Error> {
+    fn parser_not_enough(words: Vec<&str>) -> Option<Action> {
+        let words = metadata::strip(&words);
         if words.len() != 2 {
-            return Err(Error::InvalidMessageFormat);
+            return None;
         }
         let s: &str = words[CLIENT_ID];
         let client_id: u32 = match s.parse::<u32>() {
             Ok(i) => i,
-            Err(_) => return Err(Error::InvalidMessageFormat),
+            Err(_) => return None,
         };
-        Ok(Action::NotEnoughPoints(client_id))
+        Some(Action::NotEnoughPoints(client_id))
     }
 
-    fn parser_already_blocked(words: Vec<&str>) -> Result<Action, Error> {
+    fn parser_already_blocked(words: Vec<&str>) -> Option<Action> {
+        let words = metadata::strip(&words);
         if words.len() != 2 {
-            return Err(Error::InvalidMessageFormat);
+            return None;
         }
         let s: &str = words[CLIENT_ID];
         let client_id: u32 = match s.parse::<u32>() {
             Ok(i) => i,
-            Err(_) => return Err(Error::InvalidMessageFormat),
+            Err(_) => return None,
         };
-        Ok(Action::ClientAlreadyBlocked(client_id))
+        Some(Action::ClientAlreadyBlocked(client_id))
     }
 
-    fn parse_failure(words: Vec<&str>) -> Result<Action, Error> {
+    fn parse_failure(words: Vec<&str>) -> Option<Action> {
+        let words = metadata::strip(&words);
         if words.len() != 3 {
-            return Err(Error::InvalidMessageFormat);
+            return None;
         }
 
         let s: &str = words[CLIENT_ID];
         let client_id: u32 = match s.parse::<u32>() {
             Ok(i) => i,
-            Err(_) => return Err(Error::InvalidMessageFormat),
+            Err(_) => return None,
         };
 
         let s: &str = words[SHOP_ID_FAIL];
         let shop_id: u32 = match s.parse::<u32>() {
             Ok(i) => i,
-            Err(_) => return Err(Error::InvalidMessageFormat),
+            Err(_) => return None,
         };
-        Ok(Action::FailOrder(client_id, shop_id))
+        Some(Action::FailOrder(client_id, shop_id))
     }
 }
 
 #[cfg(test)]
 mod message_parser_tests {
     use super::*;
+    use crate::{
+        local_server::{
+            history::history_parts,
+            sync::{encode_chunk, snapshot_parts},
+        },
+        wire::{digests_to_text, Account, EntryKind, PNCounter},
+    };
+    use proptest::{collection::vec, option, prelude::*, sample::select};
 
     #[test]
     #[should_panic]
@@ -229,4 +767,671 @@ mod message_parser_tests {
         let s: String = "alreadyBlocked 123".to_string();
         MessageParser::parse(s).unwrap();
     }
+
+    #[test]
+    fn can_parse_offline() {
+        let s: String = "offline 0 10 0-10-1 15 complete 123 10 cash 0".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    fn can_parse_offline_ack() {
+        let s: String = "offlineAck 1 3".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        assert!(got == Action::OfflineAck(1, 3));
+    }
+
+    #[test]
+    fn can_parse_sync_chunk() {
+        let s: String = "SYNCCHUNK 3 block 123 0|fail 123 0".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        assert!(
+            got == Action::SyncChunk(3, vec!["block 123 0".to_string(), "fail 123 0".to_string()])
+        );
+    }
+
+    #[test]
+    fn can_parse_snapshot() {
+        let s: String = "SNAPSHOT 10 0 2 123:10:0,124:-5:1".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        assert!(got == Action::Snapshot(10, 0, 2, vec![(123, 10, false), (124, -5, true)]));
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_on_snapshot_part_out_of_range() {
+        let s: String = "SNAPSHOT 10 2 2".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    fn can_parse_offline_redeem() {
+        let s: String = "offlineRedeem 123 10 0".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    fn can_parse_history() {
+        let s: String = "history 123 100 200".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_on_history_with_invalid_range() {
+        let s: String = "history 123 abc".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    fn can_parse_history_part() {
+        let s: String = "HISTORY 123 0 1 100:accrual:10:0:4|200:redemption:-5:1:-".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    fn can_parse_admin_operations() {
+        for s in [
+            "grant 123 10 0 7 birthday gift",
+            "deduct 123 10 0 7 wrong accrual @reply=127.0.0.1:5000",
+            "refund 123 4 0 7 cold coffee",
+            "reverse 123 4 0 7 -10 cold coffee",
+        ] {
+            MessageParser::parse(s.to_string()).unwrap();
+        }
+    }
+
+    #[test]
+    fn can_parse_transfer() {
+        let s: String = "TRANSFER 2 @reply=127.0.0.1:5000".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        assert!(got == Action::Transfer(2));
+    }
+
+    #[test]
+    fn can_parse_takeover() {
+        let s: String = "TAKEOVER 57".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        assert!(got == Action::TakeOver(57));
+    }
+
+    #[test]
+    fn can_parse_alive() {
+        let s: String = "ALIVE 2".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        assert!(got == Action::Alive(2));
+    }
+
+    #[test]
+    fn can_parse_partition_state() {
+        let s: String = "PARTSTATE 7 1 3 123:10:0".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        assert!(got == Action::PartitionState(7, 1, 3, vec![(123, 10, false)]));
+        let s: String = "PARTSYNC 7".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        assert!(got == Action::PartitionSync(7));
+    }
+
+    #[test]
+    fn can_parse_balance() {
+        let s: String = "balance 123 @reply=127.0.0.1:5000".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        assert!(got == Action::Balance(123, None));
+        let s: String = "balance 123 5".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        assert!(got == Action::Balance(123, Some(5)));
+        let s: String = "BALANCE 123 -10 1 42 3".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        assert!(got == Action::BalanceState(123, -10, true, 42, 3));
+    }
+
+    #[test]
+    fn can_parse_answers_with_index() {
+        let s: String = "ACK @index=42".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        assert!(got == Action::Ack);
+        let s: String = "notEnough 123 @index=42".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        assert!(got == Action::NotEnoughPoints(123));
+    }
+
+    #[test]
+    fn can_parse_settle() {
+        let s: String = "settle 123 15 0 40 @trace=ab".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        assert!(got == Action::Settle(123, 15, 0, 40));
+    }
+
+    #[test]
+    fn can_parse_accruals() {
+        let s: String = "ACCRUALS 123:0/10/5;1/3/0,124:2/7/0".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        let mut first = PNCounter::new();
+        first.increment(0, 10);
+        first.decrement(0, 5);
+        first.increment(1, 3);
+        let mut second = PNCounter::new();
+        second.increment(2, 7);
+        assert!(got == Action::Accruals(vec![(123, first), (124, second)]));
+    }
+
+    #[test]
+    fn can_parse_anti_entropy_messages() {
+        let digests = vec![7; DIGEST_RANGES as usize];
+        let s = format!("DIGEST 42 {}", digests_to_text(&digests));
+        assert!(MessageParser::parse(s).unwrap() == Action::Digest(42, digests));
+
+        let s: String = "REPAIR 3,15".to_string();
+        assert!(MessageParser::parse(s).unwrap() == Action::Repair(vec![3, 15]));
+        let s: String = "REPAIR".to_string();
+        assert!(MessageParser::parse(s).unwrap() == Action::Repair(vec![]));
+
+        let s: String = "REPAIRSTATE 42 0 1 3:10:0,19:-5:1".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        assert!(got == Action::RepairState(42, 0, 1, vec![(3, 10, false), (19, -5, true)]));
+    }
+
+    #[test]
+    fn can_parse_state() {
+        let s: String = "state".to_string();
+        assert!(MessageParser::parse(s).unwrap() == Action::State);
+
+        let s: String = "STATE 1 42 follower 0 2 3:10:0,19:-5:1".to_string();
+        let got = MessageParser::parse(s).unwrap();
+        let accounts = vec![(3, 10, false), (19, -5, true)];
+        assert!(got == Action::StatePart(1, 42, ServerStatus::Follower, 0, 2, accounts));
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_on_state_with_unknown_status() {
+        let s: String = "STATE 1 42 primary 0 1".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_on_repair_of_unknown_range() {
+        let s = format!("REPAIR {}", DIGEST_RANGES);
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_on_accruals_with_invalid_counter() {
+        let s: String = "ACCRUALS 123:0/10".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_on_balance_with_invalid_blocked_flag() {
+        let s: String = "BALANCE 123 10 yes 42 0".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_on_partition_state_part_out_of_range() {
+        let s: String = "PARTSTATE 7 3 3".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_on_transfer_without_target() {
+        let s: String = "TRANSFER".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_on_admin_operation_without_reason() {
+        let s: String = "grant 123 10 0 7".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_on_reason_with_entry_separator() {
+        let s: String = "deduct 123 10 0 7 a|b".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    fn can_parse_complete_with_metadata() {
+        let s: String = "complete 123 10 cash 0 @machine=1".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_on_metadata_before_the_end() {
+        let s: String = "complete 123 @machine=1 10 cash 0".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_on_nested_offline() {
+        let s: String = "offline 0 10 0-10-1 15 offlineEnd 0".to_string();
+        MessageParser::parse(s).unwrap();
+    }
+
+    #[test]
+    fn does_not_panic_on_accruals_with_repeated_shop() {
+        let s: String = format!("ACCRUALS 1:0/1/0;0/{}/0", u64::MAX);
+
+        assert!(MessageParser::parse(s).is_err());
+    }
+
+    /// Returns the message the coffee machines and the servers send for the action,
+    /// written with the same encoders they use.
+    fn message(action: &Action) -> String {
+        let method = |method: &Method| match method {
+            Method::Cash => "cash",
+            Method::Points => "points",
+        };
+        match action {
+            Action::Block(client_id, shop_id) => format!("block {} {}", client_id, shop_id),
+            Action::CompleteOrder(client_id, price, payment, shop_id) => format!(
+                "complete {} {} {} {}",
+                client_id,
+                price,
+                method(payment),
+                shop_id
+            ),
+            Action::FailOrder(client_id, shop_id) => format!("fail {} {}", client_id, shop_id),
+            Action::ClientAlreadyBlocked(client_id) => format!("alreadyBlocked {}", client_id),
+            Action::NotEnoughPoints(client_id) => format!("notEnough {}", client_id),
+            Action::Ack => "ACK".to_string(),
+            Action::Try => "TRY".to_string(),
+            Action::Up => "UP".to_string(),
+            Action::Down => "DOWN".to_string(),
+            Action::Sync(index) => format!("SYNC {}", index),
+            Action::SyncChunk(start, entries) => encode_chunk(*start, entries).0,
+            Action::SyncEnd(index) => format!("SYNCEND {}", index),
+            Action::Snapshot(index, part, parts, accounts) => format!(
+                "SNAPSHOT {} {} {} {}",
+                index,
+                part,
+                parts,
+                snapshot_parts(accounts)[0]
+            ),
+            Action::SnapshotRequest(index, part) => format!("SYNCSNAP {} {}", index, part),
+            Action::Offline(shop_id, since, operation) => {
+                format!("offline {} {} {}", shop_id, since, operation.to_line())
+            }
+            Action::OfflineEnd(shop_id) => format!("offlineEnd {}", shop_id),
+            Action::OfflineAck(shop_id, reconciled) => {
+                format!("offlineAck {} {}", shop_id, reconciled)
+            }
+            Action::OfflineRedeem(client_id, points, shop_id) => {
+                format!("offlineRedeem {} {} {}", client_id, points, shop_id)
+            }
+            Action::History(client_id, from, to) => {
+                let range: Vec<String> = from.iter().chain(to).map(|t| t.to_string()).collect();
+                format!("history {} {}", client_id, range.join(" "))
+                    .trim_end()
+                    .to_string()
+            }
+            Action::HistoryPart(client_id, _, _, entries) => {
+                history_parts(*client_id, entries)[0].clone()
+            }
+            Action::Grant(client_id, amount, shop_id, operator, reason)
+            | Action::Deduct(client_id, amount, shop_id, operator, reason)
+            | Action::Refund(client_id, amount, shop_id, operator, reason) => {
+                let header = match action {
+                    Action::Grant(..) => "grant",
+                    Action::Deduct(..) => "deduct",
+                    _ => "refund",
+                };
+                format!(
+                    "{} {} {} {} {} {}",
+                    header, client_id, amount, shop_id, operator, reason
+                )
+            }
+            Action::Reverse(client_id, order_id, shop_id, operator, points, reason) => format!(
+                "reverse {} {} {} {} {} {}",
+                client_id, order_id, shop_id, operator, points, reason
+            ),
+            Action::Transfer(target) => format!("TRANSFER {}", target),
+            Action::TakeOver(index) => format!("TAKEOVER {}", index),
+            Action::Alive(shop_id) => format!("ALIVE {}", shop_id),
+            Action::PartitionSync(partition) => format!("PARTSYNC {}", partition),
+            Action::PartitionState(partition, part, parts, accounts) => format!(
+                "PARTSTATE {} {} {} {}",
+                partition,
+                part,
+                parts,
+                snapshot_parts(accounts)[0]
+            ),
+            Action::Balance(client_id, Some(max_lag)) => {
+                format!("balance {} {}", client_id, max_lag)
+            }
+            Action::Balance(client_id, None) => format!("balance {}", client_id),
+            Action::BalanceState(client_id, points, blocked, index, lag) => format!(
+                "BALANCE {} {} {} {} {}",
+                client_id, points, *blocked as u8, index, lag
+            ),
+            Action::Settle(client_id, points, shop_id, total) => {
+                format!("settle {} {} {} {}", client_id, points, shop_id, total)
+            }
+            Action::Accruals(counters) => {
+                let counters: Vec<String> = counters
+                    .iter()
+                    .map(|(client_id, counter)| format!("{}:{}", client_id, counter.to_text()))
+                    .collect();
+                format!("ACCRUALS {}", counters.join(","))
+            }
+            Action::Digest(index, digests) => {
+                format!("DIGEST {} {}", index, digests_to_text(digests))
+            }
+            Action::Repair(ranges) => {
+                let ranges: Vec<String> = ranges.iter().map(|r| r.to_string()).collect();
+                format!("REPAIR {}", ranges.join(","))
+                    .trim_end()
+                    .to_string()
+            }
+            Action::RepairState(index, part, parts, accounts) => format!(
+                "REPAIRSTATE {} {} {} {}",
+                index,
+                part,
+                parts,
+                snapshot_parts(accounts)[0]
+            ),
+            Action::State => "state".to_string(),
+            Action::StatePart(shop_id, index, status, part, parts, accounts) => format!(
+                "STATE {} {} {} {} {} {}",
+                shop_id,
+                index,
+                status,
+                part,
+                parts,
+                snapshot_parts(accounts)[0]
+            ),
+            Action::Update(..) => unreachable!("updates are not sent as messages"),
+        }
+    }
+
+    fn accounts() -> impl Strategy<Value = Vec<Account>> {
+        vec(any::<Account>(), 0..5)
+    }
+
+    /// A part and the amount of parts of a message split in parts.
+    fn part() -> impl Strategy<Value = (u32, u32)> {
+        (1u32..10).prop_flat_map(|parts| (0..parts, Just(parts)))
+    }
+
+    fn reason() -> impl Strategy<Value = String> {
+        "[a-z]{1,8}( [a-z]{1,8}){0,2}"
+    }
+
+    fn method() -> impl Strategy<Value = Method> {
+        prop_oneof![Just(Method::Cash), Just(Method::Points)]
+    }
+
+    fn order_action() -> impl Strategy<Value = Action> {
+        prop_oneof![
+            (any::<u32>(), any::<u32>()).prop_map(|(c, s)| Action::Block(c, s)),
+            (any::<u32>(), any::<u32>(), method(), any::<u32>())
+                .prop_map(|(c, p, m, s)| Action::CompleteOrder(c, p, m, s)),
+            (any::<u32>(), any::<u32>()).prop_map(|(c, s)| Action::FailOrder(c, s)),
+        ]
+    }
+
+    fn answer_action() -> impl Strategy<Value = Action> {
+        prop_oneof![
+            any::<u32>().prop_map(Action::ClientAlreadyBlocked),
+            any::<u32>().prop_map(Action::NotEnoughPoints),
+            Just(Action::Ack),
+            Just(Action::Try),
+            Just(Action::Up),
+            Just(Action::Down),
+            Just(Action::State),
+            (any::<u32>(), option::of(any::<u64>())).prop_map(|(c, l)| Action::Balance(c, l)),
+            (
+                any::<u32>(),
+                any::<i32>(),
+                any::<bool>(),
+                any::<u64>(),
+                any::<u64>()
+            )
+                .prop_map(|(c, p, b, i, l)| Action::BalanceState(c, p, b, i, l)),
+        ]
+    }
+
+    fn sync_action() -> impl Strategy<Value = Action> {
+        prop_oneof![
+            any::<u64>().prop_map(Action::Sync),
+            (
+                any::<u64>(),
+                vec("[a-z0-9]{1,6}( [a-z0-9]{1,6}){0,2}", 1..4)
+            )
+                .prop_map(|(start, entries)| Action::SyncChunk(start, entries)),
+            any::<u64>().prop_map(Action::SyncEnd),
+            (any::<u64>(), part(), accounts())
+                .prop_map(|(i, (part, parts), a)| Action::Snapshot(i, part, parts, a)),
+            (any::<u64>(), any::<u32>()).prop_map(|(i, p)| Action::SnapshotRequest(i, p)),
+            any::<u32>().prop_map(Action::Transfer),
+            any::<u64>().prop_map(Action::TakeOver),
+            any::<u32>().prop_map(Action::Alive),
+            any::<u32>().prop_map(Action::PartitionSync),
+            (any::<u32>(), part(), accounts())
+                .prop_map(|(p, (part, parts), a)| Action::PartitionState(p, part, parts, a)),
+        ]
+    }
+
+    fn offline_action() -> impl Strategy<Value = Action> {
+        prop_oneof![
+            (
+                any::<u32>(),
+                any::<u64>(),
+                "[0-9]{1,3}-[0-9]{1,3}-[0-9]{1,3}",
+                any::<u64>()
+            )
+                .prop_flat_map(|(shop_id, since, id, timestamp)| {
+                    order_action().prop_map(move |order| {
+                        let operation = OfflineOperation {
+                            id: id.clone(),
+                            timestamp,
+                            message: message(&order),
+                        };
+                        Action::Offline(shop_id, since, operation)
+                    })
+                }),
+            any::<u32>().prop_map(Action::OfflineEnd),
+            (any::<u32>(), any::<usize>()).prop_map(|(s, r)| Action::OfflineAck(s, r)),
+            (any::<u32>(), any::<u32>(), any::<u32>())
+                .prop_map(|(c, p, s)| Action::OfflineRedeem(c, p, s)),
+        ]
+    }
+
+    fn history_action() -> impl Strategy<Value = Action> {
+        let kind = select(vec![
+            EntryKind::Accrual,
+            EntryKind::Redemption,
+            EntryKind::Expiration,
+            EntryKind::Adjustment,
+        ]);
+        let entry = (
+            kind,
+            any::<i32>(),
+            option::of(any::<u32>()),
+            any::<u32>(),
+            any::<u64>(),
+        );
+        prop_oneof![
+            (
+                any::<u32>(),
+                option::of((any::<u64>(), option::of(any::<u64>())))
+            )
+                .prop_map(|(c, range)| match range {
+                    Some((from, to)) => Action::History(c, Some(from), to),
+                    None => Action::History(c, None, None),
+                }),
+            (any::<u32>(), vec(entry, 0..5)).prop_map(|(client, entries)| {
+                let entries = entries
+                    .into_iter()
+                    .map(|(kind, points, order, shop, timestamp)| HistoryEntry {
+                        client,
+                        kind,
+                        points,
+                        order,
+                        shop,
+                        timestamp,
+                    })
+                    .collect();
+                Action::HistoryPart(client, 0, 1, entries)
+            }),
+        ]
+    }
+
+    fn admin_action() -> impl Strategy<Value = Action> {
+        let ids = (any::<u32>(), any::<u32>(), any::<u32>(), any::<u32>());
+        prop_oneof![
+            (ids, reason()).prop_map(|((c, a, s, o), r)| Action::Grant(c, a, s, o, r)),
+            (ids, reason()).prop_map(|((c, a, s, o), r)| Action::Deduct(c, a, s, o, r)),
+            (ids, reason()).prop_map(|((c, a, s, o), r)| Action::Refund(c, a, s, o, r)),
+            (ids, any::<i32>(), reason())
+                .prop_map(|((c, o, s, op), p, r)| Action::Reverse(c, o, s, op, p, r)),
+        ]
+    }
+
+    fn replication_action() -> impl Strategy<Value = Action> {
+        let counter = vec((0u32..4, any::<u32>(), any::<u32>()), 0..4).prop_map(|totals| {
+            let mut counter = PNCounter::new();
+            for (shop, increment, decrement) in totals {
+                counter.increment(shop, increment as u64);
+                counter.decrement(shop, decrement as u64);
+            }
+            counter
+        });
+        let status = select(vec![
+            ServerStatus::Leader,
+            ServerStatus::Follower,
+            ServerStatus::Down,
+            ServerStatus::Syncing,
+            ServerStatus::Partitioned,
+        ]);
+        prop_oneof![
+            (any::<u32>(), any::<u32>(), any::<u32>(), any::<u64>())
+                .prop_map(|(c, p, s, t)| Action::Settle(c, p, s, t)),
+            vec((any::<u32>(), counter), 0..4).prop_map(Action::Accruals),
+            (any::<u64>(), vec(any::<u64>(), DIGEST_RANGES as usize))
+                .prop_map(|(i, d)| Action::Digest(i, d)),
+            vec(0..DIGEST_RANGES, 0..4).prop_map(Action::Repair),
+            (any::<u64>(), part(), accounts())
+                .prop_map(|(i, (part, parts), a)| Action::RepairState(i, part, parts, a)),
+            (any::<u32>(), any::<u64>(), status, part(), accounts()).prop_map(
+                |(s, i, status, (part, parts), a)| Action::StatePart(s, i, status, part, parts, a)
+            ),
+        ]
+    }
+
+    fn action() -> impl Strategy<Value = Action> {
+        prop_oneof![
+            order_action(),
+            answer_action(),
+            sync_action(),
+            offline_action(),
+            history_action(),
+            admin_action(),
+            replication_action(),
+        ]
+    }
+
+    /// Headers of every message, so the random messages reach each parser.
+    const HEADERS: &[&str] = &[
+        "block",
+        "complete",
+        "ACK",
+        "notEnough",
+        "alreadyBlocked",
+        "fail",
+        "TRY",
+        "DOWN",
+        "UP",
+        "SYNC",
+        "SYNCCHUNK",
+        "SYNCEND",
+        "SNAPSHOT",
+        "SYNCSNAP",
+        "offline",
+        "offlineEnd",
+        "offlineAck",
+        "offlineRedeem",
+        "history",
+        "HISTORY",
+        "grant",
+        "deduct",
+        "refund",
+        "reverse",
+        "TRANSFER",
+        "TAKEOVER",
+        "ALIVE",
+        "PARTSYNC",
+        "PARTSTATE",
+        "balance",
+        "BALANCE",
+        "settle",
+        "ACCRUALS",
+        "DIGEST",
+        "REPAIR",
+        "REPAIRSTATE",
+        "state",
+        "STATE",
+    ];
+
+    /// Words that look like the fields of the messages: numbers, accounts, counters,
+    /// history entries, ids of offline operations and metadata.
+    fn word() -> impl Strategy<Value = String> {
+        prop_oneof![
+            "[0-9]{1,20}",
+            "-?[0-9]{1,3}",
+            "[0-9a-z@=:,|;./-]{0,8}",
+            "[0-9]{1,3}:-?[0-9]{1,3}:[0-2](,[0-9]{1,3}:-?[0-9]{1,3}:[0-2]){0,2}",
+            "[0-9]{1,2}:([0-9]/[0-9]{1,20}/[0-9]{1,20};?){1,3}",
+            "[0-9]{1,3}:[a-z]{1,10}:-?[0-9]{1,3}:[0-9]:[0-9-]",
+            "[0-9]{1,3}-[0-9]{1,3}-[0-9]",
+            "@[a-z]{1,7}=[0-9]{1,3}",
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn can_parse_serialized_actions(action in action()) {
+            let parsed = MessageParser::parse(message(&action));
+
+            prop_assert_eq!(parsed, Ok(action));
+        }
+
+        #[test]
+        fn can_parse_serialized_actions_with_metadata(action in order_action(), machine in any::<u32>()) {
+            let message = metadata::with(&message(&action), metadata::MACHINE, &machine.to_string());
+
+            prop_assert_eq!(MessageParser::parse(message), Ok(action));
+        }
+
+        #[test]
+        fn does_not_panic_on_truncated_messages(action in action(), kept in any::<prop::sample::Index>()) {
+            let message = message(&action);
+            let words: Vec<&str> = message.split(' ').collect();
+            let kept = kept.index(words.len()).max(1);
+
+            let _ = MessageParser::parse(words[..kept].join(" "));
+        }
+
+        #[test]
+        fn does_not_panic_on_random_words(header in select(HEADERS), words in vec(word(), 0..8)) {
+            let message = std::iter::once(header.to_string()).chain(words).collect::<Vec<String>>();
+
+            let _ = MessageParser::parse(message.join(" "));
+        }
+
+        #[test]
+        fn does_not_panic_on_arbitrary_input(bytes in vec(any::<u8>(), 0..64)) {
+            let _ = MessageParser::parse(String::from_utf8_lossy(&bytes).into_owned());
+        }
+    }
 }
@@ -3,13 +3,28 @@ use std::{
     sync::Arc,
     time::Duration,
 };
+use tracing::{debug, warn};
 
-use crate::{action::Action, errors::Error, message_parser::MessageParser};
+use crate::{
+    action::Action,
+    auth::Signer,
+    constants::MESSAGE_BYTES,
+    errors::{Error, OrderError, TransportError},
+    logging::SENDER,
+    message_parser::MessageParser,
+    metrics::{
+        message_type, registry, MESSAGES_RECEIVED, MESSAGES_SENT, PARSE_FAILURES, SENDER_RETRIES,
+        SENDER_TIMEOUTS,
+    },
+};
 pub const ACK: &str = "ACK";
 
 pub struct MessageSender {}
 
 impl MessageSender {
+    /// Sends the message to the server, signed again on every attempt so the server
+    /// does not take the retries for replays, and waits for its answer.
+    /// Returns false if no attempt got an answer, the message is then taken as acknowledged.
     pub fn send(
         socket: Arc<UdpSocket>,
         addr: SocketAddr,
@@ -17,38 +32,54 @@ impl MessageSender {
         attempts: Option<usize>,
         timeout: Option<Duration>,
         id: u32,
-    ) -> Result<(), Error> {
+        signer: &Signer,
+    ) -> Result<bool, Error> {
         let mut attempts = set_attempts(attempts);
         let timeout = set_duration(timeout);
         set_read_timeout(&socket, timeout)?;
 
-        let mut buf = [0u8; 1024];
+        let machine = id.to_string();
+        let mut buf = [0u8; MESSAGE_BYTES];
+        let mut sent = false;
         while attempts > 0 {
             attempts -= 1;
-            send_message(&socket, message.clone(), addr, id)?;
+            if sent {
+                registry().inc(&SENDER_RETRIES, &[("machine", &machine)]);
+            }
+            send_message(&socket, signer.sign(&message), addr, id)?;
+            sent = true;
             match socket.recv_from(&mut buf) {
                 Ok((size, _from)) => {
-                    let message = String::from_utf8_lossy(&buf[..size]);
-                    println!("[COFFEE MACHINE {}]: get {}", id, message);
-                    if let Ok(received) = MessageParser::parse(message.into_owned()) {
+                    let message = String::from_utf8_lossy(&buf[..size]).into_owned();
+                    debug!(target: SENDER, machine = id, "get {}", message);
+                    let labels = [("socket", "server"), ("type", message_type(&message))];
+                    registry().inc(&MESSAGES_RECEIVED, &labels);
+                    let parsed = MessageParser::parse(message.clone());
+                    if parsed.is_err() {
+                        registry().inc(&PARSE_FAILURES, &[("socket", "server")]);
+                    }
+                    if let Ok(received) = parsed {
                         match received {
-                            Action::NotEnoughPoints(_) => return Err(Error::NotEnoughPoints),
-                            Action::ClientAlreadyBlocked(_) => {
-                                return Err(Error::ClientAlreadyBlocked)
+                            Action::NotEnoughPoints(client_id) => {
+                                return Err(OrderError::NotEnoughPoints(client_id).into())
                             }
-                            Action::Ack => (),
-                            _ => return Err(Error::InvalidMessageFormat),
+                            Action::ClientAlreadyBlocked(client_id) => {
+                                return Err(OrderError::ClientAlreadyBlocked(client_id).into())
+                            }
+                            Action::Ack => return Ok(true),
+                            _ => return Err(OrderError::UnexpectedAnswer(message).into()),
                         }
                     }
                 }
                 Err(_) => {
-                    println!("[COFFEE MACHINE {}]: timeout", id);
+                    warn!(target: SENDER, machine = id, "timeout waiting for {}", addr);
+                    registry().inc(&SENDER_TIMEOUTS, &[("machine", &machine)]);
                     continue;
                 }
             };
         }
 
-        Ok(())
+        Ok(false)
     }
 }
 
@@ -58,17 +89,23 @@ fn send_message(
     addr: SocketAddr,
     id: u32,
 ) -> Result<(), Error> {
-    println!("[COFFEE MACHINE {}]: send {} to {}", id, message, addr);
+    debug!(target: SENDER, machine = id, to = %addr, "send {}", message);
+    let labels = [("socket", "server"), ("type", message_type(&message))];
+    registry().inc(&MESSAGES_SENT, &labels);
     match socket.send_to(message.as_bytes(), addr) {
         Ok(_) => Ok(()),
-        Err(_) => Err(Error::CantSendMessage),
+        Err(err) => Err(TransportError::CantSendMessage {
+            to: addr.to_string(),
+            cause: err.into(),
+        }
+        .into()),
     }
 }
 
 fn set_read_timeout(socket: &Arc<UdpSocket>, timeout: Duration) -> Result<(), Error> {
     match socket.set_read_timeout(Some(timeout)) {
         Ok(_) => Ok(()),
-        Err(_) => Err(Error::CantSetReadTimeout),
+        Err(err) => Err(TransportError::CantSetReadTimeout(err.into()).into()),
     }
 }
 
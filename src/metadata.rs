@@ -0,0 +1,97 @@
+/// Key of the metadata with the id of the coffee machine that sent an operation.
+pub const MACHINE: &str = "machine";
+
+/// Key of the metadata with the id of the order of an operation.
+pub const ORDER: &str = "order";
+
+/// Key of the metadata with the address the answer to an admin operation has to be sent to.
+pub const REPLY: &str = "reply";
+
+/// Key of the metadata with the primary that replicates a write of its partition.
+pub const PRIMARY: &str = "primary";
+
+/// Key of the metadata with the index of the log of the leader after a write.
+pub const INDEX: &str = "index";
+
+/// Key of the metadata with the id of an offline operation merged by the leader.
+pub const OFFLINE: &str = "offline";
+
+/// Prefix of the metadata tokens appended at the end of a message, like `@machine=1`.
+const PREFIX: char = '@';
+
+/// Returns the message with the metadata `key=value` appended.
+pub fn with(message: &str, key: &str, value: &str) -> String {
+    format!("{} {}{}={}", message, PREFIX, key, value)
+}
+
+/// Returns the message with the metadata `key=value`, replacing its previous value if it had one.
+pub fn set(message: &str, key: &str, value: &str) -> String {
+    let words: Vec<&str> = message.split(' ').collect();
+    let body = strip(&words);
+    let token = format!("{}{}=", PREFIX, key);
+    let kept: Vec<&str> = body
+        .iter()
+        .chain(
+            words[body.len()..]
+                .iter()
+                .filter(|word| !word.starts_with(&token)),
+        )
+        .copied()
+        .collect();
+    with(&kept.join(" "), key, value)
+}
+
+/// Returns the words of the message without its trailing metadata tokens.
+pub fn strip<'a>(words: &[&'a str]) -> Vec<&'a str> {
+    let end = words
+        .iter()
+        .rposition(|word| !is_metadata(word))
+        .map_or(0, |i| i + 1);
+    words[..end].to_vec()
+}
+
+/// Returns the value of the metadata `key` of the message.
+pub fn get(message: &str, key: &str) -> Option<String> {
+    message
+        .split(' ')
+        .rev()
+        .take_while(|word| is_metadata(word))
+        .filter_map(|word| word[1..].split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| value.to_string())
+}
+
+fn is_metadata(word: &str) -> bool {
+    word.starts_with(PREFIX) && word.contains('=')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test01_metadata_is_appended_and_read() {
+        let message = with("complete 123 10 cash 0", MACHINE, "1");
+
+        assert_eq!(message, "complete 123 10 cash 0 @machine=1");
+        assert_eq!(get(&message, MACHINE), Some("1".to_string()));
+        assert_eq!(get(&message, "trace"), None);
+    }
+
+    #[test]
+    fn test02_strip_only_removes_trailing_metadata() {
+        let words: Vec<&str> = "block 123 0 @machine=1 @a=b".split(' ').collect();
+
+        assert_eq!(strip(&words), vec!["block", "123", "0"]);
+        assert_eq!(strip(&["block", "@x", "0"]), vec!["block", "@x", "0"]);
+    }
+
+    #[test]
+    fn test03_set_replaces_the_previous_value() {
+        let message = with("fail 123 0 @machine=1", "span", "a");
+        let message = set(&message, "span", "b");
+
+        assert_eq!(message, "fail 123 0 @machine=1 @span=b");
+        assert_eq!(set("fail 123 0", MACHINE, "0"), "fail 123 0 @machine=0");
+    }
+}
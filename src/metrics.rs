@@ -0,0 +1,392 @@
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener},
+    sync::{Mutex, OnceLock},
+    thread,
+};
+
+use crate::errors::{Error, TransportError};
+
+/// Upper bounds in seconds of the buckets of every histogram.
+const BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+/// Name, description and type of a metric.
+#[derive(Debug)]
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind,
+}
+
+pub const MESSAGES_RECEIVED: Metric = Metric {
+    name: "tp2_messages_received_total",
+    help: "Messages received, by socket and type.",
+    kind: Kind::Counter,
+};
+pub const MESSAGES_SENT: Metric = Metric {
+    name: "tp2_messages_sent_total",
+    help: "Messages sent, by socket and type.",
+    kind: Kind::Counter,
+};
+pub const PARSE_FAILURES: Metric = Metric {
+    name: "tp2_parse_failures_total",
+    help: "Messages received that could not be parsed, by socket.",
+    kind: Kind::Counter,
+};
+pub const REJECTED_MESSAGES: Metric = Metric {
+    name: "tp2_rejected_messages_total",
+    help: "Messages rejected by the authentication or the roles, by socket and reason.",
+    kind: Kind::Counter,
+};
+pub const SENDER_RETRIES: Metric = Metric {
+    name: "tp2_sender_retries_total",
+    help: "Messages sent again by the coffee machines after a timeout, by machine.",
+    kind: Kind::Counter,
+};
+pub const SENDER_TIMEOUTS: Metric = Metric {
+    name: "tp2_sender_timeouts_total",
+    help: "Answers of the server not received in time by the coffee machines, by machine.",
+    kind: Kind::Counter,
+};
+pub const ELECTIONS: Metric = Metric {
+    name: "tp2_elections_total",
+    help: "Leader elections started by the server.",
+    kind: Kind::Counter,
+};
+pub const ELECTION_DURATION: Metric = Metric {
+    name: "tp2_election_duration_seconds",
+    help: "Time from the start of an election until the server knows the new leader.",
+    kind: Kind::Histogram,
+};
+pub const LEADER_SECONDS: Metric = Metric {
+    name: "tp2_leader_seconds_total",
+    help: "Time the server has been the leader.",
+    kind: Kind::Counter,
+};
+pub const BLOCKED_ACCOUNTS: Metric = Metric {
+    name: "tp2_blocked_accounts",
+    help: "Accounts blocked by an order in progress.",
+    kind: Kind::Gauge,
+};
+pub const SYNC_DURATION: Metric = Metric {
+    name: "tp2_sync_duration_seconds",
+    help: "Time to synchronize with the leader after being down, by result.",
+    kind: Kind::Histogram,
+};
+pub const LOG_ENTRIES: Metric = Metric {
+    name: "tp2_log_entries",
+    help: "Entries kept in the operation log file.",
+    kind: Kind::Gauge,
+};
+pub const LOG_BYTES: Metric = Metric {
+    name: "tp2_log_bytes",
+    help: "Size of the operation log file.",
+    kind: Kind::Gauge,
+};
+pub const PARTITIONS: Metric = Metric {
+    name: "tp2_partitions",
+    help: "Partitions of the accounts kept by the server, by role.",
+    kind: Kind::Gauge,
+};
+pub const REBALANCES: Metric = Metric {
+    name: "tp2_rebalances_total",
+    help: "Reassignments of the partitions after a shop joined or left.",
+    kind: Kind::Counter,
+};
+pub const READS: Metric = Metric {
+    name: "tp2_reads_total",
+    help: "Balance lookups answered by the server, by source.",
+    kind: Kind::Counter,
+};
+pub const READ_LAG: Metric = Metric {
+    name: "tp2_read_lag_entries",
+    help: "Entries of the log of the leader the server knows of and has not applied yet.",
+    kind: Kind::Gauge,
+};
+pub const LOCAL_ACCRUALS: Metric = Metric {
+    name: "tp2_local_accruals_total",
+    help: "Cash payments accrued by the server in its counter without going through the leader.",
+    kind: Kind::Counter,
+};
+pub const GOSSIP_MERGES: Metric = Metric {
+    name: "tp2_gossip_merges_total",
+    help: "Counters received by gossip from other servers, by whether they changed the local ones.",
+    kind: Kind::Counter,
+};
+pub const ANTI_ENTROPY_REPAIRS: Metric = Metric {
+    name: "tp2_anti_entropy_repairs_total",
+    help: "Repairs of the accounts or the log position requested after comparing digests with the leader.",
+    kind: Kind::Counter,
+};
+pub const ORDER_LATENCY: Metric = Metric {
+    name: "tp2_order_latency_seconds",
+    help: "Time to process an order, by coffee machine and result.",
+    kind: Kind::Histogram,
+};
+
+/// Values of a metric for one set of labels.
+#[derive(Debug, Clone, PartialEq)]
+enum Sample {
+    Value(f64),
+    Histogram {
+        buckets: [u64; BUCKETS.len()],
+        sum: f64,
+        count: u64,
+    },
+}
+
+#[derive(Debug)]
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    samples: BTreeMap<Vec<(String, String)>, Sample>,
+}
+
+/// Registry of the metrics of a process, rendered in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+}
+
+/// Returns the registry of the process.
+pub fn registry() -> &'static Metrics {
+    static REGISTRY: OnceLock<Metrics> = OnceLock::new();
+    REGISTRY.get_or_init(Metrics::default)
+}
+
+impl Metrics {
+    /// Adds one to a counter.
+    pub fn inc(&self, metric: &Metric, labels: &[(&str, &str)]) {
+        self.add(metric, labels, 1.0);
+    }
+
+    /// Adds the value to a counter or a gauge.
+    pub fn add(&self, metric: &Metric, labels: &[(&str, &str)], value: f64) {
+        self.update(metric, labels, |sample| {
+            if let Sample::Value(current) = sample {
+                *current += value;
+            }
+        });
+    }
+
+    /// Sets the value of a gauge, or of a counter kept by someone else.
+    pub fn set(&self, metric: &Metric, labels: &[(&str, &str)], value: f64) {
+        self.update(metric, labels, |sample| *sample = Sample::Value(value));
+    }
+
+    /// Adds an observation to a histogram.
+    pub fn observe(&self, metric: &Metric, labels: &[(&str, &str)], value: f64) {
+        self.update(metric, labels, |sample| {
+            if let Sample::Histogram {
+                buckets,
+                sum,
+                count,
+            } = sample
+            {
+                for (bucket, bound) in buckets.iter_mut().zip(BUCKETS) {
+                    if value <= bound {
+                        *bucket += 1;
+                    }
+                }
+                *sum += value;
+                *count += 1;
+            }
+        });
+    }
+
+    fn update(&self, metric: &Metric, labels: &[(&str, &str)], update: impl FnOnce(&mut Sample)) {
+        let mut families = match self.families.lock() {
+            Ok(families) => families,
+            Err(_) => return,
+        };
+        let family = families.entry(metric.name).or_insert_with(|| Family {
+            help: metric.help,
+            kind: metric.kind,
+            samples: BTreeMap::new(),
+        });
+        let labels = labels
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let sample = family.samples.entry(labels).or_insert(match metric.kind {
+            Kind::Histogram => Sample::Histogram {
+                buckets: [0; BUCKETS.len()],
+                sum: 0.0,
+                count: 0,
+            },
+            _ => Sample::Value(0.0),
+        });
+        update(sample);
+    }
+
+    /// Returns every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let families = match self.families.lock() {
+            Ok(families) => families,
+            Err(_) => return String::new(),
+        };
+        let mut text = String::new();
+        for (name, family) in families.iter() {
+            let kind = match family.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+                Kind::Histogram => "histogram",
+            };
+            text.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n",
+                name, family.help, name, kind
+            ));
+            for (labels, sample) in &family.samples {
+                match sample {
+                    Sample::Value(value) => {
+                        text.push_str(&format!("{}{} {}\n", name, render_labels(labels), value))
+                    }
+                    Sample::Histogram {
+                        buckets,
+                        sum,
+                        count,
+                    } => {
+                        for (bucket, bound) in buckets.iter().zip(BUCKETS) {
+                            let labels = with_label(labels, "le", &bound.to_string());
+                            text.push_str(&format!("{}_bucket{} {}\n", name, labels, bucket));
+                        }
+                        let labels_inf = with_label(labels, "le", "+Inf");
+                        text.push_str(&format!("{}_bucket{} {}\n", name, labels_inf, count));
+                        text.push_str(&format!("{}_sum{} {}\n", name, render_labels(labels), sum));
+                        text.push_str(&format!(
+                            "{}_count{} {}\n",
+                            name,
+                            render_labels(labels),
+                            count
+                        ));
+                    }
+                }
+            }
+        }
+        text
+    }
+}
+
+fn with_label(labels: &[(String, String)], key: &str, value: &str) -> String {
+    let mut labels = labels.to_vec();
+    labels.push((key.to_string(), value.to_string()));
+    render_labels(&labels)
+}
+
+fn render_labels(labels: &[(String, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Returns the type of a message, its first word, to label the metrics of messages.
+pub fn message_type(message: &str) -> &str {
+    message.split(' ').next().unwrap_or_default()
+}
+
+/// Serves the metrics of the registry over HTTP at `GET /metrics` on the given address.
+/// `refresh` runs before each scrape to update the gauges read from the state of the process.
+pub fn serve(addr: SocketAddr, refresh: impl Fn() + Send + 'static) -> Result<(), Error> {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(err) => {
+            return Err(TransportError::CantBindSocket {
+                addr: addr.to_string(),
+                cause: err.into(),
+            }
+            .into())
+        }
+    };
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut buf = [0u8; 1024];
+            let size = stream.read(&mut buf).unwrap_or(0);
+            let request = String::from_utf8_lossy(&buf[..size]);
+            let response = if request.starts_with("GET /metrics") {
+                refresh();
+                let body = registry().render();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string()
+            };
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test01_render_counters_and_gauges() {
+        let metrics = Metrics::default();
+        metrics.inc(
+            &MESSAGES_RECEIVED,
+            &[("socket", "operator"), ("type", "DOWN")],
+        );
+        metrics.inc(
+            &MESSAGES_RECEIVED,
+            &[("socket", "operator"), ("type", "DOWN")],
+        );
+        metrics.set(&BLOCKED_ACCOUNTS, &[], 3.0);
+
+        let text = metrics.render();
+
+        assert!(text.contains("# TYPE tp2_messages_received_total counter\n"));
+        assert!(text.contains("tp2_messages_received_total{socket=\"operator\",type=\"DOWN\"} 2\n"));
+        assert!(text.contains("# TYPE tp2_blocked_accounts gauge\ntp2_blocked_accounts 3\n"));
+    }
+
+    #[test]
+    fn test02_render_histograms() {
+        let metrics = Metrics::default();
+        metrics.observe(&ORDER_LATENCY, &[("machine", "1")], 0.3);
+        metrics.observe(&ORDER_LATENCY, &[("machine", "1")], 40.0);
+
+        let text = metrics.render();
+
+        assert!(text.contains("tp2_order_latency_seconds_bucket{machine=\"1\",le=\"0.25\"} 0\n"));
+        assert!(text.contains("tp2_order_latency_seconds_bucket{machine=\"1\",le=\"0.5\"} 1\n"));
+        assert!(text.contains("tp2_order_latency_seconds_bucket{machine=\"1\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("tp2_order_latency_seconds_sum{machine=\"1\"} 40.3\n"));
+        assert!(text.contains("tp2_order_latency_seconds_count{machine=\"1\"} 2\n"));
+    }
+
+    #[test]
+    fn test03_label_values_are_escaped() {
+        let metrics = Metrics::default();
+        metrics.inc(&PARSE_FAILURES, &[("socket", "a\"b")]);
+
+        assert!(metrics
+            .render()
+            .contains("tp2_parse_failures_total{socket=\"a\\\"b\"} 1\n"));
+    }
+}
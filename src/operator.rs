@@ -0,0 +1,139 @@
+use std::{
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use crate::{
+    action::Action,
+    auth::{operator_signer, Signer},
+    constants::{MESSAGE_BYTES, TIMEOUT},
+    errors::{Error, TransportError},
+    local_server::consistency::ServerState,
+    message_parser::MessageParser,
+    wire::Account,
+};
+
+/// Socket of the tools of the operators, which send signed messages to the control socket
+/// of the servers and wait for their answers.
+pub struct OperatorSocket {
+    socket: UdpSocket,
+    signer: Signer,
+}
+
+impl OperatorSocket {
+    /// Binds a socket in any port that signs the messages with the given signer
+    /// and waits at most `timeout` for each answer.
+    pub fn bind(signer: Signer, timeout: Duration) -> Result<OperatorSocket, Error> {
+        let socket = match UdpSocket::bind("127.0.0.1:0") {
+            Ok(socket) => socket,
+            Err(err) => {
+                return Err(TransportError::CantBindSocket {
+                    addr: "127.0.0.1:0".to_string(),
+                    cause: err.into(),
+                }
+                .into())
+            }
+        };
+        if let Err(err) = socket.set_read_timeout(Some(timeout)) {
+            return Err(TransportError::CantSetReadTimeout(err.into()).into());
+        }
+        Ok(OperatorSocket { socket, signer })
+    }
+
+    /// Signs the message and sends it to the given address.
+    pub fn send(&self, addr: SocketAddr, message: &str) -> Result<(), Error> {
+        match self
+            .socket
+            .send_to(self.signer.sign(message).as_bytes(), addr)
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(TransportError::CantSendMessage {
+                to: addr.to_string(),
+                cause: err.into(),
+            }
+            .into()),
+        }
+    }
+
+    /// Returns the next message received. Returns error if none arrives in time.
+    pub fn receive(&self) -> Result<String, Error> {
+        let mut buf = [0u8; MESSAGE_BYTES];
+        match self.socket.recv_from(&mut buf) {
+            Ok((size, _)) => Ok(String::from_utf8_lossy(&buf[..size]).into_owned()),
+            Err(_) => Err(TransportError::Timeout.into()),
+        }
+    }
+}
+
+/// Sends the message to the given address and returns the answer.
+/// The message is signed with the key of the operator.
+pub fn query(addr: SocketAddr, message: &str) -> Result<String, Error> {
+    let socket = OperatorSocket::bind(operator_signer()?, TIMEOUT)?;
+    socket.send(addr, message)?;
+    socket.receive()
+}
+
+/// Asks the server of the given address for its accounts, the index of its log and its status.
+pub fn request_state(addr: SocketAddr) -> Result<ServerState, Error> {
+    let socket = OperatorSocket::bind(operator_signer()?, TIMEOUT)?;
+    socket.send(addr, "state")?;
+
+    let mut state = None;
+    let mut parts: Vec<Option<Vec<Account>>> = vec![None];
+    while parts.iter().any(|part| part.is_none()) {
+        if let Ok(Action::StatePart(shop, index, status, part, amount, accounts)) =
+            MessageParser::parse(socket.receive()?)
+        {
+            state = Some((shop, index, status));
+            parts.resize(amount as usize, None);
+            if let Some(slot) = parts.get_mut(part as usize) {
+                *slot = Some(accounts);
+            }
+        }
+    }
+    match state {
+        Some((shop, index, status)) => Ok(ServerState {
+            shop,
+            index,
+            status,
+            accounts: parts.into_iter().flatten().flatten().collect(),
+        }),
+        None => Err(TransportError::Timeout.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::auth::{Authenticator, KeyId, KeyStore};
+
+    use super::*;
+
+    const KEYS: &str = "{\"operators\": {\"7\": \"operator-key\"}}";
+
+    #[test]
+    fn test01_answer_of_a_signed_message_is_received() {
+        let keys = KeyStore::from_json(KEYS).expect("The keys are invalid");
+        let signer = keys.signer(KeyId::Operator(7)).expect("There is no key");
+        let server = UdpSocket::bind("127.0.0.1:0").expect("Error binding the server");
+        let addr = server.local_addr().expect("Error reading the address");
+        let answer = thread::spawn(move || {
+            let mut buf = [0u8; MESSAGE_BYTES];
+            let (size, from) = server.recv_from(&mut buf).expect("Error receiving");
+            let message = String::from_utf8_lossy(&buf[..size]).into_owned();
+            let auth = Authenticator::new(keys);
+            let (_, message) = auth.verify(&message, |_| true).expect("Invalid signature");
+            server
+                .send_to(format!("{} ACK", message).as_bytes(), from)
+                .expect("Error answering");
+        });
+
+        let socket = OperatorSocket::bind(signer, TIMEOUT).expect("Error binding the socket");
+        socket.send(addr, "state").expect("Error sending");
+
+        assert_eq!(socket.receive(), Ok("state ACK".to_string()));
+        answer.join().expect("Error joining the server");
+        assert_eq!(socket.receive(), Err(TransportError::Timeout.into()));
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use crate::errors::Error;
+use crate::errors::{Error, OrderError};
 
 #[derive(Clone)]
 pub struct PointsHandler {
@@ -35,7 +35,7 @@ impl PointsHandler {
         if !current.1 {
             self.points.insert(client_id, (current.0, true));
         } else {
-            return Err(Error::UserAlreadyBlocked);
+            return Err(OrderError::ClientAlreadyBlocked(client_id).into());
         }
 
         Ok(())
@@ -48,18 +48,56 @@ impl PointsHandler {
         self.points.insert(client_id, (current.0, false));
     }
 
+    /// Returns the points of the client, zero if the account does not exist.
+    pub fn balance(&self, client_id: u32) -> i32 {
+        match self.points.get(&client_id) {
+            Some(info) => info.0,
+            None => 0,
+        }
+    }
+
     /// Updates the points associated with the client id.
-    /// Returns error If there are no enough points to subtract in the client account.
+    /// Returns error If there are no enough points to subtract in the client account
+    /// or the points do not fit in it.
     pub fn update_points(&mut self, client_id: u32, points: i32) -> Result<(), Error> {
         let current = self.clone().get_client(client_id);
-        let updated_points = current.0 + points;
-        if updated_points >= 0 {
+        let updated_points = match current.0.checked_add(points) {
+            Some(updated_points) => updated_points,
+            None => return Err(OrderError::PointsOutOfRange(client_id).into()),
+        };
+        if points >= 0 || updated_points >= 0 {
             self.points.insert(client_id, (updated_points, current.1));
         } else {
-            return Err(Error::NotEnoughPoints);
+            return Err(OrderError::NotEnoughPoints(client_id).into());
         }
         Ok(())
     }
+
+    /// Updates the points associated with the client id even if the balance becomes negative.
+    /// A negative balance is a debt of the client.
+    /// Returns error if the points do not fit in the client account.
+    pub fn force_update_points(&mut self, client_id: u32, points: i32) -> Result<(), Error> {
+        let current = self.clone().get_client(client_id);
+        match current.0.checked_add(points) {
+            Some(updated_points) => {
+                self.points.insert(client_id, (updated_points, current.1));
+                Ok(())
+            }
+            None => Err(OrderError::PointsOutOfRange(client_id).into()),
+        }
+    }
+
+    /// Returns the clients with a negative balance and their debt, sorted by client id.
+    pub fn debts(&self) -> Vec<(u32, u32)> {
+        let mut debts: Vec<(u32, u32)> = self
+            .points
+            .iter()
+            .filter(|(_, info)| info.0 < 0)
+            .map(|(client_id, info)| (*client_id, info.0.unsigned_abs()))
+            .collect();
+        debts.sort();
+        debts
+    }
 }
 
 impl Default for PointsHandler {
@@ -70,7 +108,11 @@ impl Default for PointsHandler {
 
 #[cfg(test)]
 mod tests {
-    use crate::errors::Error;
+    use std::collections::HashMap;
+
+    use proptest::{collection::vec, prelude::*};
+
+    use crate::errors::{Error, OrderError};
 
     use super::PointsHandler;
 
@@ -119,6 +161,177 @@ mod tests {
             .update_points(0, -15)
             .expect_err("Error when subtracting points");
 
-        assert_eq!(err_got, Error::NotEnoughPoints);
+        assert_eq!(err_got, Error::Order(OrderError::NotEnoughPoints(0)));
+    }
+
+    #[test]
+    pub fn test_05_force_subtract_points_leaves_a_debt() {
+        let mut client_points = PointsHandler::new();
+
+        client_points
+            .update_points(0, 10)
+            .expect("Error when adding points");
+        client_points
+            .force_update_points(0, -15)
+            .expect("Error when subtracting points");
+
+        assert_eq!(client_points.balance(0), -5);
+        assert_eq!(client_points.debts(), vec![(0, 5)]);
+    }
+
+    #[test]
+    pub fn test_06_add_points_to_client_with_debt() {
+        let mut client_points = PointsHandler::new();
+
+        client_points
+            .force_update_points(0, -15)
+            .expect("Error when subtracting points");
+        client_points
+            .update_points(0, 10)
+            .expect("Error when adding points");
+
+        assert_eq!(client_points.balance(0), -5);
+        assert!(client_points.update_points(0, -1).is_err());
+    }
+
+    /// Operation on the account of a client, as the server applies them.
+    #[derive(Debug, Clone)]
+    enum Operation {
+        Block(u32),
+        Unblock(u32),
+        Update(u32, i32),
+        ForceUpdate(u32, i32),
+    }
+
+    /// Operations on a few clients, so they often hit the same account.
+    /// Forced updates are left out when "force" is false.
+    fn operation(force: bool) -> impl Strategy<Value = Operation> {
+        let client = 0u32..4;
+        let points = -50i32..50;
+        let operations = prop_oneof![
+            client.clone().prop_map(Operation::Block),
+            client.clone().prop_map(Operation::Unblock),
+            (client.clone(), points.clone()).prop_map(|(c, p)| Operation::Update(c, p)),
+        ];
+        if !force {
+            return operations.boxed();
+        }
+        prop_oneof![
+            3 => operations,
+            1 => (client, points).prop_map(|(c, p)| Operation::ForceUpdate(c, p)),
+        ]
+        .boxed()
+    }
+
+    /// Reference model of the accounts: the points and the block of each client.
+    /// A rejected operation does not open the account of the client.
+    #[derive(Default)]
+    struct Model {
+        accounts: HashMap<u32, (i32, bool)>,
+    }
+
+    impl Model {
+        /// Applies the operation and returns false if the handler should reject it.
+        fn apply(&mut self, operation: &Operation) -> bool {
+            let client = match operation {
+                Operation::Block(c)
+                | Operation::Unblock(c)
+                | Operation::Update(c, _)
+                | Operation::ForceUpdate(c, _) => *c,
+            };
+            let (mut points, mut blocked) = self.accounts.get(&client).copied().unwrap_or_default();
+            match operation {
+                Operation::Block(_) if blocked => return false,
+                Operation::Block(_) => blocked = true,
+                Operation::Unblock(_) => blocked = false,
+                Operation::Update(_, change) | Operation::ForceUpdate(_, change)
+                    if points.checked_add(*change).is_none() =>
+                {
+                    return false
+                }
+                Operation::Update(_, change) if *change < 0 && points + change < 0 => return false,
+                Operation::Update(_, change) | Operation::ForceUpdate(_, change) => {
+                    points += change
+                }
+            }
+            self.accounts.insert(client, (points, blocked));
+            true
+        }
+    }
+
+    /// Applies the operation to the handler and returns false if it was rejected.
+    fn apply(handler: &mut PointsHandler, operation: &Operation) -> bool {
+        match operation {
+            Operation::Block(c) => handler.block(*c).is_ok(),
+            Operation::Unblock(c) => {
+                handler.unblock(*c);
+                true
+            }
+            Operation::Update(c, p) => handler.update_points(*c, *p).is_ok(),
+            Operation::ForceUpdate(c, p) => handler.force_update_points(*c, *p).is_ok(),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_07_random_operations_match_the_model(operations in vec(operation(true), 0..64)) {
+            let mut handler = PointsHandler::new();
+            let mut model = Model::default();
+
+            for operation in &operations {
+                prop_assert_eq!(apply(&mut handler, operation), model.apply(operation));
+            }
+            prop_assert_eq!(&handler.points, &model.accounts);
+            for (client, (points, _)) in &model.accounts {
+                prop_assert_eq!(handler.balance(*client), *points);
+            }
+        }
+
+        #[test]
+        fn test_08_balances_are_never_negative(operations in vec(operation(false), 0..64)) {
+            let mut handler = PointsHandler::new();
+
+            for operation in &operations {
+                apply(&mut handler, operation);
+                prop_assert!(handler.points.values().all(|(points, _)| *points >= 0));
+                prop_assert!(handler.debts().is_empty());
+            }
+        }
+
+        #[test]
+        fn test_09_blocks_are_exclusive(operations in vec(operation(true), 0..64), client in 0u32..4) {
+            let mut handler = PointsHandler::new();
+            for operation in &operations {
+                apply(&mut handler, operation);
+            }
+
+            let first = handler.block(client);
+            let second = handler.block(client);
+
+            prop_assert!(second.is_err());
+            if first.is_ok() {
+                handler.unblock(client);
+                prop_assert!(handler.block(client).is_ok());
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_10_points_out_of_range_are_rejected() {
+        let mut client_points = PointsHandler::new();
+
+        client_points
+            .update_points(0, i32::MAX - 5)
+            .expect("Error when adding points");
+        let added = client_points.update_points(0, 10);
+        let forced = client_points.force_update_points(0, 10);
+        client_points
+            .force_update_points(1, i32::MIN)
+            .expect("Error when subtracting points");
+
+        assert_eq!(added, Err(Error::Order(OrderError::PointsOutOfRange(0))));
+        assert_eq!(forced, Err(Error::Order(OrderError::PointsOutOfRange(0))));
+        assert_eq!(client_points.balance(0), i32::MAX - 5);
+        assert!(client_points.force_update_points(1, -1).is_err());
     }
 }
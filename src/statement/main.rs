@@ -0,0 +1,69 @@
+use std::{env, fs, process::ExitCode};
+
+use tp2::{
+    action::Action,
+    auth::operator_signer,
+    constants::TIMEOUT,
+    errors::{self, ConfigError, Error, StorageError},
+    local_server::{history::statement_csv, server::operator_addr},
+    message_parser::MessageParser,
+    operator::OperatorSocket,
+    wire::HistoryEntry,
+};
+
+const USAGE: &str = "statement <shop_id> <client_id> [from_ms] [to_ms]";
+
+/// Asks the server of the shop for the history of the client and returns its entries.
+fn request_history(shop_id: u32, query: String) -> Result<Vec<HistoryEntry>, Error> {
+    let socket = OperatorSocket::bind(operator_signer()?, TIMEOUT)?;
+    socket.send(operator_addr(shop_id), &query)?;
+
+    let mut parts: Vec<Option<Vec<HistoryEntry>>> = vec![None];
+    while parts.iter().any(|part| part.is_none()) {
+        if let Ok(Action::HistoryPart(_, part, amount, entries)) =
+            MessageParser::parse(socket.receive()?)
+        {
+            parts.resize(amount as usize, None);
+            if let Some(slot) = parts.get_mut(part as usize) {
+                *slot = Some(entries);
+            }
+        }
+    }
+    Ok(parts.into_iter().flatten().flatten().collect())
+}
+
+fn main() -> ExitCode {
+    errors::report(run())
+}
+
+fn run() -> Result<(), Error> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 || args.len() > 5 {
+        return Err(ConfigError::Usage(USAGE).into());
+    }
+    let (shop_id, client_id) = match (args[1].parse::<u32>(), args[2].parse::<u32>()) {
+        (Ok(shop_id), Ok(client_id)) => (shop_id, client_id),
+        _ => return Err(ConfigError::Usage(USAGE).into()),
+    };
+
+    let query = format!("history {}", args[2..].join(" "));
+    let entries = request_history(shop_id, query)?;
+
+    let csv = statement_csv(&entries);
+    print!("{}", csv);
+    let filename = format!("statement_{}.csv", client_id);
+    if let Err(err) = fs::write(&filename, csv) {
+        return Err(StorageError::CantWrite {
+            path: filename,
+            cause: err.into(),
+        }
+        .into());
+    }
+    println!(
+        "[STATEMENT]: {} entries of client {} exported to {}",
+        entries.len(),
+        client_id,
+        filename
+    );
+    Ok(())
+}
@@ -0,0 +1,260 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    sync::{Mutex, OnceLock},
+};
+
+use rand::Rng;
+use serde_json::{json, Value};
+
+use crate::{
+    clock::now_nanos,
+    errors::{ConfigError, Error},
+    metadata,
+};
+
+/// Key of the metadata with the id of the trace of a message, 32 hexadecimal digits.
+pub const TRACE: &str = "trace";
+
+/// Key of the metadata with the id of the span that sent a message, 16 hexadecimal digits.
+pub const SPAN: &str = "span";
+
+/// Kind of a span, as defined by OpenTelemetry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+/// Trace and span of the hop that sent a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+impl TraceContext {
+    /// Returns the trace context carried by the message, if it has one.
+    pub fn from_message(message: &str) -> Option<TraceContext> {
+        Some(TraceContext {
+            trace_id: metadata::get(message, TRACE)?,
+            span_id: metadata::get(message, SPAN)?,
+        })
+    }
+
+    /// Returns the message carrying this trace context, replacing the previous one.
+    pub fn inject(&self, message: &str) -> String {
+        let message = metadata::set(message, TRACE, &self.trace_id);
+        metadata::set(&message, SPAN, &self.span_id)
+    }
+}
+
+/// An operation of one hop of a trace, exported to the traces file when it ends.
+/// Spans of messages without trace context are not sampled: they are neither
+/// exported nor propagated.
+#[derive(Debug)]
+pub struct Span {
+    name: String,
+    kind: Kind,
+    context: Option<TraceContext>,
+    parent_id: Option<String>,
+    start: u64,
+    attributes: Vec<(String, String)>,
+    failed: bool,
+}
+
+impl Span {
+    /// Starts a new trace.
+    pub fn root(name: &str, kind: Kind) -> Span {
+        let trace_id = format!("{:032x}", rand::thread_rng().gen_range(1..=u128::MAX));
+        Span::start(name, kind, Some(trace_id), None)
+    }
+
+    /// Starts a span of the same trace as "parent".
+    pub fn child(name: &str, kind: Kind, parent: &TraceContext) -> Span {
+        Span::start(
+            name,
+            kind,
+            Some(parent.trace_id.clone()),
+            Some(parent.span_id.clone()),
+        )
+    }
+
+    /// Starts a span of the trace carried by the message.
+    /// The span is not sampled if the message has no trace context.
+    pub fn from_message(name: &str, kind: Kind, message: &str) -> Span {
+        match TraceContext::from_message(message) {
+            Some(parent) => Span::child(name, kind, &parent),
+            None => Span::start(name, kind, None, None),
+        }
+    }
+
+    fn start(name: &str, kind: Kind, trace_id: Option<String>, parent_id: Option<String>) -> Span {
+        let context = trace_id.map(|trace_id| TraceContext {
+            trace_id,
+            span_id: format!("{:016x}", rand::thread_rng().gen_range(1..=u64::MAX)),
+        });
+        Span {
+            name: name.to_string(),
+            kind,
+            context,
+            parent_id,
+            start: now_nanos(),
+            attributes: vec![],
+            failed: false,
+        }
+    }
+
+    /// Returns the context that the messages sent within this span carry.
+    pub fn context(&self) -> Option<&TraceContext> {
+        self.context.as_ref()
+    }
+
+    /// Returns the message carrying the context of this span, or the message as it is
+    /// if the span is not sampled.
+    pub fn inject(&self, message: &str) -> String {
+        match &self.context {
+            Some(context) => context.inject(message),
+            None => message.to_string(),
+        }
+    }
+
+    pub fn attribute(&mut self, key: &str, value: &str) {
+        self.attributes.push((key.to_string(), value.to_string()));
+    }
+
+    /// Marks the operation of the span as failed.
+    pub fn fail(&mut self) {
+        self.failed = true;
+    }
+
+    /// Ends the span and exports it.
+    pub fn end(self) {
+        if let (Some(exporter), Some(_)) = (EXPORTER.get(), &self.context) {
+            if let Ok(mut exporter) = exporter.lock() {
+                exporter.export(&self, now_nanos());
+            }
+        }
+    }
+
+    /// Returns the span in the JSON encoding of OTLP.
+    fn to_otlp(&self, end: u64) -> Value {
+        let (trace_id, span_id) = match &self.context {
+            Some(context) => (context.trace_id.as_str(), context.span_id.as_str()),
+            None => ("", ""),
+        };
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+            .collect();
+        json!({
+            "traceId": trace_id,
+            "spanId": span_id,
+            "parentSpanId": self.parent_id.clone().unwrap_or_default(),
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": self.start.to_string(),
+            "endTimeUnixNano": end.to_string(),
+            "attributes": attributes,
+            "status": {"code": if self.failed { 2 } else { 1 }},
+        })
+    }
+}
+
+/// Writes the spans of the process to its traces file, one OTLP/JSON request per line.
+struct Exporter {
+    service: String,
+    file: File,
+}
+
+impl Exporter {
+    fn export(&mut self, span: &Span, end: u64) {
+        let line = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{"key": "service.name", "value": {"stringValue": self.service}}]
+                },
+                "scopeSpans": [{"scope": {"name": "tp2"}, "spans": [span.to_otlp(end)]}]
+            }]
+        });
+        let _ = writeln!(self.file, "{}", line);
+    }
+}
+
+static EXPORTER: OnceLock<Mutex<Exporter>> = OnceLock::new();
+
+/// Exports the spans of the process, named "service", to the file at "path".
+/// Spans are not exported until this is called.
+pub fn init(service: &str, path: &str) -> Result<(), Error> {
+    let file = match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            return Err(ConfigError::CantWriteTraces {
+                path: path.to_string(),
+                cause: err.into(),
+            }
+            .into())
+        }
+    };
+    let exporter = Exporter {
+        service: service.to_string(),
+        file,
+    };
+    match EXPORTER.set(Mutex::new(exporter)) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(ConfigError::CantWriteTraces {
+            path: path.to_string(),
+            cause: io::Error::new(io::ErrorKind::AlreadyExists, "traces already exported").into(),
+        }
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test01_context_travels_in_the_message() {
+        let root = Span::root("order", Kind::Internal);
+        let message = root.inject("block 123 0 @machine=1");
+        let hop = Span::from_message("resend_message_to_leader", Kind::Server, &message);
+        let forwarded = hop.inject(&message);
+
+        let context = TraceContext::from_message(&forwarded).unwrap();
+        assert_eq!(context, hop.context().unwrap().clone());
+        assert_eq!(context.trace_id, root.context().unwrap().trace_id);
+        assert_eq!(hop.parent_id, Some(root.context().unwrap().span_id.clone()));
+        assert_eq!(forwarded.matches("@span=").count(), 1);
+    }
+
+    #[test]
+    fn test02_messages_without_context_are_not_sampled() {
+        let span = Span::from_message("process_action", Kind::Server, "TRY");
+
+        assert!(span.context().is_none());
+        assert_eq!(span.inject("TRY"), "TRY");
+    }
+
+    #[test]
+    fn test03_span_is_encoded_as_otlp() {
+        let root = Span::root("order", Kind::Internal);
+        let mut span = Span::child("send", Kind::Client, root.context().unwrap());
+        span.attribute("message.type", "block");
+        span.fail();
+
+        let otlp = span.to_otlp(span.start + 10);
+        assert_eq!(otlp["traceId"], root.context().unwrap().trace_id.as_str());
+        assert_eq!(
+            otlp["parentSpanId"],
+            root.context().unwrap().span_id.as_str()
+        );
+        assert_eq!(otlp["kind"], 3);
+        assert_eq!(otlp["attributes"][0]["value"]["stringValue"], "block");
+        assert_eq!(otlp["status"]["code"], 2);
+        assert_eq!(otlp["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(otlp["spanId"].as_str().unwrap().len(), 16);
+    }
+}
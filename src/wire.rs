@@ -0,0 +1,398 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{Error, ParseError};
+
+/// Separator of the entries of the SYNCCHUNK and HISTORY messages.
+pub const ENTRY_SEPARATOR: char = '|';
+
+/// Account of a snapshot: client id, points and whether it is blocked.
+pub type Account = (u32, i32, bool);
+
+/// Parses the accounts of a part of a snapshot.
+pub fn parse_accounts(part: &str) -> Option<Vec<Account>> {
+    let mut accounts = vec![];
+    for account in part.split(',').filter(|a| !a.is_empty()) {
+        let fields: Vec<&str> = account.split(':').collect();
+        if fields.len() != 3 {
+            return None;
+        }
+        let client_id = fields[0].parse::<u32>().ok()?;
+        let points = fields[1].parse::<i32>().ok()?;
+        let blocked = match fields[2] {
+            "0" => false,
+            "1" => true,
+            _ => return None,
+        };
+        accounts.push((client_id, points, blocked));
+    }
+    Some(accounts)
+}
+
+/// Role of a server when it answered the state of its accounts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerStatus {
+    Leader,
+    Follower,
+    Down,
+    Syncing,
+    /// The accounts are partitioned, the server only has the accounts of its partitions.
+    Partitioned,
+}
+
+impl fmt::Display for ServerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            ServerStatus::Leader => "leader",
+            ServerStatus::Follower => "follower",
+            ServerStatus::Down => "down",
+            ServerStatus::Syncing => "syncing",
+            ServerStatus::Partitioned => "partitioned",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+impl FromStr for ServerStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "leader" => Ok(ServerStatus::Leader),
+            "follower" => Ok(ServerStatus::Follower),
+            "down" => Ok(ServerStatus::Down),
+            "syncing" => Ok(ServerStatus::Syncing),
+            "partitioned" => Ok(ServerStatus::Partitioned),
+            _ => Err(ParseError::InvalidMessage(s.to_string()).into()),
+        }
+    }
+}
+
+/// Counter incremented and decremented by several shops at the same time, that converges
+/// when the states of the shops are merged. Each shop only changes its own totals,
+/// so merging keeps the highest total of each shop.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PNCounter {
+    increments: BTreeMap<u32, u64>,
+    decrements: BTreeMap<u32, u64>,
+}
+
+impl PNCounter {
+    /// Creates a counter with value zero.
+    pub fn new() -> PNCounter {
+        PNCounter::default()
+    }
+
+    /// Adds "amount" to the increments of the shop.
+    pub fn increment(&mut self, shop: u32, amount: u64) {
+        if amount == 0 {
+            return;
+        }
+        *self.increments.entry(shop).or_insert(0) += amount;
+    }
+
+    /// Adds "amount" to the decrements of the shop.
+    pub fn decrement(&mut self, shop: u32, amount: u64) {
+        if amount == 0 {
+            return;
+        }
+        *self.decrements.entry(shop).or_insert(0) += amount;
+    }
+
+    /// Returns the total decremented by the shop.
+    pub fn decrements_of(&self, shop: u32) -> u64 {
+        self.decrements.get(&shop).copied().unwrap_or(0)
+    }
+
+    /// Returns the sum of the increments minus the sum of the decrements of every shop.
+    /// Totals sent by a faulty shop can't make it overflow, the value saturates instead.
+    pub fn value(&self) -> i64 {
+        let sum = |totals: &BTreeMap<u32, u64>| {
+            totals
+                .values()
+                .fold(0i64, |sum, total| sum.saturating_add_unsigned(*total))
+        };
+        sum(&self.increments).saturating_sub(sum(&self.decrements))
+    }
+
+    /// Merges the state of another replica of the counter.
+    /// Returns true if the counter changed.
+    pub fn merge(&mut self, other: &PNCounter) -> bool {
+        let mut changed = false;
+        for (totals, others) in [
+            (&mut self.increments, &other.increments),
+            (&mut self.decrements, &other.decrements),
+        ] {
+            for (shop, total) in others.iter().filter(|(_, total)| **total > 0) {
+                let current = totals.entry(*shop).or_insert(0);
+                if *total > *current {
+                    *current = *total;
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+
+    /// Returns the counter as `shop/increments/decrements` for each shop, separated by `;`.
+    pub fn to_text(&self) -> String {
+        let mut shops: Vec<u32> = self
+            .increments
+            .keys()
+            .chain(self.decrements.keys())
+            .copied()
+            .collect();
+        shops.sort();
+        shops.dedup();
+        shops
+            .iter()
+            .map(|shop| {
+                let increments = self.increments.get(shop).copied().unwrap_or(0);
+                format!("{}/{}/{}", shop, increments, self.decrements_of(*shop))
+            })
+            .collect::<Vec<String>>()
+            .join(";")
+    }
+
+    /// Parses a counter written by [`PNCounter::to_text`], which has each shop once.
+    pub fn from_text(text: &str) -> Option<PNCounter> {
+        let mut counter = PNCounter::new();
+        let mut shops = vec![];
+        for totals in text.split(';').filter(|t| !t.is_empty()) {
+            let fields: Vec<&str> = totals.split('/').collect();
+            if fields.len() != 3 {
+                return None;
+            }
+            let shop = fields[0].parse::<u32>().ok()?;
+            if shops.contains(&shop) {
+                return None;
+            }
+            shops.push(shop);
+            counter.increment(shop, fields[1].parse::<u64>().ok()?);
+            counter.decrement(shop, fields[2].parse::<u64>().ok()?);
+        }
+        Some(counter)
+    }
+}
+
+/// Parses the counters of a part written by `Accruals::parts`.
+pub fn parse_counters(part: &str) -> Option<Vec<(u32, PNCounter)>> {
+    let mut counters = vec![];
+    for counter in part.split(',').filter(|c| !c.is_empty()) {
+        let (client_id, counter) = counter.split_once(':')?;
+        counters.push((
+            client_id.parse::<u32>().ok()?,
+            PNCounter::from_text(counter)?,
+        ));
+    }
+    Some(counters)
+}
+
+/// Ranges of client ids whose accounts are compared separately, so a divergence
+/// only repairs the accounts of the ranges that differ.
+pub const DIGEST_RANGES: u32 = 16;
+
+/// Returns the digests as they are sent in a DIGEST message, in hexadecimal separated by commas.
+pub fn digests_to_text(digests: &[u64]) -> String {
+    digests
+        .iter()
+        .map(|digest| format!("{:016x}", digest))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Parses the digests written by [`digests_to_text`].
+pub fn parse_digests(text: &str) -> Option<Vec<u64>> {
+    let digests = text
+        .split(',')
+        .map(|digest| u64::from_str_radix(digest, 16).ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if digests.len() != DIGEST_RANGES as usize {
+        return None;
+    }
+    Some(digests)
+}
+
+/// Kind of movement of the points of a client.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    /// Points earned paying an order with cash.
+    Accrual,
+    /// Points spent paying an order with points.
+    Redemption,
+    /// Points removed because they expired.
+    Expiration,
+    /// Points granted or deducted by an operator.
+    Adjustment,
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            EntryKind::Accrual => "accrual",
+            EntryKind::Redemption => "redemption",
+            EntryKind::Expiration => "expiration",
+            EntryKind::Adjustment => "adjustment",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+impl FromStr for EntryKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "accrual" => Ok(EntryKind::Accrual),
+            "redemption" => Ok(EntryKind::Redemption),
+            "expiration" => Ok(EntryKind::Expiration),
+            "adjustment" => Ok(EntryKind::Adjustment),
+            _ => Err(ParseError::InvalidLine(s.to_string()).into()),
+        }
+    }
+}
+
+/// A movement of the points of a client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub client: u32,
+    pub kind: EntryKind,
+    /// Points added to the balance, negative if they were taken from it.
+    pub points: i32,
+    pub order: Option<u32>,
+    pub shop: u32,
+    pub timestamp: u64,
+}
+
+impl HistoryEntry {
+    /// Returns the entry as it is sent in a HISTORY message: `timestamp:kind:points:shop:order`.
+    pub fn to_text(&self) -> String {
+        let order = match self.order {
+            Some(order) => order.to_string(),
+            None => "-".to_string(),
+        };
+        format!(
+            "{}:{}:{}:{}:{}",
+            self.timestamp, self.kind, self.points, self.shop, order
+        )
+    }
+
+    /// Parses an entry of a HISTORY message of the client.
+    pub fn from_text(client: u32, text: &str) -> Result<HistoryEntry, Error> {
+        let fields: Vec<&str> = text.split(':').collect();
+        if fields.len() != 5 {
+            return Err(ParseError::InvalidLine(text.to_string()).into());
+        }
+        let order = match fields[4] {
+            "-" => None,
+            order => match order.parse::<u32>() {
+                Ok(order) => Some(order),
+                Err(_) => return Err(ParseError::InvalidLine(text.to_string()).into()),
+            },
+        };
+        match (
+            fields[0].parse::<u64>(),
+            fields[1].parse::<EntryKind>(),
+            fields[2].parse::<i32>(),
+            fields[3].parse::<u32>(),
+        ) {
+            (Ok(timestamp), Ok(kind), Ok(points), Ok(shop)) => Ok(HistoryEntry {
+                client,
+                kind,
+                points,
+                order,
+                shop,
+                timestamp,
+            }),
+            _ => Err(ParseError::InvalidLine(text.to_string()).into()),
+        }
+    }
+}
+
+/// An operation accepted by a shop while it was down.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OfflineOperation {
+    pub id: String,
+    pub timestamp: u64,
+    pub message: String,
+}
+
+impl OfflineOperation {
+    /// Returns the line that represents the operation in the down log.
+    pub fn to_line(&self) -> String {
+        format!("{} {} {}", self.id, self.timestamp, self.message)
+    }
+
+    /// Parses an operation from its line in the down log.
+    pub fn from_line(line: &str) -> Result<OfflineOperation, Error> {
+        let words: Vec<&str> = line.split(' ').collect();
+        if words.len() < 3 {
+            return Err(ParseError::InvalidLine(line.to_string()).into());
+        }
+        let timestamp = match words[1].parse::<u64>() {
+            Ok(timestamp) => timestamp,
+            Err(_) => return Err(ParseError::InvalidLine(line.to_string()).into()),
+        };
+        Ok(OfflineOperation {
+            id: words[0].to_string(),
+            timestamp,
+            message: words[2..].join(" "),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test01_parse_offline_operation_line() {
+        let op = OfflineOperation {
+            id: "0-10-1".to_string(),
+            timestamp: 15,
+            message: "complete 123 10 cash 0".to_string(),
+        };
+
+        let got = OfflineOperation::from_line(&op.to_line()).expect("Invalid line");
+
+        assert_eq!(got, op);
+        assert!(OfflineOperation::from_line("0-10-1 abc complete").is_err());
+    }
+
+    #[test]
+    fn test02_replicas_converge_in_any_merge_order() {
+        let mut first = PNCounter::new();
+        let mut second = PNCounter::new();
+        first.increment(0, 10);
+        second.increment(1, 5);
+        second.decrement(1, 3);
+
+        let mut merged = first.clone();
+        assert!(merged.merge(&second));
+        assert!(second.merge(&first));
+        assert!(!merged.merge(&second));
+
+        assert_eq!(merged, second);
+        assert_eq!(merged.value(), 12);
+    }
+
+    #[test]
+    fn test03_counter_text_round_trip() {
+        let mut counter = PNCounter::new();
+        counter.increment(2, 30);
+        counter.decrement(0, 7);
+
+        assert_eq!(counter.to_text(), "0/0/7;2/30/0");
+        assert_eq!(PNCounter::from_text(&counter.to_text()), Some(counter));
+        assert_eq!(PNCounter::from_text("0/1"), None);
+        assert_eq!(
+            PNCounter::from_text(&format!("0/1/0;0/{}/0", u64::MAX)),
+            None
+        );
+
+        let huge = PNCounter::from_text(&format!("0/{}/0;1/{}/0", u64::MAX, u64::MAX));
+        assert_eq!(huge.map(|counter| counter.value()), Some(i64::MAX));
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use tp2::{
+    constants::{PROXY_OFFSET_VAR, RECORD_VAR},
+    linearizability::{check, read_history},
+    local_server::{consistency::ServerState, server::operator_addr},
+    operator::request_state,
+    wire::ServerStatus,
+};
+
+const SHOPS: u32 = 2;
+const LEADER: u32 = SHOPS - 1;
+const OFFSET: &str = "10000";
+const ELECTION_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_CASH_RUNS: usize = 5;
+
+/// Lost messages make the coffee machines wait for blocks that are never released,
+/// so the proxy only delays and reorders them.
+const FAULTS: &str = r#"{
+    "shops": 2,
+    "offset": 10000,
+    "partitions": [],
+    "rules": [
+        {"channel": "data", "reorder": 0.1, "jitter_ms": 20},
+        {"channel": "coffee_machine", "reorder": 0.1, "delay_ms": 10}
+    ]
+}"#;
+
+/// Processes of the cluster, killed when the test ends even if it fails.
+struct Cluster {
+    dir: PathBuf,
+    proxy: Child,
+    servers: Vec<Child>,
+}
+
+impl Cluster {
+    /// Starts the fault proxy and the servers in a new directory with the resources of the repo.
+    fn start(name: &str) -> Cluster {
+        let dir = env::temp_dir().join(format!("tp2_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("resources")).expect("Error creating the directory");
+        let resources = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources");
+        for entry in fs::read_dir(resources).expect("Error reading the resources") {
+            let path = entry.expect("Error reading the resources").path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                let file_name = path.file_name().expect("Invalid resource");
+                fs::copy(&path, dir.join("resources").join(file_name))
+                    .expect("Error copying the resources");
+            }
+        }
+        fs::write(dir.join("resources/faults.json"), FAULTS).expect("Error writing the faults");
+        for (file, cash_only) in [("cash_orders.json", true), ("lin_orders.json", false)] {
+            fs::write(dir.join("resources").join(file), orders(cash_only))
+                .expect("Error writing the orders");
+        }
+
+        let proxy = spawn(&dir, env!("CARGO_BIN_EXE_fault_proxy"), &["faults.json"]);
+        let mut cluster = Cluster {
+            dir,
+            proxy,
+            servers: vec![],
+        };
+        cluster.start_servers();
+        cluster
+    }
+
+    /// Starts the servers and waits until every one follows the leader.
+    fn start_servers(&mut self) {
+        for shop_id in 0..SHOPS {
+            let args = [shop_id.to_string(), SHOPS.to_string()];
+            let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+            let server = spawn(&self.dir, env!("CARGO_BIN_EXE_local_server"), &args);
+            self.servers.push(server);
+        }
+        let deadline = Instant::now() + ELECTION_TIMEOUT;
+        while !(0..SHOPS).all(|shop_id| match request_state(operator_addr(shop_id)) {
+            Ok(state) if shop_id == LEADER => state.status == ServerStatus::Leader,
+            Ok(state) => state.status == ServerStatus::Follower,
+            Err(_) => false,
+        }) {
+            assert!(
+                Instant::now() < deadline,
+                "The servers did not elect a leader"
+            );
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+
+    /// Kills the servers, which recover their accounts from their logs when started again.
+    fn crash_servers(&mut self) {
+        for mut server in self.servers.drain(..) {
+            let _ = server.kill();
+            let _ = server.wait();
+        }
+    }
+
+    /// Starts the coffee machines of the shop with the orders of the file.
+    fn run_coffee_machines(&self, shop_id: u32, orders: &str, record: bool) -> Child {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_coffee_machine"));
+        command
+            .args([orders, &shop_id.to_string()])
+            .current_dir(&self.dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if record {
+            command.env(RECORD_VAR, "1");
+        }
+        command.spawn().expect("Error starting the coffee machine")
+    }
+
+    fn leader_state(&self) -> ServerState {
+        request_state(operator_addr(LEADER)).expect("Error asking the leader for its state")
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        self.crash_servers();
+        let _ = self.proxy.kill();
+        let _ = self.proxy.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn spawn(dir: &Path, binary: &str, args: &[&str]) -> Child {
+    Command::new(binary)
+        .args(args)
+        .current_dir(dir)
+        .env(PROXY_OFFSET_VAR, OFFSET)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Error starting the process")
+}
+
+/// Orders of two clients, each one served by its own coffee machine of the shop.
+/// Unless they are all paid with cash, every other order is paid with points.
+fn orders(cash_only: bool) -> String {
+    let orders: Vec<String> = (0..4)
+        .map(|id| {
+            let method = if cash_only || id % 4 < 2 {
+                "cash"
+            } else {
+                "points"
+            };
+            format!(
+                "{{\"id\": {}, \"customer_id\": {}, \"price\": 5, \"payment_method\": \"{}\"}}",
+                id,
+                200 + id % 2,
+                method
+            )
+        })
+        .collect();
+    format!("[{}]", orders.join(", "))
+}
+
+#[test]
+fn test01_history_after_a_restart_is_linearizable() {
+    let mut cluster = Cluster::start("lincheck");
+    // Orders fail at random, so the accounts may still be empty after a run
+    for _ in 0..MAX_CASH_RUNS {
+        let status = cluster
+            .run_coffee_machines(0, "cash_orders.json", false)
+            .wait()
+            .expect("Error running the coffee machine");
+        assert!(status.success());
+        let accounts = cluster.leader_state().accounts;
+        if accounts.iter().any(|(_, points, _)| *points != 0) {
+            break;
+        }
+    }
+    cluster.crash_servers();
+    cluster.start_servers();
+
+    let accounts = cluster.leader_state().accounts;
+    let machines: Vec<Child> = (0..SHOPS)
+        .map(|shop_id| cluster.run_coffee_machines(shop_id, "lin_orders.json", true))
+        .collect();
+    for mut machine in machines {
+        let status = machine.wait().expect("Error running the coffee machine");
+        assert!(status.success());
+    }
+    let mut history = vec![];
+    for shop_id in 0..SHOPS {
+        let path = cluster
+            .dir
+            .join(format!("operations_coffee_machine_{}.jsonl", shop_id));
+        history.extend(read_history(&path.display().to_string()).expect("Error reading history"));
+    }
+
+    assert!(accounts.iter().any(|(_, points, _)| *points != 0));
+    assert!(!history.is_empty());
+    assert_eq!(check(&history, &accounts), vec![]);
+}